@@ -0,0 +1,147 @@
+//! Procedural macros for portus. Use them through their re-exports in `portus`.
+
+#![feature(box_patterns)]
+#![feature(proc_macro_span)]
+#![cfg_attr(test, feature(stmt_expr_attributes))]
+#![cfg_attr(test, feature(test))]
+
+extern crate bytes;
+extern crate fnv;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate nom;
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+// The datapath program compiler is shared with portus by path, since portus depends on this
+// crate to re-export its macros.
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/lang/mod.rs"]
+mod lang;
+mod locate;
+
+// The parts of `portus::serialize` which the compiler uses.
+mod serialize {
+    use bytes::{ByteOrder, LittleEndian};
+
+    pub(crate) fn u32_to_u8s(buf: &mut [u8], num: u32) {
+        LittleEndian::write_u32(buf, num);
+    }
+
+    pub(crate) fn u64_to_u8s(buf: &mut [u8], num: u64) {
+        LittleEndian::write_u64(buf, num);
+    }
+
+    pub(crate) fn u32_from_u8s(buf: &[u8]) -> u32 {
+        LittleEndian::read_u32(buf)
+    }
+
+    pub(crate) fn u64_from_u8s(buf: &[u8]) -> u64 {
+        LittleEndian::read_u64(buf)
+    }
+}
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::ops::Range;
+
+/// Compile a datapath program at build time, into a `portus::lang::Precompiled`.
+///
+/// The argument must be a string literal. If the program does not compile, the build fails with
+/// the compiler's error, pointing at the part of the literal the error is about if it can be
+/// found.
+#[proc_macro]
+pub fn program(input: TokenStream) -> TokenStream {
+    let lit = match literal(input) {
+        Some(lit) => lit,
+        None => return error(Span::call_site(), "program! takes a string literal"),
+    };
+
+    let src = match syn::parse::<syn::LitStr>(TokenTree::from(lit.clone()).into()) {
+        Ok(src) => src,
+        Err(_) => return error(lit.span(), "program! takes a string literal"),
+    };
+
+    let source = src.value();
+    let compiled = lang::compile(source.as_bytes(), &[]).and_then(|(bin, sc)| {
+        Ok((
+            bin.serialize()?,
+            bin.events.len() as u32,
+            bin.instrs.len() as u32,
+            sc.serialize()?,
+        ))
+    });
+
+    match compiled {
+        Ok((bin, num_events, num_instrs, scope)) => {
+            let bin = syn::LitByteStr::new(&bin, proc_macro2::Span::call_site());
+            let scope = syn::LitByteStr::new(&scope, proc_macro2::Span::call_site());
+            quote!(::portus::lang::Precompiled {
+                source: #src,
+                bin: #bin,
+                num_events: #num_events,
+                num_instrs: #num_instrs,
+                scope: #scope,
+            })
+            .into()
+        }
+        Err(e) => {
+            let span = locate::locate(&source, &e.0)
+                .and_then(|r| subspan(&lit, r))
+                .unwrap_or_else(|| lit.span());
+            error(span, &format!("datapath program does not compile: {}", e.0))
+        }
+    }
+}
+
+// The single literal in `input`, which may be wrapped in an invisible group if it was passed
+// through a `macro_rules!` macro.
+fn literal(input: TokenStream) -> Option<Literal> {
+    let mut tts = input.into_iter();
+    let lit = match tts.next()? {
+        TokenTree::Literal(lit) => lit,
+        TokenTree::Group(ref g) if g.delimiter() == Delimiter::None => literal(g.stream())?,
+        _ => return None,
+    };
+
+    match tts.next() {
+        None => Some(lit),
+        Some(_) => None,
+    }
+}
+
+// The span of `range` in the value of the string literal `lit`. Offsets in the value are only
+// offsets in the literal's source text if it has no escapes.
+fn subspan(lit: &Literal, range: Range<usize>) -> Option<Span> {
+    let text = lit.to_string();
+    let start = if text.starts_with('r') {
+        text.find('"')? + 1
+    } else if text.contains('\\') {
+        return None;
+    } else {
+        1
+    };
+
+    lit.subspan(start + range.start..start + range.end)
+}
+
+// `compile_error!("msg")`, reported at `span`.
+fn error(span: Span, msg: &str) -> TokenStream {
+    let mut lit = Literal::string(msg);
+    lit.set_span(span);
+    let mut args = Group::new(Delimiter::Parenthesis, TokenTree::from(lit).into());
+    args.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+
+    vec![
+        TokenTree::from(Ident::new("compile_error", span)),
+        TokenTree::from(bang),
+        TokenTree::from(args),
+    ]
+    .into_iter()
+    .collect()
+}
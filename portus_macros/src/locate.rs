@@ -0,0 +1,116 @@
+//! Find the part of a datapath program's source which a compile error is about.
+
+use std::ops::Range;
+
+/// The byte range in `src` which the compile error `msg` is about, if it can be found.
+pub fn locate(src: &str, msg: &str) -> Option<Range<usize>> {
+    position(src, msg).or_else(|| quoted_name(src, msg))
+}
+
+// Syntax errors end with "at line L, column C": the token which starts there.
+fn position(src: &str, msg: &str) -> Option<Range<usize>> {
+    let at = msg.rfind("at line ")?;
+    let mut parts = msg[at + "at line ".len()..].split(", column ");
+    let line: usize = parts.next()?.parse().ok()?;
+    let col: usize = parts.next()?.trim().parse().ok()?;
+    let line_start: usize = src
+        .split('\n')
+        .take(line.checked_sub(1)?)
+        .map(|l| l.len() + 1)
+        .sum();
+    let start = line_start + col.checked_sub(1)?;
+    if start >= src.len() || !src.is_char_boundary(start) {
+        return None;
+    }
+
+    let first = src[start..].chars().next()?.len_utf8();
+    let len = src[start + first..]
+        .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .unwrap_or_else(|| src.len() - start - first);
+    Some(start..start + first + len)
+}
+
+// Other errors usually quote the name they are about, e.g. `unknown macro: "plus"`: its first
+// use outside a comment.
+fn quoted_name(src: &str, msg: &str) -> Option<Range<usize>> {
+    let start = msg.find('"')? + 1;
+    let name = &msg[start..start + msg[start..].find('"')?];
+    if name.is_empty() {
+        return None;
+    }
+
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    let mut offset = 0;
+    for line in src.split('\n') {
+        let code = line.split('#').next().unwrap_or("");
+        for (i, _) in code.match_indices(name) {
+            let before = code[..i].chars().next_back();
+            let after = code[i + name.len()..].chars().next();
+            if !before.map_or(false, is_name_char) && !after.map_or(false, is_name_char) {
+                return Some(offset + i..offset + i + name.len());
+            }
+        }
+
+        offset += line.len() + 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::locate;
+    use lang;
+
+    fn error_at(src: &str) -> Option<&str> {
+        let e = lang::compile(src.as_bytes(), &[]).unwrap_err();
+        locate(src, &e.0).map(|r| &src[r])
+    }
+
+    #[test]
+    fn syntax_error() {
+        let src = "
+            (def (Report (volatile acked 0)))
+            (whne true (:= Report.acked 4))
+        ";
+        assert_eq!(error_at(src), Some("(whne"));
+
+        let src = "
+            (def (Report (volatile acked 0)))
+            (when true (:= Report.acked 4))
+            (report)
+        ";
+        assert_eq!(error_at(src), Some("(report"));
+    }
+
+    #[test]
+    fn quoted_name() {
+        let src = "
+            (def (Report (volatile acked 0)))
+            (when true (:= Report.acked (+ Report.acked Ack.bytes_ackd)))
+        ";
+        assert_eq!(error_at(src), Some("Ack.bytes_ackd"));
+
+        // the first mention of plus is in a comment
+        let src = "
+            (def (Report (volatile acked 0)))
+            # plus adds
+            (when true
+                (:= Report.acked (plus Report.acked 4))
+            )
+        ";
+        let e = lang::compile(src.as_bytes(), &[]).unwrap_err();
+        let r = locate(src, &e.0).unwrap();
+        assert_eq!(&src[r.clone()], "plus");
+        assert!(r.start > src.find("(when").unwrap());
+    }
+
+    #[test]
+    fn not_found() {
+        let src = "
+            (def (Report (volatile acked 0)))
+            (when 5 (report))
+        ";
+        assert_eq!(error_at(src), None);
+    }
+}
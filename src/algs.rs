@@ -17,8 +17,11 @@ pub fn make_logger() -> slog::Logger {
 #[cfg(all(target_os = "linux"))]
 pub fn ipc_valid(v: String) -> std::result::Result<(), String> {
     match v.as_str() {
-        "netlink" | "unix" | "char" => Ok(()),
-        _ => Err(format!("ipc must be one of (netlink|unix|char): {:?}", v)),
+        "netlink" | "unix" | "seqpacket" | "char" => Ok(()),
+        _ => Err(format!(
+            "ipc must be one of (netlink|unix|seqpacket|char): {:?}",
+            v
+        )),
     }
 }
 
@@ -35,11 +38,14 @@ pub fn ipc_valid(v: String) -> std::result::Result<(), String> {
 /// single-algorithm case. The 3-argument form will use blocking IPC sockets.
 /// Arguments are:
 /// 1. ipc, a &str specifying the IPC type
-/// (either "unix", "netlink", or "char"): see [`ipc`](./ipc/index.html).
+/// (either "unix", "seqpacket", "netlink", or "char"): see [`ipc`](./ipc/index.html).
 /// 2. log, an instance of `Option<slog::Logger>`.
 /// 3. alg, an instance of `impl CongAlg<T: Ipc>`.
 /// 4. blk, optional argument, either [`Blocking`](./ipc/struct.Blocking.html) or
 ///    [`Nonblocking`](./ipc/struct.Nonblocking.html).
+/// 5. unix, optional argument, a [`unix::SocketBuilder`](./ipc/unix/struct.SocketBuilder.html)
+///    to use instead of the default sockets if ipc is "unix" or "seqpacket", e.g. to run several
+///    CCP instances on one host.
 ///
 ///
 /// # Example
@@ -104,14 +110,40 @@ macro_rules! start {
         $crate::start!($ipc, $log, $alg, Blocking)
     }};
     ($ipc:expr, $log:expr, $alg: expr, $blk: ty) => {{
+        use $crate::ipc::unix::SocketBuilder;
+        $crate::start!($ipc, $log, $alg, $blk, SocketBuilder::default())
+    }};
+    ($ipc:expr, $log:expr, $alg: expr, $blk: ty, $unix: expr) => {{
         use $crate::ipc::BackendBuilder;
         match $ipc {
             "unix" => {
                 use $crate::ipc::unix::Socket;
-                let b = Socket::<$blk>::new("in", "out")
+                let b = Socket::<$blk>::with_builder(&$unix)
+                    .map(|sk| BackendBuilder { sock: sk })
+                    .expect("ipc initialization");
+                $crate::run::<_, _>(
+                    b,
+                    $crate::Config {
+                        logger: $log,
+                        ..Default::default()
+                    },
+                    $alg,
+                )
+            }
+            #[cfg(all(target_os = "linux"))]
+            "seqpacket" => {
+                use $crate::ipc::seqpacket::Socket;
+                let b = Socket::<$blk>::with_builder(&$unix)
                     .map(|sk| BackendBuilder { sock: sk })
                     .expect("ipc initialization");
-                $crate::run::<_, _>(b, $crate::Config { logger: $log }, $alg)
+                $crate::run::<_, _>(
+                    b,
+                    $crate::Config {
+                        logger: $log,
+                        ..Default::default()
+                    },
+                    $alg,
+                )
             }
             #[cfg(all(target_os = "linux"))]
             "netlink" => {
@@ -119,7 +151,14 @@ macro_rules! start {
                 let b = Socket::<$blk>::new()
                     .map(|sk| BackendBuilder { sock: sk })
                     .expect("ipc initialization");
-                $crate::run::<_, _>(b, $crate::Config { logger: $log }, $alg)
+                $crate::run::<_, _>(
+                    b,
+                    $crate::Config {
+                        logger: $log,
+                        ..Default::default()
+                    },
+                    $alg,
+                )
             }
             #[cfg(all(target_os = "linux"))]
             "char" => {
@@ -127,7 +166,14 @@ macro_rules! start {
                 let b = Socket::<$blk>::new()
                     .map(|sk| BackendBuilder { sock: sk })
                     .expect("ipc initialization");
-                $crate::run::<_, _>(b, $crate::Config { logger: $log }, $alg)
+                $crate::run::<_, _>(
+                    b,
+                    $crate::Config {
+                        logger: $log,
+                        ..Default::default()
+                    },
+                    $alg,
+                )
             }
             _ => unreachable!(),
         }
@@ -3,27 +3,39 @@ extern crate quote;
 extern crate syn;
 extern crate walkdir;
 
+use std::collections::HashMap;
 use std::env::args;
-use std::fs::File;
-use std::io::Read;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
 use portus::lang;
 use quote::ToTokens;
-use syn::punctuated::{Pair::End, Pair::Punctuated};
-use syn::visit::Visit;
-use syn::{Expr, Expr::Lit, Expr::MethodCall, Item::Impl, Lit::ByteStr, Lit::Str};
+use syn::punctuated::Punctuated;
+use syn::synom::Parser;
+use syn::visit::{self, Visit};
+use syn::{
+    Expr, ImplItemConst, ImplItemMethod, ItemConst, ItemFn, ItemImpl, ItemMod, ItemStatic, ItemUse,
+    Lit, UseTree,
+};
 use walkdir::{DirEntry, WalkDir};
 
 const ESC: &str = "\u{1B}";
-const RED: &str = "\031";
-const GREEN: &str = "\032";
-const BLUE: &str = "\034";
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const BLUE: &str = "34";
 
 macro_rules! bold_red {
     ($s:expr) => {
         format!("{}[{};1m{}{}[0m", ESC, RED, $s, ESC)
     };
 }
+macro_rules! bold_yellow {
+    ($s:expr) => {
+        format!("{}[{};1m{}{}[0m", ESC, YELLOW, $s, ESC)
+    };
+}
 macro_rules! bold_blue {
     ($s:expr) => {
         format!("{}[{};1m{}{}[0m", ESC, BLUE, $s, ESC)
@@ -40,194 +52,621 @@ macro_rules! bold {
     };
 }
 
-struct FastPathProgramFinder {
-    total: u32,
-    failed: u32,
-    impl_str: String,
-    filename: String,
+// Limits how deeply consts may refer to other consts, so that cycles terminate.
+const MAX_EVAL_DEPTH: usize = 16;
+
+/// A `const` or `static` item found in the searched files.
+struct Const {
+    expr: Expr,
+    // where it is, for resolving `include_str!` and the paths in `expr`
+    scope: Scope,
 }
-impl FastPathProgramFinder {
-    fn new(impl_str: String, filename: String) -> Self {
-        Self {
-            total: 0,
-            failed: 0,
-            impl_str,
-            filename,
-        }
-    }
-}
-impl<'v> Visit<'v> for FastPathProgramFinder {
-    fn visit_expr(&mut self, e: &Expr) {
-        if let MethodCall(ref emc) = *e {
-            let method_name = emc.method.to_string();
-            if method_name == "install" {
-                match emc.args.first() {
-                    Some(Punctuated(&Lit(ref l), _)) | Some(End(&Lit(ref l))) => {
-                        let compile_result = match l.lit {
-                                ByteStr(ref ls) => { lang::compile(&ls.value(), &[]) }
-                                Str(ref ls)     => { lang::compile(ls.value().as_bytes(), &[]) }
-                                _           => { panic!("Non-string passed to install(). This shouldn't have compiled in the first place...") }
-                            };
-                        self.total += 1;
-                        match compile_result {
-                            Ok(_) => {}
-                            Err(e) => {
-                                self.failed += 1;
-                                eprintln!("{}{}", bold_red!("error"), bold!(format!(": {:?}", e)));
-                                eprintln!("{} {}", bold_blue!("-->"), self.filename);
-                                eprintln!("{} {}", bold_blue!("-->"), self.impl_str);
-                                let prog_src = l.into_tokens().to_string();
-                                eprintln!(
-                                    "{}\n\n",
-                                    prog_src
-                                        .split('\n')
-                                        .enumerate()
-                                        .map(|(i, l)| format!(
-                                            "{} {}",
-                                            bold_blue!(format!("{:3} |", i)),
-                                            l
-                                        ))
-                                        .collect::<Vec<String>>()
-                                        .join("\n")
-                                );
-                            }
-                        }
-                    }
-                    Some(Punctuated(&MethodCall(ref mcmc), _))
-                    | Some(End(&MethodCall(ref mcmc))) => {
-                        self.visit_expr(&mcmc.receiver);
-                    }
-                    _ => {}
+
+/// Where an expression appears: its file, its module, and the type of the impl it is in, if any.
+#[derive(Clone)]
+struct Scope {
+    file: PathBuf,
+    module: Vec<String>,
+    self_ty: Option<String>,
+}
+
+/// The `const` and `static` items found in the searched files, by module path and name, and the
+/// names each module imports with `use`. Associated consts are under the name of their impl's
+/// type.
+#[derive(Default)]
+struct Consts {
+    items: HashMap<Vec<String>, Vec<Const>>,
+    imports: HashMap<Vec<String>, HashMap<String, Vec<String>>>,
+}
+
+/// The path from the crate root of `segs`, a path which appears in `module`, in an impl for
+/// `self_ty` if any. As in the 2015 edition, a path starting with `crate`, `self`, `super` or
+/// `Self` is relative to the module or impl, and any other is relative to the crate root if
+/// `relative` is false, e.g. in a `use` item.
+fn absolute(
+    segs: &[String],
+    module: &[String],
+    self_ty: Option<&String>,
+    relative: bool,
+) -> Vec<String> {
+    let mut full = match segs.first().map(|s| s.as_str()) {
+        Some("crate") => vec![],
+        Some("Self") => module.iter().chain(self_ty).cloned().collect(),
+        Some("self") | Some("super") => module.to_vec(),
+        _ if relative => module.to_vec(),
+        _ => vec![],
+    };
+    let mut rest = match segs.first().map(|s| s.as_str()) {
+        Some("crate") | Some("Self") | Some("self") => &segs[1..],
+        _ => segs,
+    };
+    while rest.first().map_or(false, |s| s == "super") {
+        full.pop();
+        rest = &rest[1..];
+    }
+
+    full.extend(rest.iter().cloned());
+    full
+}
+
+impl Consts {
+    /// The const or static `path` refers to from `scope`. A path which names none of those found,
+    /// e.g. because it was imported in a way this does not follow, may name the only one whose
+    /// path ends the same way; if several do, it is ambiguous.
+    fn resolve(&self, path: &syn::Path, scope: &Scope) -> Result<&Const, String> {
+        let segs: Vec<String> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+        let name = segs.join("::");
+
+        let imported = self
+            .imports
+            .get(&scope.module)
+            .and_then(|names| names.get(&segs[0]));
+        let full = match imported {
+            Some(target) if path.leading_colon.is_none() => {
+                let mut full = target.clone();
+                full.extend(segs[1..].iter().cloned());
+                full
+            }
+            _ => absolute(
+                &segs,
+                &scope.module,
+                scope.self_ty.as_ref(),
+                path.leading_colon.is_none(),
+            ),
+        };
+
+        let found = match self.items.get(&full) {
+            Some(cs) => vec![(&full, cs)],
+            None => {
+                let tail: Vec<String> = segs
+                    .iter()
+                    .filter(|s| !["crate", "self", "super", "Self"].contains(&s.as_str()))
+                    .cloned()
+                    .collect();
+                self.items
+                    .iter()
+                    .filter(|&(k, _)| k.ends_with(&tail))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        match (found.len(), found.first()) {
+            (1, Some(&(_, cs))) if cs.len() == 1 => Ok(&cs[0]),
+            (0, _) => Err(format!("no const or static named {}", name)),
+            _ => {
+                let mut defs: Vec<String> = found
+                    .iter()
+                    .flat_map(|&(k, cs)| {
+                        cs.iter()
+                            .map(move |c| format!("{} ({})", k.join("::"), c.scope.file.display()))
+                    })
+                    .collect();
+                defs.sort();
+                Err(format!("{} is ambiguous: {}", name, defs.join(", ")))
+            }
+        }
+    }
+}
+
+struct ConstFinder<'a> {
+    consts: &'a mut Consts,
+    scope: Scope,
+}
+
+impl<'a> ConstFinder<'a> {
+    fn new(consts: &'a mut Consts, file: &Path, module: Vec<String>) -> Self {
+        ConstFinder {
+            consts,
+            scope: Scope {
+                file: file.to_path_buf(),
+                module,
+                self_ty: None,
+            },
+        }
+    }
+
+    fn add(&mut self, ident: String, e: &Expr) {
+        let mut path = self.scope.module.clone();
+        path.extend(self.scope.self_ty.clone());
+        path.push(ident);
+        self.consts.items.entry(path).or_default().push(Const {
+            expr: e.clone(),
+            scope: self.scope.clone(),
+        });
+    }
+
+    // Record the names `tree`, the part of a `use` item after `prefix`, imports.
+    fn add_imports(&mut self, prefix: &mut Vec<String>, tree: &UseTree) {
+        let (ident, rename) = match *tree {
+            UseTree::Path(ref p) => {
+                prefix.push(p.ident.to_string());
+                self.add_imports(prefix, &p.tree);
+                prefix.pop();
+                return;
+            }
+            UseTree::Group(ref g) => {
+                for t in &g.items {
+                    self.add_imports(prefix, t);
+                }
+                return;
+            }
+            UseTree::Name(ref n) => (n.ident.to_string(), n.ident.to_string()),
+            UseTree::Rename(ref r) => (r.ident.to_string(), r.rename.to_string()),
+            UseTree::Glob(_) => return,
+        };
+
+        // `use a::{self, b}` imports `a`
+        let mut path = prefix.clone();
+        if ident != "self" {
+            path.push(ident);
+        }
+        let rename = if rename == "self" {
+            path.last().cloned().unwrap_or(rename)
+        } else {
+            rename
+        };
+
+        let target = absolute(&path, &self.scope.module, None, false);
+        self.consts
+            .imports
+            .entry(self.scope.module.clone())
+            .or_default()
+            .insert(rename, target);
+    }
+}
+
+impl<'a, 'v> Visit<'v> for ConstFinder<'a> {
+    fn visit_item_mod(&mut self, i: &'v ItemMod) {
+        self.scope.module.push(i.ident.to_string());
+        visit::visit_item_mod(self, i);
+        self.scope.module.pop();
+    }
+
+    fn visit_item_impl(&mut self, i: &'v ItemImpl) {
+        let outer = self.scope.self_ty.replace(self_ty_name(i));
+        visit::visit_item_impl(self, i);
+        self.scope.self_ty = outer;
+    }
+
+    fn visit_item_use(&mut self, i: &'v ItemUse) {
+        self.add_imports(&mut vec![], &i.tree);
+    }
+
+    fn visit_item_const(&mut self, i: &ItemConst) {
+        self.add(i.ident.to_string(), &i.expr);
+    }
+
+    fn visit_item_static(&mut self, i: &ItemStatic) {
+        self.add(i.ident.to_string(), &i.expr);
+    }
+
+    fn visit_impl_item_const(&mut self, i: &ImplItemConst) {
+        self.add(i.ident.to_string(), &i.expr);
+    }
+}
+
+/// The module path of `file`, a file under `root`: e.g. `a::b` for `root/a/b.rs` or
+/// `root/a/b/mod.rs`, and the crate root for `root/lib.rs` or `root/main.rs`.
+fn module_path(root: &Path, file: &Path) -> Vec<String> {
+    let rel = file.strip_prefix(root).unwrap_or(file).with_extension("");
+    let mut module: Vec<String> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if module.len() == 1 && (module[0] == "lib" || module[0] == "main")
+        || module.last().map_or(false, |m| m == "mod")
+    {
+        module.pop();
+    }
+
+    module
+}
+
+// The name of the type an impl is for.
+fn self_ty_name(i: &ItemImpl) -> String {
+    match *i.self_ty {
+        syn::Type::Path(ref tp) => tp.path.segments.last().unwrap().value().ident.to_string(),
+        ref t => t.clone().into_tokens().to_string(),
+    }
+}
+
+fn tokens(e: &Expr) -> String {
+    e.clone().into_tokens().to_string()
+}
+
+/// Statically evaluate the `String` or `&str` expression `e`, which appears in `scope`.
+fn eval(e: &Expr, scope: &Scope, consts: &Consts, depth: usize) -> Result<String, String> {
+    if depth > MAX_EVAL_DEPTH {
+        return Err(format!("too many levels of consts: {}", tokens(e)));
+    }
+
+    match *e {
+        Expr::Lit(ref l) => eval_lit(&l.lit),
+        Expr::Reference(ref r) => eval(&r.expr, scope, consts, depth),
+        Expr::Paren(ref p) => eval(&p.expr, scope, consts, depth),
+        Expr::Group(ref g) => eval(&g.expr, scope, consts, depth),
+        // "..".to_owned(), "..".to_string(), "..".into()
+        Expr::MethodCall(ref m)
+            if m.args.is_empty()
+                && ["to_owned", "to_string", "into"].contains(&m.method.as_ref()) =>
+        {
+            eval(&m.receiver, scope, consts, depth)
+        }
+        // String::from(".."), String::from_str(".."), str::to_owned("..")
+        Expr::Call(ref c) if c.args.len() == 1 => match *c.func {
+            Expr::Path(ref p)
+                if p.path.segments.last().map_or(false, |s| {
+                    ["from", "from_str", "to_owned", "to_string"]
+                        .contains(&s.value().ident.as_ref())
+                }) =>
+            {
+                eval(&c.args[0], scope, consts, depth)
+            }
+            _ => Err(format!("unsupported function call: {}", tokens(e))),
+        },
+        // NAME, Self::NAME, module::NAME
+        Expr::Path(ref p) if p.qself.is_none() => {
+            let c = consts.resolve(&p.path, scope)?;
+            eval(&c.expr, &c.scope, consts, depth + 1)
+        }
+        Expr::Macro(ref m) => {
+            let name = m
+                .mac
+                .path
+                .segments
+                .last()
+                .unwrap()
+                .value()
+                .ident
+                .to_string();
+            let args = Punctuated::<Expr, syn::token::Comma>::parse_terminated
+                .parse2(m.mac.tts.clone())
+                .map_err(|_| format!("cannot parse arguments of {}!", name))?;
+            match name.as_str() {
+                "concat" => args
+                    .iter()
+                    .map(|a| eval(a, scope, consts, depth))
+                    .collect::<Result<Vec<String>, String>>()
+                    .map(|parts| parts.concat()),
+                "include_str" if args.len() == 1 => {
+                    let rel = eval(&args[0], scope, consts, depth)?;
+                    let path = scope
+                        .file
+                        .parent()
+                        .unwrap_or_else(|| Path::new("."))
+                        .join(&rel);
+                    fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))
                 }
+                _ => Err(format!("unsupported macro: {}!", name)),
             }
-            self.visit_expr(&emc.receiver)
         }
+        _ => Err(format!("unsupported expression: {}", tokens(e))),
+    }
+}
+
+fn eval_lit(l: &Lit) -> Result<String, String> {
+    match *l {
+        Lit::Str(ref s) => Ok(s.value()),
+        Lit::ByteStr(ref s) => String::from_utf8(s.value()).map_err(|e| format!("{}", e)),
+        Lit::Char(ref c) => Ok(c.value().to_string()),
+        Lit::Int(ref i) => Ok(i.value().to_string()),
+        Lit::Bool(ref b) => Ok(b.value.to_string()),
+        _ => Err(format!("unsupported literal: {}", l.clone().into_tokens())),
+    }
+}
+
+/// A program passed to `insert` in a `datapath_programs` implementation.
+struct Program {
+    name: String,
+    impl_str: String,
+    src: Result<String, String>,
+}
+
+struct FastPathProgramFinder<'a> {
+    scope: Scope,
+    consts: &'a Consts,
+    impl_str: String,
+    in_datapath_programs: bool,
+    programs: Vec<Program>,
+    // the impls whose `datapath_programs` inserts no programs this can find
+    no_programs: Vec<String>,
+}
+
+impl<'a> FastPathProgramFinder<'a> {
+    fn new(file: &Path, module: Vec<String>, consts: &'a Consts) -> Self {
+        Self {
+            scope: Scope {
+                file: file.to_path_buf(),
+                module,
+                self_ty: None,
+            },
+            consts,
+            impl_str: String::new(),
+            in_datapath_programs: false,
+            programs: vec![],
+            no_programs: vec![],
+        }
+    }
+
+    // Look for programs in the body of a `datapath_programs` function.
+    fn visit_datapath_programs<F: FnOnce(&mut Self)>(&mut self, visit_body: F) {
+        let (outer, found) = (self.in_datapath_programs, self.programs.len());
+        self.in_datapath_programs = true;
+        visit_body(self);
+        self.in_datapath_programs = outer;
+        if self.programs.len() == found {
+            self.no_programs.push(self.impl_str.clone());
+        }
+    }
+}
+
+impl<'a, 'v> Visit<'v> for FastPathProgramFinder<'a> {
+    fn visit_item_mod(&mut self, i: &'v ItemMod) {
+        self.scope.module.push(i.ident.to_string());
+        visit::visit_item_mod(self, i);
+        self.scope.module.pop();
+    }
+
+    fn visit_item_impl(&mut self, i: &'v ItemImpl) {
+        let struct_name = self_ty_name(i);
+        self.impl_str = match i.trait_ {
+            Some((_, ref tr, _)) => format!(
+                "impl {} for {}",
+                tr.segments.last().unwrap().value().ident,
+                struct_name
+            ),
+            None => format!("impl {}", struct_name),
+        };
+
+        let outer = self.scope.self_ty.replace(struct_name);
+        visit::visit_item_impl(self, i);
+        self.scope.self_ty = outer;
+    }
+
+    fn visit_impl_item_method(&mut self, i: &'v ImplItemMethod) {
+        if i.sig.ident == "datapath_programs" {
+            self.visit_datapath_programs(|f| visit::visit_impl_item_method(f, i));
+        } else {
+            visit::visit_impl_item_method(self, i);
+        }
+    }
+
+    fn visit_item_fn(&mut self, i: &'v ItemFn) {
+        if i.ident == "datapath_programs" {
+            self.visit_datapath_programs(|f| visit::visit_item_fn(f, i));
+        } else {
+            visit::visit_item_fn(self, i);
+        }
+    }
+
+    fn visit_expr_method_call(&mut self, e: &'v syn::ExprMethodCall) {
+        if self.in_datapath_programs && e.method == "insert" && e.args.len() == 2 {
+            let name = eval(&e.args[0], &self.scope, self.consts, 0)
+                .unwrap_or_else(|_| tokens(&e.args[0]));
+            self.programs.push(Program {
+                name,
+                impl_str: self.impl_str.clone(),
+                src: eval(&e.args[1], &self.scope, self.consts, 0),
+            });
+        }
+
+        visit::visit_expr_method_call(self, e);
     }
 }
 
 const HELP_MSG: &str = r#"Tests compilation of fast-path programs
 
+Finds the programs inserted into the map returned by each `datapath_programs`
+implementation, and compiles them. Programs may be given as string literals,
+`concat!` and `include_str!` invocations, or consts, optionally converted with
+`.to_owned()`, `.to_string()` or `String::from`. Consts are looked up by their
+module path, following `use` items. Exits with an error if any program fails to
+compile, if the source of any program cannot be found, or if a `datapath_programs`
+implementation adds no programs with `insert`.
+
 Usage:
-    cargo compile-fast-path [--path PATH]
+    cargo compile-fast-path [--path PATH] [--allow-unresolved]
 
 Options:
-    -h, --help    Print this message
-    --path        Root directory of files to check, assumes ./src
+    -h, --help            Print this message
+    --path                Root directory of files to check, assumes ./src
+    --allow-unresolved    Only warn about programs whose source cannot be found
 "#;
 
 fn show_help() {
     eprintln!("{}", HELP_MSG);
 }
 
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|s| s.starts_with('.'))
+        .unwrap_or(false)
+}
+
+fn is_rs(entry: &DirEntry) -> bool {
+    entry.file_type().is_file() && entry.path().extension().map_or(false, |e| e == "rs")
+}
+
+fn print_listing(src: &str) {
+    eprintln!(
+        "{}\n",
+        src.split('\n')
+            .enumerate()
+            .map(|(i, l)| format!("{} {}", bold_blue!(format!("{:3} |", i + 1)), l))
+            .collect::<Vec<String>>()
+            .join("\n")
+    );
+}
+
 fn main() {
     if args().any(|a| a == "--help" || a == "-h") {
-        println!("help");
         show_help();
         return;
     }
-    let num_args = args().len();
-    if num_args != 2 && num_args != 4 {
-        show_help();
-        return;
-    }
-    let mut opts = args().skip(2);
-    let path = {
-        if opts.len() == 2 {
-            if opts.next() != Some("--path".to_string()) {
-                show_help();
-                return;
-            }
-            opts.next().unwrap()
-        } else {
-            "./src".to_string()
+
+    // invoked as `cargo compile-fast-path [--path PATH] [--allow-unresolved]`, so the first
+    // argument is the subcommand
+    let mut opts: Vec<String> = args().skip(2).collect();
+    let allow_unresolved = opts.iter().any(|a| a == "--allow-unresolved");
+    opts.retain(|a| a != "--allow-unresolved");
+    let path = match opts.len() {
+        0 => "./src".to_string(),
+        2 if opts[0] == "--path" => opts[1].clone(),
+        _ => {
+            show_help();
+            process::exit(1);
         }
     };
 
-    let walker = WalkDir::new(path.clone()).into_iter();
-    fn is_hidden(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with('.'))
-            .unwrap_or(false)
-    }
-    fn is_dir(entry: &DirEntry) -> bool {
-        entry.file_type().is_dir()
-    }
-    fn is_rs(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .unwrap()
-            .to_string()
-            .split('.')
-            .last()
-            .unwrap_or("")
-            == "rs"
+    let mut files = vec![];
+    let mut failed = 0;
+    for entry in WalkDir::new(&path)
+        .into_iter()
+        .filter_entry(|e| !is_hidden(e))
+        .filter_map(|e| e.ok())
+        .filter(is_rs)
+    {
+        let file = entry.path().to_path_buf();
+        match fs::read_to_string(&file)
+            .map_err(|e| format!("{}", e))
+            .and_then(|src| syn::parse_file(&src).map_err(|e| format!("{}", e)))
+        {
+            Ok(syntax) => files.push((file, syntax)),
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}: {}", bold_red!("error"), file.display(), e);
+            }
+        }
+    }
+
+    let root = Path::new(&path);
+    let mut consts = Consts::default();
+    for &(ref file, ref syntax) in &files {
+        ConstFinder::new(&mut consts, file, module_path(root, file)).visit_file(syntax);
     }
 
+    let unresolved = if allow_unresolved {
+        bold_yellow!("warning")
+    } else {
+        bold_red!("error")
+    };
     let mut total = 0;
-    let mut failed = 0;
+    let mut skipped = 0;
+    let mut no_programs = 0;
+    for &(ref file, ref syntax) in &files {
+        let mut pf = FastPathProgramFinder::new(file, module_path(root, file), &consts);
+        pf.visit_file(syntax);
+        for impl_str in pf.no_programs {
+            no_programs += 1;
+            eprintln!(
+                "{}{}",
+                unresolved,
+                bold!(": found no programs in datapath_programs; only those added with `insert` are checked")
+            );
+            eprintln!("{} {}", bold_blue!("-->"), file.display());
+            eprintln!("{} {}\n", bold_blue!("-->"), impl_str);
+        }
 
-    for entry in walker
-        .filter_entry(|e| !is_hidden(&e))
-        .filter(|e| e.is_ok())
-        .map(|e| e.unwrap())
-        .filter(|e| !is_dir(e) && is_rs(e))
-    {
-        let filepath = &entry.path();
-        let mut file = File::open(&filepath).expect("Unable to open file");
-        let mut src = String::new();
-        file.read_to_string(&mut src).expect("Unable to read file");
-        let syntax = syn::parse_file(&src).expect("Unable to parse file");
-        for item in syntax.items {
-            match item {
-                Impl(imp) => {
-                    let struct_name = match *imp.self_ty {
-                        syn::Type::Path(tp) => tp.path.segments[0].ident.to_string(),
-                        _ => panic!("no struct name!"), // TODO better msg
-                    };
-                    let trait_name = match imp.trait_ {
-                        Some(tr) => Some(tr.1.segments[0].ident.to_string()),
-                        None => None,
-                    };
-                    let impl_str = match trait_name {
-                        Some(tn) => format!("impl {} for {}", tn, struct_name),
-                        None => format!("impl {}", struct_name),
-                    };
-                    let mut pf =
-                        FastPathProgramFinder::new(impl_str, filepath.display().to_string());
-                    for imp_item in imp.items {
-                        pf.visit_impl_item(&imp_item);
-                    }
-                    total += pf.total;
-                    failed += pf.failed;
+        for prog in pf.programs {
+            total += 1;
+            let src = match prog.src {
+                Ok(src) => src,
+                Err(e) => {
+                    skipped += 1;
+                    eprintln!(
+                        "{}{}",
+                        unresolved,
+                        bold!(format!(
+                            ": could not find the source of {}: {}",
+                            prog.name, e
+                        ))
+                    );
+                    eprintln!("{} {}", bold_blue!("-->"), file.display());
+                    eprintln!("{} {}\n", bold_blue!("-->"), prog.impl_str);
+                    continue;
+                }
+            };
+
+            match lang::compile(src.as_bytes(), &[]) {
+                Ok(_) => println!(
+                    "{} {} ({})",
+                    bold_green!("    Compiled"),
+                    prog.name,
+                    file.display()
+                ),
+                Err(e) => {
+                    failed += 1;
+                    eprintln!(
+                        "{}{}",
+                        bold_red!("error"),
+                        bold!(format!(": {} failed to compile: {}", prog.name, e))
+                    );
+                    eprintln!("{} {}", bold_blue!("-->"), file.display());
+                    eprintln!("{} {}", bold_blue!("-->"), prog.impl_str);
+                    print_listing(&src);
                 }
-                _ => continue,
             }
         }
     }
-    if total > 0 {
-        if failed > 0 {
-            eprintln!("{}{}", bold_red!("error"), bold!(format!(": {}/{} fast-path programs failed to compile.\n       You should resolve these issues before running the CCP.", failed, total)))
+
+    println!(
+        "       {} {} fast-path programs in {}",
+        bold_green!("Found"),
+        total,
+        path
+    );
+    if skipped > 0 || no_programs > 0 {
+        let mut unchecked = vec![];
+        if skipped > 0 {
+            unchecked.push(format!("{} programs", skipped));
+        }
+        if no_programs > 0 {
+            unchecked.push(format!(
+                "the programs of {} datapath_programs implementations",
+                no_programs
+            ));
+        }
+        let msg = format!(": {} could not be checked", unchecked.join(" and "));
+        if allow_unresolved {
+            eprintln!("{}{}", unresolved, bold!(msg));
         } else {
-            println!(
-                "       {} {} fast-path programs in {}",
-                bold_green!("Found"),
-                total,
-                path
-            );
-            println!(
-                "{} {}",
-                bold_green!("    Verified"),
-                format!("{} programs compile successfully", total)
-            );
+            eprintln!("{}{}", unresolved, bold!(format!("{}.\n       Give their sources as literals or consts, and add them with `insert`, or pass --allow-unresolved.", msg)));
         }
-    } else {
+    }
+
+    if failed > 0 {
+        eprintln!("{}{}", bold_red!("error"), bold!(format!(": {} fast-path programs failed to compile.\n       You should resolve these issues before running the CCP.", failed)));
+    }
+
+    if failed > 0 || ((skipped > 0 || no_programs > 0) && !allow_unresolved) {
+        process::exit(1);
+    } else if total > skipped {
         println!(
-            "       {} 0 fast-path programs in {}",
-            bold_green!("Found"),
-            path
+            "{} {} programs compile successfully",
+            bold_green!("    Verified"),
+            total - skipped
         );
     }
 }
@@ -1,6 +1,7 @@
 extern crate portus;
 
 use portus::{lang, serialize};
+use std::fs;
 use std::io::{self, Read};
 
 /// It is sometimes helpful to deconstruct a datapath program.
@@ -8,18 +9,28 @@ use std::io::{self, Read};
 /// program source from stdin, and outputs to stdout:
 /// 0. An echo of the input program.
 /// 1. The AST representation of that program
-/// 2. The compiled instructions
+/// 2. The disassembled instructions
 /// 3. The serialized binary which will be sent to the datapath
 ///
 /// On compilation failure, `dump_fold` will panic with the compilation error.
+///
+/// `dump_fold --fmt [FILE...]` instead reformats each program file in place,
+/// or the program on stdin to stdout if no files are given. Files with comments
+/// are left alone, since formatting would remove them.
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a == "--fmt").unwrap_or(false) {
+        fmt(&args[1..]);
+        return;
+    }
+
     let mut buffer = String::new();
     io::stdin().read_to_string(&mut buffer).unwrap();
     println!("buffer:\n{}", buffer);
     let (ast, mut sc) = lang::Prog::new_with_scope(buffer.as_bytes()).unwrap();
-    println!("ast:\n{:?}", ast);
+    println!("ast:\n{}", ast);
     let bin = lang::Bin::compile_prog(&ast, &mut sc).unwrap();
-    println!("instructions:\n{:?}", bin);
+    print!("instructions:\n{}", lang::disassemble(&bin, Some(&sc)));
     let msg = serialize::install::Msg {
         sid: 1,
         program_uid: 9,
@@ -31,3 +42,23 @@ fn main() {
     let buf = serialize::serialize(&msg).unwrap();
     println!("serialized:\n{:?}", buf);
 }
+
+fn fmt(files: &[String]) {
+    if files.is_empty() {
+        let mut buffer = String::new();
+        io::stdin().read_to_string(&mut buffer).unwrap();
+        print!("{}", lang::format(buffer.as_bytes()).unwrap());
+        return;
+    }
+
+    for f in files {
+        let src = fs::read(f).unwrap();
+        let formatted = lang::format(&src).unwrap_or_else(|e| panic!("{}: {}", f, e));
+        if formatted.as_bytes() != &src[..] && lang::has_comments(&src) {
+            eprintln!("not formatting {}: formatting would remove its comments", f);
+        } else if formatted.as_bytes() != &src[..] {
+            fs::write(f, formatted).unwrap();
+            println!("formatted {}", f);
+        }
+    }
+}
@@ -306,12 +306,69 @@ macro_rules! unix_bench {
 unix_bench!(unix_blocking, Blocking);
 unix_bench!(unix_nonblocking, Nonblocking);
 
+macro_rules! shm_bench {
+    ($name: ident, $mode: ident) => {
+        #[cfg(target_os = "linux")] // futexes are linux-only
+        fn $name(iter: u32) -> Vec<Duration> {
+            let path = "/dev/shm/portus-ipc-latency";
+            let (tx, rx) = mpsc::channel::<Vec<Duration>>();
+            let (ready_tx, ready_rx) = mpsc::channel::<bool>();
+
+            // listen
+            let c1 = thread::spawn(move || {
+                let mut receive_buf = [0u8; 1024];
+                let shm = portus::ipc::shm::Socket::<$mode>::create(path, 1 << 16)
+                    .map(|sk| {
+                        Backend::new(
+                            sk,
+                            Arc::new(atomic::AtomicBool::new(true)),
+                            &mut receive_buf[..],
+                        )
+                    })
+                    .expect("shm ipc initialization");
+                ready_tx.send(true).expect("sync");
+                tx.send(bench(shm.sender(), shm, iter))
+                    .expect("report rtts");
+            });
+
+            // echo-er
+            ready_rx.recv().expect("sync");
+            let c2 = thread::spawn(move || {
+                let sk = portus::ipc::shm::Socket::<Blocking>::open(path).expect("sk init");
+                let mut buf = [0u8; 1024];
+                let mut echoed = 0;
+                while echoed < iter {
+                    let rcv = sk.recv(&mut buf[..]).expect("recv");
+                    if rcv > 0 {
+                        sk.send(&buf[..rcv]).expect("echo");
+                        echoed += 1;
+                    }
+                }
+            });
+
+            c1.join().expect("join thread");
+            c2.join().expect("join echo thread");
+            std::fs::remove_file(path).unwrap_or_else(|_| ());
+            rx.recv().expect("get rtts")
+        }
+
+        #[cfg(not(target_os = "linux"))] // futexes are linux-only
+        fn $name(_: u32) -> Vec<Duration> {
+            vec![]
+        }
+    };
+}
+
+shm_bench!(shm_blocking, Blocking);
+shm_bench!(shm_nonblocking, Nonblocking);
+
 arg_enum! {
     #[derive(PartialEq, Debug)]
     pub enum IpcType {
         Nl,
         Unix,
         Kp,
+        Shm,
     }
 }
 
@@ -410,4 +467,20 @@ fn main() {
             }
         }
     }
+
+    if imps.contains(&IpcType::Shm) {
+        for t in shm_nonblocking(trials)
+            .iter()
+            .map(|d| d.num_nanoseconds().unwrap())
+        {
+            println!("shm nonblk {:?} 0 0", t);
+        }
+
+        for t in shm_blocking(trials)
+            .iter()
+            .map(|d| d.num_nanoseconds().unwrap())
+        {
+            println!("shm blk {:?} 0 0", t);
+        }
+    }
 }
@@ -0,0 +1,197 @@
+//! `portus bench-ipc`: measure the round-trip latency of the userspace IPC mechanisms.
+//!
+//! Each round trip sends a small message through a `Backend` to an echo thread and waits for it
+//! to come back, so the measurement includes message parsing.
+
+use bytes::{ByteOrder, LittleEndian};
+use clap::ArgMatches;
+use crossbeam::channel;
+use portus::ipc::{chan, tcp, udp, unix, Backend, Blocking, Ipc, Nonblocking};
+use portus::serialize::{self, AsRawMsg, Msg, RawMsg, HDR_LENGTH};
+use portus::{Error, Result};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+arg_enum! {
+    #[derive(PartialEq, Debug)]
+    pub enum IpcType {
+        Unix,
+        Chan,
+        Udp,
+        Tcp,
+    }
+}
+
+/// A sequence number, echoed back unchanged.
+struct Ping(u64);
+
+impl AsRawMsg for Ping {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (0xff, HDR_LENGTH + 8, 0)
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 8];
+        LittleEndian::write_u64(&mut buf, self.0);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 8 {
+            return Err(Error(format!("short ping: {:?}", b)));
+        }
+
+        Ok(Ping(LittleEndian::read_u64(&b[0..8])))
+    }
+}
+
+fn echo<E: Ipc>(sk: E, iters: u32) -> Result<()> {
+    let mut buf = [0u8; 1024];
+    for _ in 0..iters {
+        // `recv` returns 0 when it times out before a ping arrives
+        let mut n = 0;
+        while n == 0 {
+            n = sk.recv(&mut buf[..])?;
+        }
+
+        sk.send(&buf[..n])?;
+    }
+
+    Ok(())
+}
+
+fn round_trips<T: Ipc, E: Ipc>(sk: T, echo_sk: E, iters: u32) -> Result<Vec<Duration>> {
+    let echoer = thread::spawn(move || echo(echo_sk, iters));
+
+    let mut buf = [0u8; 1024];
+    let mut b = Backend::new(sk, Arc::new(AtomicBool::new(true)), &mut buf[..]);
+    let sender = b.sender();
+    let mut rtts = Vec::with_capacity(iters as usize);
+    for i in 0..u64::from(iters) {
+        let msg = serialize::serialize(&Ping(i))?;
+        let then = Instant::now();
+        sender.send_msg(&msg[..])?;
+        match b.next() {
+            Some(Msg::Other(raw)) => {
+                let got = Ping::from_raw_msg(raw)?;
+                rtts.push(then.elapsed());
+                if got.0 != i {
+                    return Err(Error(format!("sent ping {}, got back {}", i, got.0)));
+                }
+            }
+            _ => return Err(Error(format!("no echo for ping {}", i))),
+        }
+    }
+
+    echoer
+        .join()
+        .map_err(|_| Error(String::from("echo thread panicked")))??;
+    Ok(rtts)
+}
+
+fn unix_round_trips<T>(ours: unix::Socket<T>, iters: u32) -> Result<Vec<Duration>>
+where
+    unix::Socket<T>: Ipc,
+{
+    let theirs = unix::Socket::<Blocking>::new("portus-bench-out", "portus-bench-in")?;
+    round_trips(ours, theirs, iters)
+}
+
+fn udp_round_trips<T>(ours: udp::Socket<T>, theirs: SocketAddr, iters: u32) -> Result<Vec<Duration>>
+where
+    udp::Socket<T>: Ipc,
+{
+    let theirs = udp::Socket::<Blocking>::new(theirs, ours.local_addr()?)?;
+    round_trips(ours, theirs, iters)
+}
+
+// A loopback address with a port nothing is bound to (at least until something else takes it).
+fn free_udp_addr() -> Result<SocketAddr> {
+    Ok(UdpSocket::bind("127.0.0.1:0")?.local_addr()?)
+}
+
+fn tcp_round_trips<T>(ours: tcp::Socket<T>, l: TcpListener, iters: u32) -> Result<Vec<Duration>>
+where
+    tcp::Socket<T>: Ipc,
+{
+    let theirs = tcp::Socket::<Blocking>::from_stream(l.accept()?.0)?;
+    round_trips(ours, theirs, iters)
+}
+
+fn chan_round_trips<T>(iters: u32) -> Result<Vec<Duration>>
+where
+    chan::Socket<T>: Ipc,
+{
+    let (to_echo, from_ccp) = channel::unbounded();
+    let (to_ccp, from_echo) = channel::unbounded();
+    let ours = chan::Socket::<T>::new(to_echo, from_echo);
+    let theirs = chan::Socket::<Blocking>::new(to_ccp, from_ccp);
+    round_trips(ours, theirs, iters)
+}
+
+fn nanos(d: &Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn summarize(name: &str, mode: &str, rtts: Vec<Duration>) {
+    let mut ns: Vec<u64> = rtts.iter().map(nanos).collect();
+    ns.sort();
+    if ns.is_empty() {
+        return;
+    }
+
+    let pct = |p: usize| ns[(ns.len() - 1) * p / 100];
+    println!(
+        "{} {}: {} round trips, min {} ns, median {} ns, p99 {} ns, max {} ns",
+        name,
+        mode,
+        ns.len(),
+        ns[0],
+        pct(50),
+        pct(99),
+        ns[ns.len() - 1]
+    );
+}
+
+pub fn bench(m: &ArgMatches) -> Result<()> {
+    let iters = value_t!(m, "iterations", u32).map_err(|e| Error(format!("{}", e)))?;
+    let imps = values_t!(m, "impl", IpcType).map_err(|e| Error(format!("{}", e)))?;
+
+    if imps.contains(&IpcType::Unix) {
+        let sk = unix::Socket::<Nonblocking>::new("portus-bench-in", "portus-bench-out")?;
+        summarize("unix", "nonblk", unix_round_trips(sk, iters)?);
+        let sk = unix::Socket::<Blocking>::new("portus-bench-in", "portus-bench-out")?;
+        summarize("unix", "blk", unix_round_trips(sk, iters)?);
+    }
+
+    if imps.contains(&IpcType::Udp) {
+        let theirs = free_udp_addr()?;
+        let sk = udp::Socket::<Nonblocking>::new("127.0.0.1:0", theirs)?;
+        summarize("udp", "nonblk", udp_round_trips(sk, theirs, iters)?);
+        let theirs = free_udp_addr()?;
+        let sk = udp::Socket::<Blocking>::new("127.0.0.1:0", theirs)?;
+        summarize("udp", "blk", udp_round_trips(sk, theirs, iters)?);
+    }
+
+    if imps.contains(&IpcType::Tcp) {
+        let l = TcpListener::bind("127.0.0.1:0")?;
+        let sk = tcp::Socket::<Nonblocking>::connect(l.local_addr()?)?;
+        summarize("tcp", "nonblk", tcp_round_trips(sk, l, iters)?);
+        let l = TcpListener::bind("127.0.0.1:0")?;
+        let sk = tcp::Socket::<Blocking>::connect(l.local_addr()?)?;
+        summarize("tcp", "blk", tcp_round_trips(sk, l, iters)?);
+    }
+
+    if imps.contains(&IpcType::Chan) {
+        summarize("chan", "nonblk", chan_round_trips::<Nonblocking>(iters)?);
+        summarize("chan", "blk", chan_round_trips::<Blocking>(iters)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! `portus decode`: print each CCP message in a capture.
+//! `portus pcapng`: convert a recording to pcapng.
+
+use clap::ArgMatches;
+use portus::ipc::pcapng;
+use portus::ipc::record::{self, Direction};
+use portus::serialize::dissect;
+use portus::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Get the raw bytes of a capture: either binary, or hex text such as the output of `xxd -p`.
+/// Hex text is detected automatically unless `hex` is set.
+pub fn read_capture(buf: Vec<u8>, hex: bool) -> Result<Vec<u8>> {
+    let is_hex = buf
+        .iter()
+        .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace());
+    if !hex && (!is_hex || buf.is_empty()) {
+        return Ok(buf);
+    }
+
+    let digits: Vec<u8> = buf
+        .into_iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if digits.len() % 2 != 0 {
+        return Err(Error(String::from("odd number of hex digits in capture")));
+    }
+
+    digits
+        .chunks(2)
+        .map(|d| {
+            let s = String::from_utf8_lossy(d);
+            u8::from_str_radix(&s, 16).map_err(|_| Error(format!("invalid hex byte: {}", s)))
+        })
+        .collect()
+}
+
+// Print each message in `buf`, indented by `indent` spaces.
+fn print_msgs(buf: &[u8], indent: usize) -> Result<()> {
+    for m in dissect::dissect(buf) {
+        let (offset, desc) = m?;
+        println!("{:indent$}{:6}: {}", "", offset, desc, indent = indent);
+    }
+
+    Ok(())
+}
+
+pub fn decode(m: &ArgMatches) -> Result<()> {
+    let buf = super::read_input(m.value_of("file"))?;
+    if buf.starts_with(record::MAGIC) {
+        for r in record::read_records(&buf[..])? {
+            println!(
+                "{}.{:09} {} ({} bytes)",
+                r.time.as_secs(),
+                r.time.subsec_nanos(),
+                match r.dir {
+                    Direction::FromDatapath => "datapath -> ccp",
+                    Direction::ToDatapath => "ccp -> datapath",
+                },
+                r.buf.len()
+            );
+            print_msgs(&r.buf, 4)?;
+        }
+
+        return Ok(());
+    }
+
+    print_msgs(&read_capture(buf, m.is_present("hex"))?, 0)
+}
+
+pub fn to_pcapng(m: &ArgMatches) -> Result<()> {
+    let records = record::read_records(&super::read_input(m.value_of("capture"))?[..])?;
+    match m.value_of("out").unwrap() {
+        "-" => pcapng::write(io::stdout().lock(), &records),
+        out => pcapng::write(BufWriter::new(File::create(out)?), &records),
+    }
+}
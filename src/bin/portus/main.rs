@@ -0,0 +1,311 @@
+//! `portus`: tools for writing and debugging datapath programs and CCP deployments.
+//!
+//! - `portus check FILE...` compiles datapath programs and reports any errors.
+//! - `portus compile FILE OUT` compiles a datapath program and saves it as a `lang::Compiled`.
+//! - `portus disasm FILE` prints the instructions a program compiles to, or, with `--msg`, the
+//!   program in a captured install message. FILE may also be a saved `lang::Compiled` program.
+//! - `portus fmt [--check] [FILE...]` reformats program source. Since formatting drops comments,
+//!   files with comments are not changed, and source with comments on stdin is refused.
+//! - `portus decode [--hex] [FILE]` prints each CCP message in a capture: raw messages, or a
+//!   recording made by `ipc::record::Recorder`.
+//! - `portus pcapng CAPTURE OUT` converts a recording to pcapng, for Wireshark.
+//! - `portus bench-ipc` measures the round-trip latency of the userspace IPC mechanisms.
+//! - `portus simulate PROGRAM TRACE` runs a program against a trace of measurements.
+//!
+//! Wherever a FILE is optional, or given as `-`, `portus` reads stdin instead.
+
+extern crate bytes;
+#[macro_use]
+extern crate clap;
+extern crate crossbeam;
+extern crate portus;
+
+mod bench;
+mod decode;
+mod simulate;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use portus::{lang, Error, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+fn main() {
+    let param = Arg::with_name("param")
+        .long("param")
+        .short("p")
+        .value_name("NAME=VALUE")
+        .help("Sets a compile-time parameter of the program")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1);
+
+    let matches = App::new("portus")
+        .version(crate_version!())
+        .about("Tools for CCP datapath programs and deployments")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Compiles datapath programs, reporting any errors")
+                .arg(param.clone())
+                .arg(Arg::with_name("files").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("compile")
+                .about("Compiles a datapath program and saves it for reuse")
+                .arg(param.clone())
+                .arg(Arg::with_name("file").required(true))
+                .arg(Arg::with_name("out").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Prints the instructions a datapath program compiles to")
+                .arg(param.clone())
+                .arg(
+                    Arg::with_name("msg")
+                        .long("msg")
+                        .help("FILE is a captured install message (hex or binary), not source"),
+                )
+                .arg(Arg::with_name("file")),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Reformats datapath programs without comments in place, or stdin to stdout")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Lists files which are not formatted instead of changing them"),
+                )
+                .arg(Arg::with_name("files").multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("decode")
+                .about("Decodes a capture of CCP messages")
+                .arg(
+                    Arg::with_name("hex")
+                        .long("hex")
+                        .help("The capture is hex text (detected automatically otherwise)"),
+                )
+                .arg(Arg::with_name("file")),
+        )
+        .subcommand(
+            SubCommand::with_name("pcapng")
+                .about("Converts a recording of CCP messages to pcapng")
+                .arg(Arg::with_name("capture").required(true))
+                .arg(
+                    Arg::with_name("out")
+                        .required(true)
+                        .help("The pcapng file to write, or - for stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench-ipc")
+                .about("Measures IPC round-trip latency")
+                .after_help(
+                    "The netlink and character device benchmarks need kernel modules built from \
+                     this repository; use the ipc_latency benchmark for those.",
+                )
+                .arg(
+                    Arg::with_name("iterations")
+                        .long("iterations")
+                        .short("i")
+                        .help("Number of round trips to measure")
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("impl")
+                        .long("impl")
+                        .help("IPC mechanisms to measure")
+                        .possible_values(&bench::IpcType::variants())
+                        .case_insensitive(true)
+                        .multiple(true)
+                        .default_value("unix"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .about("Runs a datapath program against a trace of measurements")
+                .after_help(simulate::TRACE_HELP)
+                .arg(param)
+                .arg(
+                    Arg::with_name("init-cwnd")
+                        .long("init-cwnd")
+                        .help("Initial value of Cwnd, in bytes")
+                        .default_value("14480"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .help("Microseconds between rows, if the trace has no Ack.now column")
+                        .default_value("1000"),
+                )
+                .arg(Arg::with_name("program").required(true))
+                .arg(Arg::with_name("trace")),
+        )
+        .get_matches();
+
+    let res = match matches.subcommand() {
+        ("check", Some(m)) => check(m),
+        ("compile", Some(m)) => compile_to(m),
+        ("disasm", Some(m)) => disasm(m),
+        ("fmt", Some(m)) => fmt(m),
+        ("decode", Some(m)) => decode::decode(m),
+        ("pcapng", Some(m)) => decode::to_pcapng(m),
+        ("bench-ipc", Some(m)) => bench::bench(m),
+        ("simulate", Some(m)) => simulate::simulate(m),
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = res {
+        eprintln!("error: {}", e.0);
+        process::exit(1);
+    }
+}
+
+/// Read `path`, or stdin if it is `None` or `-`.
+fn read_input(path: Option<&str>) -> Result<Vec<u8>> {
+    match path {
+        None | Some("-") => {
+            let mut buf = vec![];
+            io::stdin().read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        Some(p) => fs::read(p).map_err(|e| Error(format!("{}: {}", p, e))),
+    }
+}
+
+/// Parse the `--param NAME=VALUE` arguments.
+fn params(m: &ArgMatches) -> Result<Vec<(String, u64)>> {
+    m.values_of("param")
+        .into_iter()
+        .flatten()
+        .map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(name), Some(value)) => value
+                    .parse()
+                    .map(|v| (name.to_string(), v))
+                    .map_err(|e| Error(format!("param {}: {}", name, e))),
+                _ => Err(Error(format!("expected NAME=VALUE: {}", p))),
+            }
+        })
+        .collect()
+}
+
+fn compile(src: &[u8], params: &[(String, u64)]) -> Result<(lang::Bin, lang::Scope)> {
+    let params: Vec<(&str, u64)> = params.iter().map(|&(ref k, v)| (k.as_str(), v)).collect();
+    lang::compile(src, &params).map_err(|e| Error(e.0))
+}
+
+fn check(m: &ArgMatches) -> Result<()> {
+    let params = params(m)?;
+    let mut failed = 0;
+    for f in m.values_of("files").unwrap() {
+        match read_input(Some(f)).and_then(|src| compile(&src, &params)) {
+            Ok((bin, _)) => println!(
+                "{}: ok ({} events, {} instructions)",
+                f,
+                bin.events.len(),
+                bin.instrs.len()
+            ),
+            Err(e) => {
+                println!("{}: {}", f, e.0);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(Error(format!(
+            "{} of the programs failed to compile",
+            failed
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn compile_to(m: &ArgMatches) -> Result<()> {
+    let params = params(m)?;
+    let params: Vec<(&str, u64)> = params.iter().map(|&(ref k, v)| (k.as_str(), v)).collect();
+    let src = read_input(m.value_of("file"))?;
+    let out = m.value_of("out").unwrap();
+    lang::Compiled::new(&src, &params)
+        .and_then(|c| c.save(out))
+        .map_err(|e| Error(e.0))
+}
+
+fn disasm(m: &ArgMatches) -> Result<()> {
+    let buf = read_input(m.value_of("file"))?;
+    if m.is_present("msg") {
+        let buf = decode::read_capture(buf, false)?;
+        print!("{}", portus::serialize::install::disassemble(&buf, None)?);
+    } else if buf.starts_with(b"CCPP") {
+        let c = lang::Compiled::deserialize(&buf).map_err(|e| Error(e.0))?;
+        println!(
+            "program {} (source hash {:016x}):",
+            c.scope.program_uid, c.source_hash
+        );
+        print!("{}", lang::disassemble(&c.bin, Some(&c.scope)));
+    } else {
+        let (bin, sc) = compile(&buf, &params(m)?)?;
+        print!("{}", lang::disassemble(&bin, Some(&sc)));
+    }
+
+    Ok(())
+}
+
+fn fmt(m: &ArgMatches) -> Result<()> {
+    let files: Vec<&str> = m.values_of("files").into_iter().flatten().collect();
+    if files.is_empty() {
+        let src = read_input(None)?;
+        let formatted = lang::format(&src).map_err(|e| Error(e.0))?;
+        if m.is_present("check") {
+            if formatted.as_bytes() != &src[..] {
+                return Err(Error(String::from("stdin is not formatted")));
+            }
+        } else if lang::has_comments(&src) {
+            return Err(Error(String::from(
+                "not formatting stdin: formatting would remove its comments",
+            )));
+        } else {
+            print!("{}", formatted);
+        }
+
+        return Ok(());
+    }
+
+    let (mut unformatted, mut commented) = (0, 0);
+    for f in files {
+        let src = read_input(Some(f))?;
+        let formatted = lang::format(&src).map_err(|e| Error(format!("{}: {}", f, e.0)))?;
+        if formatted.as_bytes() == &src[..] {
+            continue;
+        }
+
+        if m.is_present("check") {
+            println!("{}", f);
+            unformatted += 1;
+        } else if lang::has_comments(&src) {
+            eprintln!("not formatting {}: formatting would remove its comments", f);
+            commented += 1;
+        } else {
+            fs::write(f, formatted)?;
+            println!("formatted {}", f);
+        }
+    }
+
+    if unformatted > 0 {
+        Err(Error(format!(
+            "{} of the files are not formatted",
+            unformatted
+        )))
+    } else if commented > 0 {
+        Err(Error(format!(
+            "{} of the files have comments and were not formatted",
+            commented
+        )))
+    } else {
+        Ok(())
+    }
+}
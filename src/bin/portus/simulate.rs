@@ -0,0 +1,343 @@
+//! `portus simulate`: run a datapath program against a trace of measurements, the way a
+//! datapath would, and print its reports and congestion window and rate changes.
+
+use clap::ArgMatches;
+use portus::lang::{Bin, Instr, Op, Reg, Scope, Type};
+use portus::{Error, Result};
+
+pub const TRACE_HELP: &str = "\
+The trace is CSV. Its first line names the primitives in each column, e.g.
+`Ack.bytes_acked,Flow.rtt_sample_us`, and each following line is one ack.
+Primitives without a column read as 0. If there is an `Ack.now` column, it
+is the time of the ack in microseconds; otherwise acks are `--interval`
+microseconds apart. Blank lines and lines starting with `#` are ignored.";
+
+// Register classes, indexing `Machine::regs`.
+const CONTROL: usize = 0;
+const IMPLICIT: usize = 1;
+const LOCAL: usize = 2;
+const PRIMITIVE: usize = 3;
+const REPORT: usize = 4;
+const TMP: usize = 5;
+
+// Registers in each class.
+const NUM_REGS: usize = 16;
+
+/// The class and index of `r` in `Machine::regs`.
+fn slot(r: &Reg) -> Result<(usize, usize)> {
+    let (c, i) = match *r {
+        Reg::Control(i, _) => (CONTROL, i),
+        Reg::Implicit(i, _) => (IMPLICIT, i),
+        Reg::Local(i, _) => (LOCAL, i),
+        Reg::Primitive(i, _) => (PRIMITIVE, i),
+        Reg::Report(i, _, _) => (REPORT, i),
+        Reg::Tmp(i, _) => (TMP, i),
+        _ => return Err(Error(format!("not a register: {:?}", r))),
+    };
+
+    if i as usize >= NUM_REGS {
+        return Err(Error(format!(
+            "register {:?} is past the last of {}",
+            r, NUM_REGS
+        )));
+    }
+
+    Ok((c, i as usize))
+}
+
+/// The state of one flow running a program.
+struct Machine<'a> {
+    bin: &'a Bin,
+    regs: [[u64; NUM_REGS]; 6],
+    // instructions which do not belong to an event, which run at install
+    defs: Vec<&'a Instr>,
+    // the time at which Micros was 0
+    micros_zero: u64,
+}
+
+impl<'a> Machine<'a> {
+    fn new(bin: &'a Bin) -> Result<Self> {
+        let in_event = |i: u32| {
+            bin.events.iter().any(|ev| {
+                (ev.flag_idx <= i && i < ev.flag_idx + ev.num_flag_instrs)
+                    || (ev.body_idx <= i && i < ev.body_idx + ev.num_body_instrs)
+            })
+        };
+
+        let mut m = Machine {
+            bin,
+            regs: [[0u64; NUM_REGS]; 6],
+            defs: bin
+                .instrs
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| !in_event(i as u32))
+                .map(|(_, instr)| instr)
+                .collect(),
+            micros_zero: 0,
+        };
+
+        for i in m.defs.clone() {
+            m.exec(i)?;
+        }
+
+        Ok(m)
+    }
+
+    fn read(&self, r: &Reg) -> Result<u64> {
+        match *r {
+            Reg::ImmNum(n) => Ok(n),
+            Reg::ImmSigned(n) => Ok(n as u64),
+            Reg::ImmBool(b) => Ok(u64::from(b)),
+            Reg::None => Ok(0),
+            _ => {
+                let (c, i) = slot(r)?;
+                Ok(self.regs[c][i])
+            }
+        }
+    }
+
+    fn write(&mut self, r: &Reg, v: u64) -> Result<()> {
+        let (c, i) =
+            slot(r).map_err(|e| Error(format!("cannot write to register {:?}: {}", r, e.0)))?;
+        self.regs[c][i] = v;
+        Ok(())
+    }
+
+    fn exec(&mut self, i: &Instr) -> Result<()> {
+        let l = self.read(&i.left)?;
+        let r = self.read(&i.right)?;
+        let (sl, sr) = (l as i64, r as i64);
+        let v = match i.op {
+            Op::Add => l.wrapping_add(r),
+            Op::Sub => l.wrapping_sub(r),
+            Op::Mul => l.wrapping_mul(r),
+            Op::Div => l
+                .checked_div(r)
+                .ok_or_else(|| Error(String::from("division by zero")))?,
+            Op::SDiv => {
+                sl.checked_div(sr)
+                    .ok_or_else(|| Error(String::from("division by zero")))? as u64
+            }
+            Op::Equiv => u64::from(l == r),
+            Op::Gt => u64::from(l > r),
+            Op::Lt => u64::from(l < r),
+            Op::SGt => u64::from(sl > sr),
+            Op::SLt => u64::from(sl < sr),
+            Op::Max => l.max(r),
+            Op::Min => l.min(r),
+            Op::SMax => sl.max(sr) as u64,
+            Op::SMin => sl.min(sr) as u64,
+            // the later of two counters which may have wrapped around
+            Op::MaxWrap => {
+                if (l.wrapping_sub(r) as i64) >= 0 {
+                    l
+                } else {
+                    r
+                }
+            }
+            Op::Ewma => {
+                let old = self.read(&i.res)?;
+                (old.wrapping_mul(l)).wrapping_add(r.wrapping_mul(10u64.wrapping_sub(l))) / 10
+            }
+            Op::And => u64::from(l != 0 && r != 0),
+            Op::Or => u64::from(l != 0 || r != 0),
+            Op::If if l != 0 => r,
+            Op::NotIf if l == 0 => r,
+            Op::If | Op::NotIf => return Ok(()),
+            Op::Bind | Op::Def => r,
+        };
+
+        self.write(&i.res, v)
+    }
+
+    fn run(&mut self, start: u32, num: u32) -> Result<()> {
+        for idx in start..start + num {
+            let i = self
+                .bin
+                .instrs
+                .get(idx as usize)
+                .ok_or_else(|| Error(format!("no instruction {}", idx)))?;
+            self.exec(i)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Indices of the implicit registers the simulator manages.
+struct Implicit {
+    event_flag: usize,
+    should_continue: usize,
+    should_report: usize,
+    micros: usize,
+    cwnd: usize,
+    rate: usize,
+}
+
+impl Implicit {
+    fn new(sc: &Scope) -> Result<Self> {
+        let idx = |name: &str| match sc.get(name) {
+            Some(r @ &Reg::Implicit(..)) => slot(r).map(|(_, i)| i),
+            r => Err(Error(format!(
+                "{} is not an implicit register: {:?}",
+                name, r
+            ))),
+        };
+
+        Ok(Implicit {
+            event_flag: idx("__eventFlag")?,
+            should_continue: idx("__shouldContinue")?,
+            should_report: idx("__shouldReport")?,
+            micros: idx("Micros")?,
+            cwnd: idx("Cwnd")?,
+            rate: idx("Rate")?,
+        })
+    }
+}
+
+/// The primitive register for each column, and each row with its line number.
+type Trace = (Vec<usize>, Vec<(usize, Vec<u64>)>);
+
+fn parse_trace(src: &str, sc: &Scope) -> Result<Trace> {
+    let mut lines = src
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n + 1, l.trim()))
+        .filter(|&(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    let columns = match lines.next() {
+        Some((_, header)) => header
+            .split(',')
+            .map(|name| match sc.get(name.trim()) {
+                Some(r @ &Reg::Primitive(..)) => slot(r).map(|(_, i)| i),
+                _ => Err(Error(format!("trace column is not a primitive: {}", name))),
+            })
+            .collect::<Result<Vec<usize>>>()?,
+        None => return Err(Error(String::from("empty trace"))),
+    };
+
+    let rows = lines
+        .map(|(n, l)| {
+            let vals = l
+                .split(',')
+                .map(|v| v.trim().parse::<u64>())
+                .collect::<::std::result::Result<Vec<u64>, _>>()
+                .map_err(|e| Error(format!("line {}: {}", n, e)))?;
+            if vals.len() != columns.len() {
+                return Err(Error(format!(
+                    "line {}: expected {} values, got {}",
+                    n,
+                    columns.len(),
+                    vals.len()
+                )));
+            }
+
+            Ok((n, vals))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((columns, rows))
+}
+
+fn show(v: u64, t: &Type) -> String {
+    match *t {
+        Type::Bool(_) => (v != 0).to_string(),
+        Type::Signed(_) => (v as i64).to_string(),
+        _ => v.to_string(),
+    }
+}
+
+pub fn simulate(m: &ArgMatches) -> Result<()> {
+    let params = super::params(m)?;
+    let src = super::read_input(m.value_of("program"))?;
+    let (bin, sc) = super::compile(&src, &params)?;
+    let trace = super::read_input(m.value_of("trace"))?;
+    let trace = String::from_utf8(trace)?;
+    let init_cwnd = value_t!(m, "init-cwnd", u64).map_err(|e| Error(format!("{}", e)))?;
+    let interval = value_t!(m, "interval", u64).map_err(|e| Error(format!("{}", e)))?;
+
+    let (columns, rows) = parse_trace(&trace, &sc)?;
+    let now_col = match sc.get("Ack.now") {
+        Some(&Reg::Primitive(i, _)) => columns.iter().position(|&c| c == i as usize),
+        _ => None,
+    };
+
+    let mut reports: Vec<(&str, usize, Type)> = sc
+        .report_fields()
+        .filter_map(|(name, _, _)| match sc.get(name) {
+            Some(r @ Reg::Report(_, t, _)) => Some(slot(r).map(|(_, i)| (name, i, t.clone()))),
+            _ => None,
+        })
+        .collect::<Result<_>>()?;
+    reports.sort_by_key(|&(_, i, _)| i);
+
+    let imp = Implicit::new(&sc)?;
+    let mut dp = Machine::new(&bin)?;
+    dp.regs[IMPLICIT][imp.cwnd] = init_cwnd;
+    let (mut cwnd, mut rate) = (init_cwnd, 0);
+
+    for (row, &(line, ref vals)) in rows.iter().enumerate() {
+        let now = now_col.map(|c| vals[c]).unwrap_or(row as u64 * interval);
+        dp.regs[PRIMITIVE] = [0u64; NUM_REGS];
+        for (&c, &v) in columns.iter().zip(vals.iter()) {
+            dp.regs[PRIMITIVE][c] = v;
+        }
+
+        dp.regs[IMPLICIT][imp.micros] = now.wrapping_sub(dp.micros_zero);
+        for ev in &bin.events {
+            dp.regs[IMPLICIT][imp.event_flag] = 0;
+            dp.regs[IMPLICIT][imp.should_continue] = 0;
+            dp.run(ev.flag_idx, ev.num_flag_instrs)
+                .and_then(|_| {
+                    if dp.regs[IMPLICIT][imp.event_flag] != 0 {
+                        dp.run(ev.body_idx, ev.num_body_instrs)
+                    } else {
+                        Ok(())
+                    }
+                })
+                .map_err(|e| Error(format!("line {}: {}", line, e.0)))?;
+            if dp.regs[IMPLICIT][imp.event_flag] != 0 && dp.regs[IMPLICIT][imp.should_continue] == 0
+            {
+                break;
+            }
+        }
+
+        // setting Micros moves the time it counts from
+        dp.micros_zero = now.wrapping_sub(dp.regs[IMPLICIT][imp.micros]);
+
+        if dp.regs[IMPLICIT][imp.should_report] != 0 {
+            let fields: Vec<String> = reports
+                .iter()
+                .map(|&(name, i, ref t)| {
+                    format!(
+                        "{}={}",
+                        &name["Report.".len()..],
+                        show(dp.regs[REPORT][i], t)
+                    )
+                })
+                .collect();
+            println!("{}: report {}", line, fields.join(" "));
+
+            // volatile Report variables go back to their initial values
+            dp.regs[IMPLICIT][imp.should_report] = 0;
+            for i in dp.defs.clone() {
+                if let Reg::Report(_, _, true) = i.res {
+                    dp.exec(i)?;
+                }
+            }
+        }
+
+        if dp.regs[IMPLICIT][imp.cwnd] != cwnd {
+            cwnd = dp.regs[IMPLICIT][imp.cwnd];
+            println!("{}: cwnd {}", line, cwnd);
+        }
+
+        if dp.regs[IMPLICIT][imp.rate] != rate {
+            rate = dp.regs[IMPLICIT][imp.rate];
+            println!("{}: rate {}", line, rate);
+        }
+    }
+
+    Ok(())
+}
@@ -6,13 +6,15 @@ use std::fs::File;
 use std::fs::OpenOptions;
 use std::marker::PhantomData;
 use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use super::Error;
 use super::Result;
 
 pub struct Socket<T> {
     fd: File,
+    // how long `recv` waits for a message, in milliseconds
+    timeout: libc::c_int,
     _phantom: PhantomData<T>,
 }
 
@@ -23,15 +25,22 @@ impl<T> Socket<T> {
         options
     }
 
-    fn open(options: std::fs::OpenOptions) -> Result<Self> {
+    fn open(options: std::fs::OpenOptions, timeout: libc::c_int) -> Result<Self> {
         let file = options.open("/dev/ccpkp")?;
         Ok(Socket {
             fd: file,
+            timeout,
             _phantom: PhantomData,
         })
     }
 }
 
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
 impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
     fn name() -> String {
         String::from("char")
@@ -45,24 +54,32 @@ impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
 
     fn recv(&self, msg: &mut [u8]) -> Result<usize> {
         let pollfd = nix::poll::PollFd::new(self.fd.as_raw_fd(), nix::poll::POLLIN);
-        let ok = nix::poll::poll(&mut [pollfd], 1000)?;
+        let ok = nix::poll::poll(&mut [pollfd], self.timeout)?;
         if ok < 0 {
             return Err(Error::from(std::io::Error::from_raw_os_error(ok)));
+        } else if ok == 0 {
+            return Ok(0);
         }
 
-        let len = nix::unistd::read(self.fd.as_raw_fd(), msg).map_err(Error::from)?;
-        Ok(len)
+        match nix::unistd::read(self.fd.as_raw_fd(), msg) {
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => Ok(0),
+            r => r.map_err(Error::from),
+        }
     }
 
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
 }
 
 use super::Blocking;
 impl Socket<Blocking> {
     pub fn new() -> Result<Self> {
-        Self::open(Self::mk_opts())
+        Self::open(Self::mk_opts(), 1000)
     }
 }
 
@@ -71,6 +88,6 @@ impl Socket<Nonblocking> {
     pub fn new() -> Result<Self> {
         let mut options = Self::mk_opts();
         options.custom_flags(libc::O_NONBLOCK);
-        Self::open(options)
+        Self::open(options, 0)
     }
 }
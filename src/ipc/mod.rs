@@ -1,8 +1,15 @@
 //! A library wrapping various IPC mechanisms with a datagram-oriented
 //! messaging layer. This is how CCP communicates with the datapath.
 
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::io::RawFd;
 use std::rc::{Rc, Weak};
 use std::sync::{atomic, Arc};
+use std::time::Duration;
+
+use libc;
+use nix;
 
 use super::Error;
 use super::Result;
@@ -15,6 +22,20 @@ pub mod kp;
 #[cfg(all(target_os = "linux"))]
 /// Netlink socket implementation
 pub mod netlink;
+/// Exporting recorded IPC messages as pcapng
+pub mod pcapng;
+/// Recording IPC messages, and replaying them
+pub mod record;
+#[cfg(all(target_os = "linux"))]
+/// Unix seqpacket socket implementation, for serving several datapaths
+pub mod seqpacket;
+#[cfg(all(target_os = "linux"))]
+/// Shared memory ring buffer implementation, for datapaths on the same host
+pub mod shm;
+/// TCP implementation, for remote datapaths
+pub mod tcp;
+/// UDP implementation, for remote datapaths
+pub mod udp;
 /// Unix domain socket implementation
 pub mod unix;
 
@@ -24,12 +45,65 @@ pub trait Ipc: 'static + Send {
     fn name() -> String;
     /// Blocking send
     fn send(&self, msg: &[u8]) -> Result<()>;
-    /// Blocking listen. Return value is how many bytes were read. Should not allocate.
+    /// Blocking listen. Return value is how many bytes were read, or 0 if there was nothing to
+    /// read before the timeout, or right away if the socket is nonblocking. Should not allocate.
     fn recv(&self, msg: &mut [u8]) -> Result<usize>;
     /// Close the underlying sockets
     fn close(&mut self) -> Result<()>;
+    /// A file descriptor which polls readable when `recv` has something to return, so that
+    /// callers can wait with poll or epoll instead of calling `recv` in a loop.
+    /// `None` if the mechanism has no such descriptor.
+    fn poll_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// Whether the other end has closed the connection for good, so that `recv` can only fail
+    /// from now on. `Backend` stops when `recv` fails and this is set.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+// Whether `e` only means that there is nothing to read yet.
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+// Wait up to `timeout` for `fd` to become readable. Returns whether it did.
+fn poll_readable(fd: RawFd, timeout: Duration) -> Result<bool> {
+    poll_events(fd, libc::POLLIN, timeout)
 }
 
+// Wait up to `timeout` for `fd` to have room to write. Returns whether it did.
+fn poll_writable(fd: RawFd, timeout: Duration) -> Result<bool> {
+    poll_events(fd, libc::POLLOUT, timeout)
+}
+
+fn poll_events(fd: RawFd, events: libc::c_short, timeout: Duration) -> Result<bool> {
+    let mut pfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    let ms = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+    let ms = std::cmp::min(ms, libc::c_int::MAX as u64) as libc::c_int;
+    match unsafe { libc::poll(&mut pfd, 1, ms) } {
+        n if n < 0 => match nix::Error::last() {
+            nix::Error::Sys(nix::errno::Errno::EINTR) => Ok(false),
+            e => Err(Error::from(e)),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+// How long `Backend::next()` and the runtime wait on a `poll_fd` between checks of whether to
+// stop.
+pub(crate) const POLL_INTERVAL_MS: u64 = 100;
+
+// How long `Backend::wait` sleeps for a socket without a `poll_fd()`, which it cannot wait on.
+// Short, since a message may arrive at any time, but long enough not to spin on `recv`.
+const NO_POLL_FD_BACKOFF_MS: u64 = 1;
+
 /// Marker type specifying that the IPC socket should make blocking calls to the underlying socket
 pub struct Blocking;
 /// Marker type specifying that the IPC socket should make nonblocking calls to the underlying socket
@@ -69,6 +143,105 @@ impl<T: Ipc> Clone for BackendSender<T> {
     }
 }
 
+/// A send-only handle which coalesces messages into fewer IPC writes.
+/// Messages are held until `flush()`, or until the next one would not fit in `max_len` bytes;
+/// a message of `max_len` bytes or more is sent by itself. Clones share the held messages.
+/// The datapath must be able to read several messages from one write.
+pub struct BatchSender<T: Ipc> {
+    sender: BackendSender<T>,
+    buf: Rc<RefCell<Vec<u8>>>,
+    max_len: usize,
+}
+
+impl<T: Ipc> BatchSender<T> {
+    /// Batch messages into writes of at most `max_len` bytes, e.g. the transport's MTU.
+    /// With `max_len` 0, every message is sent at once.
+    pub fn new(sender: BackendSender<T>, max_len: usize) -> Self {
+        BatchSender {
+            sender,
+            buf: Rc::new(RefCell::new(Vec::with_capacity(max_len))),
+            max_len,
+        }
+    }
+
+    /// Send `msg` with the next batch.
+    pub fn send_msg(&self, msg: &[u8]) -> Result<()> {
+        if msg.len() >= self.max_len {
+            self.flush()?;
+            return self.sender.send_msg(msg);
+        }
+
+        let mut buf = self.buf.borrow_mut();
+        if buf.len() + msg.len() > self.max_len {
+            let res = self.sender.send_msg(&buf[..]);
+            buf.clear();
+            res?;
+        }
+
+        buf.extend_from_slice(msg);
+        Ok(())
+    }
+
+    /// Send `msg` with the next batch, like `send_msg()`. It is serialized straight into the
+    /// batch, or, if it is sent by itself, into a buffer kept for the purpose, so sending does
+    /// not allocate once that buffer has grown to fit.
+    pub fn send<M: AsRawMsg>(&self, msg: &M) -> Result<()> {
+        let len = msg.get_hdr().1 as usize;
+        let mut buf = self.buf.borrow_mut();
+        if !buf.is_empty() && (len >= self.max_len || buf.len() + len > self.max_len) {
+            let res = self.sender.send_msg(&buf[..]);
+            buf.clear();
+            res?;
+        }
+
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        if let Err(e) = ::serialize::serialize_to_buf(msg, &mut buf[start..]) {
+            buf.truncate(start);
+            return Err(e);
+        }
+
+        if len >= self.max_len {
+            // too long to batch, so it is sent by itself
+            let res = self.sender.send_msg(&buf[..]);
+            buf.clear();
+            return res;
+        }
+
+        Ok(())
+    }
+
+    /// Send the messages held so far. They are dropped if the send fails.
+    pub fn flush(&self) -> Result<()> {
+        let mut buf = self.buf.borrow_mut();
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let res = self.sender.send_msg(&buf[..]);
+        buf.clear();
+        res
+    }
+}
+
+impl<T: Ipc> Clone for BatchSender<T> {
+    fn clone(&self) -> Self {
+        BatchSender {
+            sender: self.sender.clone(),
+            buf: self.buf.clone(),
+            max_len: self.max_len,
+        }
+    }
+}
+
+/// What `Backend::try_next()` found.
+pub enum TryNext<'a> {
+    /// The next message.
+    Msg(Msg<'a>),
+    /// There is nothing to read yet: wait for `Backend::poll_fd()` to become readable.
+    WouldBlock,
+}
+
 /// Backend will yield incoming IPC messages forever via `next()`.
 /// It owns the socket; `BackendSender` holds weak references.
 /// The atomic bool is a way to stop iterating.
@@ -80,7 +253,7 @@ pub struct Backend<'a, T: Ipc> {
     read_until: usize,
 }
 
-use serialize::Msg;
+use serialize::{AsRawMsg, Msg};
 impl<'a, T: Ipc> Backend<'a, T> {
     pub fn new(
         sock: T,
@@ -106,45 +279,90 @@ impl<'a, T: Ipc> Backend<'a, T> {
         Arc::clone(&(self.continue_listening))
     }
 
+    /// Whether messages from the last read are left for `next()` to return without reading again.
+    pub fn has_buffered(&self) -> bool {
+        self.read_until < self.tot_read
+    }
+
+    /// The descriptor to wait on when `try_next()` returns `WouldBlock`, e.g. to register the
+    /// backend with an event loop. See `Ipc::poll_fd()`.
+    pub fn poll_fd(&self) -> Option<RawFd> {
+        self.sock.poll_fd()
+    }
+
+    /// Wait up to `timeout` for the socket to have something to read, and return whether it does.
+    /// A socket without a `poll_fd()` cannot be waited on, so this sleeps briefly instead and
+    /// returns `false`, for the caller to try reading again.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        match self.sock.poll_fd() {
+            Some(fd) => poll_readable(fd, timeout),
+            None => {
+                std::thread::sleep(std::cmp::min(
+                    timeout,
+                    Duration::from_millis(NO_POLL_FD_BACKOFF_MS),
+                ));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Get the next IPC message if there is one, without waiting for one to arrive if the socket
+    /// is nonblocking. Messages from one read are returned before reading again, so call this
+    /// until it returns `WouldBlock` before waiting on `poll_fd()`.
+    pub fn try_next(&mut self) -> Result<TryNext<'_>> {
+        if !self.has_buffered() && !self.read()? {
+            return Ok(TryNext::WouldBlock);
+        }
+
+        let (msg, consumed) = Msg::from_buf(&self.receive_buf[self.read_until..self.tot_read])?;
+        self.read_until += consumed;
+        Ok(TryNext::Msg(msg))
+    }
+
     /// Get the next IPC message.
     // This is similar to `impl Iterator`, but the returned value is tied to the lifetime
     // of `self`, so we cannot implement that trait.
     pub fn next(&mut self) -> Option<Msg<'_>> {
         // if we have leftover buffer from the last read, parse another message.
-        if self.read_until < self.tot_read {
-            let (msg, consumed) = Msg::from_buf(&self.receive_buf[self.read_until..]).ok()?;
-            self.read_until += consumed;
-            Some(msg)
-        } else {
-            self.tot_read = self.get_next_read().ok()?;
-            self.read_until = 0;
-            let (msg, consumed) =
-                Msg::from_buf(&self.receive_buf[self.read_until..self.tot_read]).ok()?;
-            self.read_until += consumed;
-
-            Some(msg)
+        while !self.has_buffered() {
+            if self.read().ok()? {
+                break;
+            }
+
+            // rather than spin on a nonblocking socket, sleep until there is something to read
+            self.wait(Duration::from_millis(POLL_INTERVAL_MS))
+                .unwrap_or(false);
         }
-    }
 
-    // calls IPC repeatedly to read one or more messages.
-    // Returns a slice into self.receive_buf covering the read data
-    fn get_next_read(&mut self) -> Result<usize> {
-        loop {
-            // if continue_loop has been set to false, stop iterating
-            if !self.continue_listening.load(atomic::Ordering::SeqCst) {
-                return Err(Error(String::from("Done")));
-            }
+        let (msg, consumed) =
+            Msg::from_buf(&self.receive_buf[self.read_until..self.tot_read]).ok()?;
+        self.read_until += consumed;
+        Some(msg)
+    }
 
-            let read = match self.sock.recv(self.receive_buf) {
-                Ok(l) => l,
-                _ => continue,
-            };
+    // Read once from the socket into `receive_buf`, and return whether anything was read.
+    // Errors from a socket which is still open count as nothing read, so that a timeout or a
+    // datapath which is not there yet does not stop the caller.
+    fn read(&mut self) -> Result<bool> {
+        // if continue_loop has been set to false, stop iterating
+        if !self.continue_listening.load(atomic::Ordering::SeqCst) {
+            return Err(Error(String::from("Done")));
+        }
 
-            if read == 0 {
-                continue;
+        match self.sock.recv(self.receive_buf) {
+            Ok(0) => Ok(false),
+            Ok(l) => {
+                self.tot_read = l;
+                self.read_until = 0;
+                Ok(true)
+            }
+            Err(e) => {
+                if self.sock.is_closed() {
+                    Err(e)
+                } else {
+                    Ok(false)
+                }
             }
-
-            return Ok(read);
         }
     }
 }
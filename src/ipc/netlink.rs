@@ -1,26 +1,218 @@
 use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bytes::{ByteOrder, LittleEndian};
 
 use super::Error;
 use super::Result;
+use serialize::{u32_from_u8s, u32_to_u8s};
 
 extern crate libc;
 use libc::c_int;
 extern crate nix;
 use nix::sys::socket;
 
-pub struct Socket<T>(c_int, PhantomData<T>);
-
 const NL_CFG_F_NONROOT_RECV: c_int = 1;
 const NL_CFG_F_NONROOT_SEND: c_int = (1 << 1);
 const NLMSG_HDRSIZE: usize = 0x10;
 
+// netlink's own message types
+const NLMSG_NOOP: u16 = 1;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_OVERRUN: u16 = 4;
+
+/// How `Socket`s are set up.
+///
+/// By default, a socket uses `NETLINK_USERSOCK`, binds the process id as its port id, joins
+/// multicast group 22, where the CCP kernel module sends, sends to the kernel, waits up to 1
+/// second in a blocking `recv`, and reads datagrams of up to 1024 bytes.
+///
+/// ```
+/// use portus::ipc::netlink::SocketBuilder;
+/// let b = SocketBuilder::new().group(23).buf_size(64 * 1024).rcvbuf(1 << 20);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SocketBuilder {
+    protocol: c_int,
+    portid: Option<u32>,
+    group: u32,
+    peer_portid: u32,
+    timeout: Option<Duration>,
+    buf_size: usize,
+    rcvbuf: Option<usize>,
+}
+
+impl Default for SocketBuilder {
+    fn default() -> Self {
+        SocketBuilder {
+            protocol: libc::NETLINK_USERSOCK,
+            portid: None,
+            group: 22,
+            peer_portid: 0,
+            timeout: Some(Duration::from_secs(1)),
+            buf_size: 1024,
+            rcvbuf: None,
+        }
+    }
+}
+
+impl SocketBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The netlink protocol, e.g. for a datapath module which registers its own family.
+    pub fn protocol(mut self, protocol: c_int) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Bind `portid` instead of the process id. 0 lets the kernel pick an unused one, e.g. for
+    /// a second socket in the same process.
+    pub fn portid(mut self, portid: u32) -> Self {
+        self.portid = Some(portid);
+        self
+    }
+
+    /// Join multicast group `group`, or none if it is 0.
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Send to the socket bound to `portid`, rather than to the kernel (port id 0).
+    pub fn peer_portid(mut self, portid: u32) -> Self {
+        self.peer_portid = portid;
+        self
+    }
+
+    /// How long a blocking `recv` waits for a datagram. `None` waits forever.
+    pub fn recv_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The largest datagram `recv` can read, including the netlink headers of every message in
+    /// it. A larger datagram is an error, since the rest of it is lost.
+    pub fn buf_size(mut self, buf_size: usize) -> Self {
+        self.buf_size = buf_size;
+        self
+    }
+
+    /// Ask the kernel to queue up to `bytes` of datagrams for the socket (`SO_RCVBUF`), so a
+    /// datapath sending in bursts does not overrun it.
+    pub fn rcvbuf(mut self, bytes: usize) -> Self {
+        self.rcvbuf = Some(bytes);
+        self
+    }
+
+    /// Make a blocking `Socket`.
+    pub fn build(&self) -> Result<Socket<Blocking>> {
+        Socket::<Blocking>::with_builder(self)
+    }
+
+    /// Make a nonblocking `Socket`.
+    pub fn build_nonblocking(&self) -> Result<Socket<Nonblocking>> {
+        Socket::<Nonblocking>::with_builder(self)
+    }
+}
+
+// The last datagram read, and where in it the next netlink message starts.
+struct RecvState {
+    buf: Vec<u8>,
+    off: usize,
+    end: usize,
+}
+
+impl RecvState {
+    // Copy the payloads of as many of the remaining netlink messages as fit into `out`, one
+    // after another. An error is only returned once the payloads before it have been returned.
+    fn take_payloads(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut written = 0;
+        while self.end - self.off >= NLMSG_HDRSIZE {
+            let msg = &self.buf[self.off..self.end];
+            let len = u32_from_u8s(&msg[0..4]) as usize;
+            let typ = LittleEndian::read_u16(&msg[4..6]);
+            if len < NLMSG_HDRSIZE || len > msg.len() {
+                if written > 0 {
+                    break;
+                }
+
+                self.off = self.end;
+                return Err(Error(format!(
+                    "malformed netlink message: length {}, {} bytes left",
+                    len,
+                    msg.len()
+                )));
+            }
+
+            // messages are padded to 4 bytes
+            let next = self.off + ((len + 3) & !3);
+            let err = match typ {
+                NLMSG_NOOP => None,
+                NLMSG_DONE => {
+                    self.off = self.end;
+                    break;
+                }
+                NLMSG_ERROR if len >= NLMSG_HDRSIZE + 4 => {
+                    // an error of 0 is an acknowledgement
+                    match u32_from_u8s(&msg[NLMSG_HDRSIZE..NLMSG_HDRSIZE + 4]) as i32 {
+                        0 => None,
+                        errno => Some(format!("netlink error {}", -errno)),
+                    }
+                }
+                NLMSG_ERROR => Some(String::from("malformed netlink error message")),
+                NLMSG_OVERRUN => Some(String::from("netlink overrun: messages were lost")),
+                _ if written + len - NLMSG_HDRSIZE > out.len() => Some(format!(
+                    "netlink message of {} bytes does not fit in buffer of {}",
+                    len - NLMSG_HDRSIZE,
+                    out.len() - written
+                )),
+                _ => {
+                    out[written..written + len - NLMSG_HDRSIZE]
+                        .copy_from_slice(&msg[NLMSG_HDRSIZE..len]);
+                    written += len - NLMSG_HDRSIZE;
+                    None
+                }
+            };
+
+            if let Some(e) = err {
+                if written > 0 {
+                    break;
+                }
+
+                self.off = ::std::cmp::min(next, self.end);
+                return Err(Error(e));
+            }
+
+            self.off = ::std::cmp::min(next, self.end);
+        }
+
+        if self.end - self.off < NLMSG_HDRSIZE {
+            self.off = self.end;
+        }
+
+        Ok(written)
+    }
+}
+
+pub struct Socket<T> {
+    fd: c_int,
+    peer: socket::SockAddr,
+    recv_state: Mutex<RecvState>,
+    _phantom: PhantomData<T>,
+}
+
 impl<T> Socket<T> {
-    fn __new() -> Result<Self> {
+    fn __new(cfg: &SocketBuilder) -> Result<Self> {
         let fd = if let Ok(fd) = socket::socket(
             nix::sys::socket::AddressFamily::Netlink,
             nix::sys::socket::SockType::Raw,
             nix::sys::socket::SockFlag::empty(),
-            libc::NETLINK_USERSOCK,
+            cfg.protocol,
         ) {
             fd
         } else {
@@ -29,42 +221,71 @@ impl<T> Socket<T> {
                 nix::sys::socket::SockType::Raw,
                 nix::sys::socket::SockFlag::from_bits_truncate(NL_CFG_F_NONROOT_RECV)
                     | nix::sys::socket::SockFlag::from_bits_truncate(NL_CFG_F_NONROOT_SEND),
-                libc::NETLINK_USERSOCK,
+                cfg.protocol,
             )?
         };
 
-        let pid = unsafe { libc::getpid() };
+        let s = Socket {
+            fd,
+            peer: socket::SockAddr::new_netlink(cfg.peer_portid, 0),
+            recv_state: Mutex::new(RecvState {
+                buf: vec![0u8; cfg.buf_size],
+                off: 0,
+                end: 0,
+            }),
+            _phantom: PhantomData,
+        };
 
-        socket::bind(fd, &nix::sys::socket::SockAddr::new_netlink(pid as u32, 0))?;
+        let pid = cfg
+            .portid
+            .unwrap_or_else(|| unsafe { libc::getpid() } as u32);
+        socket::bind(fd, &nix::sys::socket::SockAddr::new_netlink(pid, 0))?;
 
-        Ok(Socket(fd, PhantomData))
-    }
-
-    pub fn new() -> Result<Self> {
-        let s = Self::__new()?;
-        let opt = 22;
         use std::mem;
-        s.setsockopt(
-            270,
-            libc::NETLINK_ADD_MEMBERSHIP,
-            &opt as *const i32 as *const libc::c_void,
-            mem::size_of::<c_int>() as u32,
-        )?;
-
-        let to = libc::timespec {
-            tv_sec: 1 as libc::time_t,
-            tv_nsec: 0 as libc::c_long,
-        };
+        if cfg.group != 0 {
+            s.setsockopt(
+                270,
+                libc::NETLINK_ADD_MEMBERSHIP,
+                &cfg.group as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as u32,
+            )?;
+        }
+
+        if let Some(t) = cfg.timeout {
+            let to = libc::timeval {
+                tv_sec: t.as_secs() as libc::time_t,
+                tv_usec: libc::suseconds_t::from(t.subsec_micros()),
+            };
+
+            s.setsockopt(
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &to as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as u32,
+            )?;
+        }
+
+        if let Some(bytes) = cfg.rcvbuf {
+            let bytes = bytes as c_int;
+            s.setsockopt(
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &bytes as *const c_int as *const libc::c_void,
+                mem::size_of::<c_int>() as u32,
+            )?;
+        }
 
-        s.setsockopt(
-            libc::SOL_SOCKET,
-            libc::SO_RCVTIMEO,
-            &to as *const libc::timespec as *const libc::c_void,
-            mem::size_of::<libc::timespec>() as u32,
-        )?;
         Ok(s)
     }
 
+    /// The port id the socket is bound to, e.g. to learn the one the kernel picked.
+    pub fn portid(&self) -> Result<u32> {
+        match socket::getsockname(self.fd)? {
+            socket::SockAddr::Netlink(addr) => Ok(addr.pid()),
+            a => Err(Error(format!("not a netlink address: {:?}", a))),
+        }
+    }
+
     fn setsockopt(
         &self,
         level: c_int,
@@ -72,7 +293,7 @@ impl<T> Socket<T> {
         val: *const libc::c_void,
         sz: u32,
     ) -> Result<()> {
-        let res = unsafe { libc::setsockopt(self.0, level, option as c_int, val, sz) };
+        let res = unsafe { libc::setsockopt(self.fd, level, option as c_int, val, sz) };
 
         if res == -1 {
             return Err(Error::from(nix::Error::last()));
@@ -81,18 +302,31 @@ impl<T> Socket<T> {
         Ok(())
     }
 
+    // A datagram may carry several netlink messages; return the payloads of as many as fit in
+    // `buf`, and keep the rest for the next call.
     fn __recv(&self, buf: &mut [u8], flags: nix::sys::socket::MsgFlags) -> Result<usize> {
-        let mut nl_buf = [0u8; 1024];
-        let end = socket::recvmsg::<()>(
-            self.0,
-            &[nix::sys::uio::IoVec::from_mut_slice(&mut nl_buf[..])],
-            None,
-            flags,
-        )
-        .map(|r| r.bytes)
-        .map_err(Error::from)?;
-        buf[..(end - NLMSG_HDRSIZE)].copy_from_slice(&nl_buf[NLMSG_HDRSIZE..end]);
-        Ok(end - NLMSG_HDRSIZE)
+        let mut st = self.recv_state.lock().unwrap();
+        if st.off >= st.end {
+            // with MSG_TRUNC, recv returns the length of the whole datagram, even if it did
+            // not fit
+            let flags = flags | socket::MsgFlags::from_bits_truncate(libc::MSG_TRUNC);
+            let n = match socket::recv(self.fd, &mut st.buf[..], flags) {
+                Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => return Ok(0),
+                r => r.map_err(Error::from)?,
+            };
+            if n > st.buf.len() {
+                return Err(Error(format!(
+                    "netlink datagram of {} bytes truncated to {}",
+                    n,
+                    st.buf.len()
+                )));
+            }
+
+            st.off = 0;
+            st.end = n;
+        }
+
+        st.take_payloads(buf)
     }
 
     // netlink header format (RFC 3549)
@@ -108,30 +342,26 @@ impl<T> Socket<T> {
     // |                      Process ID (PID)                       |
     // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     fn __send(&self, buf: &[u8]) -> Result<()> {
-        let len = NLMSG_HDRSIZE + buf.len();
-        let mut msg = Vec::<u8>::with_capacity(len);
-        msg.resize(4, 0u8);
-        // write the netlink header
-        super::super::serialize::u32_to_u8s(&mut msg[0..4], len as u32);
-        // rest is 0s
-        msg.extend_from_slice(&[0u8; 12]);
-        // payload
-        msg.extend_from_slice(buf);
-
-        // send
+        // the rest of the header is 0s
+        let mut hdr = [0u8; NLMSG_HDRSIZE];
+        u32_to_u8s(&mut hdr[0..4], (NLMSG_HDRSIZE + buf.len()) as u32);
+
         socket::sendmsg(
-            self.0,
-            &[nix::sys::uio::IoVec::from_slice(&msg[..])],
+            self.fd,
+            &[
+                nix::sys::uio::IoVec::from_slice(&hdr[..]),
+                nix::sys::uio::IoVec::from_slice(buf),
+            ],
             &[],
             nix::sys::socket::MsgFlags::empty(),
-            None,
+            Some(&self.peer),
         )
         .map(|_| ())
         .map_err(Error::from)
     }
 
     fn __close(&mut self) -> Result<()> {
-        let ok = unsafe { libc::close(self.0) as i32 };
+        let ok = unsafe { libc::close(self.fd) as i32 };
         if ok < 0 {
             Err(Error(format!("could not close netlink socket: {}", ok)))
         } else {
@@ -140,7 +370,25 @@ impl<T> Socket<T> {
     }
 }
 
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
 use super::Blocking;
+impl Socket<Blocking> {
+    /// Make a socket with the default settings of `SocketBuilder`.
+    pub fn new() -> Result<Self> {
+        Self::with_builder(&SocketBuilder::default())
+    }
+
+    /// Make a socket with the settings of `cfg`.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        Socket::__new(cfg)
+    }
+}
+
 impl super::Ipc for Socket<Blocking> {
     fn name() -> String {
         String::from("netlink")
@@ -157,9 +405,25 @@ impl super::Ipc for Socket<Blocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
 }
 
 use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Make a socket with the default settings of `SocketBuilder`.
+    pub fn new() -> Result<Self> {
+        Self::with_builder(&SocketBuilder::default())
+    }
+
+    /// Make a socket with the settings of `cfg`.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        Socket::__new(cfg)
+    }
+}
+
 impl super::Ipc for Socket<Nonblocking> {
     fn name() -> String {
         String::from("netlink")
@@ -176,4 +440,8 @@ impl super::Ipc for Socket<Nonblocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.fd)
+    }
 }
@@ -0,0 +1,143 @@
+//! Exporting captures made by `record::Recorder` as pcapng, to look at with Wireshark or tshark.
+//!
+//! The capture has one interface, of link type `LINKTYPE_USER0`, and one packet per recorded
+//! buffer, with nanosecond timestamps. Inbound packets came from the datapath, and outbound ones
+//! were sent by CCP. Since the recording does not note the wall-clock time it started, packet
+//! times count from the Unix epoch as if the recording had started then; view them relative to
+//! the first packet. Each packet's comment describes the CCP messages in it; see
+//! `serialize::dissect`.
+
+use std::io::Write;
+
+use bytes::{ByteOrder, LittleEndian};
+
+use super::record::{Direction, Record};
+use super::Result;
+use serialize::dissect;
+
+/// The link type of the packets, the first of those reserved for private use.
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SHB: u32 = 0x0A0D_0D0A;
+const IDB: u32 = 1;
+const EPB: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+// epb_flags values for the packet's direction
+const INBOUND: u32 = 1;
+const OUTBOUND: u32 = 2;
+
+fn pad(body: &mut Vec<u8>) {
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+}
+
+fn push_u16(body: &mut Vec<u8>, v: u16) {
+    let mut b = [0u8; 2];
+    LittleEndian::write_u16(&mut b, v);
+    body.extend_from_slice(&b);
+}
+
+fn push_u32(body: &mut Vec<u8>, v: u32) {
+    let mut b = [0u8; 4];
+    LittleEndian::write_u32(&mut b, v);
+    body.extend_from_slice(&b);
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, val: &[u8]) {
+    push_u16(body, code);
+    push_u16(body, val.len() as u16);
+    body.extend_from_slice(val);
+    pad(body);
+}
+
+fn end_options(body: &mut Vec<u8>) {
+    push_u16(body, OPT_END);
+    push_u16(body, 0);
+}
+
+// A block is its type and total length, the body, then the total length again.
+fn write_block<W: Write>(w: &mut W, typ: u32, body: &[u8]) -> Result<()> {
+    let mut hdr = [0u8; 8];
+    let len = (body.len() + 12) as u32;
+    LittleEndian::write_u32(&mut hdr[0..4], typ);
+    LittleEndian::write_u32(&mut hdr[4..8], len);
+    w.write_all(&hdr)?;
+    w.write_all(body)?;
+    w.write_all(&hdr[4..8])?;
+    Ok(())
+}
+
+fn comment(buf: &[u8]) -> String {
+    dissect::dissect(buf)
+        .map(|m| match m {
+            Ok((_, s)) => s,
+            Err(e) => e.0,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Write `records` to `w` as a pcapng capture.
+pub fn write<W: Write>(mut w: W, records: &[Record]) -> Result<()> {
+    let mut body = vec![];
+    push_u32(&mut body, BYTE_ORDER_MAGIC);
+    push_u16(&mut body, 1);
+    push_u16(&mut body, 0);
+    // the section length is not known up front
+    body.extend_from_slice(&[0xff; 8]);
+    push_option(&mut body, SHB_USERAPPL, b"portus");
+    end_options(&mut body);
+    write_block(&mut w, SHB, &body)?;
+
+    body.clear();
+    push_u16(&mut body, LINKTYPE_USER0);
+    push_u16(&mut body, 0);
+    // no limit on packet length
+    push_u32(&mut body, 0);
+    push_option(&mut body, IF_NAME, b"ccp");
+    // timestamps are in units of 10^-9 seconds
+    push_option(&mut body, IF_TSRESOL, &[9]);
+    end_options(&mut body);
+    write_block(&mut w, IDB, &body)?;
+
+    for r in records {
+        let ts = r.time.as_secs() * 1_000_000_000 + u64::from(r.time.subsec_nanos());
+        body.clear();
+        push_u32(&mut body, 0);
+        push_u32(&mut body, (ts >> 32) as u32);
+        push_u32(&mut body, ts as u32);
+        push_u32(&mut body, r.buf.len() as u32);
+        push_u32(&mut body, r.buf.len() as u32);
+        body.extend_from_slice(&r.buf);
+        pad(&mut body);
+
+        let mut flags = [0u8; 4];
+        LittleEndian::write_u32(
+            &mut flags,
+            match r.dir {
+                Direction::FromDatapath => INBOUND,
+                Direction::ToDatapath => OUTBOUND,
+            },
+        );
+        push_option(&mut body, EPB_FLAGS, &flags);
+        let c = comment(&r.buf);
+        if !c.is_empty() && c.len() <= usize::from(u16::MAX) {
+            push_option(&mut body, OPT_COMMENT, c.as_bytes());
+        }
+
+        end_options(&mut body);
+        write_block(&mut w, EPB, &body)?;
+    }
+
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,336 @@
+//! Recording the messages CCP exchanges with a datapath, and replaying them later.
+//!
+//! A `Recorder` wraps another `Ipc`, and writes each buffer it sends or receives to a capture,
+//! along with when it did so. A `Replay` is an `Ipc` which gives `run` or `spawn` the buffers
+//! a capture says the datapath sent, with their recorded spacing or faster, so that an algorithm
+//! sees the same creates and measurements it saw on the recording host. What CCP sends during a
+//! replay is dropped; wrap the `Replay` in a `Recorder` to compare it with the capture.
+//!
+//! A capture starts with the magic bytes `CCPCAP`, then the format version as a `u16`. Each
+//! record is the time since recording started in nanoseconds (`u64`), the direction (`u8`,
+//! 0 from the datapath and 1 to the datapath), the buffer length (`u32`), then the buffer.
+//! Integers are little-endian. A buffer holds one or more CCP messages.
+
+use std;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::{ByteOrder, LittleEndian};
+
+use super::Error;
+use super::Ipc;
+use super::Result;
+
+/// The first bytes of every capture.
+pub const MAGIC: &[u8] = b"CCPCAP";
+const VERSION: u16 = 1;
+const HDR_SIZE: usize = 8;
+const RECORD_HDR_SIZE: usize = 13;
+
+// How long `Replay::recv` waits before returning nothing, like a blocking socket's timeout.
+const WAIT: Duration = Duration::from_secs(1);
+
+/// Which way a recorded buffer went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Received by CCP.
+    FromDatapath,
+    /// Sent by CCP.
+    ToDatapath,
+}
+
+/// One buffer in a capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The time since recording started.
+    pub time: Duration,
+    pub dir: Direction,
+    /// What one `send` or `recv` carried, which may be several messages.
+    pub buf: Vec<u8>,
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())
+}
+
+fn write_header<W: Write>(w: &mut W) -> std::io::Result<()> {
+    let mut hdr = [0u8; HDR_SIZE];
+    hdr[..MAGIC.len()].copy_from_slice(MAGIC);
+    LittleEndian::write_u16(&mut hdr[MAGIC.len()..], VERSION);
+    w.write_all(&hdr)
+}
+
+fn write_record<W: Write>(w: &mut W, time: Duration, dir: Direction, buf: &[u8]) -> Result<()> {
+    let mut hdr = [0u8; RECORD_HDR_SIZE];
+    LittleEndian::write_u64(&mut hdr[0..8], nanos(time));
+    hdr[8] = match dir {
+        Direction::FromDatapath => 0,
+        Direction::ToDatapath => 1,
+    };
+    LittleEndian::write_u32(&mut hdr[9..13], buf.len() as u32);
+    w.write_all(&hdr)?;
+    w.write_all(buf)?;
+    Ok(())
+}
+
+/// Read every record of a capture from `r`.
+/// A record cut short, e.g. because the recording process was killed, ends the capture.
+pub fn read_records<R: Read>(mut r: R) -> Result<Vec<Record>> {
+    let mut hdr = [0u8; HDR_SIZE];
+    r.read_exact(&mut hdr)?;
+    if &hdr[..MAGIC.len()] != MAGIC {
+        return Err(Error(String::from("not a CCP capture")));
+    }
+
+    let version = LittleEndian::read_u16(&hdr[MAGIC.len()..]);
+    if version != VERSION {
+        return Err(Error(format!("unsupported capture version {}", version)));
+    }
+
+    let mut records = vec![];
+    loop {
+        let mut hdr = [0u8; RECORD_HDR_SIZE];
+        match r.read_exact(&mut hdr) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        let time = LittleEndian::read_u64(&hdr[0..8]);
+        let dir = match hdr[8] {
+            0 => Direction::FromDatapath,
+            1 => Direction::ToDatapath,
+            d => return Err(Error(format!("unknown direction {} in capture", d))),
+        };
+        let mut buf = vec![0u8; LittleEndian::read_u32(&hdr[9..13]) as usize];
+        match r.read_exact(&mut buf) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        records.push(Record {
+            time: Duration::new(time / 1_000_000_000, (time % 1_000_000_000) as u32),
+            dir,
+            buf,
+        });
+    }
+
+    Ok(records)
+}
+
+/// Read every record of the capture file at `path`.
+pub fn read_capture<P: AsRef<Path>>(path: P) -> Result<Vec<Record>> {
+    read_records(BufReader::new(File::open(path)?))
+}
+
+/// Records everything an `Ipc` sends and receives.
+///
+/// Each record is written out before `send` or `recv` returns, so the capture is complete up to
+/// the last message even if the process dies. If writing the capture fails, recording stops,
+/// but messages still pass through.
+pub struct Recorder<I: Ipc> {
+    inner: I,
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+    start: Instant,
+}
+
+impl<I: Ipc> Recorder<I> {
+    /// Record the messages `inner` carries in a new capture file at `path`.
+    pub fn new<P: AsRef<Path>>(inner: I, path: P) -> Result<Self> {
+        Recorder::with_writer(inner, BufWriter::new(File::create(path)?))
+    }
+
+    /// Record the messages `inner` carries to `w`, e.g. a pipe to another process.
+    pub fn with_writer<W: Write + Send + 'static>(inner: I, mut w: W) -> Result<Self> {
+        write_header(&mut w)?;
+        w.flush()?;
+        Ok(Recorder {
+            inner,
+            out: Mutex::new(Some(Box::new(w))),
+            start: Instant::now(),
+        })
+    }
+
+    /// The `Ipc` being recorded.
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    fn record(&self, dir: Direction, buf: &[u8]) {
+        let mut out = self.out.lock().unwrap();
+        let ok = match out.as_mut() {
+            Some(w) => write_record(w, self.start.elapsed(), dir, buf)
+                .and_then(|_| w.flush().map_err(Error::from))
+                .is_ok(),
+            None => return,
+        };
+
+        if !ok {
+            out.take();
+        }
+    }
+}
+
+impl<I: Ipc> Ipc for Recorder<I> {
+    fn name() -> String {
+        I::name()
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        self.inner.send(msg)?;
+        self.record(Direction::ToDatapath, msg);
+        Ok(())
+    }
+
+    fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        let n = self.inner.recv(msg)?;
+        if n > 0 {
+            self.record(Direction::FromDatapath, &msg[..n]);
+        }
+
+        Ok(n)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let flushed = match self.out.lock().unwrap().take() {
+            Some(mut w) => w.flush().map_err(Error::from),
+            None => Ok(()),
+        };
+        self.inner.close()?;
+        flushed
+    }
+
+    fn poll_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.inner.poll_fd()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+struct ReplayState {
+    // the buffers from the datapath which are still to come
+    records: VecDeque<Record>,
+    // when the first buffer was replayed, and its recorded time
+    start: Option<(Instant, Duration)>,
+}
+
+/// Replays what a datapath sent in a capture.
+///
+/// `recv` returns each buffer once as much time has passed since the first one as had in the
+/// recording, divided by the replay `speed`. Once every buffer has been replayed, `finished()`
+/// is set, and `recv` has nothing more to return.
+///
+/// ```no_run
+/// use portus::ipc::record::Replay;
+/// let replay = Replay::open("/tmp/ccp.cap").expect("read capture").speed(10.0);
+/// let finished = replay.finished();
+/// // let h = portus::spawn(portus::ipc::BackendBuilder { sock: replay }, cfg, alg);
+/// // while !finished.load(std::sync::atomic::Ordering::SeqCst) { ... }
+/// // h.kill();
+/// ```
+pub struct Replay {
+    state: Mutex<ReplayState>,
+    speed: f64,
+    finished: Arc<AtomicBool>,
+}
+
+impl Replay {
+    /// Replay the buffers in `records` which came from the datapath.
+    pub fn new(records: Vec<Record>) -> Self {
+        let records: VecDeque<_> = records
+            .into_iter()
+            .filter(|r| r.dir == Direction::FromDatapath)
+            .collect();
+        Replay {
+            finished: Arc::new(AtomicBool::new(records.is_empty())),
+            state: Mutex::new(ReplayState {
+                records,
+                start: None,
+            }),
+            speed: 1.0,
+        }
+    }
+
+    /// Replay the capture file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Replay::new(read_capture(path)?))
+    }
+
+    /// Replay `speed` times faster than recorded, e.g. `10.0`, or `f64::INFINITY` to
+    /// replay without waiting. The default, `1.0`, keeps the recorded timing.
+    pub fn speed(mut self, speed: f64) -> Self {
+        assert!(speed > 0.0, "replay speed must be positive");
+        self.speed = speed;
+        self
+    }
+
+    /// Set once the last buffer has been replayed, e.g. to know when to stop a spawned CCP.
+    pub fn finished(&self) -> Arc<AtomicBool> {
+        self.finished.clone()
+    }
+}
+
+impl Ipc for Replay {
+    fn name() -> String {
+        String::from("replay")
+    }
+
+    fn send(&self, _msg: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        let mut st = self.state.lock().unwrap();
+        let time = match st.records.front() {
+            Some(r) => r.time,
+            None => {
+                drop(st);
+                thread::sleep(WAIT);
+                return Ok(0);
+            }
+        };
+
+        let (start, first) = *st.start.get_or_insert_with(|| (Instant::now(), time));
+        let offset = time.checked_sub(first).unwrap_or_default();
+        let due = start + Duration::from_secs_f64(offset.as_secs_f64() / self.speed);
+        let now = Instant::now();
+        if due > now {
+            let wait = due - now;
+            if wait > WAIT {
+                thread::sleep(WAIT);
+                return Ok(0);
+            }
+
+            thread::sleep(wait);
+        }
+
+        let r = st.records.pop_front().unwrap();
+        if st.records.is_empty() {
+            self.finished.store(true, Ordering::SeqCst);
+        }
+
+        if r.buf.len() > msg.len() {
+            return Err(Error(format!(
+                "{} byte recorded buffer does not fit in {} byte buffer",
+                r.buf.len(),
+                msg.len()
+            )));
+        }
+
+        msg[..r.buf.len()].copy_from_slice(&r.buf);
+        Ok(r.buf.len())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
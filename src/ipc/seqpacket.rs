@@ -0,0 +1,493 @@
+//! A connection-oriented unix socket (`SOCK_SEQPACKET`), which any number of datapaths, e.g.
+//! several userspace stacks on one host, can connect to.
+//!
+//! Each datapath numbers its own flows, so the socket gives each flow of each connection a sid
+//! of its own: it rewrites the sid in the header of every message it receives, and when CCP sends
+//! to that sid, rewrites it back and sends the message to the connection the flow belongs to.
+//! Messages CCP sends with sid 0, such as program installs, go to every connected datapath, and
+//! the latest install of each program also goes to each datapath which connects later. When a
+//! datapath disconnects, `recv` reports each of its flows as closed, as if the datapath had sent
+//! a measurement with no fields.
+//! The socket's `poll_fd` is an epoll descriptor covering the listener and every connection,
+//! which `recv` also waits on. Connections are nonblocking: what a datapath has no room for yet
+//! is queued, and written when it has, so that a slow datapath does not hold up the others.
+//! `send` writes to every datapath it can, and its error names each connection it could not
+//! write to.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+
+use bytes::{ByteOrder, LittleEndian};
+use fnv::FnvHashMap as HashMap;
+
+extern crate libc;
+extern crate nix;
+use nix::sys::socket;
+
+use super::unix::SocketBuilder;
+use super::Error;
+use super::Result;
+use serialize::{self, install, measure, HDR_LENGTH};
+
+// How many writes a connection may hold back before sends to it fail.
+const MAX_QUEUED: usize = 1024;
+
+struct Conn {
+    id: u32,
+    sk: UnixStream,
+    // writes the socket had no room for, in order
+    queued: VecDeque<Vec<u8>>,
+    // whether `epoll` reports when the socket has room, which it does while writes are queued
+    wants_out: bool,
+}
+
+impl Conn {
+    // Write `buf` after the writes already queued, queueing whatever the socket has no room for.
+    fn send(&mut self, buf: Vec<u8>, epoll: RawFd) -> Result<()> {
+        if self.queued.len() >= MAX_QUEUED {
+            return Err(Error(format!(
+                "{} writes are already waiting for the datapath to read",
+                MAX_QUEUED
+            )));
+        }
+
+        self.queued.push_back(buf);
+        self.flush(epoll)
+    }
+
+    // Write as many of the queued writes as the socket has room for.
+    fn flush(&mut self, epoll: RawFd) -> Result<()> {
+        while let Some(buf) = self.queued.pop_front() {
+            match self.sk.write(&buf) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => self.queued.push_front(buf),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.queued.push_front(buf);
+                    break;
+                }
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+
+        let wants_out = !self.queued.is_empty();
+        if wants_out != self.wants_out {
+            epoll_set(epoll, libc::EPOLL_CTL_MOD, self.sk.as_raw_fd(), wants_out)?;
+            self.wants_out = wants_out;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    conns: Vec<Conn>,
+    next_conn: u32,
+    // the connection, and the connection's own sid, of each flow by the sid CCP knows it by
+    flows: HashMap<u32, (u32, u32)>,
+    sids: HashMap<(u32, u32), u32>,
+    next_sid: u32,
+    // the latest install message of each program uid, for the datapaths which connect later
+    installs: Vec<(u32, Vec<u8>)>,
+    // messages for `recv` to return before reading any more
+    pending: VecDeque<Vec<u8>>,
+    // where to start looking for a readable connection, so a busy one cannot starve the others
+    next_poll: usize,
+}
+
+impl State {
+    // The sid CCP knows flow `local` of connection `conn` by.
+    fn sid(&mut self, conn: u32, local: u32) -> u32 {
+        if let Some(&sid) = self.sids.get(&(conn, local)) {
+            return sid;
+        }
+
+        loop {
+            self.next_sid = self.next_sid.wrapping_add(1);
+            if self.next_sid != 0 && !self.flows.contains_key(&self.next_sid) {
+                break;
+            }
+        }
+
+        let sid = self.next_sid;
+        self.flows.insert(sid, (conn, local));
+        self.sids.insert((conn, local), sid);
+        sid
+    }
+
+    fn forget(&mut self, sid: u32) {
+        if let Some(f) = self.flows.remove(&sid) {
+            self.sids.remove(&f);
+        }
+    }
+
+    // Rewrite the sids of the messages in `buf`, which were read from connection `conn`.
+    fn rewrite(&mut self, conn: u32, buf: &mut [u8]) {
+        let mut off = 0;
+        while off + HDR_LENGTH as usize <= buf.len() {
+            let len = LittleEndian::read_u16(&buf[off + 2..off + 4]) as usize;
+            if len < HDR_LENGTH as usize || off + len > buf.len() {
+                break;
+            }
+
+            let local = LittleEndian::read_u32(&buf[off + 4..off + 8]);
+            if local != 0 {
+                let sid = self.sid(conn, local);
+                LittleEndian::write_u32(&mut buf[off + 4..off + 8], sid);
+
+                // a measurement with no fields means the flow has ended
+                let typ = LittleEndian::read_u16(&buf[off..off + 2]);
+                if typ == u16::from(measure::MEASURE)
+                    && len >= HDR_LENGTH as usize + 8
+                    && LittleEndian::read_u32(&buf[off + 12..off + 16]) == 0
+                {
+                    self.forget(sid);
+                }
+            }
+
+            off += len;
+        }
+    }
+
+    // Drop connection `idx`, and queue a close message for each of its flows.
+    // Closing the connection's socket also takes it out of the epoll set.
+    fn disconnect(&mut self, idx: usize) -> Result<()> {
+        let conn = self.conns.remove(idx).id;
+
+        let mut closed: Vec<u32> = self
+            .flows
+            .iter()
+            .filter(|&(_, &(c, _))| c == conn)
+            .map(|(&sid, _)| sid)
+            .collect();
+        closed.sort();
+        for sid in closed {
+            self.forget(sid);
+            self.pending.push_back(serialize::serialize(&measure::Msg {
+                sid,
+                program_uid: 0,
+                num_fields: 0,
+                fields: vec![],
+            })?);
+        }
+
+        Ok(())
+    }
+
+    // Copy as many whole pending messages into `buf` as fit.
+    fn take_pending(&mut self, buf: &mut [u8]) -> usize {
+        let mut off = 0;
+        while let Some(len) = self.pending.front().map(|m| m.len()) {
+            if off + len > buf.len() {
+                break;
+            }
+
+            let m = self.pending.pop_front().unwrap();
+            buf[off..off + len].copy_from_slice(&m);
+            off += len;
+        }
+
+        off
+    }
+}
+
+// Have `epoll` report when `fd` is readable, and when it has room to write if `out`.
+// `op` adds `fd` to `epoll`, or modifies it.
+fn epoll_set(epoll: RawFd, op: libc::c_int, fd: RawFd, out: bool) -> Result<()> {
+    let mut ev = libc::epoll_event {
+        events: if out {
+            (libc::EPOLLIN | libc::EPOLLOUT) as u32
+        } else {
+            libc::EPOLLIN as u32
+        },
+        u64: fd as u64,
+    };
+    if unsafe { libc::epoll_ctl(epoll, op, fd, &mut ev) } == -1 {
+        return Err(Error::from(nix::Error::last()));
+    }
+
+    Ok(())
+}
+
+// What to send to connection `conn`, in the order the messages were given.
+fn conn_buf(out: &mut Vec<(u32, Vec<u8>)>, conn: u32) -> &mut Vec<u8> {
+    let i = match out.iter().position(|&(c, _)| c == conn) {
+        Some(i) => i,
+        None => {
+            out.push((conn, vec![]));
+            out.len() - 1
+        }
+    };
+
+    &mut out[i].1
+}
+
+pub struct Socket<T> {
+    listener: UnixListener,
+    state: Mutex<State>,
+    // readable when the listener or any connection is; connections leave it when they close
+    epoll: RawFd,
+    // how long `recv` waits for a message, in milliseconds
+    timeout: libc::c_int,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new(cfg: &SocketBuilder, timeout: libc::c_int) -> Result<Self> {
+        cfg.prepare_bind()?;
+        let addr = cfg.sock_addr(&cfg.bind_addr())?;
+        let fd = socket::socket(
+            socket::AddressFamily::Unix,
+            socket::SockType::SeqPacket,
+            socket::SockFlag::empty(),
+            0,
+        )?;
+        // the UnixListener owns fd from here on, and closes it if bind fails
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        socket::bind(fd, &addr)?;
+        socket::listen(fd, 128)?;
+        cfg.set_access()?;
+
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll == -1 {
+            return Err(Error::from(nix::Error::last()));
+        }
+
+        let sk = Socket {
+            listener,
+            state: Mutex::new(State::default()),
+            epoll,
+            timeout,
+            _phantom: PhantomData,
+        };
+        epoll_set(
+            sk.epoll,
+            libc::EPOLL_CTL_ADD,
+            sk.listener.as_raw_fd(),
+            false,
+        )?;
+        Ok(sk)
+    }
+
+    /// The number of datapaths currently connected.
+    pub fn num_connections(&self) -> usize {
+        self.state.lock().unwrap().conns.len()
+    }
+
+    fn accept(&self, st: &mut State) -> Result<()> {
+        let (sk, _) = self.listener.accept()?;
+        sk.set_nonblocking(true)?;
+        epoll_set(self.epoll, libc::EPOLL_CTL_ADD, sk.as_raw_fd(), false)?;
+        let mut conn = Conn {
+            id: st.next_conn,
+            sk,
+            queued: st.installs.iter().map(|i| i.1.clone()).collect(),
+            wants_out: false,
+        };
+        st.next_conn = st.next_conn.wrapping_add(1);
+        conn.flush(self.epoll)?;
+        st.conns.push(conn);
+        Ok(())
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll
+    }
+}
+
+impl<T> Drop for Socket<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.epoll);
+        }
+    }
+}
+
+impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
+    fn name() -> String {
+        String::from("seqpacket")
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        if msg.len() < HDR_LENGTH as usize {
+            return Err(Error(format!("message too short: {:?}", msg)));
+        }
+
+        // A batch may hold messages for flows of several datapaths, so route each message on its
+        // own, and send each datapath its share of the batch in one write.
+        let mut st = self.state.lock().unwrap();
+        let mut out: Vec<(u32, Vec<u8>)> = vec![];
+        let mut errs = vec![];
+        let mut off = 0;
+        while off < msg.len() {
+            let rest = &msg[off..];
+            let len = if rest.len() >= HDR_LENGTH as usize {
+                LittleEndian::read_u16(&rest[2..4]) as usize
+            } else {
+                0
+            };
+            if len < HDR_LENGTH as usize || len > rest.len() {
+                return Err(Error(format!(
+                    "malformed message at byte {}: {:?}",
+                    off, msg
+                )));
+            }
+
+            let m = &rest[..len];
+            off += len;
+            let sid = LittleEndian::read_u32(&m[4..8]);
+            if sid == 0 {
+                // resync requests and the like are for the datapaths connected now, but a datapath
+                // which connects later needs each program installed to run it
+                if m[0] == install::INSTALL && m.len() >= 12 {
+                    let uid = LittleEndian::read_u32(&m[8..12]);
+                    st.installs.retain(|&(u, _)| u != uid);
+                    st.installs.push((uid, m.to_vec()));
+                }
+
+                for c in &st.conns {
+                    conn_buf(&mut out, c.id).extend_from_slice(m);
+                }
+
+                continue;
+            }
+
+            let (conn, local) = match st.flows.get(&sid) {
+                Some(&f) => f,
+                None => {
+                    errs.push(format!("no datapath has flow {}", sid));
+                    continue;
+                }
+            };
+            if !st.conns.iter().any(|c| c.id == conn) {
+                errs.push(format!("datapath of flow {} has disconnected", sid));
+                continue;
+            }
+
+            let buf = conn_buf(&mut out, conn);
+            let start = buf.len();
+            buf.extend_from_slice(m);
+            LittleEndian::write_u32(&mut buf[start + 4..start + 8], local);
+        }
+
+        for (conn, buf) in out {
+            let c = match st.conns.iter_mut().find(|c| c.id == conn) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            // the other datapaths still get their messages; `recv` notices datapaths which have
+            // gone away, and closes their flows
+            if let Err(e) = c.send(buf, self.epoll) {
+                errs.push(format!("datapath connection {}: {}", conn, e.0));
+            }
+        }
+
+        if errs.is_empty() {
+            Ok(())
+        } else {
+            Err(Error(errs.join("; ")))
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut st = self.state.lock().unwrap();
+        if !st.pending.is_empty() {
+            return Ok(st.take_pending(buf));
+        }
+
+        // one event for the listener and each connection, so every readable one is reported
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; st.conns.len() + 1];
+        let n = unsafe {
+            libc::epoll_wait(
+                self.epoll,
+                events.as_mut_ptr(),
+                events.len() as libc::c_int,
+                self.timeout,
+            )
+        };
+        if n < 0 {
+            return Err(Error::from(nix::Error::last()));
+        }
+
+        let ready: Vec<RawFd> = events[..n as usize]
+            .iter()
+            .filter(|e| e.events & (libc::EPOLLIN | libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0)
+            .map(|e| e.u64 as RawFd)
+            .collect();
+        let writable: Vec<RawFd> = events[..n as usize]
+            .iter()
+            .filter(|e| e.events & libc::EPOLLOUT as u32 != 0)
+            .map(|e| e.u64 as RawFd)
+            .collect();
+        for c in st.conns.iter_mut() {
+            if writable.contains(&c.sk.as_raw_fd()) {
+                // a connection which fails here fails its next read as well, which disconnects it
+                let _ = c.flush(self.epoll);
+            }
+        }
+
+        if ready.contains(&self.listener.as_raw_fd()) {
+            self.accept(&mut st)?;
+            return Ok(0);
+        }
+
+        let num_conns = st.conns.len();
+        let idx = match (0..num_conns)
+            .map(|i| (st.next_poll + i) % num_conns)
+            .find(|&i| ready.contains(&st.conns[i].sk.as_raw_fd()))
+        {
+            Some(idx) => idx,
+            None => return Ok(0),
+        };
+        st.next_poll = idx + 1;
+
+        loop {
+            match st.conns[idx].sk.read(buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let conn = st.conns[idx].id;
+                    st.rewrite(conn, &mut buf[..n]);
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+                Err(_) => break,
+            }
+        }
+
+        st.disconnect(idx)?;
+        Ok(st.take_pending(buf))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.state.lock().unwrap().conns.clear();
+        Ok(())
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.epoll)
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Listen at the `bind_addr()` of `cfg`; the peer address of `cfg` is not used.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        Socket::__new(cfg, 1000)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Listen at the `bind_addr()` of `cfg`; the peer address of `cfg` is not used.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        Socket::__new(cfg, 0)
+    }
+}
@@ -0,0 +1,414 @@
+//! A pair of ring buffers in shared memory, for a userspace datapath on the same host.
+//!
+//! CCP creates the rings in a file, typically under `/dev/shm`, and the datapath maps the same
+//! file. Each direction is a single-producer single-consumer ring of length-prefixed messages,
+//! so sending and receiving take no syscalls while the other side is busy; a receiver with
+//! nothing to read sleeps on a futex, and the sender only wakes it if it is asleep.
+//!
+//! The file starts with a 64-byte header:
+//!
+//! |--------|---------|----------|
+//! | magic  | version | capacity |
+//! | "CCPR" | u32     | u32      |
+//! |--------|---------|----------|
+//!
+//! followed by the ring from the datapath to CCP, then the ring from CCP to the datapath. Each
+//! ring is a head counter, which the consumer advances, a tail counter and a waiting flag at
+//! offsets 64 and 68, which the producer advances and checks, and `capacity` bytes of messages
+//! from offset 128, each preceded by its length as a u32.
+//!
+//! A futex cannot be waited on with poll or epoll, so these sockets have no `Ipc::poll_fd()`,
+//! and a nonblocking one cannot wake an event loop: poll it on a timer (`run()` and `spawn()`
+//! read it every millisecond), or use a blocking socket, which waits on the futex in `recv`. Waking an eventfd instead would need the datapath to be
+//! handed the descriptor over a unix socket, as well as the file.
+
+use std;
+use std::fs::{File, OpenOptions};
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use bytes::{ByteOrder, LittleEndian};
+
+extern crate libc;
+
+use super::Error;
+use super::Result;
+
+const MAGIC: &[u8; 4] = b"CCPR";
+const VERSION: u32 = 1;
+const HDR_SIZE: usize = 64;
+const RING_HDR_SIZE: usize = 128;
+const TAIL: usize = 64;
+const WAITING: usize = 68;
+const LEN_SIZE: usize = 4;
+
+const FUTEX_WAIT: libc::c_int = 0;
+const FUTEX_WAKE: libc::c_int = 1;
+
+// One direction of the shared memory: a ring of `cap` bytes at `base`.
+struct Ring {
+    base: *mut u8,
+    cap: u32,
+}
+
+impl Ring {
+    fn counter(&self, off: usize) -> &AtomicU32 {
+        unsafe { &*(self.base.add(off) as *const AtomicU32) }
+    }
+
+    fn head(&self) -> &AtomicU32 {
+        self.counter(0)
+    }
+
+    fn tail(&self) -> &AtomicU32 {
+        self.counter(TAIL)
+    }
+
+    fn waiting(&self) -> &AtomicU32 {
+        self.counter(WAITING)
+    }
+
+    // Copy `buf` into the ring at position `pos`, wrapping around its end.
+    fn write_at(&self, pos: u32, buf: &[u8]) {
+        let off = (pos & (self.cap - 1)) as usize;
+        let first = std::cmp::min(buf.len(), self.cap as usize - off);
+        unsafe {
+            let data = self.base.add(RING_HDR_SIZE);
+            ptr::copy_nonoverlapping(buf.as_ptr(), data.add(off), first);
+            ptr::copy_nonoverlapping(buf[first..].as_ptr(), data, buf.len() - first);
+        }
+    }
+
+    // Copy from the ring at position `pos` into `buf`, wrapping around its end.
+    fn read_at(&self, pos: u32, buf: &mut [u8]) {
+        let off = (pos & (self.cap - 1)) as usize;
+        let first = std::cmp::min(buf.len(), self.cap as usize - off);
+        unsafe {
+            let data = self.base.add(RING_HDR_SIZE);
+            ptr::copy_nonoverlapping(data.add(off), buf.as_mut_ptr(), first);
+            let rest = buf.len() - first;
+            ptr::copy_nonoverlapping(data, buf[first..].as_mut_ptr(), rest);
+        }
+    }
+
+    fn push(&self, msg: &[u8]) -> Result<()> {
+        let head = self.head().load(Ordering::Acquire);
+        let tail = self.tail().load(Ordering::Relaxed);
+        let used = tail.wrapping_sub(head);
+        if used > self.cap {
+            return Err(Error(format!(
+                "corrupt shared memory ring: consumer is {} bytes behind",
+                used
+            )));
+        }
+
+        let free = self.cap - used;
+        if (LEN_SIZE + msg.len()) as u64 > u64::from(free) {
+            return Err(Error(format!(
+                "shared memory ring full: {} bytes free, message is {} bytes",
+                free,
+                msg.len()
+            )));
+        }
+
+        let mut len = [0u8; LEN_SIZE];
+        LittleEndian::write_u32(&mut len, msg.len() as u32);
+        self.write_at(tail, &len);
+        self.write_at(tail.wrapping_add(LEN_SIZE as u32), msg);
+        self.tail().store(
+            tail.wrapping_add((LEN_SIZE + msg.len()) as u32),
+            Ordering::SeqCst,
+        );
+
+        // the consumer sets the flag before checking the tail one last time, so either it sees
+        // the new tail or this sees the flag
+        if self.waiting().load(Ordering::SeqCst) != 0 {
+            futex(self.tail(), FUTEX_WAKE, 1, None);
+        }
+
+        Ok(())
+    }
+
+    // Pop the next message into `buf`, if there is one.
+    fn pop(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let head = self.head().load(Ordering::Relaxed);
+        let tail = self.tail().load(Ordering::Acquire);
+        if head == tail {
+            return Ok(None);
+        }
+
+        // The other process writes the counters and lengths, so check them before copying: a
+        // bad length would have us read past the end of the mapping. Since what is queued
+        // cannot be split into messages any more, drop all of it.
+        let queued = tail.wrapping_sub(head);
+        if queued > self.cap || (queued as usize) < LEN_SIZE {
+            self.head().store(tail, Ordering::Release);
+            return Err(Error(format!(
+                "corrupt shared memory ring: {} bytes queued",
+                queued
+            )));
+        }
+
+        let mut len = [0u8; LEN_SIZE];
+        self.read_at(head, &mut len);
+        let len = LittleEndian::read_u32(&len) as usize;
+        if LEN_SIZE + len > queued as usize {
+            self.head().store(tail, Ordering::Release);
+            return Err(Error(format!(
+                "corrupt shared memory ring: {} byte message, but {} bytes queued",
+                len, queued
+            )));
+        }
+
+        let next = head.wrapping_add((LEN_SIZE + len) as u32);
+        if len > buf.len() {
+            self.head().store(next, Ordering::Release);
+            return Err(Error(format!(
+                "message of {} bytes does not fit in buffer of {}",
+                len,
+                buf.len()
+            )));
+        }
+
+        self.read_at(head.wrapping_add(LEN_SIZE as u32), &mut buf[..len]);
+        self.head().store(next, Ordering::Release);
+        Ok(Some(len))
+    }
+
+    // Sleep until the producer moves the tail past `head`, for at most `timeout`.
+    fn wait(&self, head: u32, timeout: std::time::Duration) {
+        self.waiting().store(1, Ordering::SeqCst);
+        if self.tail().load(Ordering::SeqCst) == head {
+            futex(self.tail(), FUTEX_WAIT, head, Some(timeout));
+        }
+
+        self.waiting().store(0, Ordering::SeqCst);
+    }
+}
+
+fn futex(word: &AtomicU32, op: libc::c_int, val: u32, timeout: Option<std::time::Duration>) {
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs() as libc::time_t,
+        tv_nsec: libc::c_long::from(t.subsec_nanos()),
+    });
+    let ts = ts
+        .as_ref()
+        .map_or(ptr::null(), |t| t as *const libc::timespec);
+    // the rings are shared between processes, so this cannot be a private futex; spurious
+    // wakeups and timeouts are fine, since the caller checks the ring again
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            op,
+            val,
+            ts,
+            ptr::null::<u32>(),
+            0,
+        );
+    }
+}
+
+pub struct Socket<T> {
+    _file: File,
+    map: *mut u8,
+    map_len: usize,
+    rx: Ring,
+    tx: Ring,
+    // each ring has one producer and one consumer per process
+    send_lock: Mutex<()>,
+    recv_lock: Mutex<()>,
+    timeout: Option<std::time::Duration>,
+    _phantom: PhantomData<T>,
+}
+
+// The mapping is only accessed through the rings, whose ends are guarded by the locks.
+unsafe impl<T> Send for Socket<T> {}
+unsafe impl<T> Sync for Socket<T> {}
+
+fn map_len(cap: u32) -> usize {
+    HDR_SIZE + 2 * (RING_HDR_SIZE + cap as usize)
+}
+
+impl<T> Socket<T> {
+    fn __new(
+        file: File,
+        cap: u32,
+        ccp: bool,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Self> {
+        let map_len = map_len(cap);
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(Error::from(std::io::Error::last_os_error()));
+        }
+
+        let map = map as *mut u8;
+        let ring = |i: usize| Ring {
+            base: unsafe { map.add(HDR_SIZE + i * (RING_HDR_SIZE + cap as usize)) },
+            cap,
+        };
+        // ring 0 carries messages from the datapath to CCP
+        let (rx, tx) = if ccp {
+            (ring(0), ring(1))
+        } else {
+            (ring(1), ring(0))
+        };
+
+        Ok(Socket {
+            _file: file,
+            map,
+            map_len,
+            rx,
+            tx,
+            send_lock: Mutex::new(()),
+            recv_lock: Mutex::new(()),
+            timeout,
+            _phantom: PhantomData,
+        })
+    }
+
+    // Make the rings in a new file at `path`, for CCP.
+    fn __create<P: AsRef<Path>>(
+        path: P,
+        capacity: u32,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Self> {
+        if !capacity.is_power_of_two() || (capacity as usize) < 2 * LEN_SIZE {
+            return Err(Error(format!(
+                "ring capacity must be a power of two of at least {} bytes, not {}",
+                2 * LEN_SIZE,
+                capacity
+            )));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // a new file is all zeroes, so both rings start out empty
+        file.set_len(map_len(capacity) as u64)?;
+        let sk = Socket::__new(file, capacity, true, timeout)?;
+        let hdr = unsafe { std::slice::from_raw_parts_mut(sk.map, HDR_SIZE) };
+        LittleEndian::write_u32(&mut hdr[4..8], VERSION);
+        LittleEndian::write_u32(&mut hdr[8..12], capacity);
+        hdr[0..4].copy_from_slice(MAGIC);
+        Ok(sk)
+    }
+
+    // Map the rings CCP made at `path`, for the datapath.
+    fn __open<P: AsRef<Path>>(path: P, timeout: Option<std::time::Duration>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut hdr = [0u8; HDR_SIZE];
+        {
+            use std::io::Read;
+            file.read_exact(&mut hdr)
+                .map_err(|e| Error(format!("{:?} is not a ring file: {}", path, e)))?;
+        }
+
+        if &hdr[0..4] != MAGIC {
+            return Err(Error(format!("{:?} is not a ring file", path)));
+        }
+
+        let version = LittleEndian::read_u32(&hdr[4..8]);
+        if version != VERSION {
+            return Err(Error(format!(
+                "ring file version {}, expected {}",
+                version, VERSION
+            )));
+        }
+
+        let capacity = LittleEndian::read_u32(&hdr[8..12]);
+        let len = file.metadata()?.len();
+        if !capacity.is_power_of_two() || len < map_len(capacity) as u64 {
+            return Err(Error(format!(
+                "ring file of {} bytes does not fit capacity {}",
+                len, capacity
+            )));
+        }
+
+        Socket::__new(file, capacity, false, timeout)
+    }
+}
+
+impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
+    fn name() -> String {
+        String::from("shm")
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        let _l = self.send_lock.lock().unwrap();
+        self.tx.push(msg)
+    }
+
+    fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        let _l = self.recv_lock.lock().unwrap();
+        if let Some(n) = self.rx.pop(msg)? {
+            return Ok(n);
+        }
+
+        match self.timeout {
+            Some(t) => {
+                self.rx.wait(self.rx.head().load(Ordering::Relaxed), t);
+                Ok(self.rx.pop(msg)?.unwrap_or(0))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> Drop for Socket<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Make the rings in a file at `path`, e.g. `/dev/shm/ccp`, each holding `capacity` bytes
+    /// of messages, which must be a power of two. Replaces any file already at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: u32) -> Result<Self> {
+        Socket::__create(path, capacity, Some(std::time::Duration::from_secs(1)))
+    }
+
+    /// Map the rings which CCP made at `path`, as the datapath.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Socket::__open(path, Some(std::time::Duration::from_secs(1)))
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Make the rings in a file at `path`, e.g. `/dev/shm/ccp`, each holding `capacity` bytes
+    /// of messages, which must be a power of two. Replaces any file already at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, capacity: u32) -> Result<Self> {
+        Socket::__create(path, capacity, None)
+    }
+
+    /// Map the rings which CCP made at `path`, as the datapath.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Socket::__open(path, None)
+    }
+}
@@ -0,0 +1,203 @@
+//! TCP connections, for a datapath on another host which needs reliable delivery.
+//!
+//! Since TCP is a byte stream, each message is framed by its length, as a little-endian `u32`.
+//! Each `recv` returns exactly one frame, which holds whatever was passed to one `send`.
+//! CCP can either connect to the datapath, or wait for the datapath to connect.
+//! On a nonblocking socket, `send` still waits until the whole frame is written.
+
+use std;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use bytes::{ByteOrder, LittleEndian};
+
+use super::Error;
+use super::Result;
+
+const LEN_SIZE: usize = 4;
+
+struct RecvState {
+    // bytes read from the stream which `recv` has not returned yet
+    pending: Vec<u8>,
+    // how much more of a frame too large for the caller's buffer to throw away
+    discard: usize,
+}
+
+pub struct Socket<T> {
+    sk: TcpStream,
+    recv: Mutex<RecvState>,
+    // set once the connection has failed or the datapath has closed it
+    closed: AtomicBool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new(sk: TcpStream) -> Result<Self> {
+        sk.set_nodelay(true)?;
+        sk.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        Ok(Socket {
+            sk,
+            recv: Mutex::new(RecvState {
+                pending: Vec::with_capacity(2048),
+                discard: 0,
+            }),
+            closed: AtomicBool::new(false),
+            _phantom: PhantomData,
+        })
+    }
+
+    fn __connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Socket::__new(TcpStream::connect(addr)?)
+    }
+
+    fn __accept<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let (sk, _) = TcpListener::bind(addr)?.accept()?;
+        Socket::__new(sk)
+    }
+
+    /// The address of the datapath at the other end of the connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.sk.peer_addr().map_err(Error::from)
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sk.as_raw_fd()
+    }
+}
+
+impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
+    fn name() -> String {
+        String::from("tcp")
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        let mut frame = vec![0u8; LEN_SIZE + msg.len()];
+        LittleEndian::write_u32(&mut frame[..LEN_SIZE], msg.len() as u32);
+        frame[LEN_SIZE..].copy_from_slice(msg);
+        let mut off = 0;
+        while off < frame.len() {
+            match (&self.sk).write(&frame[off..]) {
+                Ok(0) => return Err(Error(String::from("datapath closed the connection"))),
+                Ok(n) => off += n,
+                // Dropping the rest of a frame would garble the stream from here on, so when a
+                // nonblocking socket is full, wait for room to finish the frame.
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    super::poll_writable(self.sk.as_raw_fd(), std::time::Duration::from_secs(1))?;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        let mut st = self.recv.lock().unwrap();
+        loop {
+            if st.discard > 0 {
+                let n = std::cmp::min(st.discard, st.pending.len());
+                st.pending.drain(..n);
+                st.discard -= n;
+                if st.discard == 0 {
+                    continue;
+                }
+            } else if st.pending.len() >= LEN_SIZE {
+                let len = LittleEndian::read_u32(&st.pending[..LEN_SIZE]) as usize;
+                if len > msg.len() {
+                    st.pending.drain(..LEN_SIZE);
+                    st.discard = len;
+                    return Err(Error(format!(
+                        "{} byte message does not fit in {} byte buffer",
+                        len,
+                        msg.len()
+                    )));
+                }
+
+                if st.pending.len() >= LEN_SIZE + len {
+                    msg[..len].copy_from_slice(&st.pending[LEN_SIZE..LEN_SIZE + len]);
+                    st.pending.drain(..LEN_SIZE + len);
+                    return Ok(len);
+                }
+            }
+
+            let mut buf = [0u8; 1024];
+            // keep a partial frame in `pending` until the rest arrives
+            let n = match (&self.sk).read(&mut buf) {
+                Err(ref e) if super::would_block(e) => return Ok(0),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.closed.store(true, Ordering::SeqCst);
+                    return Err(Error::from(e));
+                }
+                Ok(n) => n,
+            };
+            if n == 0 {
+                self.closed.store(true, Ordering::SeqCst);
+                return Err(Error(String::from("datapath closed the connection")));
+            }
+
+            st.pending.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.sk.shutdown(Shutdown::Both).map_err(Error::from)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Connect to the datapath at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Socket::__connect(addr)
+    }
+
+    /// Listen at `addr`, and wait for a datapath to connect.
+    pub fn accept<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Socket::__accept(addr)
+    }
+
+    /// Use a connection to the datapath which is already open.
+    pub fn from_stream(sk: TcpStream) -> Result<Self> {
+        Socket::__new(sk)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Connect to the datapath at `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let sk = Socket::__connect(addr)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+
+    /// Listen at `addr`, and wait for a datapath to connect.
+    pub fn accept<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let sk = Socket::__accept(addr)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+
+    /// Use a connection to the datapath which is already open.
+    pub fn from_stream(sk: TcpStream) -> Result<Self> {
+        let sk = Socket::__new(sk)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+}
@@ -118,3 +118,805 @@ fn test_chan() {
 
     c2.join().expect("join sender thread");
 }
+
+#[test]
+fn test_unix_builder_instances() {
+    use super::unix::SocketBuilder;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("portus-unix-{}", std::process::id()));
+    let ccp = |id| SocketBuilder::new("in", "out").dir(&dir).id(id).mode(0o600);
+    let dp = |id| SocketBuilder::new("out", "in").dir(&dir).id(id);
+
+    // two CCP instances with the same socket names do not see each other's messages
+    let ccp1 = ccp(1).build().expect("init ccp 1");
+    let ccp2 = ccp(2).build().expect("init ccp 2");
+    let dp1 = dp(1).build().expect("init datapath 1");
+    let dp2 = dp(2).build().expect("init datapath 2");
+    dp1.send(b"one").expect("send 1");
+    dp2.send(b"two").expect("send 2");
+
+    let mut buf = [0u8; 16];
+    let n = ccp1.recv(&mut buf).expect("recv 1");
+    assert_eq!(&buf[..n], b"one");
+    let n = ccp2.recv(&mut buf).expect("recv 2");
+    assert_eq!(&buf[..n], b"two");
+
+    ccp2.send(b"back").expect("send back");
+    let n = dp2.recv(&mut buf).expect("recv back");
+    assert_eq!(&buf[..n], b"back");
+
+    let mode = std::fs::metadata(ccp(1).bind_addr())
+        .expect("socket file")
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    std::fs::remove_dir_all(&dir).expect("remove socket dir");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_unix_abstract() {
+    use super::unix::SocketBuilder;
+
+    let dir = format!("portus-abstract-{}", std::process::id());
+    let ccp = SocketBuilder::new("in", "out")
+        .dir(&dir)
+        .abstract_namespace(true)
+        .build()
+        .expect("init ccp");
+    let dp = SocketBuilder::new("out", "in")
+        .dir(&dir)
+        .abstract_namespace(true)
+        .build()
+        .expect("init datapath");
+    assert!(!std::path::Path::new(&dir).exists());
+
+    dp.send(b"hello").expect("send");
+    let mut buf = [0u8; 16];
+    let n = ccp.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..n], b"hello");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_seqpacket_datapaths() {
+    use super::seqpacket::Socket;
+    use super::unix::SocketBuilder;
+    use super::Blocking;
+    use lang::Bin;
+    use nix::sys::socket;
+    use serialize;
+    use serialize::Msg;
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join(format!("portus-seqpacket-{}", std::process::id()));
+    let cfg = SocketBuilder::new("in", "out").dir(&dir);
+    let ccp = Socket::<Blocking>::with_builder(&cfg).expect("init ccp");
+    let connect = || {
+        let fd = socket::socket(
+            socket::AddressFamily::Unix,
+            socket::SockType::SeqPacket,
+            socket::SockFlag::empty(),
+            0,
+        )
+        .expect("socket");
+        let sk = unsafe { UnixStream::from_raw_fd(fd) };
+        let addr = socket::UnixAddr::new(&cfg.bind_addr()).expect("addr");
+        socket::connect(fd, &socket::SockAddr::Unix(addr)).expect("connect");
+        sk.set_read_timeout(Some(std::time::Duration::from_millis(100)))
+            .expect("set timeout");
+        sk
+    };
+    let sid_of = |buf: &[u8]| match Msg::from_buf(buf).expect("parse") {
+        (Msg::Ins(m), _) => m.sid,
+        (Msg::Rs(m), _) => m.sid,
+        (Msg::Cr(m), _) => m.sid,
+        (Msg::Ms(ref m), _) if m.num_fields == 0 => m.sid,
+        (m, _) => panic!("unexpected message {:?}", m),
+    };
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .expect("serialize")
+    };
+    let resync = |sid| serialize::serialize(&serialize::resync::Msg { sid }).expect("serialize");
+    let install = |program_uid| {
+        serialize::serialize(&serialize::install::Msg {
+            sid: 0,
+            program_uid,
+            num_events: 0,
+            num_instrs: 0,
+            instrs: Bin {
+                events: vec![],
+                instrs: vec![],
+            },
+        })
+        .expect("serialize")
+    };
+    let mut buf = [0u8; 1024];
+
+    // datapaths which connect later get the latest install of each program, but not the other
+    // messages sent to all datapaths
+    ccp.send(&install(7)).expect("install 7");
+    ccp.send(&install(8)).expect("install 8");
+    ccp.send(&resync(0)).expect("send to all");
+    ccp.send(&install(7)).expect("install 7 again");
+    let mut dp1 = connect();
+    let mut dp2 = connect();
+    while ccp.num_connections() < 2 {
+        assert_eq!(ccp.recv(&mut buf).expect("accept"), 0);
+    }
+
+    for dp in &mut [&mut dp1, &mut dp2] {
+        for &uid in &[8, 7] {
+            let n = dp.read(&mut buf).expect("read replayed install");
+            match Msg::from_buf(&buf[..n]).expect("parse") {
+                (Msg::Ins(m), _) => assert_eq!((m.sid, m.program_uid), (0, uid)),
+                (m, _) => panic!("unexpected message {:?}", m),
+            }
+        }
+
+        assert!(dp.read(&mut buf).is_err());
+    }
+
+    // both datapaths call their flow 1, but CCP sees two flows
+    dp1.write_all(&create(1)).expect("create 1");
+    let n = ccp.recv(&mut buf).expect("recv create 1");
+    let sid1 = sid_of(&buf[..n]);
+    dp2.write_all(&create(1)).expect("create 2");
+    let n = ccp.recv(&mut buf).expect("recv create 2");
+    let sid2 = sid_of(&buf[..n]);
+    assert!(sid1 != 0 && sid2 != 0 && sid1 != sid2);
+
+    // a message to a flow goes back to its datapath, under the datapath's sid
+    ccp.send(&resync(sid2)).expect("send to flow 2");
+    let n = dp2.read(&mut buf).expect("read on datapath 2");
+    assert_eq!(sid_of(&buf[..n]), 1);
+    assert!(dp1.read(&mut buf).is_err());
+
+    // a datapath which does not read what it is sent does not hold up the others
+    for _ in 0..500 {
+        ccp.send(&resync(sid1)).expect("send to flow 1");
+    }
+
+    ccp.send(&resync(sid2)).expect("send to flow 2");
+    let n = dp2.read(&mut buf).expect("read on datapath 2");
+    assert_eq!(sid_of(&buf[..n]), 1);
+
+    // the flows of a datapath which disconnects are closed
+    drop(dp2);
+    let n = ccp.recv(&mut buf).expect("recv close");
+    assert_eq!(sid_of(&buf[..n]), sid2);
+    assert_eq!(ccp.num_connections(), 1);
+    assert!(ccp.send(&resync(sid2)).is_err());
+    ccp.send(&resync(sid1)).expect("send to flow 1");
+
+    // a broadcast reports the datapaths it could not reach, even before `recv` notices them
+    drop(dp1);
+    let err = ccp.send(&resync(0)).expect_err("send to a closed datapath");
+    assert!(err.0.contains("datapath connection 0"), "{}", err.0);
+
+    std::fs::remove_dir_all(&dir).expect("remove socket dir");
+}
+
+#[test]
+fn test_udp() {
+    use super::udp::Socket;
+    use super::Blocking;
+
+    // the datapath stand-in binds first, so CCP knows where to send
+    let dp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind datapath");
+    let ccp = Socket::<Blocking>::new("127.0.0.1:0", dp.local_addr().unwrap()).expect("init ccp");
+    dp.connect(ccp.local_addr().expect("ccp addr"))
+        .expect("connect datapath");
+
+    dp.send(b"hello").expect("send to ccp");
+    let mut buf = [0u8; 16];
+    let n = ccp.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..n], b"hello");
+
+    ccp.send(b"world").expect("send to datapath");
+    let n = dp.recv(&mut buf).expect("recv on datapath");
+    assert_eq!(&buf[..n], b"world");
+}
+
+#[test]
+fn test_try_next_nonblocking() {
+    use super::udp::Socket;
+    use super::{Backend, Nonblocking, TryNext};
+    use serialize;
+    use serialize::Msg;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic;
+    use std::time::Duration;
+    use test_helper::TestMsg;
+
+    let dp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind datapath");
+    let ccp =
+        Socket::<Nonblocking>::new("127.0.0.1:0", dp.local_addr().unwrap()).expect("init ccp");
+    dp.connect(ccp.local_addr().expect("ccp addr"))
+        .expect("connect datapath");
+    let fd = ccp.as_raw_fd();
+
+    let mut buf = [0u8; 1024];
+    let mut b = Backend::new(ccp, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    assert_eq!(b.poll_fd(), Some(fd));
+    match b.try_next().expect("try_next") {
+        TryNext::WouldBlock => (),
+        TryNext::Msg(_) => panic!("message before any was sent"),
+    }
+    assert!(!b.wait(Duration::from_millis(10)).expect("wait"));
+
+    // both messages arrive in one datagram
+    let mut dgram = serialize::serialize(&TestMsg(String::from("one"))).expect("serialize one");
+    dgram.extend(serialize::serialize(&TestMsg(String::from("two"))).expect("serialize two"));
+    dp.send(&dgram).expect("send to ccp");
+    assert!(b.wait(Duration::from_secs(1)).expect("wait"));
+    for expected in &["one", "two"] {
+        match b.try_next().expect("try_next") {
+            TryNext::Msg(Msg::Other(r)) => {
+                assert_eq!(r.get_bytes().unwrap(), expected.as_bytes())
+            }
+            _ => panic!("expected {:?}", expected),
+        }
+    }
+
+    match b.try_next().expect("try_next") {
+        TryNext::WouldBlock => (),
+        TryNext::Msg(_) => panic!("message after the datagram was read"),
+    }
+}
+
+#[test]
+fn test_next_nonblocking_waits() {
+    use super::udp::Socket;
+    use super::{Backend, Nonblocking};
+    use serialize;
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::thread;
+    use std::time::Duration;
+    use test_helper::TestMsg;
+
+    struct CountingIpc(Socket<Nonblocking>, Arc<AtomicUsize>);
+
+    impl Ipc for CountingIpc {
+        fn name() -> String {
+            String::from("counting")
+        }
+
+        fn send(&self, msg: &[u8]) -> super::Result<()> {
+            self.0.send(msg)
+        }
+
+        fn recv(&self, msg: &mut [u8]) -> super::Result<usize> {
+            self.1.fetch_add(1, atomic::Ordering::SeqCst);
+            self.0.recv(msg)
+        }
+
+        fn close(&mut self) -> super::Result<()> {
+            self.0.close()
+        }
+
+        fn poll_fd(&self) -> Option<RawFd> {
+            self.0.poll_fd()
+        }
+    }
+
+    let dp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind datapath");
+    let ccp =
+        Socket::<Nonblocking>::new("127.0.0.1:0", dp.local_addr().unwrap()).expect("init ccp");
+    dp.connect(ccp.local_addr().expect("ccp addr"))
+        .expect("connect datapath");
+
+    let recvs = Arc::new(AtomicUsize::new(0));
+    let mut buf = [0u8; 1024];
+    let mut b = Backend::new(
+        CountingIpc(ccp, recvs.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+
+    let sender = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        let msg = serialize::serialize(&TestMsg(String::from("late"))).expect("serialize");
+        dp.send(&msg).expect("send to ccp");
+    });
+
+    b.next().expect("receive message");
+    sender.join().expect("join sender thread");
+    // waiting 300ms sleeps in poll between reads rather than spinning on recv
+    assert!(recvs.load(atomic::Ordering::SeqCst) <= 5);
+}
+
+#[test]
+fn test_tcp() {
+    use super::tcp::Socket;
+    use super::Blocking;
+    use std::io::{Read, Write};
+
+    let l = std::net::TcpListener::bind("127.0.0.1:0").expect("bind datapath");
+    let ccp = Socket::<Blocking>::connect(l.local_addr().unwrap()).expect("init ccp");
+    let (mut dp, _) = l.accept().expect("accept");
+
+    // two frames in one write, then a frame split across writes
+    dp.write_all(&[3, 0, 0, 0, b'o', b'n', b'e', 3, 0, 0, 0, b't', b'w', b'o'])
+        .expect("write frames");
+    dp.write_all(&[5, 0, 0, 0, b't', b'h']).expect("write part");
+    let mut buf = [0u8; 16];
+    let n = ccp.recv(&mut buf).expect("recv one");
+    assert_eq!(&buf[..n], b"one");
+    let n = ccp.recv(&mut buf).expect("recv two");
+    assert_eq!(&buf[..n], b"two");
+    dp.write_all(&[b'r', b'e', b'e']).expect("write rest");
+    let n = ccp.recv(&mut buf).expect("recv three");
+    assert_eq!(&buf[..n], b"three");
+
+    // a frame too large for the buffer is skipped
+    dp.write_all(&[20, 0, 0, 0]).expect("write large frame");
+    dp.write_all(&[0u8; 20]).expect("write large frame");
+    dp.write_all(&[4, 0, 0, 0, b'f', b'o', b'u', b'r'])
+        .expect("write frame");
+    assert!(ccp.recv(&mut buf).is_err());
+    let n = ccp.recv(&mut buf).expect("recv four");
+    assert_eq!(&buf[..n], b"four");
+
+    ccp.send(b"back").expect("send to datapath");
+    let mut got = [0u8; 8];
+    dp.read_exact(&mut got).expect("read frame");
+    assert_eq!(got, [4, 0, 0, 0, b'b', b'a', b'c', b'k']);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_tcp_nonblocking_send() {
+    use super::tcp::Socket;
+    use super::Nonblocking;
+    use bytes::{ByteOrder, LittleEndian};
+    use std::io::Read;
+
+    const FRAMES: usize = 200;
+    const FRAME_LEN: usize = 16 * 1024;
+
+    let l = std::net::TcpListener::bind("127.0.0.1:0").expect("bind datapath");
+    let ccp = Socket::<Nonblocking>::connect(l.local_addr().unwrap()).expect("init ccp");
+    let (mut dp, _) = l.accept().expect("accept ccp");
+
+    // far more than the socket buffers hold, so sends find the socket full
+    let reader = std::thread::spawn(move || {
+        let mut frame = vec![0u8; FRAME_LEN];
+        for i in 0..FRAMES {
+            let mut len = [0u8; 4];
+            dp.read_exact(&mut len).expect("read length");
+            assert_eq!(LittleEndian::read_u32(&len) as usize, FRAME_LEN);
+            dp.read_exact(&mut frame).expect("read frame");
+            assert!(frame.iter().all(|&b| b == i as u8));
+            if i == 0 {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+        }
+    });
+
+    for i in 0..FRAMES {
+        ccp.send(&vec![i as u8; FRAME_LEN]).expect("send frame");
+    }
+
+    reader.join().expect("every frame arrives whole");
+}
+
+#[test]
+fn test_shm() {
+    use super::shm::Socket;
+    use super::{Blocking, Nonblocking};
+
+    let path = std::env::temp_dir().join(format!("portus-shm-{}", std::process::id()));
+    assert!(Socket::<Blocking>::create(&path, 100).is_err());
+    let ccp = Socket::<Blocking>::create(&path, 64).expect("create rings");
+    let dp = Socket::<Nonblocking>::open(&path).expect("open rings");
+    let mut buf = [0u8; 64];
+    assert_eq!(dp.recv(&mut buf).expect("empty recv"), 0);
+
+    // wrap around the end of the ring several times
+    for i in 0..20u8 {
+        dp.send(&[i; 13]).expect("send to ccp");
+        dp.send(b"x").expect("send to ccp");
+        let n = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], &[i; 13]);
+        let n = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..n], b"x");
+    }
+
+    // a full ring refuses more messages
+    ccp.send(&[1u8; 40]).expect("send to datapath");
+    assert!(ccp.send(&[2u8; 40]).is_err());
+    let n = dp.recv(&mut buf).expect("recv on datapath");
+    assert_eq!(&buf[..n], &[1u8; 40][..]);
+
+    // a blocked receiver is woken by the sender
+    let c = std::thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        let n = ccp.recv(&mut buf).expect("blocking recv");
+        buf[..n].to_vec()
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    dp.send(b"wake").expect("send to ccp");
+    assert_eq!(c.join().expect("join ccp"), b"wake".to_vec());
+    std::fs::remove_file(&path).expect("remove ring file");
+}
+
+#[test]
+fn test_shm_corrupt() {
+    use super::shm::Socket;
+    use super::Nonblocking;
+    use std::os::unix::fs::FileExt;
+
+    // the ring from the datapath: head at 64, tail at 128, messages from 192
+    const TAIL: u64 = 128;
+    const DATA: u64 = 192;
+
+    let path = std::env::temp_dir().join(format!("portus-shm-corrupt-{}", std::process::id()));
+    let ccp = Socket::<Nonblocking>::create(&path, 64).expect("create rings");
+    let dp = Socket::<Nonblocking>::open(&path).expect("open rings");
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .expect("open ring file");
+    let mut buf = [0u8; 4096];
+
+    // a length longer than the ring is refused, not read past the end of the mapping
+    dp.send(b"hello").expect("send to ccp");
+    file.write_at(&1000u32.to_le_bytes(), DATA)
+        .expect("corrupt length");
+    assert!(ccp.recv(&mut buf).is_err());
+    assert_eq!(ccp.recv(&mut buf).expect("ring emptied"), 0);
+
+    // so is a tail too far ahead of the head
+    file.write_at(&1000u32.to_le_bytes(), TAIL)
+        .expect("corrupt tail");
+    assert!(ccp.recv(&mut buf).is_err());
+    assert_eq!(ccp.recv(&mut buf).expect("ring emptied"), 0);
+
+    dp.send(b"ok").expect("send to ccp");
+    let n = ccp.recv(&mut buf).expect("recv after corruption");
+    assert_eq!(&buf[..n], b"ok");
+    std::fs::remove_file(&path).expect("remove ring file");
+}
+
+#[test]
+fn test_batch_sender() {
+    use super::{Backend, BatchSender};
+    use std::sync::atomic;
+
+    let sk = FakeIpc::new();
+    let mut buf = [0u8; 64];
+    let b = Backend::new(
+        sk.clone(),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    let batch = BatchSender::new(b.sender(), 8);
+    let clone = batch.clone();
+
+    batch.send_msg(b"abc").expect("send");
+    clone.send_msg(b"def").expect("send");
+    assert!(sk.0.lock().unwrap().is_empty());
+
+    // does not fit with the held messages, which go first
+    batch.send_msg(b"ghi").expect("send");
+    assert_eq!(&sk.0.lock().unwrap()[..], b"abcdef");
+
+    // too large to batch at all
+    batch.send_msg(b"jklmnopq").expect("send");
+    assert_eq!(&sk.0.lock().unwrap()[..], b"abcdefghijklmnopq");
+
+    clone.send_msg(b"r").expect("send");
+    batch.flush().expect("flush");
+    batch.flush().expect("flush nothing");
+    assert_eq!(&sk.0.lock().unwrap()[..], b"abcdefghijklmnopqr");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_netlink_multi_msg() {
+    use super::netlink::SocketBuilder;
+    use nix::sys::socket;
+
+    // a netlink message of type `typ`, padded to 4 bytes
+    fn nlmsg(typ: u16, payload: &[u8]) -> Vec<u8> {
+        let mut m = vec![0u8; 16];
+        ::serialize::u32_to_u8s(&mut m[0..4], 16 + payload.len() as u32);
+        m[4] = typ as u8;
+        m.extend_from_slice(payload);
+        while m.len() % 4 != 0 {
+            m.push(0);
+        }
+
+        m
+    }
+
+    // the datapath stand-in
+    let dp = socket::socket(
+        socket::AddressFamily::Netlink,
+        socket::SockType::Raw,
+        socket::SockFlag::empty(),
+        ::libc::NETLINK_USERSOCK,
+    )
+    .expect("datapath socket");
+    socket::bind(dp, &socket::SockAddr::new_netlink(0, 0)).expect("bind datapath");
+    let dp_portid = match socket::getsockname(dp).expect("datapath address") {
+        socket::SockAddr::Netlink(a) => a.pid(),
+        a => panic!("not a netlink address: {:?}", a),
+    };
+
+    let ccp = SocketBuilder::new()
+        .portid(0)
+        .group(0)
+        .peer_portid(dp_portid)
+        .buf_size(256)
+        .build()
+        .expect("init ccp");
+    let to_ccp = socket::SockAddr::new_netlink(ccp.portid().expect("ccp portid"), 0);
+    let send = |msgs: &[Vec<u8>]| {
+        socket::sendto(dp, &msgs.concat(), &to_ccp, socket::MsgFlags::empty())
+            .expect("send to ccp");
+    };
+
+    // acknowledgements and no-ops are skipped, and nothing after NLMSG_DONE is read
+    let ack = vec![0u8; 20];
+    send(&[
+        nlmsg(16, b"one"),
+        nlmsg(1, b""),
+        nlmsg(2, &ack),
+        nlmsg(16, b"two"),
+        nlmsg(3, b""),
+        nlmsg(16, b"three"),
+    ]);
+    let mut buf = [0u8; 8];
+    let n = ccp.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..n], b"onetwo");
+
+    // what does not fit is kept for the next recv
+    send(&[nlmsg(16, b"abcd"), nlmsg(16, b"efgh"), nlmsg(16, b"ij")]);
+    let n = ccp.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..n], b"abcdefgh");
+    let n = ccp.recv(&mut buf).expect("recv rest");
+    assert_eq!(&buf[..n], b"ij");
+
+    // an error comes after the messages before it
+    let mut err = vec![0u8; 20];
+    ::serialize::u32_to_u8s(&mut err[0..4], (-::libc::EINVAL) as u32);
+    send(&[nlmsg(16, b"xy"), nlmsg(2, &err), nlmsg(16, b"z")]);
+    let n = ccp.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..n], b"xy");
+    assert!(ccp.recv(&mut buf).is_err());
+    let n = ccp.recv(&mut buf).expect("recv after error");
+    assert_eq!(&buf[..n], b"z");
+
+    // a datagram larger than the buffer is an error rather than silently cut short
+    send(&[nlmsg(16, &[7u8; 300])]);
+    assert!(ccp.recv(&mut buf).is_err());
+
+    ccp.send(b"back").expect("send to datapath");
+    let mut got = [0u8; 64];
+    let n = socket::recv(dp, &mut got, socket::MsgFlags::empty()).expect("recv on datapath");
+    assert_eq!(&got[..n], &nlmsg(0, b"back")[..]);
+    ::nix::unistd::close(dp).expect("close datapath");
+}
+
+#[test]
+fn test_record_replay() {
+    use super::record::{self, Direction, Recorder, Replay};
+    use super::Blocking;
+    use std::io::Write;
+    use std::sync::atomic;
+    use std::time::{Duration, Instant};
+
+    let path = std::env::temp_dir().join(format!("portus-record-{}.cap", std::process::id()));
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded();
+    let sk = super::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let mut rec = Recorder::new(sk, &path).expect("init recorder");
+
+    let mut buf = [0u8; 16];
+    to_ccp.send(b"one".to_vec()).expect("send one");
+    let n = rec.recv(&mut buf).expect("recv one");
+    assert_eq!(&buf[..n], b"one");
+    rec.send(b"two").expect("send two");
+    assert_eq!(from_ccp.recv().expect("recv two"), b"two");
+    std::thread::sleep(Duration::from_millis(200));
+    to_ccp.send(b"three".to_vec()).expect("send three");
+    let n = rec.recv(&mut buf).expect("recv three");
+    assert_eq!(&buf[..n], b"three");
+    rec.close().expect("close recorder");
+
+    // a record cut short is left out
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(&[0u8; 5]))
+        .expect("append partial record");
+    let records = record::read_capture(&path).expect("read capture");
+    std::fs::remove_file(&path).expect("remove capture");
+    let bufs: Vec<_> = records.iter().map(|r| (r.dir, &r.buf[..])).collect();
+    assert_eq!(
+        bufs,
+        vec![
+            (Direction::FromDatapath, &b"one"[..]),
+            (Direction::ToDatapath, &b"two"[..]),
+            (Direction::FromDatapath, &b"three"[..]),
+        ]
+    );
+    assert!(records[2].time - records[0].time >= Duration::from_millis(200));
+
+    // at twice the speed, the gap between the datapath's messages halves
+    let replay = Replay::new(records).speed(2.0);
+    let finished = replay.finished();
+    let start = Instant::now();
+    let n = replay.recv(&mut buf).expect("replay one");
+    assert_eq!(&buf[..n], b"one");
+    assert!(!finished.load(atomic::Ordering::SeqCst));
+    let n = replay.recv(&mut buf).expect("replay three");
+    assert_eq!(&buf[..n], b"three");
+    let gap = start.elapsed();
+    assert!(gap >= Duration::from_millis(100) && gap < Duration::from_millis(200));
+    assert!(finished.load(atomic::Ordering::SeqCst));
+    replay.send(b"dropped").expect("send during replay");
+}
+
+#[test]
+fn test_pcapng() {
+    use super::record::{Direction, Record};
+    use bytes::{ByteOrder, LittleEndian};
+    use serialize::{self, resync};
+    use std::time::Duration;
+
+    let msg = serialize::serialize(&resync::Msg { sid: 7 }).unwrap();
+    let records = vec![Record {
+        time: Duration::new(1, 5),
+        dir: Direction::ToDatapath,
+        buf: msg.clone(),
+    }];
+    let mut out = vec![];
+    super::pcapng::write(&mut out, &records).unwrap();
+
+    // walk the blocks: each one's length is at both its ends
+    let mut blocks = vec![];
+    let mut off = 0;
+    while off < out.len() {
+        let typ = LittleEndian::read_u32(&out[off..]);
+        let len = LittleEndian::read_u32(&out[off + 4..]) as usize;
+        assert_eq!(len % 4, 0);
+        assert_eq!(LittleEndian::read_u32(&out[off + len - 4..]) as usize, len);
+        blocks.push((typ, &out[off + 8..off + len - 4]));
+        off += len;
+    }
+
+    assert_eq!(
+        blocks.iter().map(|b| b.0).collect::<Vec<_>>(),
+        vec![0x0A0D_0D0A, 1, 6]
+    );
+    assert_eq!(LittleEndian::read_u32(blocks[0].1), 0x1A2B_3C4D);
+    assert_eq!(
+        LittleEndian::read_u16(blocks[1].1),
+        super::pcapng::LINKTYPE_USER0
+    );
+
+    let epb = blocks[2].1;
+    let ts = (u64::from(LittleEndian::read_u32(&epb[4..])) << 32)
+        | u64::from(LittleEndian::read_u32(&epb[8..]));
+    assert_eq!(ts, 1_000_000_005);
+    assert_eq!(LittleEndian::read_u32(&epb[12..]) as usize, msg.len());
+    assert_eq!(&epb[20..20 + msg.len()], &msg[..]);
+
+    let opts = &epb[20 + msg.len()..];
+    assert_eq!(LittleEndian::read_u16(opts), 2);
+    assert_eq!(LittleEndian::read_u32(&opts[4..]), 2);
+    assert_eq!(LittleEndian::read_u16(&opts[8..]), 1);
+    let len = LittleEndian::read_u16(&opts[10..]) as usize;
+    assert_eq!(&opts[12..12 + len], b"resync sid=7");
+}
+
+#[test]
+fn test_seqpacket_batch() {
+    use super::seqpacket::Socket;
+    use super::unix::SocketBuilder;
+    use super::{Backend, BatchSender, Blocking};
+    use nix::sys::socket;
+    use serialize;
+    use serialize::Msg;
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic;
+
+    let dir = std::env::temp_dir().join(format!("portus-seqbatch-{}", std::process::id()));
+    let cfg = SocketBuilder::new("in", "out").dir(&dir);
+    let ccp = Socket::<Blocking>::with_builder(&cfg).expect("init ccp");
+    let connect = || {
+        let fd = socket::socket(
+            socket::AddressFamily::Unix,
+            socket::SockType::SeqPacket,
+            socket::SockFlag::empty(),
+            0,
+        )
+        .expect("socket");
+        let sk = unsafe { UnixStream::from_raw_fd(fd) };
+        let addr = socket::UnixAddr::new(&cfg.bind_addr()).expect("addr");
+        socket::connect(fd, &socket::SockAddr::Unix(addr)).expect("connect");
+        sk.set_read_timeout(Some(std::time::Duration::from_millis(100)))
+            .expect("set timeout");
+        sk
+    };
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .expect("serialize")
+    };
+    let resync = |sid| serialize::serialize(&serialize::resync::Msg { sid }).expect("serialize");
+    // the sids of the messages in one read
+    let read_sids = |dp: &mut UnixStream| {
+        let mut buf = [0u8; 1024];
+        let n = dp.read(&mut buf).expect("read");
+        let mut sids = vec![];
+        let mut off = 0;
+        while off < n {
+            match Msg::from_buf(&buf[off..n]).expect("parse") {
+                (Msg::Rs(m), len) => {
+                    sids.push(m.sid);
+                    off += len;
+                }
+                (m, _) => panic!("unexpected message {:?}", m),
+            }
+        }
+
+        sids
+    };
+
+    let mut buf = [0u8; 1024];
+    let mut b = Backend::new(ccp, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let mut dp1 = connect();
+    let mut dp2 = connect();
+    let create_sid = |b: &mut Backend<_>, dp: &mut UnixStream| {
+        dp.write_all(&create(1)).expect("create");
+        match b.next().expect("recv create") {
+            Msg::Cr(c) => c.sid,
+            m => panic!("unexpected message {:?}", m),
+        }
+    };
+    let sid1 = create_sid(&mut b, &mut dp1);
+    let sid2 = create_sid(&mut b, &mut dp2);
+
+    // one batch with messages for both datapaths' flows and for every datapath
+    let batch = BatchSender::new(b.sender(), 1500);
+    batch.send_msg(&resync(sid1)).expect("batch flow 1");
+    batch.send_msg(&resync(0)).expect("batch broadcast");
+    batch.send_msg(&resync(sid2)).expect("batch flow 2");
+    batch.flush().expect("flush");
+    assert_eq!(read_sids(&mut dp1), vec![1, 0]);
+    assert_eq!(read_sids(&mut dp2), vec![0, 1]);
+
+    // a resync request is not replayed to a datapath which connects later
+    let mut dp3 = connect();
+    create_sid(&mut b, &mut dp3);
+    assert!(dp3.read(&mut [0u8; 16]).is_err());
+
+    drop(b);
+    std::fs::remove_dir_all(&dir).expect("remove socket dir");
+}
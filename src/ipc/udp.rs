@@ -0,0 +1,84 @@
+//! UDP sockets, for a datapath on another host, e.g. a smart NIC or a middlebox.
+//!
+//! Each CCP message travels in a datagram of its own. UDP does not retransmit, so a lost report
+//! is simply missed, as when a datapath drops a report under load; a lost control message is not
+//! resent.
+
+use std;
+use std::marker::PhantomData;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::Error;
+use super::Result;
+
+pub struct Socket<T> {
+    sk: UdpSocket,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new<A: ToSocketAddrs, B: ToSocketAddrs>(bind_to: A, peer: B) -> Result<Self> {
+        let sk = UdpSocket::bind(bind_to)?;
+        sk.connect(peer)?;
+        sk.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        Ok(Socket {
+            sk,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The address the socket is bound to, e.g. to learn the port when binding port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.sk.local_addr().map_err(Error::from)
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sk.as_raw_fd()
+    }
+}
+
+impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
+    fn name() -> String {
+        String::from("udp")
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<()> {
+        self.sk.send(msg).map(|_| ()).map_err(Error::from)
+    }
+
+    fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        match self.sk.recv(msg) {
+            Err(ref e) if super::would_block(e) => Ok(0),
+            r => r.map_err(Error::from),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Bind `bind_to`, and exchange datagrams with the datapath at `peer` only.
+    pub fn new<A: ToSocketAddrs, B: ToSocketAddrs>(bind_to: A, peer: B) -> Result<Self> {
+        Socket::__new(bind_to, peer)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Bind `bind_to`, and exchange datagrams with the datapath at `peer` only.
+    pub fn new<A: ToSocketAddrs, B: ToSocketAddrs>(bind_to: A, peer: B) -> Result<Self> {
+        let sk = Socket::__new(bind_to, peer)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+}
@@ -1,87 +1,295 @@
 use std;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
 
 use super::Error;
 use super::Result;
 use std::marker::PhantomData;
 
-macro_rules! unix_addr {
-    // TODO for now assumes just a single CCP (id=0)
-    ($x:expr) => {
-        format!("/tmp/ccp/0/{}", $x)
-    };
+extern crate libc;
+extern crate nix;
+use nix::sys::socket;
+
+/// Where `Socket`s bind and send to, and who may use them.
+///
+/// By default, CCP instance `id` binds `/tmp/ccp/{id}/{bind_to}` and sends to
+/// `/tmp/ccp/{id}/{send_to}`. Give each CCP instance on a host a different directory or id
+/// to keep them apart, e.g. one per container network namespace, or one per test.
+///
+/// ```
+/// use portus::ipc::unix::SocketBuilder;
+/// let b = SocketBuilder::new("in", "out").dir("/run/ccp").id(3).mode(0o660);
+/// assert_eq!(b.bind_addr(), std::path::PathBuf::from("/run/ccp/3/in"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct SocketBuilder {
+    dir: PathBuf,
+    id: u32,
+    bind_to: String,
+    send_to: String,
+    peer: Option<PathBuf>,
+    abstract_namespace: bool,
+    mode: Option<u32>,
+    owner: Option<(Option<u32>, Option<u32>)>,
 }
 
-pub struct Socket<T> {
-    sk: UnixDatagram,
-    dest: String,
-    _phantom: PhantomData<T>,
+impl Default for SocketBuilder {
+    /// The sockets CCP uses by default: bind `/tmp/ccp/0/in`, send to `/tmp/ccp/0/out`.
+    fn default() -> Self {
+        SocketBuilder::new("in", "out")
+    }
 }
 
-impl<T> Socket<T> {
-    // Only the CCP process is allowed to use id = 0.
-    // For all other datapaths, they should use a known unique identifier
-    // such as the port number.
-    fn __new(bind_to: &str, send_to: &str) -> Result<Self> {
-        let bind_to_addr = unix_addr!(bind_to.to_string());
-        let send_to_addr = unix_addr!(send_to.to_string());
+impl SocketBuilder {
+    /// Bind the socket named `bind_to`, and send to the socket named `send_to`.
+    pub fn new(bind_to: &str, send_to: &str) -> Self {
+        SocketBuilder {
+            dir: PathBuf::from("/tmp/ccp"),
+            id: 0,
+            bind_to: String::from(bind_to),
+            send_to: String::from(send_to),
+            peer: None,
+            abstract_namespace: false,
+            mode: None,
+            owner: None,
+        }
+    }
+
+    /// Keep the sockets of each CCP instance under `dir` instead of `/tmp/ccp`.
+    pub fn dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// The id of the CCP instance, which names the directory under `dir` its sockets are in:
+    /// `{dir}/{id}/`. CCP and the datapaths it serves must use the same id, and each CCP
+    /// instance sharing `dir` needs an id of its own. Defaults to 0.
+    pub fn id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Send to `addr` as given, instead of to the socket named `send_to` in this instance's
+    /// directory. With `abstract_namespace`, `addr` is an abstract name.
+    pub fn peer_addr<P: AsRef<Path>>(mut self, addr: P) -> Self {
+        self.peer = Some(addr.as_ref().to_path_buf());
+        self
+    }
+
+    /// Use Linux's abstract socket namespace: the addresses are names rather than files, so
+    /// nothing is created on disk, and sockets in different network namespaces are isolated
+    /// from each other. `mode` and `owner` do not apply to abstract sockets.
+    pub fn abstract_namespace(mut self, abstract_namespace: bool) -> Self {
+        self.abstract_namespace = abstract_namespace;
+        self
+    }
+
+    /// Set the permissions of the bound socket file, e.g. `0o660`, to control which processes
+    /// may send to it.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the owning user and group of the bound socket file. `None` leaves either unchanged.
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// The address the socket binds.
+    pub fn bind_addr(&self) -> PathBuf {
+        self.dir.join(self.id.to_string()).join(&self.bind_to)
+    }
+
+    /// The address the socket sends to.
+    pub fn peer(&self) -> PathBuf {
+        self.peer
+            .clone()
+            .unwrap_or_else(|| self.dir.join(self.id.to_string()).join(&self.send_to))
+    }
+
+    /// Make a blocking `Socket`.
+    pub fn build(&self) -> Result<Socket<Blocking>> {
+        Socket::<Blocking>::with_builder(self)
+    }
+
+    /// Make a nonblocking `Socket`.
+    pub fn build_nonblocking(&self) -> Result<Socket<Nonblocking>> {
+        Socket::<Nonblocking>::with_builder(self)
+    }
+
+    pub(super) fn sock_addr(&self, path: &Path) -> Result<socket::SockAddr> {
+        let addr = if self.abstract_namespace {
+            abstract_addr(path)?
+        } else {
+            socket::UnixAddr::new(path)?
+        };
+
+        Ok(socket::SockAddr::Unix(addr))
+    }
+
+    // Make way for a socket to be bound at `bind_addr()`.
+    pub(super) fn prepare_bind(&self) -> Result<()> {
+        if self.abstract_namespace {
+            return Ok(());
+        }
+
         // create dir if not already exists
-        match std::fs::create_dir_all("/tmp/ccp/0").err() {
+        match std::fs::create_dir_all(self.dir.join(self.id.to_string())).err() {
             Some(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
             Some(e) => Err(e),
             None => Ok(()),
         }?;
 
         // unlink before bind
-        match std::fs::remove_file(&bind_to_addr).err() {
+        match std::fs::remove_file(self.bind_addr()).err() {
             Some(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
             Some(e) => Err(e),
             None => Ok(()),
         }?;
-        let sock = UnixDatagram::bind(bind_to_addr)?;
+
+        Ok(())
+    }
+
+    // Give the bound socket file the requested permissions and owner.
+    pub(super) fn set_access(&self) -> Result<()> {
+        if self.abstract_namespace {
+            return Ok(());
+        }
+
+        let path = &self.bind_addr();
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        if let Some((uid, gid)) = self.owner {
+            use std::ffi::CString;
+            use std::os::unix::ffi::OsStrExt;
+            let path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| Error(format!("socket path contains a nul byte: {:?}", path)))?;
+            // -1 leaves the id unchanged
+            let uid = uid.map_or(!0, |u| u as libc::uid_t);
+            let gid = gid.map_or(!0, |g| g as libc::gid_t);
+            if unsafe { libc::chown(path.as_ptr(), uid, gid) } == -1 {
+                return Err(Error::from(nix::Error::last()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(path: &Path) -> Result<socket::UnixAddr> {
+    use std::os::unix::ffi::OsStrExt;
+    socket::UnixAddr::new_abstract(path.as_os_str().as_bytes()).map_err(Error::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_: &Path) -> Result<socket::UnixAddr> {
+    Err(Error(String::from(
+        "abstract unix sockets are only supported on Linux",
+    )))
+}
+
+pub struct Socket<T> {
+    sk: UnixDatagram,
+    dest: socket::SockAddr,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new(cfg: &SocketBuilder) -> Result<Self> {
+        cfg.prepare_bind()?;
+        let bind_to = cfg.sock_addr(&cfg.bind_addr())?;
+        let dest = cfg.sock_addr(&cfg.peer())?;
+        let fd = socket::socket(
+            socket::AddressFamily::Unix,
+            socket::SockType::Datagram,
+            socket::SockFlag::empty(),
+            0,
+        )?;
+        // the UnixDatagram owns fd from here on, and closes it if bind fails
+        let sock = unsafe { UnixDatagram::from_raw_fd(fd) };
+        socket::bind(sock.as_raw_fd(), &bind_to)?;
+        cfg.set_access()?;
         sock.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
 
         Ok(Socket {
             sk: sock,
-            dest: send_to_addr,
+            dest,
             _phantom: PhantomData,
         })
     }
 }
 
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sk.as_raw_fd()
+    }
+}
+
 impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
     fn name() -> String {
         String::from("unix")
     }
 
     fn send(&self, msg: &[u8]) -> Result<()> {
-        self.sk
-            .send_to(msg, self.dest.clone())
-            .map(|_| ())
-            .map_err(Error::from)
+        socket::sendto(
+            self.sk.as_raw_fd(),
+            msg,
+            &self.dest,
+            socket::MsgFlags::empty(),
+        )
+        .map(|_| ())
+        .map_err(Error::from)
     }
 
     fn recv(&self, msg: &mut [u8]) -> Result<usize> {
-        self.sk.recv(msg).map_err(Error::from)
+        match self.sk.recv(msg) {
+            Err(ref e) if super::would_block(e) => Ok(0),
+            r => r.map_err(Error::from),
+        }
     }
 
     fn close(&mut self) -> Result<()> {
         use std::net::Shutdown;
         self.sk.shutdown(Shutdown::Both).map_err(Error::from)
     }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
 }
 
 use super::Blocking;
 impl Socket<Blocking> {
+    /// Bind `/tmp/ccp/0/{bind_to}` and send to `/tmp/ccp/0/{send_to}`.
+    /// Use a `SocketBuilder` for other addresses.
     pub fn new(bind_to: &str, send_to: &str) -> Result<Self> {
-        Socket::__new(bind_to, send_to)
+        Self::with_builder(&SocketBuilder::new(bind_to, send_to))
+    }
+
+    /// Make a socket with the addresses and permissions given by `cfg`.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        Socket::__new(cfg)
     }
 }
 
 use super::Nonblocking;
 impl Socket<Nonblocking> {
+    /// Bind `/tmp/ccp/0/{bind_to}` and send to `/tmp/ccp/0/{send_to}`.
+    /// Use a `SocketBuilder` for other addresses.
     pub fn new(bind_to: &str, send_to: &str) -> Result<Self> {
-        let sk = Socket::__new(bind_to, send_to)?;
+        Self::with_builder(&SocketBuilder::new(bind_to, send_to))
+    }
+
+    /// Make a socket with the addresses and permissions given by `cfg`.
+    pub fn with_builder(cfg: &SocketBuilder) -> Result<Self> {
+        let sk = Socket::__new(cfg)?;
         sk.sk.set_nonblocking(true).map_err(Error::from)?;
         Ok(sk)
     }
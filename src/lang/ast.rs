@@ -6,6 +6,7 @@ pub enum Prim {
     Bool(bool),
     Name(String),
     Num(u64),
+    Signed(i64),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -33,6 +34,13 @@ pub enum Op {
 
     // SPECIAL: reads return register
     Ewma, // (ewma a b) ret * a/10 + b * (10-a)/10.
+
+    // SPECIAL: cannot be called by user, only generated for Signed operands
+    SDiv, // (div a b) with signed a, b
+    SGt,  // (> a b) with signed a, b
+    SLt,  // (< a b) with signed a, b
+    SMax, // (max a b) with signed a, b
+    SMin, // (min a b) with signed a, b
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -44,11 +52,106 @@ pub enum Command {
 #[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Atom(Prim),
+    Call(String, Vec<Expr>),
     Cmd(Command),
     Sexp(Op, Box<Expr>, Box<Expr>),
     None,
 }
 
+// Words which cannot be used as macro names.
+pub(crate) const RESERVED: &[&str] = &[
+    "add",
+    "and",
+    "bind",
+    "def",
+    "defmacro",
+    "div",
+    "eq",
+    "ewma",
+    "fallthrough",
+    "gt",
+    "if",
+    "lt",
+    "max",
+    "min",
+    "mul",
+    "or",
+    "report",
+    "sub",
+    "when",
+    "wrapped_max",
+];
+
+use std::fmt;
+
+impl fmt::Display for Prim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Prim::Bool(b) => write!(f, "{}", b),
+            Prim::Name(ref n) => write!(f, "{}", n),
+            Prim::Num(n) if n == u64::max_value() => write!(f, "+infinity"),
+            Prim::Num(n) => write!(f, "{}", n),
+            Prim::Signed(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // generated signed ops are written the same way as their unsigned counterparts
+        f.write_str(match *self {
+            Op::Add => "+",
+            Op::And => "&&",
+            Op::Bind => ":=",
+            Op::Div | Op::SDiv => "/",
+            Op::Equiv => "==",
+            Op::Gt | Op::SGt => ">",
+            Op::Lt | Op::SLt => "<",
+            Op::Max | Op::SMax => "max",
+            Op::MaxWrap => "wrapped_max",
+            Op::Min | Op::SMin => "min",
+            Op::Mul => "*",
+            Op::Or => "||",
+            Op::Sub => "-",
+            Op::Def => "def",
+            Op::If => "if",
+            Op::NotIf => "!if",
+            Op::Ewma => "ewma",
+        })
+    }
+}
+
+/// Canonical s-expression form. Comments are not preserved.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Atom(ref p) => write!(f, "{}", p),
+            Expr::Call(ref name, ref args) => {
+                write!(f, "({}", name)?;
+                for a in args {
+                    write!(f, " {}", a)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Cmd(Command::Fallthrough) => write!(f, "(fallthrough)"),
+            Expr::Cmd(Command::Report) => write!(f, "(report)"),
+            // commands after desugaring, which cannot be written as bindings
+            Expr::Sexp(
+                Op::Bind,
+                box Expr::Atom(Prim::Name(ref name)),
+                box Expr::Atom(Prim::Bool(true)),
+            ) if name == "__shouldContinue" => write!(f, "(fallthrough)"),
+            Expr::Sexp(
+                Op::Bind,
+                box Expr::Atom(Prim::Name(ref name)),
+                box Expr::Atom(Prim::Bool(true)),
+            ) if name == "__shouldReport" => write!(f, "(report)"),
+            Expr::Sexp(o, box ref left, box ref right) => write!(f, "({} {} {})", o, left, right),
+            Expr::None => Ok(()),
+        }
+    }
+}
+
 use std::str;
 named_complete!(
     op<Result<Op>>,
@@ -106,6 +209,24 @@ named_complete!(
     ))
 );
 
+// (name arg...) invokes the macro `name`, which is expanded by `Prog::new_with_scope()`.
+named_complete!(
+    call<Result<Expr>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            n: map_res!(name, |n: String| if RESERVED.contains(&n.as_str()) {
+                Err(Error::from(format!("reserved word: {:?}", n)))
+            } else {
+                Ok(n)
+            }) >>
+            args: many0!(expr) >>
+            (args.into_iter().collect::<Result<Vec<Expr>>>().map(|a| Expr::Call(n, a)))
+        ),
+        tag!(")")
+    ))
+);
+
 use nom::digit;
 use nom::types::CompleteByteSlice;
 use std::str::FromStr;
@@ -120,6 +241,18 @@ named_complete!(
     )
 );
 
+// A negative integer literal, e.g. `-5`.
+named_complete!(
+    pub neg_num<i64>,
+    map_res!(
+        preceded!(tag!("-"), digit),
+        |d: CompleteByteSlice| {
+            let st = str::from_utf8(d.0)?;
+            FromStr::from_str(&format!("-{}", st)).map_err(Error::from)
+        }
+    )
+);
+
 use nom::is_alphanumeric;
 named_complete!(
     pub name<String>,
@@ -145,6 +278,7 @@ named_complete!(
             tag!("false") => { |_| Ok(Prim::Bool(false)) } |
             tag!("+infinity") => { |_| Ok(Prim::Num(u64::max_value())) } |
             num => { |n: u64| Ok(Prim::Num(n)) } |
+            neg_num => { |n: i64| Ok(Prim::Signed(n)) } |
             name => { |n: String| Ok(Prim::Name(n)) }
         ) >>
         (val.and_then(|t| Ok(Expr::Atom(t))))
@@ -177,7 +311,7 @@ named_complete!(
 
 named_complete!(
     pub expr<Result<Expr>>,
-    alt_complete!(comment | sexp | command | atom)
+    alt_complete!(comment | call | sexp | command | atom)
 );
 
 named_complete!(
@@ -187,6 +321,8 @@ named_complete!(
 
 impl Expr {
     // TODO make return Iter
+    // Macros are only defined within a program (see `Prog::new_with_scope()`),
+    // so standalone expressions cannot contain macro calls.
     pub fn new(src: &[u8]) -> Result<Vec<Self>> {
         use nom::Needed;
         match exprs(CompleteByteSlice(src)) {
@@ -196,6 +332,12 @@ impl Expr {
                     Ok(Expr::None) => false,
                     _ => true,
                 })
+                .map(|e| match e {
+                    Ok(ref e) if e.has_call() => {
+                        Err(Error::from(format!("undefined macro call: {:?}", e)))
+                    }
+                    e => e,
+                })
                 .collect(),
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
             Err(nom::Err::Incomplete(Needed::Unknown)) => Err(Error::from("need more src")),
@@ -205,6 +347,14 @@ impl Expr {
         }
     }
 
+    fn has_call(&self) -> bool {
+        match *self {
+            Expr::Call(_, _) => true,
+            Expr::Sexp(_, box ref left, box ref right) => left.has_call() || right.has_call(),
+            _ => false,
+        }
+    }
+
     pub fn desugar(&mut self) {
         match *self {
             Expr::Cmd(Command::Fallthrough) => {
@@ -223,6 +373,7 @@ impl Expr {
             }
             Expr::None => {}
             Expr::Atom(_) => {}
+            Expr::Call(_, ref mut args) => args.iter_mut().for_each(|a| a.desugar()),
             Expr::Sexp(_, box ref mut left, box ref mut right) => {
                 left.desugar();
                 right.desugar();
@@ -317,6 +468,147 @@ mod tests {
         );
     }
 
+    #[test]
+    fn atom_neg() {
+        let foo = b"-42";
+        let er = Expr::new(foo);
+        let e = er.unwrap();
+        assert_eq!(e, vec![Expr::Atom(Prim::Signed(-42))]);
+    }
+
+    #[test]
+    fn neg_sexp() {
+        let foo = b"(- -5 3)";
+        let er = Expr::new(foo);
+        let e = er.unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Sexp(
+                Op::Sub,
+                Box::new(Expr::Atom(Prim::Signed(-5))),
+                Box::new(Expr::Atom(Prim::Num(3)))
+            )]
+        );
+    }
+
+    #[test]
+    fn call() {
+        use super::exprs;
+        let parse = |src: &'static [u8]| -> Vec<Expr> {
+            let (rest, e) = exprs(CompleteByteSlice(src)).unwrap();
+            assert_eq!(rest, CompleteByteSlice(b""));
+            e.into_iter().map(|e| e.unwrap()).collect()
+        };
+
+        let foo = b"(track_min Report.minrtt (+ Flow.rtt_sample_us 1))";
+        let e = parse(foo);
+        assert_eq!(
+            e,
+            vec![Expr::Call(
+                String::from("track_min"),
+                vec![
+                    Expr::Atom(Prim::Name(String::from("Report.minrtt"))),
+                    Expr::Sexp(
+                        Op::Add,
+                        Box::new(Expr::Atom(Prim::Name(String::from("Flow.rtt_sample_us")))),
+                        Box::new(Expr::Atom(Prim::Num(1)))
+                    ),
+                ]
+            )]
+        );
+
+        // operators are not macro calls, even if a longer name starts with one
+        let e = parse(b"(min 1 2) (minrtt) (report)");
+        assert_eq!(
+            e,
+            vec![
+                Expr::Sexp(
+                    Op::Min,
+                    Box::new(Expr::Atom(Prim::Num(1))),
+                    Box::new(Expr::Atom(Prim::Num(2)))
+                ),
+                Expr::Call(String::from("minrtt"), vec![]),
+                Expr::Cmd(Command::Report),
+            ]
+        );
+
+        // calls are only valid inside a program
+        assert!(Expr::new(foo).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let foo: &[u8] = b"(:= Report.minrtt (min Report.minrtt (ewma 2 (- -5 +infinity))))
+            (fallthrough) (:= x (!if false 3)) (wrapped_max 3 (&& true (> 1 2)))";
+        let e = Expr::new(foo).unwrap();
+        let printed: Vec<String> = e.iter().map(|e| format!("{}", e)).collect();
+        assert_eq!(
+            printed[0],
+            "(:= Report.minrtt (min Report.minrtt (ewma 2 (- -5 +infinity))))"
+        );
+        assert_eq!(printed[1], "(fallthrough)");
+
+        // parsing the printed form gives the same AST
+        let reparsed = Expr::new(printed.join(" ").as_bytes()).unwrap();
+        assert_eq!(reparsed, e);
+    }
+
+    // Generate a random expression tree from the xorshift state `seed`.
+    fn random_expr(seed: &mut u64, depth: u32) -> Expr {
+        let mut next = || {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *seed
+        };
+
+        let ops = [
+            Op::Add,
+            Op::And,
+            Op::Div,
+            Op::Equiv,
+            Op::Gt,
+            Op::Lt,
+            Op::Max,
+            Op::MaxWrap,
+            Op::Min,
+            Op::Mul,
+            Op::Or,
+            Op::Sub,
+            Op::Ewma,
+        ];
+        let names = ["Report.foo", "Ack.bytes_acked", "Micros", "x"];
+        let choice = next() % if depth == 0 { 4 } else { 6 };
+        match choice {
+            0 => Expr::Atom(Prim::Bool(next() % 2 == 0)),
+            1 => Expr::Atom(Prim::Name(String::from(
+                names[next() as usize % names.len()],
+            ))),
+            2 => Expr::Atom(Prim::Num(match next() % 3 {
+                0 => u64::max_value(),
+                _ => next() % 100_000,
+            })),
+            3 => Expr::Atom(Prim::Signed(-((next() % 100_000) as i64) - 1)),
+            _ => {
+                let op = ops[next() as usize % ops.len()];
+                let left = random_expr(seed, depth - 1);
+                let right = random_expr(seed, depth - 1);
+                Expr::Sexp(op, Box::new(left), Box::new(right))
+            }
+        }
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..500 {
+            let e = random_expr(&mut seed, 4);
+            let printed = format!("{}", e);
+            let reparsed = Expr::new(printed.as_bytes()).unwrap();
+            assert_eq!(reparsed, vec![e], "{}", printed);
+        }
+    }
+
     #[test]
     fn simple_exprs() {
         let foo = b"(+ 10 20)";
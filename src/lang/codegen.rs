@@ -0,0 +1,374 @@
+//! Generate typed Rust accessors for a datapath program.
+//!
+//! Algorithms read reports with `Report::get_field("Report.minrtt", &scope)`, which is easy to
+//! typo and returns every field as a `u64`. `gen_accessors` instead reads a program at build time
+//! and emits:
+//!
+//! 1. A `{Name}Report` struct with one typed field per `Report` variable, and a checked
+//!    `from_report()` conversion.
+//! 2. A `{Name}Control` builder with one typed setter per updatable field (`Control` variables,
+//!    `Cwnd` and `Rate`), whose updates can be applied with `DatapathTrait::update_field`.
+//!
+//! A program whose variables would give two setters the same name, such as `Control.cwnd`
+//! alongside the `Cwnd` setter, or a setter the name of one of the builder's own methods, is
+//! rejected.
+//!
+//! ### Example
+//!
+//! In `build.rs`:
+//!
+//! ```no-run
+//! let src = std::fs::read("src/my_program.ccp").unwrap();
+//! let code = portus::lang::gen_accessors("MyProgram", &src).unwrap();
+//! let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("my_program.rs");
+//! std::fs::write(out, code).unwrap();
+//! ```
+//!
+//! And then in the algorithm:
+//!
+//! ```no-run
+//! include!(concat!(env!("OUT_DIR"), "/my_program.rs"));
+//!
+//! fn on_report(&mut self, _sock_id: u32, m: Report) {
+//!     let r = MyProgramReport::from_report(&m, &self.sc).unwrap();
+//!     MyProgramControl::new()
+//!         .cwnd(r.acked)
+//!         .apply(&self.control, &self.sc)
+//!         .unwrap();
+//! }
+//! ```
+
+use std::fmt::Write;
+
+use super::datapath::{Reg, Type};
+use super::prog::Prog;
+use super::{Error, Result};
+
+// Rust keywords which cannot be used as plain identifiers, but can as raw ones.
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do", "dyn",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let", "loop",
+    "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return", "static",
+    "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while", "yield",
+];
+
+// Rust keywords which cannot be used as identifiers at all, not even raw ones.
+const RESERVED: &[&str] = &["crate", "self", "Self", "super"];
+
+fn rust_ident(name: &str) -> String {
+    let ident = name.replace('.', "_");
+    if KEYWORDS.contains(&ident.as_str()) {
+        format!("r#{}", ident)
+    } else {
+        ident
+    }
+}
+
+fn rust_type(t: &Type) -> &'static str {
+    match *t {
+        Type::Bool(_) => "bool",
+        Type::Signed(_) => "i64",
+        _ => "u64",
+    }
+}
+
+/// Generate Rust source for the typed `Report` and `Control` accessors of the program `src`.
+/// The generated types are named `{name}Report` and `{name}Control`.
+pub fn gen_accessors(name: &str, src: &[u8]) -> Result<String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(Error::from(format!("invalid type name prefix: {:?}", name)));
+    }
+
+    let (_, sc) = Prog::new_with_scope(src)?;
+
+    let mut reports: Vec<(u8, &str, &Type)> = sc
+        .named
+        .0
+        .iter()
+        .filter_map(|&(ref var, ref reg)| match *reg {
+            Reg::Report(idx, ref t, _) => Some((idx, &var["Report.".len()..], t)),
+            _ => None,
+        })
+        .collect();
+    reports.sort_by_key(|&(idx, _, _)| idx);
+
+    let mut controls: Vec<(u8, &str, &str, &Type)> = sc
+        .named
+        .0
+        .iter()
+        .filter_map(|&(ref var, ref reg)| match *reg {
+            Reg::Control(idx, ref t) => {
+                Some((idx, var.as_str(), var.trim_start_matches("Control."), t))
+            }
+            _ => None,
+        })
+        .collect();
+    controls.sort_by_key(|&(idx, _, _, _)| idx);
+
+    let fields = reports
+        .iter()
+        .map(|&(_, field, _)| (format!("Report.{}", field), field))
+        .chain(
+            controls
+                .iter()
+                .map(|&(_, var, field, _)| (var.to_owned(), field)),
+        );
+    for (var, field) in fields {
+        if RESERVED.contains(&field.replace('.', "_").as_str()) {
+            return Err(Error::from(format!(
+                "{} would be named `{}`, which Rust does not allow even as a raw identifier; \
+                 rename the variable",
+                var, field
+            )));
+        }
+    }
+
+    // the name of each method of the Control builder, and what it is for
+    let mut methods: Vec<(String, String)> = ["new", "fields", "apply"]
+        .iter()
+        .map(|m| (m.to_string(), format!("the {} method", m)))
+        .chain(
+            ["Cwnd", "Rate"]
+                .iter()
+                .map(|v| (v.to_lowercase(), format!("the setter for {}", v))),
+        )
+        .collect();
+    for &(_, var, field, _) in &controls {
+        let ident = rust_ident(field);
+        if let Some(&(_, ref other)) = methods.iter().find(|&&(ref m, _)| *m == ident) {
+            return Err(Error::from(format!(
+                "the setter for {} would clash with {} of {}Control; rename the variable",
+                var, other, name
+            )));
+        }
+
+        methods.push((ident, format!("the setter for {}", var)));
+    }
+
+    let mut out = String::new();
+    gen_report(&mut out, name, &reports).map_err(|e| Error::from(format!("{}", e)))?;
+    gen_control(&mut out, name, &controls).map_err(|e| Error::from(format!("{}", e)))?;
+    Ok(out)
+}
+
+fn gen_report(out: &mut String, name: &str, fields: &[(u8, &str, &Type)]) -> ::std::fmt::Result {
+    writeln!(
+        out,
+        "/// Typed view of a `Report` from datapath program `{}`.",
+        name
+    )?;
+    writeln!(out, "#[derive(Clone, Copy, Debug, PartialEq)]")?;
+    writeln!(out, "pub struct {}Report {{", name)?;
+    for &(_, field, t) in fields {
+        writeln!(out, "    pub {}: {},", rust_ident(field), rust_type(t))?;
+    }
+    writeln!(out, "}}\n")?;
+
+    writeln!(out, "impl {}Report {{", name)?;
+    writeln!(
+        out,
+        "    /// Read every field of the `Report` struct, checking that `r` matches `sc`."
+    )?;
+    writeln!(
+        out,
+        "    pub fn from_report(r: &::portus::Report, sc: &::portus::lang::Scope) -> ::portus::Result<Self> {{"
+    )?;
+    writeln!(out, "        Ok({}Report {{", name)?;
+    for &(_, field, t) in fields {
+        let (getter, conv) = match *t {
+            Type::Bool(_) => ("get_field", " != 0"),
+            Type::Signed(_) => ("get_signed_field", ""),
+            _ => ("get_field", ""),
+        };
+        writeln!(
+            out,
+            "            {}: r.{}(\"Report.{}\", sc)?{},",
+            rust_ident(field),
+            getter,
+            field,
+            conv
+        )?;
+    }
+    writeln!(out, "        }})")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}\n")
+}
+
+fn gen_control(
+    out: &mut String,
+    name: &str,
+    fields: &[(u8, &str, &str, &Type)],
+) -> ::std::fmt::Result {
+    writeln!(
+        out,
+        "/// Typed updates to the mutable fields of datapath program `{}`.",
+        name
+    )?;
+    writeln!(out, "#[derive(Clone, Debug, Default, PartialEq)]")?;
+    writeln!(out, "pub struct {}Control {{", name)?;
+    writeln!(out, "    fields: Vec<(&'static str, u64)>,")?;
+    writeln!(out, "}}\n")?;
+
+    writeln!(out, "impl {}Control {{", name)?;
+    writeln!(out, "    pub fn new() -> Self {{")?;
+    writeln!(out, "        Self::default()")?;
+    writeln!(out, "    }}\n")?;
+
+    let implicit = [
+        ("Cwnd", "cwnd", &Type::Num(None)),
+        ("Rate", "rate", &Type::Num(None)),
+    ];
+    for (var, field, t) in fields
+        .iter()
+        .map(|&(_, var, field, t)| (var, field, t))
+        .chain(implicit.iter().cloned())
+    {
+        let (arg, conv) = match *t {
+            Type::Bool(_) => ("bool", "u64::from(v)"),
+            Type::Signed(_) => ("i64", "v as u64"),
+            _ => ("u64", "v"),
+        };
+        writeln!(out, "    /// Set `{}`.", var)?;
+        writeln!(
+            out,
+            "    pub fn {}(mut self, v: {}) -> Self {{",
+            rust_ident(field),
+            arg
+        )?;
+        writeln!(out, "        self.fields.push((\"{}\", {}));", var, conv)?;
+        writeln!(out, "        self")?;
+        writeln!(out, "    }}\n")?;
+    }
+
+    writeln!(
+        out,
+        "    /// The updates, in the form accepted by `DatapathTrait::update_field`."
+    )?;
+    writeln!(out, "    pub fn fields(&self) -> &[(&'static str, u64)] {{")?;
+    writeln!(out, "        &self.fields[..]")?;
+    writeln!(out, "    }}\n")?;
+
+    writeln!(out, "    /// Send the updates to the datapath.")?;
+    writeln!(
+        out,
+        "    pub fn apply<D: ::portus::DatapathTrait>(&self, dp: &D, sc: &::portus::lang::Scope) -> ::portus::Result<()> {{"
+    )?;
+    writeln!(out, "        dp.update_field(sc, &self.fields[..])")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn report_struct() {
+        let src = b"
+            (def (Report (volatile acked 0) (timeout false) (minrtt +infinity)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (:= Report.timeout Flow.was_timeout)
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            )
+        ";
+
+        let code = super::gen_accessors("Foo", src).unwrap();
+        assert!(code.contains(
+            "pub struct FooReport {
+    pub acked: u64,
+    pub timeout: bool,
+    pub minrtt: u64,
+}"
+        ));
+        assert!(code.contains("acked: r.get_field(\"Report.acked\", sc)?,"));
+        assert!(code.contains("timeout: r.get_field(\"Report.timeout\", sc)? != 0,"));
+    }
+
+    #[test]
+    fn signed_fields() {
+        let src = b"
+            (def (Control.offset -3) (Report (signed grad 0)))
+            (when true
+                (:= Report.grad (- Flow.rtt_sample_us Control.offset))
+            )
+        ";
+
+        let code = super::gen_accessors("Baz", src).unwrap();
+        assert!(code.contains("pub grad: i64,"));
+        assert!(code.contains("grad: r.get_signed_field(\"Report.grad\", sc)?,"));
+        assert!(code.contains("pub fn offset(mut self, v: i64) -> Self {"));
+        assert!(code.contains("self.fields.push((\"Control.offset\", v as u64));"));
+    }
+
+    #[test]
+    fn control_setters() {
+        let src = b"
+            (def (Control.thresh 10) (Control.enabled true) (type 0) (Report (acked 0)))
+            (when (> Micros Control.thresh)
+                (:= Report.acked type)
+                (report)
+            )
+        ";
+
+        let code = super::gen_accessors("Bar", src).unwrap();
+        assert!(code.contains("pub fn thresh(mut self, v: u64) -> Self {"));
+        assert!(code.contains("self.fields.push((\"Control.thresh\", v));"));
+        assert!(code.contains("pub fn enabled(mut self, v: bool) -> Self {"));
+        assert!(code.contains("self.fields.push((\"Control.enabled\", u64::from(v)));"));
+        assert!(code.contains("pub fn r#type(mut self, v: u64) -> Self {"));
+        assert!(code.contains("pub fn cwnd(mut self, v: u64) -> Self {"));
+        assert!(code.contains("pub fn rate(mut self, v: u64) -> Self {"));
+    }
+
+    #[test]
+    fn setter_clash() {
+        let err = |src: &[u8]| match super::gen_accessors("Qux", src) {
+            Err(e) => e.0,
+            Ok(_) => panic!("expected a clash"),
+        };
+
+        assert_eq!(
+            err(b"(def (Control.cwnd 0) (Report (a 0))) (when true (report))"),
+            "the setter for Control.cwnd would clash with the setter for Cwnd of QuxControl; \
+             rename the variable"
+        );
+        assert!(
+            err(b"(def (Control.apply 0) (Report (a 0))) (when true (report))")
+                .contains("the apply method")
+        );
+        assert!(
+            err(b"(def (Control.x 0) (x 0) (Report (a 0))) (when true (report))")
+                .contains("the setter for Control.x")
+        );
+    }
+
+    #[test]
+    fn reserved_names() {
+        let err = |src: &[u8]| match super::gen_accessors("Qux", src) {
+            Err(e) => e.0,
+            Ok(_) => panic!("expected a reserved name"),
+        };
+
+        assert_eq!(
+            err(b"(def (Report (self 0))) (when true (report))"),
+            "Report.self would be named `self`, which Rust does not allow even as a raw \
+             identifier; rename the variable"
+        );
+        assert!(
+            err(b"(def (Control.super 0) (Report (a 0))) (when true (report))")
+                .starts_with("Control.super would be named `super`")
+        );
+        assert!(
+            err(b"(def (Control.crate 0) (Report (a 0))) (when true (report))")
+                .starts_with("Control.crate would be named `crate`")
+        );
+    }
+
+    #[test]
+    fn bad_name() {
+        assert!(
+            super::gen_accessors("My Program", b"(def (Report (a 0))) (when true (report))")
+                .is_err()
+        );
+    }
+}
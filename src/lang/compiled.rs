@@ -0,0 +1,214 @@
+//! A stable on-disk format for compiled datapath programs.
+//!
+//! A `Compiled` program keeps the `program_uid` it was installed with, so a CCP which restarts
+//! and loads it again reads the reports of flows still running the installed program, instead
+//! of treating them as stale, even if a newer compiler would give the program a different uid.
+//! It also keeps a hash of its source, to tell whether it is still up to date.
+
+use fnv::FnvHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::datapath::{Bin, Scope};
+use super::serialize::{EVENT_SIZE, INSTR_SIZE};
+use super::uid::{fingerprint, register_uid};
+use super::{Error, Result};
+use serialize::{u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s};
+
+const MAGIC: &[u8; 4] = b"CCPP";
+const VERSION: u32 = 1;
+const HDR_SIZE: usize = 32;
+
+/// The hash of a program's source and the params it is compiled with, as used by `Compiled`.
+pub fn source_hash(src: &[u8], params: &[(&str, u64)]) -> u64 {
+    let mut h = FnvHasher::default();
+    h.write(src);
+    for &(name, val) in params {
+        h.write_u8(0);
+        h.write(name.as_bytes());
+        h.write_u64(val);
+    }
+
+    h.finish()
+}
+
+/// A compiled datapath program: its `Bin`, its `Scope` (including its `program_uid`), and the
+/// hash of the source it was compiled from.
+#[derive(Clone, Debug)]
+pub struct Compiled {
+    pub source_hash: u64,
+    pub bin: Bin,
+    pub scope: Scope,
+}
+
+impl Compiled {
+    /// Compile `src` with `params`, like `lang::compile()`.
+    pub fn new(src: &[u8], params: &[(&str, u64)]) -> Result<Self> {
+        let (bin, scope) = super::compile(src, params)?;
+        Ok(Compiled {
+            source_hash: source_hash(src, params),
+            bin,
+            scope,
+        })
+    }
+
+    /// serialization format:
+    ///
+    /// |--------|---------|-------------|-------------|------------|------------|-----------|
+    /// | magic  | version | program uid | source hash | num events | num instrs | scope len |
+    /// | "CCPP" | u32     | u32         | u64         | u32        | u32        | u32       |
+    /// |--------|---------|-------------|-------------|------------|------------|-----------|
+    ///
+    /// followed by the `Bin`, as written by `Bin::serialize()`, and the `Scope`, as written by
+    /// `Scope::serialize()`.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let bin = self.bin.serialize()?;
+        let scope = self.scope.serialize()?;
+        let mut buf = vec![0u8; HDR_SIZE];
+        buf[0..4].copy_from_slice(MAGIC);
+        u32_to_u8s(&mut buf[4..8], VERSION);
+        u32_to_u8s(&mut buf[8..12], self.scope.program_uid);
+        u64_to_u8s(&mut buf[12..20], self.source_hash);
+        u32_to_u8s(&mut buf[20..24], self.bin.events.len() as u32);
+        u32_to_u8s(&mut buf[24..28], self.bin.instrs.len() as u32);
+        u32_to_u8s(&mut buf[28..32], scope.len() as u32);
+        buf.extend(bin);
+        buf.extend(scope);
+        Ok(buf)
+    }
+
+    /// Read back a program written by `Compiled::serialize()`.
+    /// See `Bin::deserialize()` for what is lost in serialization.
+    ///
+    /// The program keeps its `program_uid`. It is an error if a different program in this process
+    /// already has that uid, or if it is 0 or `UNASSIGNED_UID`, which no program has.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HDR_SIZE || &buf[0..4] != MAGIC {
+            return Err(Error::from("not a compiled datapath program"));
+        }
+
+        let version = u32_from_u8s(&buf[4..8]);
+        if version != VERSION {
+            return Err(Error::from(format!(
+                "compiled program version {}, expected {}",
+                version, VERSION
+            )));
+        }
+
+        let program_uid = u32_from_u8s(&buf[8..12]);
+        let num_events = u32_from_u8s(&buf[20..24]);
+        let num_instrs = u32_from_u8s(&buf[24..28]);
+        let scope_len = u32_from_u8s(&buf[28..32]) as usize;
+        let bin_len = u64::from(num_events) * u64::from(EVENT_SIZE)
+            + u64::from(num_instrs) * u64::from(INSTR_SIZE);
+        if (buf.len() - HDR_SIZE) as u64 != bin_len + scope_len as u64 {
+            return Err(Error::from(format!(
+                "compiled program is {} bytes, expected {}",
+                buf.len(),
+                HDR_SIZE as u64 + bin_len + scope_len as u64
+            )));
+        }
+
+        let scope_start = HDR_SIZE + bin_len as usize;
+        let bin = Bin::deserialize(&buf[HDR_SIZE..scope_start], num_events, num_instrs)?;
+        let mut scope = Scope::deserialize(&buf[scope_start..])?;
+        register_uid(
+            program_uid,
+            fingerprint(&buf[HDR_SIZE..scope_start], &buf[scope_start..]),
+        )?;
+        scope.program_uid = program_uid;
+
+        Ok(Compiled {
+            source_hash: u64_from_u8s(&buf[12..20]),
+            bin,
+            scope,
+        })
+    }
+
+    /// Write the program to `path`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let buf = self.serialize()?;
+        fs::File::create(path)?.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read a program written by `Compiled::save()`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Compiled::deserialize(&fs::read(path)?)
+    }
+
+    /// Where a cache in directory `dir` keeps the program compiled from source with `hash`.
+    pub fn cache_path<P: AsRef<Path>>(dir: P, hash: u64) -> PathBuf {
+        dir.as_ref().join(format!("{:016x}.ccpp", hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{source_hash, Compiled};
+    use std::fs;
+
+    const SRC: &[u8] = b"
+        (param init 3)
+        (def (Report (volatile acked init) (signed gradient -1)) (Control.state false))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (fallthrough)
+        )
+        (when (> Micros 1000)
+            (report)
+        )
+    ";
+
+    #[test]
+    fn roundtrip() {
+        let c = Compiled::new(SRC, &[("init", 4)]).unwrap();
+        assert_eq!(c.source_hash, source_hash(SRC, &[("init", 4)]));
+        assert_ne!(c.source_hash, source_hash(SRC, &[]));
+
+        let buf = c.serialize().unwrap();
+        let got = Compiled::deserialize(&buf).unwrap();
+        assert_eq!(got.source_hash, c.source_hash);
+        assert_eq!(got.scope.program_uid, c.scope.program_uid);
+        assert_eq!(got.scope.named.0, c.scope.named.0);
+        assert_eq!(got.bin.serialize().unwrap(), c.bin.serialize().unwrap());
+
+        assert!(Compiled::deserialize(&buf[..buf.len() - 1]).is_err());
+        let mut bad = buf.clone();
+        bad[4] = 2;
+        assert!(Compiled::deserialize(&bad).is_err());
+    }
+
+    #[test]
+    fn uid_conflict() {
+        let c = Compiled::new(SRC, &[]).unwrap();
+        let other = Compiled::new(b"(def (Report.a 0)) (when true (report))", &[]).unwrap();
+        let mut c2 = c.clone();
+        c2.scope.program_uid = other.scope.program_uid;
+        assert!(Compiled::deserialize(&c2.serialize().unwrap()).is_err());
+
+        // a uid no program has yet is fine, and then belongs to this one
+        c2.scope.program_uid = 0x7fff_fff0;
+        let got = Compiled::deserialize(&c2.serialize().unwrap()).unwrap();
+        assert_eq!(got.scope.program_uid, 0x7fff_fff0);
+        assert!(Compiled::deserialize(&c.serialize().unwrap()).is_ok());
+
+        // nor can a cached program claim uid 0, which hands a flow back to the datapath
+        c2.scope.program_uid = 0;
+        assert!(Compiled::deserialize(&c2.serialize().unwrap()).is_err());
+    }
+
+    #[test]
+    fn save_load() {
+        let dir = ::std::env::temp_dir().join(format!("portus-compiled-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let c = Compiled::new(SRC, &[]).unwrap();
+        let path = Compiled::cache_path(&dir, c.source_hash);
+        c.save(&path).unwrap();
+        let got = Compiled::load(&path).unwrap();
+        assert_eq!(got.scope.program_uid, c.scope.program_uid);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,6 +7,7 @@ pub enum Type {
     Bool(Option<bool>),
     Name(String),
     Num(Option<u64>),
+    Signed(Option<i64>),
     None,
 }
 
@@ -16,6 +17,7 @@ pub(crate) fn check_atom_type(e: &Expr) -> Result<Type> {
             Prim::Bool(t) => Ok(Type::Bool(Some(t))),
             Prim::Name(ref name) => Ok(Type::Name(name.clone())),
             Prim::Num(n) => Ok(Type::Num(Some(n))),
+            Prim::Signed(n) => Ok(Type::Signed(Some(n))),
         },
         _ => Err(Error::from(format!("not an atom: {:?}", e))),
     }
@@ -26,6 +28,7 @@ pub(crate) fn check_atom_type(e: &Expr) -> Result<Type> {
 pub enum Reg {
     Control(u8, Type),
     ImmNum(u64),
+    ImmSigned(i64),
     ImmBool(bool),
     Implicit(u8, Type),
     Local(u8, Type),
@@ -36,9 +39,10 @@ pub enum Reg {
 }
 
 impl Reg {
-    fn get_type(&self) -> Result<Type> {
+    pub(crate) fn get_type(&self) -> Result<Type> {
         match *self {
             Reg::ImmNum(n) => Ok(Type::Num(Some(n))),
+            Reg::ImmSigned(n) => Ok(Type::Signed(Some(n))),
             Reg::ImmBool(b) => Ok(Type::Bool(Some(b))),
             Reg::Control(_, ref t)
             | Reg::Implicit(_, ref t)
@@ -173,6 +177,49 @@ impl Bin {
     }
 }
 
+/// Check that both operands of an arithmetic or comparison `Op` are numeric.
+/// Returns whether the operation is signed: if either operand is `Signed`, the other is
+/// reinterpreted as signed as well. Unsigned immediates must fit in an `i64` to do so.
+fn check_num_operands(o: Op, left: &Reg, right: &Reg) -> Result<bool> {
+    let is_num = |r: &Reg| match r.get_type() {
+        Ok(Type::Num(_)) => Ok(false),
+        Ok(Type::Signed(_)) => Ok(true),
+        x => Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
+    };
+
+    let signed = is_num(left)? || is_num(right)?;
+    if signed {
+        for r in &[left, right] {
+            if let Reg::ImmNum(n) = **r {
+                if n > i64::max_value() as u64 {
+                    return Err(Error::from(format!(
+                        "{:?}: immediate {} does not fit in a Signed value",
+                        o, n
+                    )));
+                }
+            }
+        }
+    }
+
+    Ok(signed)
+}
+
+/// The variant of `o` which treats its operands as signed.
+fn signed_op(o: Op) -> Result<Op> {
+    match o {
+        Op::Add | Op::Equiv | Op::Mul | Op::Sub => Ok(o),
+        Op::Div => Ok(Op::SDiv),
+        Op::Gt => Ok(Op::SGt),
+        Op::Lt => Ok(Op::SLt),
+        Op::Max => Ok(Op::SMax),
+        Op::Min => Ok(Op::SMin),
+        _ => Err(Error::from(format!(
+            "{:?} does not support Signed operands",
+            o
+        ))),
+    }
+}
+
 // TODO make iterative instead of recursive, and return impl Iterator<Instr>
 /// Given a single Expr, return
 /// a Vec<Instr> that evaluates that Expr
@@ -196,7 +243,9 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                 }
             }
             Prim::Num(n) => Ok((vec![], Reg::ImmNum(n as u64))),
+            Prim::Signed(n) => Ok((vec![], Reg::ImmSigned(n))),
         },
+        Expr::Call(ref name, _) => Err(Error::from(format!("unexpanded macro: {:?}", name))),
         Expr::Cmd(_) | Expr::None => unreachable!(),
         Expr::Sexp(ref o, box ref left_expr, box ref right_expr) => {
             let (mut instrs, mut left) = compile_expr(left_expr, &mut scope)?;
@@ -205,24 +254,15 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
             match *o {
                 Op::Add | Op::Div | Op::Max | Op::MaxWrap | Op::Min | Op::Mul | Op::Sub => {
                     // left and right should have type num
-                    match left.get_type() {
-                        Ok(Type::Num(_)) => (),
-                        x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
-                    }
-                    match right.get_type() {
-                        Ok(Type::Num(_)) => (),
-                        x => {
-                            return Err(Error::from(format!(
-                                "{:?} expected Num, got {:?}: {:?}",
-                                o, x, scope
-                            )));
-                        }
-                    }
+                    let (op, res) = if check_num_operands(*o, &left, &right)? {
+                        (signed_op(*o)?, scope.new_tmp(Type::Signed(None)))
+                    } else {
+                        (*o, scope.new_tmp(Type::Num(None)))
+                    };
 
-                    let res = scope.new_tmp(Type::Num(None));
                     instrs.push(Instr {
                         res: res.clone(),
-                        op: *o,
+                        op,
                         left,
                         right,
                     });
@@ -233,11 +273,15 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                     // left and right should have type num
                     match left.get_type() {
                         Ok(Type::Bool(_)) => (),
-                        x => return Err(Error::from(format!("{:?} expected Bool, got {:?}", o, x))),
+                        x => {
+                            return Err(Error::from(format!("{:?} expected Bool, got {:?}", o, x)))
+                        }
                     }
                     match right.get_type() {
                         Ok(Type::Bool(_)) => (),
-                        x => return Err(Error::from(format!("{:?} expected Bool, got {:?}", o, x))),
+                        x => {
+                            return Err(Error::from(format!("{:?} expected Bool, got {:?}", o, x)))
+                        }
                     }
 
                     let res = scope.new_tmp(Type::Bool(None));
@@ -256,19 +300,16 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                 }
                 Op::Equiv | Op::Gt | Op::Lt => {
                     // left and right should have type num
-                    match left.get_type() {
-                        Ok(Type::Num(_)) => (),
-                        x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
-                    }
-                    match right.get_type() {
-                        Ok(Type::Num(_)) => (),
-                        x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
-                    }
+                    let op = if check_num_operands(*o, &left, &right)? {
+                        signed_op(*o)?
+                    } else {
+                        *o
+                    };
 
                     let res = scope.new_tmp(Type::Bool(None));
                     instrs.push(Instr {
                         res: res.clone(),
-                        op: *o,
+                        op,
                         left,
                         right,
                     });
@@ -328,6 +369,10 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                         ))),
                     }
                 }
+                Op::Ewma if check_num_operands(*o, &left, &right)? => Err(Error::from(format!(
+                    "{:?} does not support Signed operands",
+                    o
+                ))),
                 Op::Ewma | Op::If | Op::NotIf => {
                     // ewma: SPECIAL: reads return register
                     // (ewma a b) ret * a/10 + b * (10-a)/10.
@@ -346,7 +391,7 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, Reg::None))
                 }
-                Op::Def => unreachable!(),
+                Op::Def | Op::SDiv | Op::SGt | Op::SLt | Op::SMax | Op::SMin => unreachable!(),
             }
         }
     }
@@ -389,6 +434,19 @@ impl RegFile {
     }
 }
 
+/// A `Report` field resolved ahead of time from a `Scope`.
+/// Use it with `Report::get` to read the field without a name lookup.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReportField {
+    pub(crate) program_uid: u32,
+    pub(crate) idx: u8,
+}
+
+/// The `program_uid` of a `Scope` which no compiled program has been assigned to, such as a new
+/// one. Neither it nor 0, which `serialize::changeprog::FALLBACK_UID` uses, is ever the uid of a
+/// program.
+pub const UNASSIGNED_UID: u32 = ::std::u32::MAX;
+
 #[derive(Clone, Debug)]
 /// A mapping from variable names defined in the datapath program to their
 /// datapath register representations.
@@ -439,21 +497,16 @@ macro_rules! expand_reg {
     });
 }
 
-use std::sync::atomic::{AtomicU32, Ordering};
-static ID_COUNTER: AtomicU32 = AtomicU32::new(0);
-macro_rules! get_next_uid {
-    () => {
-        ID_COUNTER.fetch_add(1, Ordering::SeqCst) + 1
-    };
-}
-
 impl Scope {
     /// Define variables always accessible in the datapath,
     /// in the context of the most recent packet.
     /// All datapaths shall recognize these Names.
+    ///
+    /// The `program_uid` is `UNASSIGNED_UID` until `lang::compile()` assigns the uid of the
+    /// compiled program.
     pub fn new() -> Self {
         let mut sc = Scope {
-            program_uid: get_next_uid!(),
+            program_uid: UNASSIGNED_UID,
             named: RegFile::new(),
             num_control: 0,
             num_local: 0,
@@ -507,6 +560,33 @@ impl Scope {
         self.named.get(name)
     }
 
+    /// Resolve the `Report` variable `name` to a handle for `Report::get`.
+    /// Returns `None` if `name` is not a `Report` variable in this scope.
+    pub fn report_field(&self, name: &str) -> Option<ReportField> {
+        match self.named.get(name) {
+            Some(&Reg::Report(idx, _, _)) => Some(ReportField {
+                program_uid: self.program_uid,
+                idx,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the `Report` variables in this scope, in name order.
+    /// Yields the variable name, its field handle, and whether it is volatile.
+    pub fn report_fields<'a>(&'a self) -> impl Iterator<Item = (&'a str, ReportField, bool)> + 'a {
+        let program_uid = self.program_uid;
+        self.named
+            .0
+            .iter()
+            .filter_map(move |&(ref name, ref reg)| match *reg {
+                Reg::Report(idx, _, is_volatile) => {
+                    Some((name.as_str(), ReportField { program_uid, idx }, is_volatile))
+                }
+                _ => None,
+            })
+    }
+
     pub(crate) fn new_tmp(&mut self, t: Type) -> Reg {
         let id = self.tmp.len() as u8;
         let r = Reg::Tmp(id, t);
@@ -594,6 +674,15 @@ impl Iterator for ScopeDefInstrIter {
                         right: Reg::ImmNum(n),
                     });
                 }
+                Reg::Report(_, Type::Signed(Some(n)), _)
+                | Reg::Control(_, Type::Signed(Some(n))) => {
+                    return Some(Instr {
+                        res: reg.clone(),
+                        op: Op::Def,
+                        left: reg.clone(),
+                        right: Reg::ImmSigned(n),
+                    });
+                }
                 Reg::Report(_, Type::Bool(Some(b)), _) | Reg::Control(_, Type::Bool(Some(b))) => {
                     return Some(Instr {
                         res: reg.clone(),
@@ -1417,4 +1506,77 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn signed_ops() {
+        let foo = b"
+        (def (Report (signed grad 0) (prev 0)))
+        (when true
+            (:= Report.grad (- Flow.rtt_sample_us Report.prev))
+            (:= Report.prev Flow.rtt_sample_us)
+        )
+        (when (< Report.grad -100)
+            (:= Report.grad (max Report.grad -1000))
+            (report)
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let grad_reg = sc.get("Report.grad").unwrap().clone();
+        assert_eq!(grad_reg, Reg::Report(0, Type::Signed(Some(0)), false));
+
+        // the unsigned subtraction wraps and is reinterpreted as signed
+        assert!(b.instrs.contains(&Instr {
+            res: Reg::Tmp(0, Type::Num(None)),
+            op: Op::Sub,
+            left: sc.get("Flow.rtt_sample_us").unwrap().clone(),
+            right: sc.get("Report.prev").unwrap().clone(),
+        }));
+        assert!(b.instrs.contains(&Instr {
+            res: sc.get("__eventFlag").unwrap().clone(),
+            op: Op::SLt,
+            left: grad_reg.clone(),
+            right: Reg::ImmSigned(-100),
+        }));
+        assert!(b.instrs.contains(&Instr {
+            res: Reg::Tmp(0, Type::Signed(None)),
+            op: Op::SMax,
+            left: grad_reg.clone(),
+            right: Reg::ImmSigned(-1000),
+        }));
+    }
+
+    #[test]
+    fn signed_type_errors() {
+        // +infinity does not fit in a signed comparison
+        let foo = b"
+        (def (Report (signed grad 0)))
+        (when (< Report.grad +infinity)
+            (report)
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+
+        // wrapped_max is only defined for unsigned values
+        let foo = b"
+        (def (Report (signed grad 0)))
+        (when true
+            (:= Report.grad (wrapped_max Report.grad -1))
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+
+        // signed values are not booleans
+        let foo = b"
+        (def (Report (signed grad 0)))
+        (when (&& Report.grad true)
+            (report)
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+    }
 }
@@ -0,0 +1,228 @@
+//! Print a compiled datapath program in a readable form.
+//!
+//! `disassemble()` prints one instruction per line, as `res = op left right`, grouped into the
+//! variable definitions and then each event's flag and body instructions. Registers are named
+//! using the program's `Scope`; temporaries are written `t0`, `t1`, ..., and immediates as their
+//! values.
+//!
+//! ### Example
+//!
+//! ```no-run
+//! def:
+//!     0: Report.acked = def Report.acked 0
+//! event 0: flag 1..2, body 2..5
+//!     1: __eventFlag = bind __eventFlag true
+//!     2: t0 = add Report.acked Ack.bytes_acked
+//!     3: Report.acked = bind Report.acked t0
+//!     4: __shouldReport = bind __shouldReport true
+//! ```
+//!
+//! Programs decoded from install messages (see `serialize::install::disassemble()`) do not carry
+//! types, so boolean and signed immediates are printed as their raw unsigned bits.
+
+use super::ast::Op;
+use super::datapath::{Bin, Instr, Reg, Scope};
+
+fn mnemonic(o: Op) -> &'static str {
+    match o {
+        Op::Add => "add",
+        Op::And => "and",
+        Op::Bind => "bind",
+        Op::Def => "def",
+        Op::Div => "div",
+        Op::Equiv => "eq",
+        Op::Ewma => "ewma",
+        Op::Gt => "gt",
+        Op::If => "if",
+        Op::Lt => "lt",
+        Op::Max => "max",
+        Op::MaxWrap => "wrapped_max",
+        Op::Min => "min",
+        Op::Mul => "mul",
+        Op::NotIf => "!if",
+        Op::Or => "or",
+        Op::Sub => "sub",
+        Op::SDiv => "sdiv",
+        Op::SGt => "sgt",
+        Op::SLt => "slt",
+        Op::SMax => "smax",
+        Op::SMin => "smin",
+    }
+}
+
+// The register class and index which identify a named register in the datapath.
+// Types and volatility are not part of a register's identity.
+fn slot(r: &Reg) -> Option<(&'static str, u8)> {
+    match *r {
+        Reg::Control(i, _) => Some(("Control", i)),
+        Reg::Implicit(i, _) => Some(("Implicit", i)),
+        Reg::Local(i, _) => Some(("Local", i)),
+        Reg::Primitive(i, _) => Some(("Primitive", i)),
+        Reg::Report(i, _, _) => Some(("Report", i)),
+        _ => None,
+    }
+}
+
+fn reg_name(r: &Reg, sc: &Scope) -> String {
+    match *r {
+        Reg::ImmBool(b) => b.to_string(),
+        Reg::ImmNum(n) => n.to_string(),
+        Reg::ImmSigned(n) => n.to_string(),
+        Reg::Tmp(i, _) => format!("t{}", i),
+        Reg::None => String::from("_"),
+        _ => {
+            let s = slot(r);
+            sc.named
+                .0
+                .iter()
+                .find(|&&(_, ref named)| slot(named) == s)
+                .map(|&(ref name, _)| name.clone())
+                .unwrap_or_else(|| {
+                    // a register the scope does not know about, e.g. from another program
+                    let (class, i) = s.unwrap();
+                    format!("{}[{}]", class, i)
+                })
+        }
+    }
+}
+
+fn instr_line(idx: usize, i: &Instr, sc: &Scope) -> String {
+    format!(
+        "    {}: {} = {} {} {}\n",
+        idx,
+        reg_name(&i.res, sc),
+        mnemonic(i.op),
+        reg_name(&i.left, sc),
+        reg_name(&i.right, sc),
+    )
+}
+
+/// Disassemble `bin`, naming its registers with `sc`, the `Scope` it was compiled with.
+///
+/// Without a `Scope`, only the primitive and implicit registers, which are the same for every
+/// program, have names; other registers are written like `Report[2]`.
+pub fn disassemble(bin: &Bin, sc: Option<&Scope>) -> String {
+    let default_scope;
+    let sc = match sc {
+        Some(sc) => sc,
+        None => {
+            default_scope = Scope::new();
+            &default_scope
+        }
+    };
+
+    let ranges: Vec<_> = bin
+        .events
+        .iter()
+        .map(|ev| {
+            let flag = ev.flag_idx as usize..(ev.flag_idx + ev.num_flag_instrs) as usize;
+            let body = ev.body_idx as usize..(ev.body_idx + ev.num_body_instrs) as usize;
+            (flag, body)
+        })
+        .collect();
+
+    let mut out = String::new();
+
+    // instructions outside of any event run once, when the program is installed
+    let defs: Vec<_> = (0..bin.instrs.len())
+        .filter(|i| {
+            !ranges
+                .iter()
+                .any(|&(ref flag, ref body)| flag.contains(i) || body.contains(i))
+        })
+        .collect();
+    if !defs.is_empty() {
+        out.push_str("def:\n");
+        for i in defs {
+            out.push_str(&instr_line(i, &bin.instrs[i], sc));
+        }
+    }
+
+    for (n, (flag, body)) in ranges.into_iter().enumerate() {
+        out.push_str(&format!(
+            "event {}: flag {}..{}, body {}..{}\n",
+            n, flag.start, flag.end, body.start, body.end
+        ));
+        for i in flag.chain(body) {
+            match bin.instrs.get(i) {
+                Some(instr) => out.push_str(&instr_line(i, instr, sc)),
+                None => out.push_str(&format!("    {}: <missing>\n", i)),
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::{Bin, Prog};
+
+    const SRC: &[u8] = b"
+        (def (Report (volatile acked 0) (signed grad 0)) (Control.thresh 10))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.grad (- Flow.rtt_sample_us -3))
+            (fallthrough)
+        )
+        (when (> Report.acked Control.thresh)
+            (report)
+        )
+    ";
+
+    #[test]
+    fn disasm() {
+        let (p, mut sc) = Prog::new_with_scope(SRC).unwrap();
+        let bin = Bin::compile_prog(&p, &mut sc).unwrap();
+        let out = super::disassemble(&bin, Some(&sc));
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "def:");
+        assert!(lines.contains(&"    0: Control.thresh = def Control.thresh 10"));
+        assert!(out.contains("= add Report.acked Ack.bytes_acked\n"));
+        assert!(out.contains("Report.acked = bind Report.acked t0\n"));
+        assert!(out.contains("= sub Flow.rtt_sample_us -3\n"));
+        assert!(out.contains("__shouldContinue = bind __shouldContinue true\n"));
+        assert!(out.contains("= gt Report.acked Control.thresh\n"));
+        assert!(out.contains("__shouldReport = bind __shouldReport true\n"));
+
+        for (n, ev) in bin.events.iter().enumerate() {
+            let header = format!(
+                "event {}: flag {}..{}, body {}..{}",
+                n,
+                ev.flag_idx,
+                ev.flag_idx + ev.num_flag_instrs,
+                ev.body_idx,
+                ev.body_idx + ev.num_body_instrs
+            );
+            assert!(lines.contains(&header.as_str()), "{}", header);
+        }
+
+        // every instruction is printed exactly once
+        assert_eq!(
+            lines.iter().filter(|l| l.starts_with("    ")).count(),
+            bin.instrs.len()
+        );
+    }
+
+    #[test]
+    fn disasm_serialized() {
+        let (p, mut sc) = Prog::new_with_scope(SRC).unwrap();
+        let bin = Bin::compile_prog(&p, &mut sc).unwrap();
+        let buf = bin.serialize().unwrap();
+        let decoded =
+            Bin::deserialize(&buf, bin.events.len() as u32, bin.instrs.len() as u32).unwrap();
+
+        let out = super::disassemble(&decoded, Some(&sc));
+        assert!(out.contains("Report.acked = bind Report.acked t0\n"));
+        assert!(out.contains("__shouldReport = bind __shouldReport 1\n"));
+        // -3, as unsigned bits
+        assert!(out.contains("= sub Flow.rtt_sample_us 18446744073709551613\n"));
+
+        // without the scope, program variables have no names
+        let out = super::disassemble(&decoded, None);
+        assert!(out.contains("Report[0] = bind Report[0] t0\n"));
+        assert!(out.contains("= gt Report[0] Control[0]\n"));
+        assert!(out.contains("__shouldReport = bind __shouldReport 1\n"));
+    }
+}
@@ -70,7 +70,6 @@
 //!         )
 //!         (when (> Micros 1000)
 //!             (report)
-//!             (reset)
 //!         )
 //!     ";
 //!     let (bin, scope) = lang::compile(my_cool_program, &[]).unwrap();
@@ -99,6 +98,139 @@
 //! "Flow.rate_outgoing"    | Outgoing rate
 //! "Flow.rtt_sample_us"    | Round-trip time
 //! "Flow.was_timeout"      | Did a timeout occur?
+//!
+//! Compiling at Build Time
+//! -----------------------
+//!
+//! `portus::program!` compiles a program literal while the crate using it is built, so errors in
+//! the program are reported by `cargo build`, pointing into the literal. It produces a
+//! `Precompiled`, which holds the compiled `Bin` and `Scope` and can be installed without
+//! compiling the program again (see `CongAlg::precompiled_programs`). Since the program is
+//! compiled before `main` runs, params always take their default values.
+//!
+//! ### Example
+//! ```
+//! #[macro_use]
+//! extern crate portus;
+//!
+//! fn main() {
+//!     let prog = program!("
+//!         (def (Report (volatile acked 0)))
+//!         (when true
+//!             (:= Report.acked (+ Report.acked Ack.bytes_acked))
+//!         )
+//!     ");
+//!     let (bin, scope) = prog.load().unwrap();
+//! }
+//! ```
+//!
+//! Persisting Compiled Programs
+//! ----------------------------
+//!
+//! A `Compiled` program holds a `Bin`, its `Scope`, and a hash of the source it was compiled
+//! from, and can be saved to and loaded from disk in a stable format. A loaded program keeps the
+//! `program_uid` it was compiled with. If `Config::program_cache` is set, the runtime uses this
+//! to reinstall the same programs, with the same uids, when CCP restarts.
+//!
+//! Program uids are derived from a hash of the compiled program, so recompiling an unchanged
+//! program also gives it the uid it had before; the cache only saves the work of compiling it.
+//!
+//! Formatting
+//! ----------
+//!
+//! `Expr`s and `Prog`s implement `Display`, which prints them as canonical s-expressions. Since a
+//! `Prog` has already had its macros expanded and its syntactic sugar removed, use
+//! `lang::format()` to reformat program source as written. Comments are not preserved, so the
+//! `portus fmt` and `dump_fold --fmt` tools, which reformat program files in place, leave files
+//! with comments (see `lang::has_comments()`) as they are.
+//!
+//! To see what was actually installed in the datapath, `lang::disassemble()` prints a compiled
+//! `Bin` with its registers named by the program's `Scope`, and
+//! `serialize::install::disassemble()` does the same for a serialized install message.
+//!
+//! Macros
+//! ------
+//!
+//! The `defmacro` keyword defines a macro, which can be used to share fold function fragments
+//! between programs. A macro has a name, a list of parameters, and one or more body expressions.
+//! Macros can be defined before the `def` clause, so a library of macros can be prepended to a
+//! program, or between events. A call `(name arg...)` is replaced by the macro body, with each
+//! argument expression substituted for the corresponding parameter. A call in an event body may
+//! expand to several expressions; elsewhere, the macro body must be a single expression. Macros
+//! may call other macros, but not recursively.
+//!
+//! ### Example
+//! ```no-run
+//! (defmacro track_min (var sample)
+//!     (:= var (min var sample))
+//! )
+//! (defmacro accumulate (var sample)
+//!     (:= var (+ var sample))
+//! )
+//! (def (Report (minrtt +infinity) (volatile acked 0)))
+//! (when true
+//!     (track_min Report.minrtt Flow.rtt_sample_us)
+//!     (accumulate Report.acked Ack.bytes_acked)
+//! )
+//! ```
+//!
+//! Parameters
+//! ----------
+//!
+//! The `param` keyword declares a compile-time parameter with a default value. Like macros,
+//! parameters can be declared before the `def` clause or between events. A parameter can be used
+//! anywhere a literal can, including as the initial value of a variable. `lang::compile()` sets
+//! parameters by name, so one program source can be compiled with different values.
+//!
+//! ### Example
+//! ```no-run
+//! (param interval_us 1000)
+//! (param min_acked 0)
+//! (def (Report (volatile acked min_acked)))
+//! (when true
+//!     (:= Report.acked (+ Report.acked Ack.bytes_acked))
+//!     (fallthrough)
+//! )
+//! (when (> Micros interval_us)
+//!     (report)
+//! )
+//! ```
+//!
+//! Signed Values
+//! -------------
+//!
+//! Values are unsigned 64-bit integers by default, and `-` wraps. A variable can instead be
+//! declared `signed`, in which case it holds a 64-bit two's complement integer. Negative literals
+//! such as `-5` are also signed. If either operand of an arithmetic operation or comparison is
+//! signed, the other operand is treated as signed as well, and the result of arithmetic is signed.
+//! Binding a value to a variable of the other signedness reinterprets its bits. `ewma` and
+//! `wrapped_max` do not support signed operands.
+//!
+//! Because `Report` fields are sent to CCP as `u64`s, use `Report::get_signed_field()` to read
+//! signed fields, and cast signed values to `u64` when updating them with `update_field`.
+//!
+//! ### Example
+//! ```no-run
+//! (def
+//!     (Report
+//!         (signed rtt_gradient 0)
+//!         (prev_rtt 0)
+//!     )
+//! )
+//! (when true
+//!     (:= Report.rtt_gradient (- Flow.rtt_sample_us Report.prev_rtt))
+//!     (:= Report.prev_rtt Flow.rtt_sample_us)
+//! )
+//! (when (< Report.rtt_gradient -100)
+//!     (report)
+//! )
+//! ```
+//!
+//! Typed Accessors
+//! ---------------
+//!
+//! `lang::gen_accessors()` generates typed Rust structs for a program's `Report` and `Control`
+//! variables, for use from a build script. See the [`codegen`](fn.gen_accessors.html) docs.
 
 use nom;
 use std;
@@ -150,6 +282,11 @@ impl From<std::str::Utf8Error> for Error {
         Error(format!("string err {}", e))
     }
 }
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error(format!("io err {}", e))
+    }
+}
 impl From<std::num::ParseIntError> for Error {
     fn from(e: std::num::ParseIntError) -> Error {
         Error(format!("int err {}", e))
@@ -172,45 +309,100 @@ macro_rules! named_complete {
 }
 
 mod ast;
+mod codegen;
+mod compiled;
 mod datapath;
+mod disasm;
 mod prog;
 mod serialize;
+mod uid;
 
+pub use self::ast::Op;
+pub use self::codegen::gen_accessors;
+pub use self::compiled::{source_hash, Compiled};
 pub use self::datapath::Bin;
+pub use self::datapath::Event;
+pub use self::datapath::Instr;
 pub use self::datapath::Reg;
+pub use self::datapath::ReportField;
 pub use self::datapath::Scope;
 pub use self::datapath::Type;
+pub use self::datapath::UNASSIGNED_UID;
+pub use self::disasm::disassemble;
 pub use self::prog::Prog;
+pub use self::prog::{format, has_comments};
+pub(crate) use self::serialize::{EVENT_SIZE, INSTR_SIZE, REG_SIZE};
 
 /// `compile()` uses 5 passes to yield Instrs.
 ///
-/// 1. `Expr::new()` (called by `Prog::new_with_scope()` internally) returns a single AST from
+/// 1. `Expr::new()` (called by `Prog::new_with_params()` internally) returns a single AST from
 ///    `src`
-/// 2. `Prog::new_with_scope()` returns a list of ASTs for multiple expressions
-/// 3. The ASTs are desugared to support (report) and (fallthrough).
-/// 4. The list of runtime updates (from `updates`) for values is applied to the Scope.
+/// 2. `Prog::new_with_params()` returns a list of ASTs for multiple expressions, with macros expanded
+/// 3. The values of params (from `updates`, or their defaults) are substituted into the ASTs.
+/// 4. The ASTs are desugared to support (report) and (fallthrough).
 /// 5. `Bin::compile_prog()` turns a `Prog` into a `Bin`, which is a `Vec` of datapath `Instr`
-pub fn compile(src: &[u8], updates: &[(&str, u32)]) -> Result<(Bin, Scope)> {
-    Prog::new_with_scope(src).and_then(|(p, mut s)| {
-        for &(name, new_val) in updates {
-            match s.update_type(name, &Type::Num(Some(new_val as u64))) {
-                Ok(_) => {}
-                Err(e) => println!("err: {}", e),
-            }
-        }
-
-        Ok((Bin::compile_prog(&p, &mut s)?, s))
-    })
+///
+/// Each name in `updates` must be a param declared in `src`, and its value must fit the type of
+/// the param's default value.
+///
+/// The program's `program_uid` is derived from a hash of the resulting `Bin` and `Scope`, so
+/// compiling the same program gives the same uid, in this process or any other.
+pub fn compile(src: &[u8], updates: &[(&str, u64)]) -> Result<(Bin, Scope)> {
+    let (p, mut s) = Prog::new_with_params(src, updates)?;
+    let bin = Bin::compile_prog(&p, &mut s)?;
+    s.program_uid = uid::program_uid(uid::fingerprint(&bin.serialize()?, &s.serialize()?));
+    Ok((bin, s))
 }
 
 /// `compile_and_serialize()` adds a fourth pass.
 /// The resulting bytes can be passed to the datapath.
 ///
 /// `serialize::serialize()` serializes a `Bin` into bytes.
-pub fn compile_and_serialize(src: &[u8], updates: &[(&str, u32)]) -> Result<(Vec<u8>, Scope)> {
+pub fn compile_and_serialize(src: &[u8], updates: &[(&str, u64)]) -> Result<(Vec<u8>, Scope)> {
     compile(src, updates).and_then(|(b, s)| Ok((b.serialize()?, s)))
 }
 
+/// A datapath program compiled while building the crate which uses it, by `portus::program!`.
+///
+/// It holds the serialized `Bin` and `Scope` of the program, so it can be installed without
+/// compiling the program again. The fields are filled in by the macro; use the methods instead.
+#[derive(Clone, Copy, Debug)]
+pub struct Precompiled {
+    #[doc(hidden)]
+    pub source: &'static str,
+    #[doc(hidden)]
+    pub bin: &'static [u8],
+    #[doc(hidden)]
+    pub num_events: u32,
+    #[doc(hidden)]
+    pub num_instrs: u32,
+    #[doc(hidden)]
+    pub scope: &'static [u8],
+}
+
+impl Precompiled {
+    /// The program source, as written.
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    /// Read back the compiled program.
+    /// See `Bin::deserialize()` for what is lost in serialization.
+    /// The program has the same `program_uid` as if it were compiled with `lang::compile()`.
+    pub fn load(&self) -> Result<(Bin, Scope)> {
+        let bin = Bin::deserialize(self.bin, self.num_events, self.num_instrs)?;
+        let mut scope = Scope::deserialize(self.scope)?;
+        scope.program_uid = uid::program_uid(uid::fingerprint(self.bin, self.scope));
+        Ok((bin, scope))
+    }
+}
+
+impl From<Precompiled> for String {
+    fn from(p: Precompiled) -> String {
+        String::from(p.source)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
@@ -1,6 +1,7 @@
 use nom;
+use std::fmt;
 
-use super::ast::{atom, comment, expr, exprs, name, Expr};
+use super::ast::{atom, comment, expr, exprs, name, Expr, Prim, RESERVED};
 use super::datapath::{check_atom_type, Scope, Type};
 use super::{Error, Result};
 
@@ -19,26 +20,169 @@ pub struct Event {
 #[derive(Debug, PartialEq)]
 pub struct Prog(pub Vec<Event>);
 
+/// A macro defined with `(defmacro name (args...) body...)`.
+/// Calls to it are replaced by its body, with the arguments substituted for its parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub args: Vec<String>,
+    pub body: Vec<Expr>,
+}
+
+// Upper bound on nested macro expansion, which rejects recursive macros.
+const MAX_MACRO_DEPTH: usize = 16;
+
+impl Macro {
+    fn apply(&self, args: &[Expr]) -> Result<Vec<Expr>> {
+        if args.len() != self.args.len() {
+            return Err(Error::from(format!(
+                "macro {:?} takes {} arguments, got {}",
+                self.name,
+                self.args.len(),
+                args.len()
+            )));
+        }
+
+        Ok(self
+            .body
+            .iter()
+            .map(|e| substitute(e, &self.args, args))
+            .collect())
+    }
+}
+
+fn substitute(e: &Expr, params: &[String], args: &[Expr]) -> Expr {
+    match *e {
+        Expr::Atom(Prim::Name(ref n)) => params
+            .iter()
+            .position(|p| p == n)
+            .map(|i| args[i].clone())
+            .unwrap_or_else(|| e.clone()),
+        Expr::Call(ref n, ref a) => Expr::Call(
+            n.clone(),
+            a.iter().map(|x| substitute(x, params, args)).collect(),
+        ),
+        Expr::Sexp(o, box ref l, box ref r) => Expr::Sexp(
+            o,
+            Box::new(substitute(l, params, args)),
+            Box::new(substitute(r, params, args)),
+        ),
+        _ => e.clone(),
+    }
+}
+
+// Expand the macro calls in `e`.
+// A call expands to the whole macro body, so the result may be several expressions.
+fn expand(e: &Expr, macros: &[Macro], depth: usize) -> Result<Vec<Expr>> {
+    match *e {
+        Expr::Call(ref name, ref args) => {
+            if depth >= MAX_MACRO_DEPTH {
+                return Err(Error::from(format!(
+                    "macro expansion too deep, is {:?} recursive?",
+                    name
+                )));
+            }
+
+            let m = macros
+                .iter()
+                .find(|m| m.name == *name)
+                .ok_or_else(|| Error::from(format!("unknown macro: {:?}", name)))?;
+            let mut expanded = vec![];
+            for body_expr in m.apply(args)? {
+                expanded.extend(expand(&body_expr, macros, depth + 1)?);
+            }
+
+            Ok(expanded)
+        }
+        Expr::Sexp(o, box ref l, box ref r) => Ok(vec![Expr::Sexp(
+            o,
+            Box::new(expand_single(l, macros, depth)?),
+            Box::new(expand_single(r, macros, depth)?),
+        )]),
+        _ => Ok(vec![e.clone()]),
+    }
+}
+
+// Expand the macro calls in `e`, which must result in a single expression.
+fn expand_single(e: &Expr, macros: &[Macro], depth: usize) -> Result<Expr> {
+    let mut expanded = expand(e, macros, depth)?;
+    if expanded.len() != 1 {
+        return Err(Error::from(format!(
+            "expected a single expression, but {:?} expands to {}",
+            e,
+            expanded.len()
+        )));
+    }
+
+    Ok(expanded.pop().unwrap())
+}
+
+impl Event {
+    fn expand(self, macros: &[Macro]) -> Result<Self> {
+        let flag = expand_single(&self.flag, macros, 0)?;
+        let mut body = vec![];
+        for e in &self.body {
+            body.extend(expand(e, macros, 0)?);
+        }
+
+        Ok(Event { flag, body })
+    }
+}
+
 // ------------------------------------------
 // (def (decl)...) grammar
 // ------------------------------------------
 
+named_complete!(
+    signed_kw<bool>,
+    map!(
+        opt!(terminated!(tag!("signed"), nom::multispace)),
+        |v: Option<nom::types::CompleteByteSlice>| v.is_some()
+    )
+);
+
+// A variable declared "signed" holds an i64. Variables with a negative initial value are signed.
+fn signed_decl(
+    (is_volatile, is_signed, name, init): (bool, bool, Type, Type),
+) -> Result<(bool, Type, Type)> {
+    if !is_signed {
+        return Ok((is_volatile, name, init));
+    }
+
+    let init = match init {
+        Type::Num(Some(n)) if n == u64::max_value() => Type::Signed(Some(i64::max_value())),
+        Type::Num(Some(n)) if n <= i64::max_value() as u64 => Type::Signed(Some(n as i64)),
+        x @ Type::Signed(_) => x,
+        x => {
+            return Err(Error::from(format!(
+                "invalid initial value for signed {:?}: {:?}",
+                name, x
+            )))
+        }
+    };
+
+    Ok((is_volatile, name, init))
+}
+
 // Declare a state variable and provide an initial value
 // Optionally declare the variable "volatile", meaning it gets reset on "(report)"
+// Optionally declare the variable "signed", meaning it holds a signed integer
 named_complete!(
-    decl<(bool, Type, Type)>,
+    decl_parts<(bool, bool, Type, Type)>,
     ws!(delimited!(
         tag!("("),
         tuple!(
             map!(opt!(tag!("volatile")), |v: Option<
                 nom::types::CompleteByteSlice,
             >| v.is_some()),
+            signed_kw,
             map!(name, Type::Name),
             map_res!(atom, |a: Result<Expr>| a.and_then(|i| check_atom_type(&i)))
         ),
         tag!(")")
     ))
 );
+named_complete!(decl<(bool, Type, Type)>, map_res!(decl_parts, signed_decl));
 named_complete!(
     report_struct<Vec<(bool, Type, Type)>>,
     ws!(delimited!(
@@ -67,7 +211,10 @@ named_complete!(
                         _ => None,
                     }
                     .map(|full_name| match init_val {
-                        x @ Type::Num(_) | x @ Type::Bool(_) => (is_volatile, full_name, x),
+                        x @ Type::Num(_)
+                        | x @ Type::Signed(_)
+                        | x @ Type::Bool(_)
+                        | x @ Type::Name(_) => (is_volatile, full_name, x),
                         _ => (is_volatile, full_name, Type::None),
                     }))
                     .chain(
@@ -75,7 +222,10 @@ named_complete!(
                             .into_iter()
                             .chain(defs2)
                             .map(|(is_volatile, name, init_val)| match init_val {
-                                x @ Type::Num(_) | x @ Type::Bool(_) => (is_volatile, name, x),
+                                x @ Type::Num(_)
+                                | x @ Type::Signed(_)
+                                | x @ Type::Bool(_)
+                                | x @ Type::Name(_) => (is_volatile, name, x),
                                 _ => (is_volatile, name, Type::None),
                             })
                     )
@@ -109,56 +259,311 @@ named_complete!(
         tag!(")")
     ))
 );
+
+// ------------------------------------------
+// (defmacro name (args...) (body)...) grammar
+// ------------------------------------------
+
+named_complete!(
+    defmacro<Result<Macro>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("defmacro")
+                >> n: name
+                >> args: delimited!(tag!("("), many0!(name), tag!(")"))
+                >> body: exprs
+                >> (body
+                    .into_iter()
+                    .filter(|e| match e {
+                        Ok(Expr::None) => false,
+                        _ => true,
+                    })
+                    .collect::<Result<Vec<Expr>>>()
+                    .and_then(|body| {
+                        if RESERVED.contains(&n.as_str()) {
+                            Err(Error::from(format!("reserved word: {:?}", n)))
+                        } else {
+                            Ok(Macro {
+                                name: n,
+                                args,
+                                body,
+                            })
+                        }
+                    }))
+        ),
+        tag!(")")
+    ))
+);
+// ------------------------------------------
+// (param name default) grammar
+// ------------------------------------------
+
+// Declare a compile-time parameter and its default value.
 named_complete!(
-    events<Vec<Result<Event>>>,
-    many1!(do_parse!(opt!(comment) >> e: event >> (e)))
+    param<Result<(String, Type)>>,
+    ws!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("param")
+                >> n: name
+                >> v: atom
+                >> (v.and_then(|a| check_atom_type(&a)).and_then(|t| match t {
+                    Type::Name(_) => Err(Error::from(format!(
+                        "param {:?}: default value must be a literal",
+                        n
+                    ))),
+                    t => Ok((n, t)),
+                }))
+        ),
+        tag!(")")
+    ))
 );
 
+// Macro and parameter definitions may come before the (def ...) clause,
+// and may be interleaved with events after it.
+enum Item {
+    Def(Vec<(bool, Type, Type)>),
+    Event(Event),
+    Macro(Macro),
+    Param(String, Type),
+}
+
+named_complete!(
+    prelude<Vec<Result<Item>>>,
+    many0!(do_parse!(
+        opt!(comment)
+            >> i: alt!(
+                defmacro => { |m: Result<Macro>| m.map(Item::Macro) } |
+                param => { |p: Result<(String, Type)>| p.map(|(n, t)| Item::Param(n, t)) }
+            )
+            >> (i)
+    ))
+);
+
+named_complete!(
+    items<Vec<Result<Item>>>,
+    many1!(do_parse!(
+        opt!(comment)
+            >> i: alt!(
+                event => { |e: Result<Event>| e.map(Item::Event) } |
+                defmacro => { |m: Result<Macro>| m.map(Item::Macro) } |
+                param => { |p: Result<(String, Type)>| p.map(|(n, t)| Item::Param(n, t)) }
+            )
+            >> (i)
+    ))
+);
+
+// The value of parameter `name`, declared with default value `default`, set to `val`.
+fn param_value(name: &str, default: &Type, val: u64) -> Result<Type> {
+    match *default {
+        Type::Num(_) => Ok(Type::Num(Some(val))),
+        Type::Signed(_) if val <= i64::max_value() as u64 => Ok(Type::Signed(Some(val as i64))),
+        Type::Bool(_) if val <= 1 => Ok(Type::Bool(Some(val == 1))),
+        _ => Err(Error::from(format!(
+            "invalid value for param {:?} of type {:?}: {}",
+            name, default, val
+        ))),
+    }
+}
+
+fn param_expr(t: &Type) -> Expr {
+    match *t {
+        Type::Bool(Some(b)) => Expr::Atom(Prim::Bool(b)),
+        Type::Num(Some(n)) => Expr::Atom(Prim::Num(n)),
+        Type::Signed(Some(n)) => Expr::Atom(Prim::Signed(n)),
+        _ => unreachable!(),
+    }
+}
+
+// The 1-based line and column of byte `offset` in `source`.
+fn line_col(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    (line, offset - line_start + 1)
+}
+
+// Describe a parse error by where in `source` it happened. The input left when a parser fails
+// is always a suffix of `source`.
+fn parse_error(source: &[u8], e: nom::Err<nom::types::CompleteByteSlice, u32>) -> Error {
+    use nom::Needed;
+    match e {
+        nom::Err::Error(nom::Context::Code(rest, kind))
+        | nom::Err::Failure(nom::Context::Code(rest, kind)) => {
+            let (line, col) = line_col(source, source.len() - rest.len());
+            Error::from(format!(
+                "syntax error ({}) at line {}, column {}",
+                kind.description(),
+                line,
+                col
+            ))
+        }
+        nom::Err::Incomplete(Needed::Unknown) => Error::from("need more src"),
+        nom::Err::Incomplete(Needed::Size(s)) => Error::from(format!("need {} more bytes", s)),
+    }
+}
+
+// Whitespace and comments may follow the last item, but nothing else.
+fn check_trailing(source: &[u8], rest: &[u8]) -> Result<()> {
+    let mut i = 0;
+    while i < rest.len() {
+        match rest[i] {
+            b'#' => {
+                i = rest[i..]
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map(|n| i + n)
+                    .unwrap_or_else(|| rest.len())
+            }
+            b if (b as char).is_whitespace() => i += 1,
+            _ => {
+                let (line, col) = line_col(source, source.len() - rest.len() + i);
+                return Err(Error::from(format!(
+                    "unexpected input at line {}, column {}",
+                    line, col
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Parse the top-level forms of a program, in order.
+fn parse_items(source: &[u8]) -> Result<Vec<Item>> {
+    use nom::types::CompleteByteSlice;
+    let (rest, mut decls) = match prelude(CompleteByteSlice(source)) {
+        Ok((rest, ds)) => (rest, ds.into_iter().collect::<Result<Vec<Item>>>()?),
+        Err(e) => return Err(parse_error(source, e)),
+    };
+
+    let rest = match defs(rest) {
+        Ok((rest, flow_state)) => {
+            decls.push(Item::Def(flow_state));
+            rest
+        }
+        Err(e) => return Err(parse_error(source, e)),
+    };
+
+    let (rest, items) = match items(rest) {
+        Ok((rest, me)) => (rest, me.into_iter().collect::<Result<Vec<Item>>>()?),
+        Err(e) => return Err(parse_error(source, e)),
+    };
+
+    check_trailing(source, &rest)?;
+    decls.extend(items);
+    Ok(decls)
+}
+
 impl Prog {
-    /// Turn raw bytes into an AST representation, including implementing syntactic sugar features
-    /// such as `(report)` and `(fallthrough)`.
+    /// Turn raw bytes into an AST representation, including expanding macros and implementing
+    /// syntactic sugar features such as `(report)` and `(fallthrough)`.
+    /// Parameters take their default values.
     pub fn new_with_scope(source: &[u8]) -> Result<(Self, Scope)> {
+        Prog::new_with_params(source, &[])
+    }
+
+    /// Like `new_with_scope`, but set the values of the parameters named in `params`.
+    /// Every name in `params` must be declared with `(param name default)` in `source`.
+    pub fn new_with_params(source: &[u8], params: &[(&str, u64)]) -> Result<(Self, Scope)> {
         let mut scope = Scope::new();
-        use nom::types::CompleteByteSlice;
-        use nom::Needed;
-        let body = match defs(CompleteByteSlice(source)) {
-            Ok((rest, flow_state)) => {
-                let (reports, controls): (Vec<(bool, String, Type)>, Vec<(bool, String, Type)>) =
-                    flow_state
-                        .into_iter()
-                        .map(|(is_volatile, var, typ)| match var {
-                            Type::Name(v) => (is_volatile, v, typ),
-                            _ => unreachable!(),
-                        })
-                        .partition(|&(_, ref var, _)| var.starts_with("Report."));
-
-                for (is_volatile, var, typ) in reports {
-                    scope.new_report(is_volatile, var, typ);
-                }
-
-                for (_is_volatile, var, typ) in controls {
-                    scope.new_control(var, typ);
-                }
-
-                Ok(rest)
+        let mut flow_state = vec![];
+        let mut evs = vec![];
+        let mut ms = vec![];
+        let mut ps: Vec<(String, Type)> = vec![];
+        for item in parse_items(source)? {
+            match item {
+                Item::Def(d) => flow_state = d,
+                Item::Event(ev) => evs.push(ev),
+                Item::Macro(m) => ms.push(m),
+                Item::Param(name, t) => ps.push((name, t)),
             }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
-            Err(nom::Err::Incomplete(Needed::Unknown)) => {
-                Err(Error::from(String::from("need more src")))
-            }
-            Err(nom::Err::Incomplete(Needed::Size(s))) => {
-                Err(Error::from(format!("need {} more bytes", s)))
+        }
+
+        if evs.is_empty() {
+            return Err(Error::from("no events defined"));
+        }
+
+        for (i, m) in ms.iter().enumerate() {
+            if ms[..i].iter().any(|prev| prev.name == m.name) {
+                return Err(Error::from(format!("macro {:?} defined twice", m.name)));
             }
-        }?;
-
-        let evs = match events(body) {
-            Ok((_, me)) => me.into_iter().collect(),
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
-            Err(nom::Err::Incomplete(Needed::Unknown)) => Err(Error::from("need more src")),
-            Err(nom::Err::Incomplete(Needed::Size(s))) => {
-                Err(Error::from(format!("need {} more bytes", s)))
+        }
+
+        for (i, &(ref name, _)) in ps.iter().enumerate() {
+            if ps[..i].iter().any(|&(ref prev, _)| prev == name) {
+                return Err(Error::from(format!("param {:?} defined twice", name)));
             }
-        }?;
+        }
+
+        for &(name, val) in params {
+            let p = ps
+                .iter_mut()
+                .find(|&&mut (ref p, _)| p == name)
+                .ok_or_else(|| Error::from(format!("unknown param: {:?}", name)))?;
+            p.1 = param_value(name, &p.1, val)?;
+        }
+
+        let (reports, controls): (Vec<(bool, String, Type)>, Vec<(bool, String, Type)>) =
+            flow_state
+                .into_iter()
+                .map(|(is_volatile, var, typ)| {
+                    let var = match var {
+                        Type::Name(v) => v,
+                        _ => unreachable!(),
+                    };
+
+                    // an initial value may be a param
+                    let typ = match typ {
+                        Type::Name(p) => ps
+                            .iter()
+                            .find(|&&(ref name, _)| *name == p)
+                            .map(|&(_, ref t)| t.clone())
+                            .ok_or_else(|| {
+                                Error::from(format!("{:?}: unknown initial value {:?}", var, p))
+                            })?,
+                        t => t,
+                    };
+
+                    Ok((is_volatile, var, typ))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .partition(|&(_, ref var, _)| var.starts_with("Report."));
+
+        for (is_volatile, var, typ) in reports {
+            scope.new_report(is_volatile, var, typ);
+        }
+
+        for (_is_volatile, var, typ) in controls {
+            scope.new_control(var, typ);
+        }
+
+        if let Some(&(ref name, _)) = ps.iter().find(|&&(ref name, _)| scope.has(name)) {
+            return Err(Error::from(format!("param {:?} shadows a variable", name)));
+        }
+
+        let param_names: Vec<String> = ps.iter().map(|&(ref n, _)| n.clone()).collect();
+        let param_vals: Vec<Expr> = ps.iter().map(|&(_, ref t)| param_expr(t)).collect();
+        let evs = evs
+            .into_iter()
+            .map(|ev| {
+                let ev = ev.expand(&ms)?;
+                Ok(Event {
+                    flag: substitute(&ev.flag, &param_names, &param_vals),
+                    body: ev
+                        .body
+                        .iter()
+                        .map(|e| substitute(e, &param_names, &param_vals))
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<Event>>>()?;
 
         let mut p = Prog(evs);
         p.desugar();
@@ -174,13 +579,150 @@ impl Prog {
     }
 }
 
+// ------------------------------------------
+// Printing
+// ------------------------------------------
+
+// Programs wider than this are printed over several lines.
+const MAX_WIDTH: usize = 100;
+const INDENT: &str = "    ";
+
+fn literal(t: &Type) -> String {
+    match *t {
+        Type::Bool(Some(b)) => format!("{}", b),
+        Type::Name(ref n) => n.clone(),
+        Type::Num(Some(n)) => format!("{}", Prim::Num(n)),
+        Type::Signed(Some(n)) => format!("{}", n),
+        ref t => format!("{:?}", t),
+    }
+}
+
+fn write_def(f: &mut fmt::Formatter, decls: &[(bool, Type, Type)]) -> fmt::Result {
+    let decl = |&(is_volatile, ref name, ref init): &(bool, Type, Type), strip: &str| {
+        let name = match *name {
+            Type::Name(ref n) => n.trim_start_matches(strip).to_string(),
+            ref t => format!("{:?}", t),
+        };
+        format!(
+            "({}{}{} {})",
+            if is_volatile { "volatile " } else { "" },
+            if let Type::Signed(_) = *init {
+                "signed "
+            } else {
+                ""
+            },
+            name,
+            literal(init)
+        )
+    };
+
+    let is_report = |&&(_, ref name, _): &&(bool, Type, Type)| match *name {
+        Type::Name(ref n) => n.starts_with("Report."),
+        _ => false,
+    };
+    let reports: Vec<String> = decls
+        .iter()
+        .filter(is_report)
+        .map(|d| decl(d, "Report."))
+        .collect();
+    let others: Vec<String> = decls
+        .iter()
+        .filter(|d| !is_report(d))
+        .map(|d| decl(d, ""))
+        .collect();
+
+    let mut one_line = String::from("(def");
+    if !reports.is_empty() {
+        one_line.push_str(&format!(" (Report {})", reports.join(" ")));
+    }
+    for d in &others {
+        one_line.push(' ');
+        one_line.push_str(d);
+    }
+    one_line.push(')');
+
+    if one_line.len() <= MAX_WIDTH {
+        return writeln!(f, "{}", one_line);
+    }
+
+    writeln!(f, "(def")?;
+    if !reports.is_empty() {
+        writeln!(f, "{}(Report", INDENT)?;
+        for d in &reports {
+            writeln!(f, "{}{}{}", INDENT, INDENT, d)?;
+        }
+        writeln!(f, "{})", INDENT)?;
+    }
+    for d in &others {
+        writeln!(f, "{}{}", INDENT, d)?;
+    }
+    writeln!(f, ")")
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(when {}", self.flag)?;
+        for e in self.body.iter().filter(|e| **e != Expr::None) {
+            writeln!(f, "{}{}", INDENT, e)?;
+        }
+        writeln!(f, ")")
+    }
+}
+
+/// Prints the events of the program as canonical s-expressions, after macro expansion and
+/// desugaring.
+impl fmt::Display for Prog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ev in &self.0 {
+            write!(f, "{}", ev)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "(defmacro {} ({})", self.name, self.args.join(" "))?;
+        for e in &self.body {
+            writeln!(f, "{}{}", INDENT, e)?;
+        }
+        writeln!(f, ")")
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Item::Def(ref decls) => write_def(f, decls),
+            Item::Event(ref ev) => write!(f, "{}", ev),
+            Item::Macro(ref m) => write!(f, "{}", m),
+            Item::Param(ref name, ref t) => writeln!(f, "(param {} {})", name, literal(t)),
+        }
+    }
+}
+
+/// Reformat the source of a datapath program in canonical form.
+/// Macros, params and syntactic sugar are printed as written, but comments are not preserved.
+pub fn format(source: &[u8]) -> Result<String> {
+    Ok(parse_items(source)?
+        .iter()
+        .map(|item| format!("{}", item))
+        .collect())
+}
+
+/// Whether `source` has any comments, which `format()` would drop.
+pub fn has_comments(source: &[u8]) -> bool {
+    source.contains(&b'#')
+}
+
 #[cfg(test)]
 mod tests {
     use nom;
     use nom::types::CompleteByteSlice;
 
     use lang::ast::{Expr, Op, Prim};
-    use lang::datapath::{Scope, Type};
+    use lang::datapath::{Reg, Scope, Type};
     use lang::prog::{Event, Prog};
 
     #[test]
@@ -236,6 +778,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn def_signed() {
+        let foo = b"(def (signedness 1) (Report (volatile signed Foo 0) (Bar -3) (signed Baz +infinity)))";
+        use nom::Needed;
+        match super::defs(CompleteByteSlice(foo)) {
+            Ok((r, me)) => {
+                assert_eq!(r, CompleteByteSlice(&[]));
+                assert_eq!(
+                    me,
+                    vec![
+                        (
+                            true,
+                            Type::Name(String::from("Report.Foo")),
+                            Type::Signed(Some(0))
+                        ),
+                        (
+                            false,
+                            Type::Name(String::from("Report.Bar")),
+                            Type::Signed(Some(-3))
+                        ),
+                        (
+                            false,
+                            Type::Name(String::from("Report.Baz")),
+                            Type::Signed(Some(i64::max_value()))
+                        ),
+                        (
+                            false,
+                            Type::Name(String::from("signedness")),
+                            Type::Num(Some(1))
+                        ),
+                    ]
+                );
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => panic!(e),
+            Err(nom::Err::Incomplete(Needed::Unknown)) => panic!("incomplete"),
+            Err(nom::Err::Incomplete(Needed::Size(s))) => panic!("need {} more bytes", s),
+        }
+
+        assert!(super::defs(CompleteByteSlice(b"(def (signed Foo true))")).is_err());
+    }
+
     #[test]
     fn reserved_names() {
         use nom::Needed;
@@ -336,10 +919,17 @@ mod tests {
         ";
         use lang::Result;
         use nom::Needed;
-        match super::events(CompleteByteSlice(foo)) {
+        match super::items(CompleteByteSlice(foo)) {
             Ok((r, me)) => {
                 assert_eq!(r, CompleteByteSlice(&[]));
-                let res_me: Vec<Event> = me.into_iter().collect::<Result<Vec<Event>>>().unwrap();
+                let res_me: Vec<Event> = me
+                    .into_iter()
+                    .map(|i| match i? {
+                        super::Item::Event(e) => Ok(e),
+                        _ => panic!("unexpected non-event item"),
+                    })
+                    .collect::<Result<Vec<Event>>>()
+                    .unwrap();
                 assert_eq!(
                     res_me,
                     vec![
@@ -476,4 +1066,284 @@ mod tests {
             ]),
         );
     }
+
+    #[test]
+    fn macros() {
+        let foo = b"
+            (defmacro track_min (var sample)
+                (:= var (min var sample))
+            )
+            (def (Report (minrtt +infinity) (volatile acked 0)))
+            (defmacro on_ack (rtt acked)
+                (track_min Report.minrtt rtt) # splices into the body
+                (:= Report.acked (+ Report.acked acked))
+            )
+            (defmacro elapsed (us) (> Micros us))
+            (when true
+                (on_ack Flow.rtt_sample_us Ack.bytes_acked)
+                (fallthrough)
+            )
+            (when (elapsed 1000)
+                (report)
+            )
+        ";
+        let bar = b"
+            (def (Report (minrtt +infinity) (volatile acked 0)))
+            (when true
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (fallthrough)
+            )
+            (when (> Micros 1000)
+                (report)
+            )
+        ";
+
+        let (p1, _) = Prog::new_with_scope(foo).unwrap();
+        let (p2, _) = Prog::new_with_scope(bar).unwrap();
+        assert_eq!(p1, p2);
+    }
+
+    #[test]
+    fn macro_errors() {
+        let progs: &[&[u8]] = &[
+            // unknown macro
+            b"(def (Report (foo 0))) (when true (bar Report.foo))",
+            // wrong number of arguments
+            b"(defmacro bar (x y) (:= x y)) (def (Report (foo 0))) (when true (bar Report.foo))",
+            // recursive
+            b"(defmacro bar (x) (bar x)) (def (Report (foo 0))) (when true (bar Report.foo))",
+            // defined twice
+            b"(defmacro bar (x) (:= x 1)) (defmacro bar (x) (:= x 2))
+              (def (Report (foo 0))) (when true (bar Report.foo))",
+            // several expressions where one is expected
+            b"(defmacro bar (x) (:= x 1) (:= x 2)) (def (Report (foo 0)))
+              (when true (:= Report.foo (+ 1 (bar Report.foo))))",
+            // reserved name
+            b"(defmacro min (x) (:= x 1)) (def (Report (foo 0))) (when true (report))",
+        ];
+
+        for src in progs {
+            assert!(
+                Prog::new_with_scope(src).is_err(),
+                "{}",
+                ::std::str::from_utf8(src).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn params() {
+        let foo = b"
+            (param interval 1000)
+            (param init 7)
+            (def (Report (volatile acked init)) (Control.enabled on))
+            (param on true)
+            (when (&& Control.enabled (> Micros interval))
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )
+        ";
+        let bar = b"
+            (def (Report (volatile acked 7)) (Control.enabled true))
+            (when (&& Control.enabled (> Micros 1000))
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )
+        ";
+        let baz = b"
+            (def (Report (volatile acked 7)) (Control.enabled false))
+            (when (&& Control.enabled (> Micros 20))
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )
+        ";
+
+        let (p1, s1) = Prog::new_with_scope(foo).unwrap();
+        let (p2, _) = Prog::new_with_scope(bar).unwrap();
+        assert_eq!(p1, p2);
+        assert_eq!(
+            s1.get("Report.acked").unwrap().clone(),
+            Reg::Report(0, Type::Num(Some(7)), true)
+        );
+
+        let (p3, s3) = Prog::new_with_params(foo, &[("interval", 20), ("on", 0)]).unwrap();
+        let (p4, _) = Prog::new_with_scope(baz).unwrap();
+        assert_eq!(p3, p4);
+        assert_eq!(
+            s3.get("Control.enabled").unwrap().clone(),
+            Reg::Control(0, Type::Bool(Some(false)))
+        );
+    }
+
+    #[test]
+    fn param_errors() {
+        let foo = b"
+            (param interval 1000)
+            (param on true)
+            (def (Report (acked 0)))
+            (when (> Micros interval)
+                (report)
+            )
+        ";
+
+        // unknown param
+        assert!(Prog::new_with_params(foo, &[("intervl", 20)]).is_err());
+        // not a bool
+        assert!(Prog::new_with_params(foo, &[("on", 2)]).is_err());
+        // out of range for a signed param
+        let signed = b"(param offset -3) (def (Report (x offset))) (when true (report))";
+        Prog::new_with_params(signed, &[("offset", i64::max_value() as u64)]).unwrap();
+        assert!(
+            Prog::new_with_params(signed, &[("offset", i64::max_value() as u64 + 1)])
+                .unwrap_err()
+                .0
+                .starts_with("invalid value for param \"offset\"")
+        );
+        // unknown initial value
+        assert!(Prog::new_with_scope(b"(def (Report (acked foo))) (when true (report))").is_err());
+        // a param cannot shadow a variable
+        assert!(Prog::new_with_scope(
+            b"(param Report.acked 1) (def (Report (acked 0))) (when true (report))"
+        )
+        .is_err());
+        // defined twice
+        assert!(Prog::new_with_scope(
+            b"(param a 1) (def (Report (acked 0))) (param a 2) (when true (report))"
+        )
+        .is_err());
+    }
+
+    const FMT_PROGS: &[&[u8]] = &[
+        b"(def (Report (volatile acked 0) (minrtt +infinity) (signed grad -3)) (Control.thresh 10))
+        (when true # comment
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (:= Report.grad (- Flow.rtt_sample_us Report.minrtt))
+            (fallthrough)
+        )
+        (when (|| (> Micros Control.thresh) Flow.was_timeout)
+            (report)
+        )",
+        b"(defmacro track_min (var sample) (:= var (min var sample)))
+        (param interval 1000)
+        (def (Report.foo +infinity) (bar 0))
+        (when true (track_min Report.foo Flow.rtt_sample_us) (:= bar (ewma 2 Ack.bytes_acked)) (fallthrough))
+        (defmacro elapsed () (> Micros interval))
+        (when (elapsed) (:= Cwnd (!if Flow.was_timeout 10)) (report))",
+        b"(def (Report (volatile a_very_long_variable_name 0) (another_very_long_variable_name true)
+            (yet_another_long_name 42)) (local_state_variable 0))
+        (when true (report))",
+    ];
+
+    #[test]
+    fn format_roundtrip() {
+        for src in FMT_PROGS {
+            let formatted = super::format(src).unwrap();
+            // formatting does not change the meaning of the program
+            let (mut p1, s1) = Prog::new_with_scope(src).unwrap();
+            let (p2, s2) = Prog::new_with_scope(formatted.as_bytes()).unwrap();
+            for ev in &mut p1.0 {
+                ev.body.retain(|e| *e != Expr::None);
+            }
+
+            assert_eq!(p1, p2, "{}", formatted);
+            assert_eq!(s1.named.0, s2.named.0);
+            // formatting is idempotent
+            assert_eq!(super::format(formatted.as_bytes()).unwrap(), formatted);
+        }
+
+        assert!(super::has_comments(FMT_PROGS[0]));
+        assert!(!super::has_comments(FMT_PROGS[1]));
+    }
+
+    #[test]
+    fn format_canonical() {
+        let formatted = super::format(FMT_PROGS[1]).unwrap();
+        assert_eq!(
+            formatted,
+            "(defmacro track_min (var sample)
+    (:= var (min var sample))
+)
+(param interval 1000)
+(def (Report (foo +infinity)) (bar 0))
+(when true
+    (track_min Report.foo Flow.rtt_sample_us)
+    (:= bar (ewma 2 Ack.bytes_acked))
+    (fallthrough)
+)
+(defmacro elapsed ()
+    (> Micros interval)
+)
+(when (elapsed)
+    (:= Cwnd (!if Flow.was_timeout 10))
+    (report)
+)
+"
+        );
+
+        let formatted = super::format(FMT_PROGS[2]).unwrap();
+        assert_eq!(
+            formatted,
+            "(def
+    (Report
+        (volatile a_very_long_variable_name 0)
+        (another_very_long_variable_name true)
+        (yet_another_long_name 42)
+    )
+    (local_state_variable 0)
+)
+(when true
+    (report)
+)
+"
+        );
+    }
+
+    #[test]
+    fn display_prog() {
+        let (p, _) = Prog::new_with_scope(FMT_PROGS[1]).unwrap();
+        assert_eq!(
+            format!("{}", p),
+            "(when true
+    (:= Report.foo (min Report.foo Flow.rtt_sample_us))
+    (:= bar (ewma 2 Ack.bytes_acked))
+    (fallthrough)
+)
+(when (> Micros 1000)
+    (:= Cwnd (!if Flow.was_timeout 10))
+    (report)
+)
+"
+        );
+
+        // the printed events parse back to the same program
+        let src = format!("(def (Report.foo +infinity) (bar 0))\n{}", p);
+        let (p2, _) = Prog::new_with_scope(src.as_bytes()).unwrap();
+        assert_eq!(p2, p);
+    }
+
+    #[test]
+    fn syntax_error_position() {
+        let src = b"(def (Report (acked 0)))
+(when true
+    (:= Report.acked Ack.bytes_acked)
+)
+(whne true (report))
+";
+        let e = Prog::new_with_scope(src).unwrap_err();
+        assert_eq!(e.0, "unexpected input at line 5, column 1");
+
+        let src = b"(def (Report (acked 0)) (Control.foo))
+(when true (report))
+";
+        let e = Prog::new_with_scope(src).unwrap_err();
+        assert_eq!(e.0, "syntax error (Tag) at line 1, column 25");
+
+        // trailing comments are fine
+        let src = b"(def (Report (acked 0)))
+(when true (report))
+# the end";
+        Prog::new_with_scope(src).unwrap();
+    }
 }
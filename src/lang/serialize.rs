@@ -1,18 +1,36 @@
 use super::ast::Op;
-use super::datapath::{Bin, Event, Instr, Reg};
+use super::datapath::{Bin, Event, Instr, Reg, RegFile, Scope, Type};
 use super::{Error, Result};
-use serialize::u32_to_u8s;
+use serialize::{u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s};
 
 /// Serialize a Bin to bytes for transfer to the datapath
 impl Bin {
     pub fn serialize(&self) -> Result<Vec<u8>> {
-        let b = self.clone();
-        let ists = b.instrs.into_iter().flat_map(|i| i.into_iter());
-        b.events
-            .into_iter()
-            .flat_map(|i| i.into_iter())
-            .chain(ists)
-            .collect()
+        let mut buf = vec![0u8; self.serialized_len()];
+        self.serialize_to(&mut buf[..])?;
+        Ok(buf)
+    }
+
+    /// The number of bytes `serialize()` writes.
+    pub fn serialized_len(&self) -> usize {
+        self.events.len() * EVENT_SIZE as usize + self.instrs.len() * INSTR_SIZE as usize
+    }
+
+    /// Write the program to the start of `buf`, as `serialize()` would, without allocating.
+    /// Returns the number of bytes written.
+    pub fn serialize_to(&self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.serialized_len();
+        check_len("program buffer", buf, len)?;
+        let (evs, ists) = buf[..len].split_at_mut(self.events.len() * EVENT_SIZE as usize);
+        for (e, b) in self.events.iter().zip(evs.chunks_mut(EVENT_SIZE as usize)) {
+            e.serialize_to(b)?;
+        }
+
+        for (i, b) in self.instrs.iter().zip(ists.chunks_mut(INSTR_SIZE as usize)) {
+            i.serialize_to(b)?;
+        }
+
+        Ok(len)
     }
 }
 /// pub struct Event {
@@ -37,15 +55,27 @@ impl IntoIterator for Event {
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let v = &mut [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        u32_to_u8s(&mut v[0..=3], self.flag_idx);
-        u32_to_u8s(&mut v[4..=7], self.num_flag_instrs);
-        u32_to_u8s(&mut v[8..=11], self.body_idx);
-        u32_to_u8s(&mut v[12..=15], self.num_body_instrs);
-        v.iter()
-            .map(|u| Ok(*u))
-            .collect::<Vec<Result<u8>>>()
-            .into_iter()
+        let v = &mut [0u8; EVENT_SIZE as usize];
+        match self.serialize_to(v) {
+            Ok(()) => v
+                .iter()
+                .map(|u| Ok(*u))
+                .collect::<Vec<Result<u8>>>()
+                .into_iter(),
+            Err(e) => vec![Err(e)].into_iter(),
+        }
+    }
+}
+
+impl Event {
+    /// Write the event's `EVENT_SIZE` bytes to the start of `buf`.
+    pub fn serialize_to(&self, buf: &mut [u8]) -> Result<()> {
+        check_len("event buffer", buf, EVENT_SIZE as usize)?;
+        u32_to_u8s(&mut buf[0..=3], self.flag_idx);
+        u32_to_u8s(&mut buf[4..=7], self.num_flag_instrs);
+        u32_to_u8s(&mut buf[8..=11], self.body_idx);
+        u32_to_u8s(&mut buf[12..=15], self.num_body_instrs);
+        Ok(())
     }
 }
 
@@ -56,23 +86,42 @@ impl IntoIterator for Event {
 ///     right: Reg,
 /// }
 ///
-/// serialization format: (16 B)
+/// serialization format: (28 B)
 /// |---------|------------|---------------|----------|-------------|----------|--------------|
 /// |Opcode   |Result Type |Result Register|Left Type |Left Register|Right Type|Right Register|
-/// |u8       |u8          |u32            |u8        |u32          |u8        |u32           |
+/// |u8       |u8          |u64            |u8        |u64          |u8        |u64           |
 /// |---------|------------|---------------|----------|-------------|----------|--------------|
+///
+/// The register value is a register index, or the value itself for immediates.
+/// It is 64 bits wide so that any `u64` immediate can be encoded directly.
 impl IntoIterator for Instr {
     type Item = Result<u8>;
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let op = vec![Ok(serialize_op(self.op))];
-        op.into_iter()
-            .chain(self.res)
-            .chain(self.left)
-            .chain(self.right)
-            .collect::<Vec<Result<u8>>>() // annoying that this collect is necessary, otherwise Self::IntoIter is unreadable
-            .into_iter()
+        let v = &mut [0u8; INSTR_SIZE as usize];
+        match self.serialize_to(v) {
+            Ok(()) => v
+                .iter()
+                .map(|u| Ok(*u))
+                .collect::<Vec<Result<u8>>>()
+                .into_iter(),
+            Err(e) => vec![Err(e)].into_iter(),
+        }
+    }
+}
+
+impl Instr {
+    /// Write the instruction's `INSTR_SIZE` bytes to the start of `buf`.
+    pub fn serialize_to(&self, buf: &mut [u8]) -> Result<()> {
+        check_len("instruction buffer", buf, INSTR_SIZE as usize)?;
+        buf[0] = serialize_op(self.op);
+        let regs = buf[1..INSTR_SIZE as usize].chunks_mut(REG_SIZE as usize);
+        for (r, b) in [&self.res, &self.left, &self.right].iter().zip(regs) {
+            r.serialize_to(b)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -95,15 +144,46 @@ fn serialize_op(o: Op) -> u8 {
         Op::NotIf => 13,
         Op::Or => unreachable!(),
         Op::Sub => 14,
+        Op::SDiv => 15,
+        Op::SGt => 16,
+        Op::SLt => 17,
+        Op::SMax => 18,
+        Op::SMin => 19,
     }
 }
 
+/// Serialized size of a `Reg`: a u8 register type and a u64 index or immediate value.
+pub(crate) const REG_SIZE: u32 = 9;
+
+/// Serialized size of an `Instr`.
+pub(crate) const INSTR_SIZE: u32 = 1 + 3 * REG_SIZE;
+
+/// Serialized size of an `Event`.
+pub(crate) const EVENT_SIZE: u32 = 16;
+
 impl IntoIterator for Reg {
     type Item = Result<u8>;
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let reg = match self {
+        let v = &mut [0u8; REG_SIZE as usize];
+        match self.serialize_to(v) {
+            Ok(()) => v
+                .iter()
+                .map(|u| Ok(*u))
+                .collect::<Vec<Result<u8>>>()
+                .into_iter(),
+            Err(e) => vec![Err(e)].into_iter(),
+        }
+    }
+}
+
+impl Reg {
+    /// Write the register's `REG_SIZE` bytes to the start of `buf`: its type, then its index or,
+    /// for immediates, its value.
+    pub fn serialize_to(&self, buf: &mut [u8]) -> Result<()> {
+        check_len("register buffer", buf, REG_SIZE as usize)?;
+        let (typ, idx) = match *self {
             Reg::Control(i, _) => {
                 if i > 15 {
                     Err(Error::from(format!(
@@ -111,20 +191,13 @@ impl IntoIterator for Reg {
                         i
                     )))
                 } else {
-                    Ok((0u8, u32::from(i)))
-                }
-            }
-            Reg::ImmBool(bl) => Ok((1u8, bl as u32)),
-            Reg::ImmNum(num) => {
-                if num == u64::max_value() || num < (1 << 31) {
-                    Ok((1u8, num as u32))
-                } else {
-                    Err(Error::from(format!(
-                        "ImmNum too big (max 32 bits): {:?}",
-                        num
-                    )))
+                    Ok((0u8, u64::from(i)))
                 }
             }
+            Reg::ImmBool(bl) => Ok((1u8, u64::from(bl))),
+            Reg::ImmNum(num) => Ok((1u8, num)),
+            // two's complement
+            Reg::ImmSigned(num) => Ok((1u8, num as u64)),
             Reg::Implicit(i, _) => {
                 if i > 5 {
                     Err(Error::from(format!(
@@ -132,7 +205,7 @@ impl IntoIterator for Reg {
                         i
                     )))
                 } else {
-                    Ok((2u8, u32::from(i)))
+                    Ok((2u8, u64::from(i)))
                 }
             }
             Reg::Local(i, _) => {
@@ -142,7 +215,7 @@ impl IntoIterator for Reg {
                         i
                     )))
                 } else {
-                    Ok((3u8, u32::from(i)))
+                    Ok((3u8, u64::from(i)))
                 }
             }
             Reg::Primitive(i, _) => {
@@ -152,7 +225,7 @@ impl IntoIterator for Reg {
                         i
                     )))
                 } else {
-                    Ok((4u8, u32::from(i)))
+                    Ok((4u8, u64::from(i)))
                 }
             }
             Reg::Report(i, _, is_volatile) => {
@@ -166,7 +239,7 @@ impl IntoIterator for Reg {
                     // VOLATILE_REPORT_REG is type #5
                     // NONVOLATILE_REPORT_REG is typ #6
                     // so, here, we differentiate between variables marked by the volatile keyword.
-                    Ok((if is_volatile { 5u8 } else { 6u8 }, u32::from(i)))
+                    Ok((if is_volatile { 5u8 } else { 6u8 }, u64::from(i)))
                 }
             }
             Reg::Tmp(i, _) => {
@@ -176,27 +249,299 @@ impl IntoIterator for Reg {
                         i
                     )))
                 } else {
-                    Ok((7u8, u32::from(i)))
+                    Ok((7u8, u64::from(i)))
                 }
             }
             Reg::None => unreachable!(),
+        }?;
+
+        buf[0] = typ;
+        u64_to_u8s(&mut buf[1..9], idx);
+        Ok(())
+    }
+}
+
+fn deserialize_op(o: u8) -> Result<Op> {
+    match o {
+        0 => Ok(Op::Add),
+        1 => Ok(Op::Bind),
+        2 => Ok(Op::Def),
+        3 => Ok(Op::Div),
+        4 => Ok(Op::Equiv),
+        5 => Ok(Op::Ewma),
+        6 => Ok(Op::Gt),
+        7 => Ok(Op::If),
+        8 => Ok(Op::Lt),
+        9 => Ok(Op::Max),
+        10 => Ok(Op::MaxWrap),
+        11 => Ok(Op::Min),
+        12 => Ok(Op::Mul),
+        13 => Ok(Op::NotIf),
+        14 => Ok(Op::Sub),
+        15 => Ok(Op::SDiv),
+        16 => Ok(Op::SGt),
+        17 => Ok(Op::SLt),
+        18 => Ok(Op::SMax),
+        19 => Ok(Op::SMin),
+        _ => Err(Error::from(format!("unknown opcode: {}", o))),
+    }
+}
+
+fn check_len(what: &str, buf: &[u8], len: usize) -> Result<()> {
+    if buf.len() < len {
+        Err(Error::from(format!(
+            "{} too short: {} bytes, expected {}",
+            what,
+            buf.len(),
+            len
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+impl Reg {
+    /// Read back a `Reg` written by `Reg::into_iter()`.
+    ///
+    /// The serialized form does not carry variable types, so registers come back with
+    /// `Type::None`, and every immediate (including booleans and signed values) comes back as a
+    /// `Reg::ImmNum` holding its raw bits.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        check_len("register", buf, REG_SIZE as usize)?;
+        let v = u64_from_u8s(&buf[1..9]);
+        if buf[0] == 1 {
+            return Ok(Reg::ImmNum(v));
+        }
+
+        if v > u64::from(u8::max_value()) {
+            return Err(Error::from(format!(
+                "register index too big: type {} index {}",
+                buf[0], v
+            )));
+        }
+
+        let i = v as u8;
+        match buf[0] {
+            0 => Ok(Reg::Control(i, Type::None)),
+            2 => Ok(Reg::Implicit(i, Type::None)),
+            3 => Ok(Reg::Local(i, Type::None)),
+            4 => Ok(Reg::Primitive(i, Type::None)),
+            5 => Ok(Reg::Report(i, Type::None, true)),
+            6 => Ok(Reg::Report(i, Type::None, false)),
+            7 => Ok(Reg::Tmp(i, Type::None)),
+            t => Err(Error::from(format!("unknown register type: {}", t))),
+        }
+    }
+}
+
+impl Instr {
+    /// Read back an `Instr` written by `Instr::into_iter()`. See `Reg::deserialize()`.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        check_len("instruction", buf, INSTR_SIZE as usize)?;
+        let reg = |i: usize| {
+            let start = 1 + i * REG_SIZE as usize;
+            Reg::deserialize(&buf[start..start + REG_SIZE as usize])
         };
 
-        reg.map(|(typ, idx)| {
-            let v = &mut [typ, 0, 0, 0, 0];
-            u32_to_u8s(&mut v[1..5], idx);
-            v.iter()
-                .map(|u| Ok(*u))
-                .collect::<Vec<Result<u8>>>()
-                .into_iter()
+        Ok(Instr {
+            op: deserialize_op(buf[0])?,
+            res: reg(0)?,
+            left: reg(1)?,
+            right: reg(2)?,
         })
-        .unwrap_or_else(|e| vec![Err(e)].into_iter())
     }
 }
 
-impl Reg {
-    pub fn deserialize(_buf: &[u8]) -> Self {
-        unimplemented!()
+impl Event {
+    /// Read back an `Event` written by `Event::into_iter()`.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        check_len("event", buf, EVENT_SIZE as usize)?;
+        Ok(Event {
+            flag_idx: u32_from_u8s(&buf[0..4]),
+            num_flag_instrs: u32_from_u8s(&buf[4..8]),
+            body_idx: u32_from_u8s(&buf[8..12]),
+            num_body_instrs: u32_from_u8s(&buf[12..16]),
+        })
+    }
+}
+
+impl Bin {
+    /// Read back a `Bin` written by `Bin::serialize()`, given its event and instruction counts.
+    /// See `Reg::deserialize()` for what is lost in serialization.
+    pub fn deserialize(buf: &[u8], num_events: u32, num_instrs: u32) -> Result<Self> {
+        // the counts come off the wire, so their sizes may not fit
+        let size = |num: u32, size: u32| (num as usize).checked_mul(size as usize);
+        let (instrs_start, len) = match (size(num_events, EVENT_SIZE), size(num_instrs, INSTR_SIZE))
+        {
+            (Some(ev), Some(ins)) if ev.checked_add(ins).is_some() => (ev, ev + ins),
+            _ => {
+                return Err(Error::from(format!(
+                    "program too large: {} events, {} instructions",
+                    num_events, num_instrs
+                )))
+            }
+        };
+        check_len("program", buf, len)?;
+
+        let events = buf[..instrs_start]
+            .chunks(EVENT_SIZE as usize)
+            .map(Event::deserialize)
+            .collect::<Result<Vec<Event>>>()?;
+        let instrs = buf[instrs_start..]
+            .chunks(INSTR_SIZE as usize)
+            .take(num_instrs as usize)
+            .map(Instr::deserialize)
+            .collect::<Result<Vec<Instr>>>()?;
+
+        let end = |idx: u32, num: u32| u64::from(idx) + u64::from(num);
+        for (i, ev) in events.iter().enumerate() {
+            if end(ev.flag_idx, ev.num_flag_instrs) > u64::from(num_instrs)
+                || end(ev.body_idx, ev.num_body_instrs) > u64::from(num_instrs)
+            {
+                return Err(Error::from(format!(
+                    "event {} refers past the last instruction ({}): {:?}",
+                    i, num_instrs, ev
+                )));
+            }
+        }
+
+        Ok(Bin { events, instrs })
+    }
+}
+
+fn serialize_type(t: &Type, buf: &mut Vec<u8>) {
+    let (tag, val) = match *t {
+        Type::None => (0u8, None),
+        Type::Bool(v) => (1u8, Some(v.map(u64::from))),
+        Type::Num(v) => (2u8, Some(v)),
+        Type::Signed(v) => (3u8, Some(v.map(|n| n as u64))),
+        Type::Name(ref n) => {
+            let mut len = [0u8; 4];
+            u32_to_u8s(&mut len, n.len() as u32);
+            buf.push(4u8);
+            buf.extend_from_slice(&len);
+            buf.extend_from_slice(n.as_bytes());
+            return;
+        }
+    };
+
+    buf.push(tag);
+    if let Some(v) = val {
+        let mut n = [0u8; 8];
+        u64_to_u8s(&mut n, v.unwrap_or(0));
+        buf.push(v.is_some() as u8);
+        buf.extend_from_slice(&n);
+    }
+}
+
+// Read `len` bytes from `buf` at `*offset`, and advance `*offset` past them.
+fn take<'a>(what: &str, buf: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8]> {
+    check_len(what, &buf[*offset..], len)?;
+    *offset += len;
+    Ok(&buf[*offset - len..*offset])
+}
+
+fn take_str(what: &str, buf: &[u8], offset: &mut usize) -> Result<String> {
+    let len = u32_from_u8s(take(what, buf, offset, 4)?) as usize;
+    let s = ::std::str::from_utf8(take(what, buf, offset, len)?)?;
+    Ok(String::from(s))
+}
+
+fn deserialize_type(buf: &[u8], offset: &mut usize) -> Result<Type> {
+    let tag = take("type", buf, offset, 1)?[0];
+    if tag == 0 {
+        return Ok(Type::None);
+    } else if tag == 4 {
+        return Ok(Type::Name(take_str("type", buf, offset)?));
+    }
+
+    let v = take("type", buf, offset, 9)?;
+    let val = if v[0] == 0 {
+        None
+    } else {
+        Some(u64_from_u8s(&v[1..9]))
+    };
+
+    match tag {
+        1 => Ok(Type::Bool(val.map(|n| n != 0))),
+        2 => Ok(Type::Num(val)),
+        3 => Ok(Type::Signed(val.map(|n| n as i64))),
+        t => Err(Error::from(format!("unknown variable type: {}", t))),
+    }
+}
+
+/// Serialize the variables of a `Scope`, with their types, so that it can be stored alongside
+/// a serialized `Bin`.
+///
+/// serialization format:
+///
+/// |-------------|-----------|------------|---------------|-----------|
+/// | num control | num local | num report | num variables | variables |
+/// | u8          | u8        | u8         | u32           |           |
+/// |-------------|-----------|------------|---------------|-----------|
+///
+/// and each variable:
+///
+/// |----------|------|------------------------|------|-------|
+/// | name len | name | register               | type | value |
+/// | u32      |      | as in `Instr` (9 B)    | u8   |       |
+/// |----------|------|------------------------|------|-------|
+///
+/// where the value is absent for `Type::None`, a u32 length and the name for `Type::Name`, and
+/// otherwise a u8 which is 1 if there is a value, followed by the value as a u64.
+impl Scope {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![self.num_control, self.num_local, self.num_perm, 0, 0, 0, 0];
+        u32_to_u8s(&mut buf[3..7], self.named.0.len() as u32);
+        for &(ref name, ref reg) in &self.named.0 {
+            let mut len = [0u8; 4];
+            u32_to_u8s(&mut len, name.len() as u32);
+            buf.extend_from_slice(&len);
+            buf.extend_from_slice(name.as_bytes());
+            for b in reg.clone() {
+                buf.push(b?);
+            }
+
+            serialize_type(&reg.get_type()?, &mut buf);
+        }
+
+        Ok(buf)
+    }
+
+    /// Read back a `Scope` written by `Scope::serialize()`.
+    /// The `program_uid` is not serialized, so the `Scope` has `program_uid` `UNASSIGNED_UID`.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        let mut offset = 0;
+        let hdr = take("scope", buf, &mut offset, 7)?;
+        let mut sc = Scope::new();
+        sc.num_control = hdr[0];
+        sc.num_local = hdr[1];
+        sc.num_perm = hdr[2];
+        let num_named = u32_from_u8s(&hdr[3..7]);
+
+        let mut named = vec![];
+        for _ in 0..num_named {
+            let name = take_str("variable", buf, &mut offset)?;
+            let reg = Reg::deserialize(take("variable", buf, &mut offset, REG_SIZE as usize)?)?;
+            let reg = match (reg, deserialize_type(buf, &mut offset)?) {
+                (Reg::Control(i, _), t) => Reg::Control(i, t),
+                (Reg::Implicit(i, _), t) => Reg::Implicit(i, t),
+                (Reg::Local(i, _), t) => Reg::Local(i, t),
+                (Reg::Primitive(i, _), t) => Reg::Primitive(i, t),
+                (Reg::Report(i, _, v), t) => Reg::Report(i, t, v),
+                (r, _) => {
+                    return Err(Error::from(format!(
+                        "variable {:?} is not a named register: {:?}",
+                        name, r
+                    )))
+                }
+            };
+
+            named.push((name, reg));
+        }
+
+        sc.named = RegFile(named);
+        Ok(sc)
     }
 }
 
@@ -240,28 +585,66 @@ mod tests {
         let v = b.serialize().expect("serialize");
         assert_eq!(
             v,
+            #[rustfmt::skip]
             vec![
                 // event description
-                0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00,
-                0x00, 0x00, // def reg::report(6) <- 0
-                0x02, 0x05, 0x06, 0x00, 0x00, 0x00, 0x05, 0x06, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
-                0x00, 0x00, // reg::eventFlag <- 1
-                0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00,
-                0x00, 0x00, // def reg::report(6) <- 0
-                0x01, 0x05, 0x06, 0x00, 0x00, 0x00, 0x05, 0x06, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00,
-                0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+                // def reg::report(6) <- 0
+                0x02,
+                0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                // reg::eventFlag <- 1
+                0x01,
+                0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                // bind reg::report(6) <- 4
+                0x01,
+                0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
             ]
         );
+        assert_eq!(v.len(), 16 + 3 * super::INSTR_SIZE as usize);
+    }
+
+    #[test]
+    fn do_ser_to_buf() {
+        let (bin, _) = lang::compile(
+            b"(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked))",
+            &[],
+        )
+        .expect("compile");
+        let v = bin.serialize().expect("serialize");
+        assert_eq!(bin.serialized_len(), v.len());
+
+        let mut buf = vec![0xffu8; v.len() + 3];
+        assert_eq!(
+            bin.serialize_to(&mut buf[..]).expect("serialize_to"),
+            v.len()
+        );
+        assert_eq!(&buf[..v.len()], &v[..]);
+        assert_eq!(&buf[v.len()..], &[0xff, 0xff, 0xff]);
+        assert!(bin.serialize_to(&mut buf[..v.len() - 1]).is_err());
+
+        let mut reg = [0u8; super::REG_SIZE as usize];
+        assert!(Reg::Control(16, Type::Num(None))
+            .serialize_to(&mut reg)
+            .is_err());
+        assert!(Reg::Control(1, Type::Num(None))
+            .serialize_to(&mut reg[..8])
+            .is_err());
     }
 
     #[test]
     fn do_ser_max_imm() {
-        // make an InstrBytes to serialize
+        // 100 Gbit/s in bytes/s does not fit in 32 bits
         let b = Instr {
             res: Reg::Tmp(0, Type::Num(None)),
             op: Op::Add,
-            left: Reg::ImmNum(0x3fff_ffff),
-            right: Reg::ImmNum(0x3fff_ffff),
+            left: Reg::ImmNum(12_500_000_000),
+            right: Reg::ImmNum(0x7fff_ffff_ffff_ffff),
         };
 
         let v = b
@@ -270,9 +653,12 @@ mod tests {
             .expect("serialize");
         assert_eq!(
             v,
+            #[rustfmt::skip]
             vec![
-                0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff, 0xff, 0x3f, 0x01, 0xff, 0xff,
-                0xff, 0x3f,
+                0x00,
+                0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0x00, 0xdd, 0x0e, 0xe9, 0x02, 0x00, 0x00, 0x00,
+                0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
             ]
         );
     }
@@ -293,10 +679,71 @@ mod tests {
             .expect("serialize");
         assert_eq!(
             v,
+            #[rustfmt::skip]
+            vec![
+                0x02,
+                0x05, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x05, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            ]
+        );
+    }
+
+    #[test]
+    fn do_ser_signed() {
+        let b = Instr {
+            res: Reg::Tmp(0, Type::Bool(None)),
+            op: Op::SLt,
+            left: Reg::Report(0, Type::Signed(Some(0)), false),
+            right: Reg::ImmSigned(-2),
+        };
+
+        let v = b
+            .into_iter()
+            .collect::<lang::Result<Vec<u8>>>()
+            .expect("serialize");
+        assert_eq!(
+            v,
+            #[rustfmt::skip]
             vec![
-                0x02, 0x05, 0x02, 0x00, 0x00, 0x00, 0x05, 0x02, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff,
-                0xff, 0xff,
+                0x11,
+                0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
             ]
         );
     }
+
+    #[test]
+    fn scope_roundtrip() {
+        let src = b"
+            (def
+                (Report (volatile acked 0) (signed gradient -3) (timeout false))
+                (Control.state 4)
+                (local_state 0)
+            )
+            (when true
+                (:= local_state Ack.bytes_acked)
+                (:= Report.acked (+ Report.acked local_state))
+            )
+        ";
+        let (_, sc) = lang::compile(src, &[]).unwrap();
+        let buf = sc.serialize().expect("serialize");
+        let got = lang::Scope::deserialize(&buf).expect("deserialize");
+        assert_eq!(got.named.0, sc.named.0);
+        assert_eq!(
+            (got.num_control, got.num_local, got.num_perm),
+            (sc.num_control, sc.num_local, sc.num_perm)
+        );
+        assert_eq!(got.program_uid, ::lang::UNASSIGNED_UID);
+        assert!(lang::Scope::deserialize(&buf[..buf.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bin_huge_counts() {
+        let buf = [0u8; 64];
+        assert!(Bin::deserialize(&buf, u32::MAX, 0).is_err());
+        assert!(Bin::deserialize(&buf, 0, u32::MAX).is_err());
+        assert!(Bin::deserialize(&buf, u32::MAX, u32::MAX).is_err());
+    }
 }
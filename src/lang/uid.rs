@@ -0,0 +1,130 @@
+//! Content-addressed program uids.
+//!
+//! A program's uid is derived from a hash of its serialized `Bin` and `Scope`, so the same
+//! program gets the same uid in every run, and a CCP which restarts recognizes the reports of
+//! programs it installed before. Two different programs whose hashes collide would get the same
+//! uid, so each process remembers which program has each uid it hands out, and gives the second
+//! program the next free uid instead.
+
+use fnv::{FnvHashMap, FnvHasher};
+use std::hash::Hasher;
+use std::sync::Mutex;
+
+use super::datapath::UNASSIGNED_UID;
+use super::{Error, Result};
+
+lazy_static! {
+    // The fingerprint of the program which has each uid handed out so far.
+    static ref UIDS: Mutex<FnvHashMap<u32, u64>> = Mutex::new(FnvHashMap::default());
+}
+
+/// A hash of a program, given its serialized `Bin` and `Scope`.
+pub(crate) fn fingerprint(bin: &[u8], scope: &[u8]) -> u64 {
+    let mut h = FnvHasher::default();
+    h.write(bin);
+    h.write(scope);
+    h.finish()
+}
+
+// Whether `uid` may be a program's uid: 0 hands a flow back to the datapath, and
+// `UNASSIGNED_UID` is the uid of a scope with no program.
+fn is_program_uid(uid: u32) -> bool {
+    uid != 0 && uid != UNASSIGNED_UID
+}
+
+/// The uid of the program with fingerprint `fp`. Never 0 or `UNASSIGNED_UID`.
+pub(crate) fn program_uid(fp: u64) -> u32 {
+    let mut uid = (fp >> 32) as u32 ^ fp as u32;
+    let mut uids = UIDS.lock().unwrap();
+    loop {
+        if is_program_uid(uid) {
+            match uids.get(&uid) {
+                Some(&f) if f == fp => return uid,
+                Some(_) => (),
+                None => {
+                    uids.insert(uid, fp);
+                    return uid;
+                }
+            }
+        }
+
+        uid = uid.wrapping_add(1);
+    }
+}
+
+/// Record that the program with fingerprint `fp` has uid `uid`, for a program which was given
+/// its uid elsewhere, such as by an earlier run.
+/// Returns an error if `uid` already belongs to a different program, or cannot be a program's.
+pub(crate) fn register_uid(uid: u32, fp: u64) -> Result<()> {
+    if !is_program_uid(uid) {
+        return Err(Error::from(format!("{} is not a program uid", uid)));
+    }
+
+    let mut uids = UIDS.lock().unwrap();
+    match uids.get(&uid).cloned() {
+        Some(f) if f != fp => Err(Error::from(format!(
+            "program uid {} already belongs to a different program",
+            uid
+        ))),
+        _ => {
+            uids.insert(uid, fp);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{program_uid, register_uid};
+    use lang;
+
+    const PROG: &[u8] = b"
+        (def (Report (volatile acked 0)))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+        )
+    ";
+
+    #[test]
+    fn same_program_same_uid() {
+        let (_, a) = lang::compile(PROG, &[]).unwrap();
+        let (_, b) = lang::compile(PROG, &[]).unwrap();
+        assert_ne!(a.program_uid, 0);
+        assert_eq!(a.program_uid, b.program_uid);
+
+        let (_, c) = lang::compile(
+            b"
+            (def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.packets_acked))
+            )
+            ",
+            &[],
+        )
+        .unwrap();
+        assert_ne!(a.program_uid, c.program_uid);
+    }
+
+    #[test]
+    fn collision() {
+        // a fingerprint no real program in these tests has, whose uid is taken by another program
+        let fp = 0x5eed_0000_0000_0001u64;
+        let uid = (fp >> 32) as u32 ^ fp as u32;
+        register_uid(uid, fp + 1).unwrap();
+
+        assert_eq!(program_uid(fp), uid + 1);
+        assert_eq!(program_uid(fp), uid + 1);
+        assert!(register_uid(uid + 1, fp + 2).is_err());
+        register_uid(uid + 1, fp).unwrap();
+    }
+
+    #[test]
+    fn reserved_uids() {
+        let fp = 0x5eed_0000_0000_0002u64;
+        assert!(register_uid(0, fp).is_err());
+        assert!(register_uid(::lang::UNASSIGNED_UID, fp).is_err());
+
+        // a fingerprint whose uid would be 0 gets the next one
+        assert_eq!(program_uid(0x5eed_0003_5eed_0003), 1);
+    }
+}
@@ -8,6 +8,7 @@
 //!
 //! The entry points into portus are [`run`](./fn.run.html) and [`spawn`](./fn.spawn.html), which start
 //! the CCP algorithm runtime. There is also the convenience macro [`start`](./macro.start.html).
+//! To drive the runtime from an existing event loop instead, use [`Runtime`](./struct.Runtime.html).
 //!
 //! The runtime listens for datapath messages and dispatches calls to
 //! the appropriate congestion control methods.
@@ -67,7 +68,6 @@
 //! ```
 
 #![feature(box_patterns)]
-#![feature(integer_atomics)]
 #![feature(never_type)]
 #![feature(stmt_expr_attributes)]
 #![feature(test)]
@@ -76,10 +76,13 @@ extern crate bytes;
 extern crate clap;
 extern crate crossbeam;
 extern crate fnv;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
 extern crate nix;
 #[macro_use]
 extern crate nom;
+extern crate portus_macros;
 extern crate time;
 
 #[macro_use]
@@ -88,6 +91,10 @@ extern crate slog_async;
 extern crate slog_term;
 
 use fnv::FnvHashMap as HashMap;
+use fnv::FnvHashSet as HashSet;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::{atomic, Arc};
 use std::thread;
@@ -101,9 +108,13 @@ pub mod algs;
 mod errors;
 pub use errors::*;
 
+/// Compile a datapath program while building the crate which uses it, into a
+/// [`lang::Precompiled`](lang/struct.Precompiled.html). See the [`lang`](lang/index.html) docs.
+pub use portus_macros::program;
+
 use ipc::Ipc;
-use ipc::{BackendBuilder, BackendSender};
-use lang::{Bin, Reg, Scope};
+use ipc::{BackendBuilder, BackendSender, BatchSender};
+use lang::{Bin, Reg, ReportField, Scope, Type};
 use serialize::Msg;
 
 /// CCP custom `Result` type, using `Error` as the `Err` type.
@@ -116,18 +127,62 @@ pub trait DatapathTrait {
     fn set_program(
         &mut self,
         program_name: &'static str,
-        fields: Option<&[(&str, u32)]>,
+        fields: Option<&[(&str, u64)]>,
     ) -> Result<Scope>;
     /// Update the value of a register in an already-installed fold function.
-    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()>;
+    fn update_field(&self, sc: &Scope, update: &[(&str, u64)]) -> Result<()>;
+    /// Send any messages held back for batching now, rather than when the runtime next waits
+    /// for the datapath. The default implementation does nothing.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// A collection of methods to interact with the datapath.
 #[derive(Clone)]
 pub struct Datapath<T: Ipc> {
     sock_id: u32,
-    sender: BackendSender<T>,
+    sender: BatchSender<T>,
     programs: Rc<HashMap<String, Scope>>,
+    // reused for the fields of each message, so that sending one does not allocate
+    fields: RefCell<Vec<(Reg, u64)>>,
+}
+
+impl<T: Ipc> Datapath<T> {
+    fn new(sock_id: u32, sender: BatchSender<T>, programs: Rc<HashMap<String, Scope>>) -> Self {
+        Datapath {
+            sock_id,
+            sender,
+            programs,
+            fields: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+// Look up the registers to set for `update` in `sc`, into `fields`.
+fn resolve_fields(sc: &Scope, update: &[(&str, u64)], fields: &mut Vec<(Reg, u64)>) -> Result<()> {
+    fields.clear();
+    for &(reg_name, new_value) in update {
+        if reg_name.starts_with("__") {
+            return Err(Error(format!(
+                "Cannot update reserved field: {:?}",
+                reg_name
+            )));
+        }
+
+        let reg = sc
+            .get(reg_name)
+            .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))?;
+        match *reg {
+            Reg::Control(idx, ref t) => fields.push((Reg::Control(idx, t.clone()), new_value)),
+            Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
+                fields.push((Reg::Implicit(idx, t.clone()), new_value))
+            }
+            _ => return Err(Error(format!("Cannot update field: {:?}", reg_name))),
+        }
+    }
+
+    Ok(())
 }
 
 impl<T: Ipc> DatapathTrait for Datapath<T> {
@@ -138,44 +193,23 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
     fn set_program(
         &mut self,
         program_name: &'static str,
-        fields: Option<&[(&str, u32)]>,
+        fields: Option<&[(&str, u64)]>,
     ) -> Result<Scope> {
         // if the program with this key exists, return it; otherwise return nothing
         match self.programs.get(program_name) {
             Some(sc) => {
                 // apply optional updates to values of registers in this scope
-                let fields: Vec<(Reg, u64)> = fields
-                    .unwrap_or_else(|| &[])
-                    .iter()
-                    .map(|&(reg_name, new_value)| {
-                        if reg_name.starts_with("__") {
-                            return Err(Error(format!(
-                                "Cannot update reserved field: {:?}",
-                                reg_name
-                            )));
-                        }
-
-                        sc.get(reg_name)
-                            .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))
-                            .and_then(|reg| match *reg {
-                                Reg::Control(idx, ref t) => {
-                                    Ok((Reg::Control(idx, t.clone()), u64::from(new_value)))
-                                }
-                                Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                                    Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                                }
-                                _ => Err(Error(format!("Cannot update field: {:?}", reg_name))),
-                            })
-                    })
-                    .collect::<Result<_>>()?;
+                let mut scratch = self.fields.borrow_mut();
+                resolve_fields(sc, fields.unwrap_or(&[]), &mut scratch)?;
                 let msg = serialize::changeprog::Msg {
                     sid: self.sock_id,
                     program_uid: sc.program_uid,
-                    num_fields: fields.len() as u32,
-                    fields,
+                    num_fields: scratch.len() as u32,
+                    fields: std::mem::take(&mut *scratch),
                 };
-                let buf = serialize::serialize(&msg)?;
-                self.sender.send_msg(&buf[..])?;
+                let res = self.sender.send(&msg);
+                *scratch = msg.fields;
+                res?;
                 Ok(sc.clone())
             }
             _ => Err(Error(format!(
@@ -185,40 +219,22 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
         }
     }
 
-    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()> {
-        let fields: Vec<(Reg, u64)> = update
-            .iter()
-            .map(|&(reg_name, new_value)| {
-                if reg_name.starts_with("__") {
-                    return Err(Error(format!(
-                        "Cannot update reserved field: {:?}",
-                        reg_name
-                    )));
-                }
-
-                sc.get(reg_name)
-                    .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))
-                    .and_then(|reg| match *reg {
-                        Reg::Control(idx, ref t) => {
-                            Ok((Reg::Control(idx, t.clone()), u64::from(new_value)))
-                        }
-                        Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                            Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                        }
-                        _ => Err(Error(format!("Cannot update field: {:?}", reg_name))),
-                    })
-            })
-            .collect::<Result<_>>()?;
-
+    fn update_field(&self, sc: &Scope, update: &[(&str, u64)]) -> Result<()> {
+        let mut scratch = self.fields.borrow_mut();
+        resolve_fields(sc, update, &mut scratch)?;
         let msg = serialize::update_field::Msg {
             sid: self.sock_id,
-            num_fields: fields.len() as u8,
-            fields,
+            num_fields: scratch.len() as u8,
+            fields: std::mem::take(&mut *scratch),
         };
 
-        let buf = serialize::serialize(&msg)?;
-        self.sender.send_msg(&buf[..])?;
-        Ok(())
+        let res = self.sender.send(&msg);
+        *scratch = msg.fields;
+        res
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.sender.flush()
     }
 }
 
@@ -239,10 +255,36 @@ where
 }
 
 /// Configuration parameters for the portus runtime.
-/// Defines a `slog::Logger` to use for (optional) logging
+/// Defines a `slog::Logger` to use for (optional) logging, optionally a directory in which
+/// to keep compiled datapath programs across restarts, whether to recover the datapath's
+/// flows after a restart, whether to batch the messages sent to the datapath, and what to do with
+/// a flow whose algorithm panicked.
 #[derive(Clone, Default)]
 pub struct Config {
     pub logger: Option<slog::Logger>,
+    /// If set, the runtime saves each datapath program it compiles here as a
+    /// [`lang::Compiled`](lang/struct.Compiled.html), and on startup reuses the saved program
+    /// for unchanged source, so that reports from flows which are still running it after a
+    /// restart keep the `program_uid` the runtime expects.
+    pub program_cache: Option<PathBuf>,
+    /// If set, the runtime asks the datapath for its live flows when it starts, and again for
+    /// any flow it gets a measurement from but does not know, and recreates them with
+    /// [`CongAlg::resume_flow`](./trait.CongAlg.html#method.resume_flow). This lets a restarted
+    /// CCP take over the flows which started before it. The datapath must understand
+    /// `serialize::resync` messages.
+    pub resync_flows: bool,
+    /// If set, messages the flows send to the datapath, such as `update_field`s, are coalesced
+    /// into IPC writes of at most this many bytes, e.g. the transport's MTU. The runtime sends
+    /// them once it has handled every message from the last read, so one write can carry
+    /// updates for all the flows whose reports arrived together. The datapath must be able to
+    /// read several messages from one write.
+    pub send_batch: Option<usize>,
+    /// If set, when a flow is removed because one of the algorithm's callbacks panicked, the
+    /// runtime tells the datapath to go back to its default congestion control for that flow,
+    /// with a `serialize::changeprog` message for `changeprog::FALLBACK_UID`. Otherwise the flow
+    /// keeps running its last datapath program unattended. The datapath must understand such
+    /// messages.
+    pub fallback_on_panic: bool,
 }
 
 /// The set of information passed by the datapath to CCP
@@ -261,6 +303,8 @@ pub struct DatapathInfo {
 
 /// Contains the values of the pre-defined Report struct from the fold function.
 /// Use `get_field` to query its values using the names defined in the fold function.
+/// For frequently accessed fields, resolve a `ReportField` once with `Scope::report_field` and
+/// read it with `get`; use `iter` or `to_map` to read every field.
 pub struct Report {
     pub program_uid: u32,
     fields: Vec<u64>,
@@ -288,6 +332,56 @@ impl Report {
             None => Err(Error::from(FieldNotFoundError)),
         }
     }
+
+    /// Like `get_field`, but for fields declared `signed` in the fold function.
+    pub fn get_signed_field(&self, field: &str, sc: &Scope) -> Result<i64> {
+        match sc.get(field) {
+            Some(&Reg::Report(_, Type::Signed(_), _)) => {
+                self.get_field(field, sc).map(|v| v as i64)
+            }
+            Some(_) => Err(Error::from(InvalidRegTypeError)),
+            None => Err(Error::from(FieldNotFoundError)),
+        }
+    }
+
+    /// Read a field using a handle previously obtained from `Scope::report_field`.
+    pub fn get(&self, field: ReportField) -> Result<u64> {
+        if field.program_uid != self.program_uid {
+            return Err(Error::from(StaleProgramError));
+        }
+
+        self.fields
+            .get(field.idx as usize)
+            .cloned()
+            .ok_or_else(|| Error::from(InvalidReportError))
+    }
+
+    /// Iterate over all the fields of the Report struct defined in `sc`.
+    /// Yields `(name, value, is_volatile)` for each field, in name order.
+    pub fn iter<'a>(
+        &'a self,
+        sc: &'a Scope,
+    ) -> Result<impl Iterator<Item = (&'a str, u64, bool)> + 'a> {
+        if sc.program_uid != self.program_uid {
+            return Err(Error::from(StaleProgramError));
+        }
+
+        Ok(sc
+            .report_fields()
+            .filter_map(move |(name, field, is_volatile)| {
+                self.fields
+                    .get(field.idx as usize)
+                    .map(|v| (name, *v, is_volatile))
+            }))
+    }
+
+    /// Collect all the fields of the Report struct defined in `sc`, keyed by name.
+    pub fn to_map(&self, sc: &Scope) -> Result<HashMap<String, u64>> {
+        Ok(self
+            .iter(sc)?
+            .map(|(name, v, _)| (String::from(name), v))
+            .collect())
+    }
 }
 
 /// Implement this trait and [`portus::CongAlg`](./trait.CongAlg.html) to define a CCP congestion control algorithm.
@@ -346,15 +440,51 @@ pub trait CongAlg<I: Ipc> {
     /// ```
     fn datapath_programs(&self) -> HashMap<&'static str, String>;
 
+    /// `precompiled_programs` returns datapath programs compiled at build time by
+    /// [`program!`](./macro.program.html). They are installed along with the
+    /// `datapath_programs`, without compiling them again, and their names must be distinct from
+    /// the names of the `datapath_programs`.
+    ///
+    /// For example,
+    /// ```
+    /// #[macro_use]
+    /// extern crate portus;
+    /// extern crate fnv;
+    /// use fnv::FnvHashMap as HashMap;
+    /// # fn main() {
+    /// let mut h = HashMap::default();
+    /// h.insert("prog1", program!("(def (Report (acked 0))) (when true (report))"));
+    /// # }
+    /// ```
+    fn precompiled_programs(&self) -> HashMap<&'static str, lang::Precompiled> {
+        HashMap::default()
+    }
+
     /// Create a new instance of the CongAlg to manage a new flow.
     /// Optionally copy any configuration parameters from `&self`.
     fn new_flow(&self, control: Datapath<I>, info: DatapathInfo) -> Self::Flow;
+
+    /// Create an instance of the CongAlg to manage a flow which the datapath already had
+    /// when CCP started, if `Config::resync_flows` is set.
+    /// `program` is the name and `Scope` of the datapath program the flow is running, if it is
+    /// one of this algorithm's programs.
+    /// The default implementation treats the flow as new, with `new_flow`.
+    fn resume_flow(
+        &self,
+        control: Datapath<I>,
+        info: DatapathInfo,
+        program: Option<(&str, Scope)>,
+    ) -> Self::Flow {
+        let _ = program;
+        self.new_flow(control, info)
+    }
 }
 
 /// A handle to manage running instances of the CCP execution loop.
 #[derive(Debug)]
 pub struct CCPHandle {
     pub continue_listening: Arc<atomic::AtomicBool>,
+    pub flow_panics: Arc<atomic::AtomicUsize>,
     pub join_handle: thread::JoinHandle<Result<()>>,
 }
 
@@ -365,6 +495,12 @@ impl CCPHandle {
             .store(false, atomic::Ordering::SeqCst);
     }
 
+    /// The number of flows of this algorithm which have been removed because
+    /// one of their callbacks panicked.
+    pub fn num_flow_panics(&self) -> usize {
+        self.flow_panics.load(atomic::Ordering::SeqCst)
+    }
+
     // TODO: join_handle.join() returns an Err instead of Ok, because
     // some function panicked, this function should return an error
     // with the same string from the panic.
@@ -383,6 +519,11 @@ impl CCPHandle {
 /// 1. The IPC socket is closed.
 /// 2. An invalid message is received.
 ///
+/// A panic inside one of the algorithm's callbacks (`new_flow`, `on_report`, or `close`) does not
+/// stop the execution loop: the offending flow is removed and the panic is logged. With
+/// `Config::fallback_on_panic`, the datapath is also told to go back to its default congestion
+/// control for the flow.
+///
 /// Callers must construct a `BackendBuilder` and a `Config`.
 /// Algorithm implementations should
 /// 1. Initializes an ipc backendbuilder (depending on the datapath).
@@ -399,6 +540,7 @@ where
     // call run_inner
     match run_inner(
         Arc::new(atomic::AtomicBool::new(true)),
+        Arc::new(atomic::AtomicUsize::new(0)),
         backend_builder,
         cfg,
         alg,
@@ -423,25 +565,33 @@ where
     U: CongAlg<I> + 'static + Send,
 {
     let stop_signal = Arc::new(atomic::AtomicBool::new(true));
+    let flow_panics = Arc::new(atomic::AtomicUsize::new(0));
     CCPHandle {
         continue_listening: stop_signal.clone(),
-        join_handle: thread::spawn(move || run_inner(stop_signal, backend_builder, cfg, alg)),
+        flow_panics: flow_panics.clone(),
+        join_handle: thread::spawn(move || {
+            run_inner(stop_signal, flow_panics, backend_builder, cfg, alg)
+        }),
     }
 }
 
 // Main execution inner loop of ccp.
-// Blocks "forever", or until the iterator stops iterating.
+// Blocks "forever", or until the runtime stops.
 //
 // `run_inner()`:
 // 1. listens for messages from the datapath
 // 2. call the appropriate message in `U: impl CongAlg`
-// The function can return for two reasons: an error, or the iterator returned None.
+// The function can return for two reasons: an error, or `continue_listening` was unset.
 // The latter should only happen for spawn(), and not for run().
 // It returns any error, either from:
 // 1. the IPC channel failing
 // 2. Receiving an install control message (only the datapath should receive these).
+//
+// Panics in the algorithm's callbacks are caught: the flow is removed and `flow_panics` is
+// incremented, but the loop keeps serving the remaining flows.
 fn run_inner<I, U>(
     continue_listening: Arc<atomic::AtomicBool>,
+    flow_panics: Arc<atomic::AtomicUsize>,
     backend_builder: BackendBuilder<I>,
     cfg: Config,
     alg: U,
@@ -451,48 +601,264 @@ where
     U: CongAlg<I>,
 {
     let mut receive_buf = [0u8; 1024];
-    let mut b = backend_builder.build(continue_listening.clone(), &mut receive_buf[..]);
-    let mut flows = HashMap::<u32, U::Flow>::default();
-    let backend = b.sender();
+    let mut rt = Runtime::with_signals(
+        continue_listening.clone(),
+        flow_panics,
+        backend_builder,
+        cfg,
+        alg,
+        &mut receive_buf[..],
+    )?;
 
-    if let Some(log) = cfg.logger.as_ref() {
-        info!(log, "starting CCP";
-            "algorithm" => U::name(),
-            "ipc"       => I::name(),
-        );
+    loop {
+        if let Err(e) = rt.poll() {
+            // if the thread has been killed, that is not an error
+            if !continue_listening.load(atomic::Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            return Err(e);
+        }
+
+        // a blocking socket waits in `recv` instead
+        rt.backend
+            .wait(std::time::Duration::from_millis(ipc::POLL_INTERVAL_MS))?;
     }
+}
 
-    let mut scope_map = Rc::new(HashMap::<String, Scope>::default());
+/// The CCP execution loop, for callers which wait for datapath messages in their own event loop
+/// rather than with [`run`](./fn.run.html) or [`spawn`](./fn.spawn.html).
+///
+/// `Runtime::new` installs the algorithm's datapath programs. Then, each time `poll_fd()` is
+/// readable, e.g. as registered with epoll, call `poll()` to handle the messages which have
+/// arrived. The socket should be nonblocking, since `poll()` reads until the socket has nothing
+/// more to return. Mechanisms without a `poll_fd()`, such as `ipc::shm`, can only be polled on a
+/// timer.
+///
+/// ```no_run
+/// # extern crate fnv;
+/// # extern crate portus;
+/// # use fnv::FnvHashMap as HashMap;
+/// # use portus::ipc::{BackendBuilder, Ipc, Nonblocking};
+/// # use portus::{CongAlg, Config, Datapath, DatapathInfo, Flow, Report, Runtime};
+/// # struct Alg;
+/// # impl Flow for Alg {
+/// #     fn on_report(&mut self, _sock_id: u32, _m: Report) {}
+/// # }
+/// # impl<I: Ipc> CongAlg<I> for Alg {
+/// #     type Flow = Alg;
+/// #     fn name() -> &'static str { "alg" }
+/// #     fn datapath_programs(&self) -> HashMap<&'static str, String> { HashMap::default() }
+/// #     fn new_flow(&self, _control: Datapath<I>, _info: DatapathInfo) -> Alg { Alg }
+/// # }
+/// # fn main() -> portus::Result<()> {
+/// let sock = portus::ipc::unix::Socket::<Nonblocking>::new("in", "out")?;
+/// let mut buf = [0u8; 1024];
+/// let mut rt = Runtime::new(BackendBuilder { sock }, Config::default(), Alg, &mut buf[..])?;
+/// let fd = rt.poll_fd().expect("unix sockets have a descriptor");
+/// loop {
+///     // wait for `fd` to be readable, along with the application's other descriptors
+///     # let _ = fd;
+///     rt.poll()?;
+/// }
+/// # }
+/// ```
+pub struct Runtime<'a, I: Ipc, U: CongAlg<I>> {
+    backend: ipc::Backend<'a, I>,
+    state: RuntimeState<I, U>,
+}
 
-    let programs = alg.datapath_programs();
-    for (program_name, program) in programs.iter() {
-        match lang::compile(program.as_bytes(), &[]) {
-            Ok((bin, sc)) => {
-                match send_and_install(0, &backend, bin, &sc) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        return Err(Error(format!(
-                            "Failed to install datapath program \"{}\": {:?}",
-                            program_name, e
-                        )));
+// The algorithm, and what the runtime knows about its flows.
+struct RuntimeState<I: Ipc, U: CongAlg<I>> {
+    cfg: Config,
+    alg: U,
+    flows: HashMap<u32, U::Flow>,
+    scope_map: Rc<HashMap<String, Scope>>,
+    batch: BatchSender<I>,
+    // Flows the datapath has been asked about since they were last created or closed.
+    resync_pending: HashSet<u32>,
+    // Flows removed because a callback panicked, since they were last created or closed. Unless
+    // `Config::fallback_on_panic` handed them back to the datapath, they still run their last
+    // program, so the datapath's live_flow messages about them are ignored.
+    panicked: HashSet<u32>,
+    flow_panics: Arc<atomic::AtomicUsize>,
+}
+
+impl<'a, I: Ipc, U: CongAlg<I>> Runtime<'a, I, U> {
+    /// Start running `alg` on the socket in `backend_builder`, reading datapath messages into
+    /// `receive_buf`, and install the algorithm's datapath programs.
+    pub fn new(
+        backend_builder: BackendBuilder<I>,
+        cfg: Config,
+        alg: U,
+        receive_buf: &'a mut [u8],
+    ) -> Result<Self> {
+        Runtime::with_signals(
+            Arc::new(atomic::AtomicBool::new(true)),
+            Arc::new(atomic::AtomicUsize::new(0)),
+            backend_builder,
+            cfg,
+            alg,
+            receive_buf,
+        )
+    }
+
+    fn with_signals(
+        continue_listening: Arc<atomic::AtomicBool>,
+        flow_panics: Arc<atomic::AtomicUsize>,
+        backend_builder: BackendBuilder<I>,
+        cfg: Config,
+        alg: U,
+        receive_buf: &'a mut [u8],
+    ) -> Result<Self> {
+        let b = backend_builder.build(continue_listening, receive_buf);
+        let backend = b.sender();
+
+        if let Some(log) = cfg.logger.as_ref() {
+            info!(log, "starting CCP";
+                "algorithm" => U::name(),
+                "ipc"       => I::name(),
+            );
+        }
+
+        let mut scope_map = Rc::new(HashMap::<String, Scope>::default());
+
+        let programs = alg.datapath_programs();
+        let precompiled = alg.precompiled_programs();
+        let sources = programs
+            .iter()
+            .map(|(name, src)| (name, src.as_str(), None))
+            .chain(
+                precompiled
+                    .iter()
+                    .map(|(name, p)| (name, p.source(), Some(p))),
+            );
+
+        // Load every cached program before compiling any: loading registers each cached uid,
+        // and `program_uid` only moves a new program off a colliding uid if the uid it collides
+        // with has been registered by then.
+        let sources: Vec<_> = sources
+            .map(|(name, src, p)| (name, src, p, load_cached(&cfg, src)))
+            .collect();
+        for (program_name, src, precompiled, cached) in sources {
+            if scope_map.contains_key(*program_name) {
+                return Err(Error(format!(
+                    "Datapath program \"{}\" is defined twice",
+                    program_name
+                )));
+            }
+
+            let res = match cached {
+                Some(c) => Ok((c.bin, c.scope)),
+                None => {
+                    let res = match precompiled {
+                        Some(p) => p.load(),
+                        None => lang::compile(src.as_bytes(), &[]),
+                    };
+                    if let Ok((ref bin, ref sc)) = res {
+                        save_cached(&cfg, src, bin, sc);
+                    }
+
+                    res
+                }
+            };
+
+            match res {
+                Ok((bin, sc)) => {
+                    match send_and_install(0, &backend, bin, &sc) {
+                        Ok(_) => {}
+                        Err(e) => {
+                            return Err(Error(format!(
+                                "Failed to install datapath program \"{}\": {:?}",
+                                program_name, e
+                            )));
+                        }
                     }
+                    Rc::get_mut(&mut scope_map)
+                        .unwrap()
+                        .insert(program_name.to_string(), sc.clone());
+                }
+                Err(e) => {
+                    return Err(Error(format!(
+                        "Datapath program \"{}\" failed to compile: {:?}",
+                        program_name, e
+                    )));
                 }
-                Rc::get_mut(&mut scope_map)
-                    .unwrap()
-                    .insert(program_name.to_string(), sc.clone());
             }
-            Err(e) => {
-                return Err(Error(format!(
-                    "Datapath program \"{}\" failed to compile: {:?}",
-                    program_name, e
-                )));
+        }
+
+        let batch = BatchSender::new(backend.clone(), cfg.send_batch.unwrap_or(0));
+        if cfg.resync_flows {
+            request_resync(&batch, 0)?;
+        }
+
+        Ok(Runtime {
+            backend: b,
+            state: RuntimeState {
+                cfg,
+                alg,
+                flows: HashMap::default(),
+                scope_map,
+                batch,
+                resync_pending: HashSet::default(),
+                panicked: HashSet::default(),
+                flow_panics,
+            },
+        })
+    }
+
+    /// The descriptor to wait on before calling `poll()`. See `ipc::Ipc::poll_fd()`.
+    pub fn poll_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.backend.poll_fd()
+    }
+
+    /// The number of flows which have been removed because one of their callbacks panicked.
+    pub fn num_flow_panics(&self) -> usize {
+        self.state.flow_panics.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Handle every datapath message which can be read without waiting, and send the messages
+    /// the flows batched. An error means the IPC channel has closed or carried something which
+    /// is not a message, and the runtime cannot go on.
+    pub fn poll(&mut self) -> Result<()> {
+        loop {
+            // send what the flows have batched before waiting for the datapath
+            if !self.backend.has_buffered() {
+                flush_batch(&self.state.cfg, &self.state.batch);
+            }
+
+            let res = match self.backend.try_next() {
+                Ok(ipc::TryNext::Msg(msg)) => self.state.handle(msg),
+                Ok(ipc::TryNext::WouldBlock) => return Ok(()),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = res {
+                flush_batch(&self.state.cfg, &self.state.batch);
+                return Err(e);
             }
         }
     }
+}
+
+impl<I: Ipc, U: CongAlg<I>> RuntimeState<I, U> {
+    // Dispatch one message from the datapath to the algorithm.
+    fn handle(&mut self, msg: Msg) -> Result<()> {
+        let RuntimeState {
+            ref cfg,
+            ref alg,
+            ref mut flows,
+            ref scope_map,
+            ref batch,
+            ref mut resync_pending,
+            ref mut panicked,
+            ref flow_panics,
+        } = *self;
 
-    while let Some(msg) = b.next() {
         match msg {
             Msg::Cr(c) => {
+                resync_pending.remove(&c.sid);
+                panicked.remove(&c.sid);
                 if flows.remove(&c.sid).is_some() {
                     if let Some(log) = cfg.logger.as_ref() {
                         debug!(log, "re-creating already created flow"; "sid" => c.sid);
@@ -511,41 +877,110 @@ where
                     );
                 }
 
-                let f = alg.new_flow(
-                    Datapath {
-                        sock_id: c.sid,
-                        sender: backend.clone(),
-                        programs: scope_map.clone(),
-                    },
-                    DatapathInfo {
-                        sock_id: c.sid,
-                        init_cwnd: c.init_cwnd,
-                        mss: c.mss,
-                        src_ip: c.src_ip,
-                        src_port: c.src_port,
-                        dst_ip: c.dst_ip,
-                        dst_port: c.dst_port,
-                    },
-                );
-                flows.insert(c.sid, f);
+                let dp = Datapath::new(c.sid, batch.clone(), scope_map.clone());
+                let info = DatapathInfo {
+                    sock_id: c.sid,
+                    init_cwnd: c.init_cwnd,
+                    mss: c.mss,
+                    src_ip: c.src_ip,
+                    src_port: c.src_port,
+                    dst_ip: c.dst_ip,
+                    dst_port: c.dst_port,
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| alg.new_flow(dp, info))) {
+                    Ok(f) => {
+                        flows.insert(c.sid, f);
+                    }
+                    Err(e) => {
+                        // do not bring the flow back by asking the datapath about it
+                        resync_pending.insert(c.sid);
+                        panicked.insert(c.sid);
+                        flow_panicked::<I, U>(cfg, batch, flow_panics, c.sid, "new_flow", e);
+                    }
+                }
+            }
+            Msg::Lf(l) => {
+                // the flow still runs the program it panicked with, not one it can resume from
+                if panicked.contains(&l.sid) && !cfg.fallback_on_panic {
+                    return Ok(());
+                }
+
+                resync_pending.remove(&l.sid);
+                panicked.remove(&l.sid);
+                if flows.contains_key(&l.sid) {
+                    return Ok(());
+                }
+
+                let program = scope_map
+                    .iter()
+                    .find(|&(_, sc)| sc.program_uid == l.program_uid)
+                    .map(|(name, sc)| (name.as_str(), sc.clone()));
+                if let Some(log) = cfg.logger.as_ref() {
+                    debug!(log, "resuming flow";
+                           "sid" => l.sid,
+                           "program" => program.as_ref().map(|&(name, _)| name),
+                           "program_uid" => l.program_uid,
+                    );
+                }
+
+                let dp = Datapath::new(l.sid, batch.clone(), scope_map.clone());
+                let info = DatapathInfo {
+                    sock_id: l.sid,
+                    init_cwnd: l.init_cwnd,
+                    mss: l.mss,
+                    src_ip: l.src_ip,
+                    src_port: l.src_port,
+                    dst_ip: l.dst_ip,
+                    dst_port: l.dst_port,
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| alg.resume_flow(dp, info, program))) {
+                    Ok(f) => {
+                        flows.insert(l.sid, f);
+                    }
+                    Err(e) => {
+                        resync_pending.insert(l.sid);
+                        panicked.insert(l.sid);
+                        flow_panicked::<I, U>(cfg, batch, flow_panics, l.sid, "resume_flow", e);
+                    }
+                }
             }
             Msg::Ms(m) => {
                 if flows.contains_key(&m.sid) {
                     if m.num_fields == 0 {
+                        resync_pending.remove(&m.sid);
                         let mut alg = flows.remove(&m.sid).unwrap();
-                        alg.close();
+                        if let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| alg.close())) {
+                            flow_panicked::<I, U>(cfg, batch, flow_panics, m.sid, "close", e);
+                        }
                     } else {
-                        let alg = flows.get_mut(&m.sid).unwrap();
-                        alg.on_report(
-                            m.sid,
-                            Report {
+                        let sid = m.sid;
+                        let res = {
+                            let alg = flows.get_mut(&sid).unwrap();
+                            let report = Report {
                                 program_uid: m.program_uid,
                                 fields: m.fields,
-                            },
-                        )
+                            };
+                            panic::catch_unwind(AssertUnwindSafe(|| alg.on_report(sid, report)))
+                        };
+
+                        if let Err(e) = res {
+                            flows.remove(&sid);
+                            resync_pending.insert(sid);
+                            panicked.insert(sid);
+                            flow_panicked::<I, U>(cfg, batch, flow_panics, sid, "on_report", e);
+                        }
+                    }
+                } else if m.num_fields == 0 {
+                    resync_pending.remove(&m.sid);
+                    panicked.remove(&m.sid);
+                } else {
+                    if let Some(log) = cfg.logger.as_ref() {
+                        debug!(log, "measurement for unknown flow"; "sid" => m.sid);
+                    }
+
+                    if cfg.resync_flows && resync_pending.insert(m.sid) {
+                        request_resync(batch, m.sid)?;
                     }
-                } else if let Some(log) = cfg.logger.as_ref() {
-                    debug!(log, "measurement for unknown flow"; "sid" => m.sid);
                 }
             }
             Msg::Ins(_) => {
@@ -553,14 +988,131 @@ where
                 //return Err(Error(String::from("The start() listener should never receive an install \
                 //    message, since it is on the CCP side.")));
             }
-            _ => continue,
+            _ => (),
         }
-    }
-    // if the thread has been killed, return that as error
-    if !continue_listening.load(atomic::Ordering::SeqCst) {
+
         Ok(())
-    } else {
-        Err(Error(String::from("The IPC channel has closed.")))
+    }
+}
+
+// Ask the datapath about flow `sid`, or about all its flows if `sid` is 0.
+fn request_resync<I: Ipc>(sender: &BatchSender<I>, sid: u32) -> Result<()> {
+    sender.send(&serialize::resync::Msg { sid })
+}
+
+// Send the batched messages. A failed send only loses those messages, so it is logged rather
+// than stopping the runtime.
+fn flush_batch<I: Ipc>(cfg: &Config, batch: &BatchSender<I>) {
+    if let Err(e) = batch.flush() {
+        if let Some(log) = cfg.logger.as_ref() {
+            warn!(log, "failed to send batched messages"; "error" => e.0);
+        }
+    }
+}
+
+// The program compiled from `src` by an earlier run, if the program cache has it.
+fn load_cached(cfg: &Config, src: &str) -> Option<lang::Compiled> {
+    let hash = lang::source_hash(src.as_bytes(), &[]);
+    let path = lang::Compiled::cache_path(cfg.program_cache.as_ref()?, hash);
+    if !path.exists() {
+        return None;
+    }
+
+    match lang::Compiled::load(&path) {
+        Ok(c) => {
+            if c.source_hash == hash {
+                if let Some(log) = cfg.logger.as_ref() {
+                    debug!(log, "using cached datapath program";
+                        "path" => path.display().to_string(),
+                        "program_uid" => c.scope.program_uid,
+                    );
+                }
+
+                return Some(c);
+            }
+        }
+        Err(e) => {
+            if let Some(log) = cfg.logger.as_ref() {
+                warn!(log, "ignoring unreadable cached datapath program";
+                    "path" => path.display().to_string(),
+                    "error" => e.0,
+                );
+            }
+        }
+    }
+
+    None
+}
+
+// Keep a program in the program cache, if there is one.
+fn save_cached(cfg: &Config, src: &str, bin: &Bin, sc: &Scope) {
+    let dir = match cfg.program_cache {
+        Some(ref dir) => dir,
+        None => return,
+    };
+
+    let c = lang::Compiled {
+        source_hash: lang::source_hash(src.as_bytes(), &[]),
+        bin: bin.clone(),
+        scope: sc.clone(),
+    };
+    let path = lang::Compiled::cache_path(dir, c.source_hash);
+    if let Err(e) = std::fs::create_dir_all(dir)
+        .map_err(lang::Error::from)
+        .and_then(|_| c.save(&path))
+    {
+        if let Some(log) = cfg.logger.as_ref() {
+            warn!(log, "could not cache datapath program";
+                "path" => path.display().to_string(),
+                "error" => e.0,
+            );
+        }
+    }
+}
+
+// Record that a callback of flow `sid` panicked, and hand the flow back to the datapath if
+// `Config::fallback_on_panic` is set. The caller has already removed the flow.
+fn flow_panicked<I, U>(
+    cfg: &Config,
+    batch: &BatchSender<I>,
+    flow_panics: &atomic::AtomicUsize,
+    sid: u32,
+    callback: &'static str,
+    payload: Box<dyn std::any::Any + Send>,
+) where
+    I: Ipc,
+    U: CongAlg<I>,
+{
+    flow_panics.fetch_add(1, atomic::Ordering::SeqCst);
+    if let Some(log) = cfg.logger.as_ref() {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|s| String::from(*s))
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic payload"));
+        error!(log, "flow panicked, removing it";
+            "sid" => sid,
+            "algorithm" => U::name(),
+            "callback" => callback,
+            "panic" => msg,
+        );
+    }
+
+    if cfg.fallback_on_panic {
+        let msg = serialize::changeprog::Msg {
+            sid,
+            program_uid: serialize::changeprog::FALLBACK_UID,
+            num_fields: 0,
+            fields: vec![],
+        };
+        if let Err(e) = batch.send(&msg) {
+            if let Some(log) = cfg.logger.as_ref() {
+                warn!(log, "failed to hand flow back to the datapath";
+                    "sid" => sid,
+                    "error" => e.0,
+                );
+            }
+        }
     }
 }
 
@@ -1,12 +1,20 @@
 //! CCP sends this message to change the datapath program currently in use.
+//!
+//! A message for `FALLBACK_UID` instead tells the datapath to stop running a CCP program for
+//! the flow, and to control it with its own default congestion control from then on.
 
-use super::{u32_to_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
-use lang::Reg;
+use super::update_field::{deserialize_fields, serialize_fields};
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use lang::{Reg, REG_SIZE};
 use std::io::prelude::*;
 use {Error, Result};
 
 pub(crate) const CHANGEPROG: u8 = 4;
 
+/// The `program_uid` which hands a flow back to the datapath's default congestion control.
+/// No compiled program has this uid.
+pub const FALLBACK_UID: u32 = 0;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
     pub sid: u32,
@@ -19,7 +27,7 @@ impl AsRawMsg for Msg {
     fn get_hdr(&self) -> (u8, u32, u32) {
         (
             CHANGEPROG,
-            HDR_LENGTH + 4 + 4 + self.num_fields * 13, // Reg size = 5, u64 size = 8
+            HDR_LENGTH + 4 + 4 + self.num_fields * (REG_SIZE + 8), // u64 value size = 8
             self.sid,
         )
     }
@@ -34,23 +42,22 @@ impl AsRawMsg for Msg {
     }
 
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
-        let mut buf = [0u8; 8];
-        for f in &self.fields {
-            let reg =
-                f.0.clone()
-                    .into_iter()
-                    .map(|e| e.map_err(Error::from))
-                    .collect::<Result<Vec<u8>>>()?;
-            w.write_all(&reg[..])?;
-            u64_to_u8s(&mut buf, f.1);
-            w.write_all(&buf[..])?;
-        }
-        Ok(())
+        serialize_fields(&self.fields, w)
     }
 
-    // at least for now, portus does not have to worry about deserializing this message
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 8 {
+            return Err(Error(format!("changeprog message too short: {:?}", b)));
+        }
+
+        let fields = deserialize_fields(&b[8..], u32_from_u8s(&b[4..8]))?;
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32_from_u8s(&b[0..4]),
+            num_fields: fields.len() as u32,
+            fields,
+        })
     }
 }
 
@@ -72,13 +79,21 @@ mod tests {
             buf,
             #[rustfmt::skip]
             vec![
-                4, 0,                                     // CHANGEPROG
-                29, 0,                                    // length = 12
-                1, 0, 0, 0,                               // sock_id = 1
-                7, 0, 0, 0,                               // program_uid = 7
-                1, 0, 0, 0,                               // num_fields = 1
-                2, 4, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0, // Reg::Implicit(4) <- 42
+                4, 1,                                                 // CHANGEPROG, wire version 1
+                33, 0,                                                // length = 33
+                1, 0, 0, 0,                                           // sock_id = 1
+                7, 0, 0, 0,                                           // program_uid = 7
+                1, 0, 0, 0,                                           // num_fields = 1
+                2, 4, 0, 0, 0, 0, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0, // Reg::Implicit(4) <- 42
             ],
         );
+
+        match ::serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+            (::serialize::Msg::Cp(got), _) => {
+                assert_eq!((got.sid, got.program_uid), (1, 7));
+                assert_eq!(got.fields, vec![(Reg::Implicit(4, ::lang::Type::None), 42)]);
+            }
+            _ => panic!("wrong type for message"),
+        }
     }
 }
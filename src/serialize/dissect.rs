@@ -0,0 +1,165 @@
+//! Human-readable descriptions of CCP messages, for debugging captured traffic.
+
+use super::Msg;
+use lang::{self, Reg};
+use {Error, Result};
+
+fn field(r: &Reg, v: u64) -> String {
+    let name = match *r {
+        Reg::Control(i, _) => format!("Control[{}]", i),
+        Reg::Implicit(4, _) => String::from("Cwnd"),
+        Reg::Implicit(5, _) => String::from("Rate"),
+        Reg::Implicit(i, _) => format!("Implicit[{}]", i),
+        Reg::Local(i, _) => format!("Local[{}]", i),
+        Reg::Report(i, _, _) => format!("Report[{}]", i),
+        ref r => format!("{:?}", r),
+    };
+
+    format!("{} = {}", name, v)
+}
+
+fn fields(fs: &[(Reg, u64)]) -> String {
+    fs.iter()
+        .map(|&(ref r, v)| field(r, v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describe `msg` on one line: its type, sid, program uid and fields.
+/// An install message is followed by its instructions, one per indented line.
+pub fn describe(msg: &Msg) -> String {
+    match *msg {
+        Msg::Cr(ref c) => format!(
+            "create sid={} init_cwnd={} mss={} src={}:{} dst={}:{}",
+            c.sid, c.init_cwnd, c.mss, c.src_ip, c.src_port, c.dst_ip, c.dst_port
+        ),
+        Msg::Ms(ref m) if m.num_fields == 0 => format!("close sid={}", m.sid),
+        Msg::Ms(ref m) => format!(
+            "measure sid={} program_uid={} fields={:?}",
+            m.sid, m.program_uid, m.fields
+        ),
+        Msg::Ins(ref m) => {
+            let mut s = format!("install sid={} program_uid={}\n", m.sid, m.program_uid);
+            for l in lang::disassemble(&m.instrs, None).lines() {
+                s.push_str("    ");
+                s.push_str(l);
+                s.push('\n');
+            }
+
+            s.pop();
+            s
+        }
+        Msg::Uf(ref m) => format!("update_field sid={} [{}]", m.sid, fields(&m.fields)),
+        Msg::Cp(ref m) => format!(
+            "changeprog sid={} program_uid={} [{}]",
+            m.sid,
+            m.program_uid,
+            fields(&m.fields)
+        ),
+        Msg::Rs(ref m) => format!("resync sid={}", m.sid),
+        Msg::Lf(ref l) => format!(
+            "live_flow sid={} program_uid={} init_cwnd={} mss={} src={}:{} dst={}:{}",
+            l.sid, l.program_uid, l.init_cwnd, l.mss, l.src_ip, l.src_port, l.dst_ip, l.dst_port
+        ),
+        Msg::Other(ref r) => format!(
+            "type={} sid={} len={} bytes={:?}",
+            r.typ,
+            r.sid,
+            r.len,
+            r.get_bytes().unwrap_or(&[][..])
+        ),
+    }
+}
+
+/// Describes each message in a buffer of messages laid end to end, such as one IPC read.
+/// See `dissect()`.
+pub struct Dissect<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Dissect<'a> {
+    /// The offset of the message in the buffer, and its description.
+    type Item = Result<(usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+
+        let offset = self.offset;
+        match Msg::from_buf(&self.buf[offset..]) {
+            Ok((msg, len)) => {
+                self.offset += len;
+                Some(Ok((offset, describe(&msg))))
+            }
+            Err(e) => {
+                // the rest of the buffer cannot be split into messages
+                self.offset = self.buf.len();
+                Some(Err(Error(format!("at byte {}: {}", offset, e.0))))
+            }
+        }
+    }
+}
+
+/// Describe each message in `buf`, stopping at the first which cannot be decoded.
+///
+/// ```
+/// use portus::serialize::{self, dissect, resync};
+/// let buf = serialize::serialize(&resync::Msg { sid: 7 }).unwrap();
+/// let msgs: Vec<_> = dissect::dissect(&buf).collect::<portus::Result<_>>().unwrap();
+/// assert_eq!(msgs, vec![(0, String::from("resync sid=7"))]);
+/// ```
+pub fn dissect(buf: &[u8]) -> Dissect<'_> {
+    Dissect { buf, offset: 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use lang::Reg;
+    use serialize::{self, create, update_field};
+
+    #[test]
+    fn dissect_buf() {
+        let mut buf = serialize::serialize(&create::Msg {
+            sid: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .unwrap();
+        let create_len = buf.len();
+        buf.extend(
+            serialize::serialize(&update_field::Msg {
+                sid: 1,
+                num_fields: 1,
+                fields: vec![(Reg::Implicit(4, ::lang::Type::Num(None)), 2896)],
+            })
+            .unwrap(),
+        );
+        // a header promising more bytes than there are
+        buf.extend(&[0xff, 0, 0x40, 0, 1, 0, 0, 0]);
+
+        let msgs: Vec<_> = super::dissect(&buf).collect();
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(
+            msgs[0].as_ref().unwrap(),
+            &(
+                0,
+                String::from("create sid=1 init_cwnd=14480 mss=1448 src=0:4242 dst=0:4242")
+            )
+        );
+        assert_eq!(
+            msgs[1].as_ref().unwrap(),
+            &(create_len, String::from("update_field sid=1 [Cwnd = 2896]"))
+        );
+        assert!(msgs[2]
+            .as_ref()
+            .unwrap_err()
+            .0
+            .starts_with(&format!("at byte {}", buf.len() - 8)));
+    }
+}
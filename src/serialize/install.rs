@@ -1,9 +1,9 @@
 //! CCP sends this message containing a datapath program.
 
-use super::{u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
-use lang::Bin;
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use lang::{self, Bin, Scope, EVENT_SIZE, INSTR_SIZE};
 use std::io::prelude::*;
-use Result;
+use {Error, Result};
 
 pub(crate) const INSTALL: u8 = 2;
 
@@ -20,7 +20,7 @@ impl AsRawMsg for Msg {
     fn get_hdr(&self) -> (u8, u32, u32) {
         (
             INSTALL,
-            HDR_LENGTH + 12 + (self.num_events * 16 + self.num_instrs * 16),
+            HDR_LENGTH + 12 + (self.num_events * EVENT_SIZE + self.num_instrs * INSTR_SIZE),
             self.sid,
         )
     }
@@ -42,9 +42,37 @@ impl AsRawMsg for Msg {
         Ok(())
     }
 
-    // at least for now, portus doesn't have to worry about deserializing this
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+    // portus only reads these back to inspect captured messages, so the registers in `instrs`
+    // are untyped; see `Reg::deserialize()`.
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 12 {
+            return Err(Error(format!("install message too short: {:?}", b)));
+        }
+
+        let num_events = u32_from_u8s(&b[4..8]);
+        let num_instrs = u32_from_u8s(&b[8..12]);
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32_from_u8s(&b[0..4]),
+            num_events,
+            num_instrs,
+            instrs: Bin::deserialize(&b[12..], num_events, num_instrs)?,
+        })
+    }
+}
+
+/// Disassemble the program in the serialized install message `buf`, naming its registers with
+/// `sc` if it is known. See `lang::disassemble()`.
+pub fn disassemble(buf: &[u8], sc: Option<&Scope>) -> Result<String> {
+    match super::Msg::from_buf(buf)? {
+        (super::Msg::Ins(m), _) => Ok(format!(
+            "program {} (sid {}):\n{}",
+            m.program_uid,
+            m.sid,
+            lang::disassemble(&m.instrs, sc)
+        )),
+        (m, _) => Err(Error(format!("not an install message: {:?}", m))),
     }
 }
 
@@ -74,19 +102,35 @@ mod tests {
         let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
         assert_eq!(
             buf,
+            #[rustfmt::skip]
             vec![
-                2, 0, // INSTALL
-                84, 0, // length = 84
+                2, 1, // INSTALL, wire version 1
+                120, 0, // length = 120
                 1, 0, 0, 0, // sock_id = 1
                 7, 0, 0, 0, // program_uid = 7
                 1, 0, 0, 0, // num_events = 1
                 3, 0, 0, 0, // num_instrs = 3
-                1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0,
-                0, // event { flag-idx=1, num-flag=1, body-idx=2, num-body=1 }
-                2, 5, 0, 0, 0, 0, 5, 0, 0, 0, 0, 1, 0, 0, 0, 0, // (def (Report.foo 0))
-                1, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0, 1, 1, 0, 0, 0, // (when true
-                1, 5, 0, 0, 0, 0, 5, 0, 0, 0, 0, 1, 4, 0, 0, 0, //     (bind Report.foo 4))
+                1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, // event { flag-idx=1, num-flag=1, body-idx=2, num-body=1 }
+                2, 5, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, // (def (Report.foo 0))
+                1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, // (when true
+                1, 5, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 4, 0, 0, 0, 0, 0, 0, 0, //     (bind Report.foo 4))
             ],
         );
+
+        // the decoded program re-serializes to the same bytes
+        match ::serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+            (::serialize::Msg::Ins(got), len) => {
+                assert_eq!(len, buf.len());
+                assert_eq!((got.sid, got.program_uid), (1, 7));
+                assert_eq!(got.instrs.events, m.instrs.events);
+                let again = ::serialize::serialize::<super::Msg>(&got).expect("reserialize");
+                assert_eq!(again, buf);
+            }
+            _ => panic!("wrong type for message"),
+        }
+
+        let out = super::disassemble(&buf[..], Some(&sc)).expect("disassemble");
+        assert!(out.starts_with("program 7 (sid 1):\ndef:\n"));
+        assert!(out.contains("Report.foo = bind Report.foo 4\n"));
     }
 }
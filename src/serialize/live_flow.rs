@@ -0,0 +1,94 @@
+//! The datapath sends this message for each of its flows when CCP asks with a `resync::Msg`.
+//! It carries what the `create::Msg` for the flow did, and the uid of the program the flow
+//! is running.
+
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use std::io::prelude::*;
+use {Error, Result};
+
+pub(crate) const LIVE_FLOW: u8 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u32,
+    pub program_uid: u32,
+    pub init_cwnd: u32,
+    pub mss: u32,
+    pub src_ip: u32,
+    pub src_port: u32,
+    pub dst_ip: u32,
+    pub dst_port: u32,
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (LIVE_FLOW, HDR_LENGTH + 7 * 4, self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        for &v in &[
+            self.program_uid,
+            self.init_cwnd,
+            self.mss,
+            self.src_ip,
+            self.src_port,
+            self.dst_ip,
+            self.dst_port,
+        ] {
+            u32_to_u8s(&mut buf, v);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 7 * 4 {
+            return Err(Error(format!("live flow message too short: {:?}", b)));
+        }
+
+        let u32s: Vec<u32> = b.chunks(4).take(7).map(u32_from_u8s).collect();
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32s[0],
+            init_cwnd: u32s[1],
+            mss: u32s[2],
+            src_ip: u32s[3],
+            src_port: u32s[4],
+            dst_ip: u32s[5],
+            dst_port: u32s[6],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_live_flow_1,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 0xdead_beef,
+            init_cwnd: 1448 * 10,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+        },
+        ::serialize::Msg::Lf(lfm),
+        lfm
+    );
+
+    #[test]
+    fn live_flow_too_short() {
+        let buf = [6, 0, 12, 0, 15, 0, 0, 0, 1, 0, 0, 0];
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+    }
+}
@@ -10,7 +10,10 @@
 //! total: 8 Bytes
 //! ```
 //!
-//! Message types 0-3 are reserved for predefined message types. All other types are treated as
+//! The high byte of the message type is the wire version of the message's layout (see
+//! `WIRE_VERSION`), and the low byte is the message type itself.
+//!
+//! Message types 0-6 are reserved for predefined message types. All other types are treated as
 //! "unknown" - the header will be parsed, and raw access to the remaining bytes is available
 //! through `RawMsg::get_bytes()`.
 //!
@@ -58,22 +61,46 @@ pub(crate) fn u64_from_u8s(buf: &[u8]) -> u64 {
 }
 
 pub const HDR_LENGTH: u32 = 8;
-fn serialize_header(typ: u8, len: u32, sid: u32) -> Vec<u8> {
-    let mut hdr = [0u8; 8];
-    u16_to_u8s(&mut hdr[0..2], u16::from(typ));
+
+/// The wire version of the install, update_field and changeprog messages, whose registers,
+/// immediates and values are 64 bits wide and whose instructions include the signed opcodes.
+/// The datapaths libccp 0.0.7 supports read an older layout, version 0, and do not know the
+/// message types these versioned messages have, so they reject them instead of misreading them.
+pub const WIRE_VERSION: u8 = 1;
+
+// The wire version of messages of type `typ`. The layout of the other messages has not changed.
+fn wire_version(typ: u8) -> u8 {
+    match typ {
+        install::INSTALL | update_field::UPDATE_FIELD | changeprog::CHANGEPROG => WIRE_VERSION,
+        _ => 0,
+    }
+}
+
+fn serialize_header(hdr: &mut [u8], typ: u8, len: u32, sid: u32) {
+    u16_to_u8s(
+        &mut hdr[0..2],
+        u16::from(wire_version(typ)) << 8 | u16::from(typ),
+    );
     u16_to_u8s(&mut hdr[2..4], len as u16);
-    u32_to_u8s(&mut hdr[4..], sid);
-    hdr.to_vec()
+    u32_to_u8s(&mut hdr[4..8], sid);
 }
 
 fn deserialize_header<R: Read>(buf: &mut R) -> Result<(u8, u32, u32)> {
     let mut hdr = [0u8; 8];
     buf.read_exact(&mut hdr)?;
-    let typ = u16_from_u8s(&hdr[0..2]);
+    let (typ, version) = (hdr[0], hdr[1]);
     let len = u16_from_u8s(&hdr[2..4]);
     let sid = u32_from_u8s(&hdr[4..]);
+    if version != wire_version(typ) {
+        return Err(super::Error(format!(
+            "message type {} has wire version {}, but portus speaks version {}",
+            typ,
+            version,
+            wire_version(typ)
+        )));
+    }
 
-    Ok((typ as u8, u32::from(len), sid))
+    Ok((typ, u32::from(len), sid))
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -92,7 +119,6 @@ impl<'a> RawMsg<'a> {
         match self.typ {
             create::CREATE => Ok(mem::transmute(&self.bytes[0..(4 * 6)])),
             measure::MEASURE => Ok(mem::transmute(&self.bytes[0..8])),
-            update_field::UPDATE_FIELD => Ok(mem::transmute(&self.bytes[0..4])),
             _ => Ok(&[]),
         }
     }
@@ -102,9 +128,6 @@ impl<'a> RawMsg<'a> {
     pub fn get_bytes(&self) -> Result<&'a [u8]> {
         match self.typ {
             measure::MEASURE => Ok(&self.bytes[8..(self.len as usize - HDR_LENGTH as usize)]),
-            update_field::UPDATE_FIELD => {
-                Ok(&self.bytes[4..(self.len as usize - HDR_LENGTH as usize)])
-            }
             _ => Ok(self.bytes),
         }
     }
@@ -153,21 +176,56 @@ mod test_helper {
 
 pub mod changeprog;
 pub mod create;
+pub mod dissect;
 pub mod install;
+pub mod live_flow;
 pub mod measure;
+pub mod resync;
 mod testmsg;
 pub mod update_field;
 
 /// Serialize a serializable message.
 pub fn serialize<T: AsRawMsg>(m: &T) -> Result<Vec<u8>> {
     let (a, b, c) = m.get_hdr();
-    let mut msg = serialize_header(a, b, c);
+    let mut hdr = [0u8; HDR_LENGTH as usize];
+    serialize_header(&mut hdr, a, b, c);
+    let mut msg = Vec::with_capacity(b as usize);
+    msg.extend_from_slice(&hdr);
     m.get_u32s(&mut msg)?;
     m.get_u64s(&mut msg)?;
     m.get_bytes(&mut msg)?;
     Ok(msg)
 }
 
+/// Serialize a serializable message into the start of `buf`, and return its length.
+/// Unlike `serialize()`, this does not allocate, so a caller can reuse one buffer for many
+/// messages. The message must be exactly as long as its header says.
+pub fn serialize_to_buf<T: AsRawMsg>(m: &T, buf: &mut [u8]) -> Result<usize> {
+    let (typ, len, sid) = m.get_hdr();
+    let len = len as usize;
+    if len < HDR_LENGTH as usize || len > buf.len() {
+        return Err(super::Error(format!(
+            "message of {} bytes does not fit in buffer of {}",
+            len,
+            buf.len()
+        )));
+    }
+
+    serialize_header(&mut buf[..HDR_LENGTH as usize], typ, len as u32, sid);
+    let mut w = &mut buf[HDR_LENGTH as usize..len];
+    m.get_u32s(&mut w)?;
+    m.get_u64s(&mut w)?;
+    m.get_bytes(&mut w)?;
+    if !w.is_empty() {
+        return Err(super::Error(format!(
+            "message is {} bytes shorter than its header says",
+            w.len()
+        )));
+    }
+
+    Ok(len)
+}
+
 fn deserialize(buf: &[u8]) -> Result<RawMsg> {
     let mut buf = Cursor::new(buf);
     let (typ, len, sid) = deserialize_header(&mut buf)?;
@@ -178,6 +236,16 @@ fn deserialize(buf: &[u8]) -> Result<RawMsg> {
         )));
     }
 
+    if len as usize > buf.get_ref().len() {
+        return Err(super::Error(format!(
+            "truncated message: ({}, {}, {}), only {} bytes",
+            typ,
+            len,
+            sid,
+            buf.get_ref().len()
+        )));
+    }
+
     let i = buf.position();
     Ok(RawMsg {
         typ,
@@ -196,6 +264,10 @@ pub enum Msg<'a> {
     Cr(create::Msg),
     Ms(measure::Msg),
     Ins(install::Msg),
+    Uf(update_field::Msg),
+    Cp(changeprog::Msg),
+    Rs(resync::Msg),
+    Lf(live_flow::Msg),
     Other(RawMsg<'a>),
 }
 
@@ -205,7 +277,10 @@ impl<'a> Msg<'a> {
             create::CREATE => Ok(Msg::Cr(create::Msg::from_raw_msg(m)?)),
             measure::MEASURE => Ok(Msg::Ms(measure::Msg::from_raw_msg(m)?)),
             install::INSTALL => Ok(Msg::Ins(install::Msg::from_raw_msg(m)?)),
-            update_field::UPDATE_FIELD => unimplemented!(),
+            update_field::UPDATE_FIELD => Ok(Msg::Uf(update_field::Msg::from_raw_msg(m)?)),
+            changeprog::CHANGEPROG => Ok(Msg::Cp(changeprog::Msg::from_raw_msg(m)?)),
+            resync::RESYNC => Ok(Msg::Rs(resync::Msg::from_raw_msg(m)?)),
+            live_flow::LIVE_FLOW => Ok(Msg::Lf(live_flow::Msg::from_raw_msg(m)?)),
             _ => Ok(Msg::Other(m)),
         }
     }
@@ -284,6 +359,25 @@ mod tests {
         assert_eq!(x, 4755873775377990144);
     }
 
+    #[test]
+    fn test_wire_version() {
+        // an update_field message in the layout of wire version 0, with a 32-bit value
+        let old = [
+            3, 0, 21, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2, 4, 0, 0, 0, 42, 0, 0, 0,
+        ];
+        match Msg::from_buf(&old[..]) {
+            Err(e) => assert_eq!(
+                e.0,
+                "message type 3 has wire version 0, but portus speaks version 1"
+            ),
+            Ok(m) => panic!("read an old update_field message as {:?}", m),
+        }
+
+        // the messages whose layout has not changed still have wire version 0
+        let buf = super::serialize(&super::resync::Msg { sid: 1 }).expect("serialize");
+        assert_eq!(&buf[..2], &[super::resync::RESYNC, 0][..]);
+    }
+
     #[test]
     fn test_other_msg() {
         use super::testmsg;
@@ -300,6 +394,35 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_serialize_to_buf() {
+        use super::testmsg;
+        let m = testmsg::Msg(String::from("testing"));
+        let v = super::serialize(&m).expect("serialize");
+
+        let mut buf = [0u8; 64];
+        let len = super::serialize_to_buf(&m, &mut buf[..]).expect("serialize_to_buf");
+        assert_eq!(&buf[..len], &v[..]);
+        assert!(super::serialize_to_buf(&m, &mut buf[..len - 1]).is_err());
+
+        // the buffer is reused for a shorter message
+        let m = testmsg::Msg(String::from("foo"));
+        let len = super::serialize_to_buf(&m, &mut buf[..]).expect("serialize_to_buf");
+        let (msg, _) = Msg::from_buf(&buf[..len]).expect("deserialize");
+        match msg {
+            Msg::Other(raw) => assert_eq!(raw.get_bytes().unwrap(), b"foo"),
+            _ => panic!("wrong type for message"),
+        }
+    }
+
+    #[test]
+    fn test_truncated_msg() {
+        use super::testmsg;
+        let m = testmsg::Msg(String::from("testing"));
+        let buf: Vec<u8> = super::serialize::<testmsg::Msg>(&m).expect("serialize");
+        assert!(Msg::from_buf(&buf[..buf.len() - 1]).is_err());
+    }
+
     #[test]
     fn test_multi_msg() {
         use super::testmsg;
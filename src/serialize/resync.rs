@@ -0,0 +1,47 @@
+//! CCP sends this message to ask the datapath which flows it has. The datapath answers with a
+//! `live_flow::Msg` for each live flow, or only for flow `sid` if `sid` is not 0.
+//!
+//! CCP sends it when it starts, since a restarted CCP knows nothing about the flows which
+//! started before it, and when it gets a measurement from a flow it does not know.
+
+use super::{AsRawMsg, RawMsg, HDR_LENGTH};
+use std::io::prelude::*;
+use Result;
+
+pub(crate) const RESYNC: u8 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u32,
+}
+
+impl AsRawMsg for Msg {
+    fn get_hdr(&self) -> (u8, u32, u32) {
+        (RESYNC, HDR_LENGTH, self.sid)
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        Ok(Msg { sid: msg.sid })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_resync_all,
+        super::Msg,
+        super::Msg { sid: 0 },
+        ::serialize::Msg::Rs(rsm),
+        rsm
+    );
+
+    #[test]
+    fn serialize_resync_msg() {
+        let buf = ::serialize::serialize(&super::Msg { sid: 3 }).expect("serialize");
+        assert_eq!(buf, vec![5, 0, 8, 0, 3, 0, 0, 0]);
+    }
+}
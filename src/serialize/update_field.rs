@@ -1,8 +1,8 @@
 //! CCP sends this message specifying that the datapath should set the values of the
 //! given fields to the given values.
 
-use super::{u32_to_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
-use lang::Reg;
+use super::{u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use lang::{Reg, REG_SIZE};
 use std::io::prelude::*;
 use {Error, Result};
 
@@ -19,7 +19,7 @@ impl AsRawMsg for Msg {
     fn get_hdr(&self) -> (u8, u32, u32) {
         (
             UPDATE_FIELD,
-            HDR_LENGTH + 4 + u32::from(self.num_fields) * 13, // Reg size = 5, u64 size = 8
+            HDR_LENGTH + 4 + u32::from(self.num_fields) * (REG_SIZE + 8), // u64 value size = 8
             self.sid,
         )
     }
@@ -32,24 +32,58 @@ impl AsRawMsg for Msg {
     }
 
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
-        let mut buf = [0u8; 8];
-        for f in &self.fields {
-            let reg =
-                f.0.clone()
-                    .into_iter()
-                    .map(|e| e.map_err(Error::from))
-                    .collect::<Result<Vec<u8>>>()?;
-            w.write_all(&reg[..])?;
-            u64_to_u8s(&mut buf, f.1);
-            w.write_all(&buf[..])?;
+        serialize_fields(&self.fields, w)
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 4 {
+            return Err(Error(format!("update_field message too short: {:?}", b)));
         }
 
-        Ok(())
+        let fields = deserialize_fields(&b[4..], u32_from_u8s(&b[0..4]))?;
+        Ok(Msg {
+            sid: msg.sid,
+            num_fields: fields.len() as u8,
+            fields,
+        })
     }
+}
 
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!()
+/// Write (register, value) pairs, without allocating.
+pub(crate) fn serialize_fields<W: Write>(fields: &[(Reg, u64)], w: &mut W) -> Result<()> {
+    let mut buf = [0u8; REG_SIZE as usize + 8];
+    for f in fields {
+        f.0.serialize_to(&mut buf[..REG_SIZE as usize])?;
+        u64_to_u8s(&mut buf[REG_SIZE as usize..], f.1);
+        w.write_all(&buf[..])?;
     }
+
+    Ok(())
+}
+
+/// Read `num_fields` (register, value) pairs, as written by `serialize_fields()`.
+/// The registers are untyped; see `Reg::deserialize()`.
+pub(crate) fn deserialize_fields(buf: &[u8], num_fields: u32) -> Result<Vec<(Reg, u64)>> {
+    let field_size = (REG_SIZE + 8) as usize;
+    let fits = (num_fields as usize)
+        .checked_mul(field_size)
+        .map_or(false, |len| len <= buf.len());
+    if !fits {
+        return Err(Error(format!(
+            "{} fields do not fit in {} bytes",
+            num_fields,
+            buf.len()
+        )));
+    }
+
+    buf.chunks(field_size)
+        .take(num_fields as usize)
+        .map(|f| {
+            let reg = Reg::deserialize(&f[..REG_SIZE as usize])?;
+            Ok((reg, u64_from_u8s(&f[REG_SIZE as usize..])))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -67,13 +101,70 @@ mod tests {
         let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
         assert_eq!(
             buf,
+            #[rustfmt::skip]
             vec![
-                3, 0, // UPDATE_FIELD
-                25, 0, // length = 25
+                3, 1, // UPDATE_FIELD, wire version 1
+                29, 0, // length = 29
                 1, 0, 0, 0, // sock_id = 1
                 1, 0, 0, 0, // num_fields = 1
-                2, 4, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0, // Reg::Implicit(4) <- 42
+                2, 4, 0, 0, 0, 0, 0, 0, 0, 0x2a, 0, 0, 0, 0, 0, 0, 0, // Reg::Implicit(4) <- 42
             ],
         );
     }
+
+    #[test]
+    fn serialize_update_msg_u64() {
+        let m = super::Msg {
+            sid: 1,
+            num_fields: 1,
+            fields: vec![(
+                Reg::Implicit(5, ::lang::Type::Num(None)),
+                12_500_000_000, // 100 Gbit/s in bytes/s
+            )],
+        };
+
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m.clone()).expect("serialize");
+        assert_eq!(
+            &buf[12..],
+            &[2, 5, 0, 0, 0, 0, 0, 0, 0, 0x00, 0xdd, 0x0e, 0xe9, 0x02, 0, 0, 0][..],
+        );
+    }
+
+    #[test]
+    fn deserialize_update_msg() {
+        let m = super::Msg {
+            sid: 3,
+            num_fields: 2,
+            fields: vec![
+                (Reg::Control(1, ::lang::Type::Num(None)), 7),
+                (Reg::Implicit(4, ::lang::Type::Num(None)), 42),
+            ],
+        };
+
+        let buf: Vec<u8> = ::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        match ::serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+            (::serialize::Msg::Uf(got), _) => {
+                assert_eq!(got.sid, 3);
+                assert_eq!(
+                    got.fields,
+                    vec![
+                        (Reg::Control(1, ::lang::Type::None), 7),
+                        (Reg::Implicit(4, ::lang::Type::None), 42),
+                    ]
+                );
+            }
+            _ => panic!("wrong type for message"),
+        }
+    }
+
+    #[test]
+    fn deserialize_update_msg_short() {
+        // a header promising 4 bytes of body, with only 2
+        let buf = [3, 1, 14, 0, 1, 0, 0, 0, 1, 0];
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+
+        // a field count whose size does not fit
+        let buf = [3, 1, 12, 0, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        assert!(::serialize::Msg::from_buf(&buf[..]).is_err());
+    }
 }
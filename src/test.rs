@@ -86,3 +86,786 @@ fn bench_ser_over_ipc(b: &mut Bencher) {
         );
     });
 }
+
+struct PanickingAlg(crossbeam::channel::Sender<u32>);
+
+struct PanickingFlow(crossbeam::channel::Sender<u32>);
+
+impl super::Flow for PanickingFlow {
+    fn on_report(&mut self, sock_id: u32, _m: super::Report) {
+        if sock_id == 1 {
+            panic!("on_report panic in flow {}", sock_id);
+        }
+
+        self.0.send(sock_id).expect("report chan send");
+    }
+}
+
+impl<I: ipc::Ipc> super::CongAlg<I> for PanickingAlg {
+    type Flow = PanickingFlow;
+
+    fn name() -> &'static str {
+        "panicking"
+    }
+
+    fn datapath_programs(&self) -> ::fnv::FnvHashMap<&'static str, String> {
+        let mut h = ::fnv::FnvHashMap::default();
+        h.insert(
+            "TestProg",
+            "(def (Report.acked 0)) (when true (:= Report.acked Ack.bytes_acked))".to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, _control: super::Datapath<I>, _info: super::DatapathInfo) -> Self::Flow {
+        PanickingFlow(self.0.clone())
+    }
+}
+
+#[test]
+fn test_flow_panic_isolated() {
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded();
+    let (report_tx, report_rx) = crossbeam::channel::unbounded();
+
+    let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: sk },
+        super::Config::default(),
+        PanickingAlg(report_tx),
+    );
+
+    // wait for the program install
+    from_ccp
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("install message");
+
+    let create = |sid| serialize::create::Msg {
+        sid,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4242,
+    };
+    let measure = |sid| serialize::measure::Msg {
+        sid,
+        program_uid: 1,
+        num_fields: 1,
+        fields: vec![42],
+    };
+
+    for sid in 1..3 {
+        let buf = serialize::serialize(&create(sid)).expect("serialize");
+        to_ccp.send(buf).expect("send create");
+    }
+
+    for sid in 1..3 {
+        let buf = serialize::serialize(&measure(sid)).expect("serialize");
+        to_ccp.send(buf).expect("send measure");
+    }
+
+    // flow 2 is unaffected by flow 1's panic
+    assert_eq!(
+        report_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("report from flow 2"),
+        2
+    );
+    assert_eq!(h.num_flow_panics(), 1);
+
+    // flow 1 was removed, so another measurement does not panic again
+    let buf = serialize::serialize(&measure(1)).expect("serialize");
+    to_ccp.send(buf).expect("send measure");
+    let buf = serialize::serialize(&measure(2)).expect("serialize");
+    to_ccp.send(buf).expect("send measure");
+    assert_eq!(
+        report_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("report from flow 2"),
+        2
+    );
+    assert_eq!(h.num_flow_panics(), 1);
+
+    h.kill();
+    h.wait().expect("ccp exits cleanly");
+}
+
+#[test]
+fn test_flow_panic_fallback() {
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded::<Vec<u8>>();
+    let (report_tx, _report_rx) = crossbeam::channel::unbounded();
+
+    let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: sk },
+        super::Config {
+            fallback_on_panic: true,
+            ..Default::default()
+        },
+        PanickingAlg(report_tx),
+    );
+
+    let timeout = std::time::Duration::from_secs(5);
+    from_ccp.recv_timeout(timeout).expect("install message");
+    let create = serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4242,
+    };
+    to_ccp
+        .send(serialize::serialize(&create).expect("serialize"))
+        .expect("send create");
+    let measure = serialize::measure::Msg {
+        sid: 1,
+        program_uid: 1,
+        num_fields: 1,
+        fields: vec![42],
+    };
+    to_ccp
+        .send(serialize::serialize(&measure).expect("serialize"))
+        .expect("send measure");
+
+    // flow 1 panics in on_report, and is handed back to the datapath
+    let buf = from_ccp.recv_timeout(timeout).expect("fallback message");
+    match serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+        (serialize::Msg::Cp(m), _) => {
+            assert_eq!(m.sid, 1);
+            assert_eq!(m.program_uid, serialize::changeprog::FALLBACK_UID);
+            assert!(m.fields.is_empty());
+        }
+        (m, _) => panic!("expected a changeprog message, got {:?}", m),
+    }
+    assert_eq!(h.num_flow_panics(), 1);
+
+    h.kill();
+    h.wait().expect("ccp exits cleanly");
+}
+
+#[test]
+fn test_program_cache_keeps_uid() {
+    let dir = std::env::temp_dir().join(format!("portus-program-cache-{}", std::process::id()));
+    let installed_uid = || {
+        let (to_ccp, from_dp) = crossbeam::channel::unbounded::<Vec<u8>>();
+        let (to_dp, from_ccp) = crossbeam::channel::unbounded();
+        let (report_tx, _) = crossbeam::channel::unbounded();
+        let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+        let h = super::spawn(
+            ipc::BackendBuilder { sock: sk },
+            super::Config {
+                program_cache: Some(dir.clone()),
+                ..Default::default()
+            },
+            PanickingAlg(report_tx),
+        );
+
+        let buf = from_ccp
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("install message");
+        h.kill();
+        drop(to_ccp);
+        h.wait().ok();
+        match serialize::Msg::from_buf(&buf[..]).expect("parse install") {
+            (serialize::Msg::Ins(m), _) => m.program_uid,
+            (m, _) => panic!("expected install message, got {:?}", m),
+        }
+    };
+
+    let first = installed_uid();
+    // the uid does not depend on what else was compiled first
+    let _ = ::lang::compile(b"(def (Report.a 0)) (when true (report))", &[]);
+    assert_eq!(installed_uid(), first);
+    std::fs::remove_dir_all(&dir).expect("remove cache");
+}
+
+struct ResumingAlg {
+    resumed: crossbeam::channel::Sender<(u32, Option<String>)>,
+    reports: crossbeam::channel::Sender<u32>,
+}
+
+const RESUMING_PROG: &str = "(def (Report.acked 0)) (when true (:= Report.acked Ack.bytes_acked))";
+
+impl<I: ipc::Ipc> super::CongAlg<I> for ResumingAlg {
+    type Flow = PanickingFlow;
+
+    fn name() -> &'static str {
+        "resuming"
+    }
+
+    fn datapath_programs(&self) -> ::fnv::FnvHashMap<&'static str, String> {
+        let mut h = ::fnv::FnvHashMap::default();
+        h.insert("TestProg", RESUMING_PROG.to_owned());
+        h
+    }
+
+    fn new_flow(&self, _control: super::Datapath<I>, _info: super::DatapathInfo) -> Self::Flow {
+        PanickingFlow(self.reports.clone())
+    }
+
+    fn resume_flow(
+        &self,
+        _control: super::Datapath<I>,
+        info: super::DatapathInfo,
+        program: Option<(&str, ::lang::Scope)>,
+    ) -> Self::Flow {
+        let program = program.map(|(name, _)| String::from(name));
+        self.resumed
+            .send((info.sock_id, program))
+            .expect("resume chan send");
+        PanickingFlow(self.reports.clone())
+    }
+}
+
+#[test]
+fn test_resync_flows() {
+    let timeout = std::time::Duration::from_secs(5);
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded();
+    let (resumed_tx, resumed_rx) = crossbeam::channel::unbounded();
+    let (report_tx, report_rx) = crossbeam::channel::unbounded();
+
+    let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: sk },
+        super::Config {
+            resync_flows: true,
+            ..Default::default()
+        },
+        ResumingAlg {
+            resumed: resumed_tx,
+            reports: report_tx,
+        },
+    );
+
+    let recv = || {
+        let buf: Vec<u8> = from_ccp.recv_timeout(timeout).expect("message from ccp");
+        match serialize::Msg::from_buf(&buf[..]).expect("parse") {
+            (serialize::Msg::Ins(m), _) => Err(m.program_uid),
+            (serialize::Msg::Rs(m), _) => Ok(m.sid),
+            (m, _) => panic!("unexpected message {:?}", m),
+        }
+    };
+    let send = |buf: Vec<u8>| to_ccp.send(buf).expect("send to ccp");
+    let live_flow = |sid, program_uid| {
+        serialize::serialize(&serialize::live_flow::Msg {
+            sid,
+            program_uid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .expect("serialize")
+    };
+    let measure = |sid| {
+        serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 1,
+            num_fields: 1,
+            fields: vec![42],
+        })
+        .expect("serialize")
+    };
+
+    // the datapath is asked about all its flows once the programs are installed
+    let (_, sc) = ::lang::compile(RESUMING_PROG.as_bytes(), &[]).expect("compile");
+    assert_eq!(recv(), Err(sc.program_uid));
+    assert_eq!(recv(), Ok(0));
+
+    send(live_flow(5, sc.program_uid));
+    assert_eq!(
+        resumed_rx.recv_timeout(timeout).expect("resumed flow"),
+        (5, Some(String::from("TestProg")))
+    );
+
+    // a measurement from an unknown flow asks about that flow, once
+    send(measure(7));
+    send(measure(7));
+    send(measure(5));
+    assert_eq!(report_rx.recv_timeout(timeout).expect("report"), 5);
+    assert_eq!(recv(), Ok(7));
+    assert!(from_ccp.try_recv().is_err());
+
+    send(live_flow(7, 0));
+    send(measure(7));
+    assert_eq!(
+        resumed_rx.recv_timeout(timeout).expect("resumed flow"),
+        (7, None)
+    );
+    assert_eq!(report_rx.recv_timeout(timeout).expect("report"), 7);
+
+    // a flow removed after a panic is not resumed, until it is closed
+    send(live_flow(1, sc.program_uid));
+    send(measure(1));
+    send(live_flow(1, sc.program_uid));
+    send(live_flow(9, sc.program_uid));
+    assert_eq!(resumed_rx.recv_timeout(timeout).expect("resumed flow").0, 1);
+    assert_eq!(resumed_rx.recv_timeout(timeout).expect("resumed flow").0, 9);
+    assert_eq!(h.num_flow_panics(), 1);
+    assert!(from_ccp.try_recv().is_err());
+
+    send(
+        serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid: 1,
+            num_fields: 0,
+            fields: vec![],
+        })
+        .expect("serialize"),
+    );
+    send(live_flow(1, sc.program_uid));
+    assert_eq!(resumed_rx.recv_timeout(timeout).expect("resumed flow").0, 1);
+
+    h.kill();
+    drop(to_ccp);
+    h.wait().ok();
+}
+
+struct UpdatingAlg;
+
+struct UpdatingFlow<I: ipc::Ipc> {
+    control: super::Datapath<I>,
+    sc: ::lang::Scope,
+}
+
+impl<I: ipc::Ipc> super::Flow for UpdatingFlow<I> {
+    fn on_report(&mut self, _sock_id: u32, _m: super::Report) {
+        use super::DatapathTrait;
+        self.control
+            .update_field(&self.sc, &[("Cwnd", 42)])
+            .expect("update cwnd");
+    }
+}
+
+impl<I: ipc::Ipc> super::CongAlg<I> for UpdatingAlg {
+    type Flow = UpdatingFlow<I>;
+
+    fn name() -> &'static str {
+        "updating"
+    }
+
+    fn datapath_programs(&self) -> ::fnv::FnvHashMap<&'static str, String> {
+        let mut h = ::fnv::FnvHashMap::default();
+        h.insert("TestProg", RESUMING_PROG.to_owned());
+        h
+    }
+
+    fn new_flow(&self, mut control: super::Datapath<I>, _info: super::DatapathInfo) -> Self::Flow {
+        use super::DatapathTrait;
+        let sc = control.set_program("TestProg", None).expect("set program");
+        UpdatingFlow { control, sc }
+    }
+}
+
+#[test]
+fn test_send_batch() {
+    let timeout = std::time::Duration::from_secs(5);
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded();
+
+    let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: sk },
+        super::Config {
+            send_batch: Some(1500),
+            ..Default::default()
+        },
+        UpdatingAlg,
+    );
+
+    // the messages in one write from ccp
+    let recv = || {
+        let buf: Vec<u8> = from_ccp.recv_timeout(timeout).expect("message from ccp");
+        let mut msgs = vec![];
+        let mut off = 0;
+        while off < buf.len() {
+            let (msg, len) = serialize::Msg::from_buf(&buf[off..]).expect("parse");
+            msgs.push(match msg {
+                serialize::Msg::Ins(_) => "install",
+                serialize::Msg::Cp(_) => "changeprog",
+                serialize::Msg::Uf(_) => "update_field",
+                m => panic!("unexpected message {:?}", m),
+            });
+            off += len;
+        }
+
+        msgs
+    };
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .expect("serialize")
+    };
+    let measure = |sid| {
+        serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 1,
+            num_fields: 1,
+            fields: vec![42],
+        })
+        .expect("serialize")
+    };
+
+    assert_eq!(recv(), vec!["install"]);
+
+    // the flows created by one read set their programs in one write
+    let mut buf = create(1);
+    buf.extend(create(2));
+    to_ccp.send(buf).expect("send creates");
+    assert_eq!(recv(), vec!["changeprog", "changeprog"]);
+
+    let mut buf = measure(1);
+    buf.extend(measure(2));
+    to_ccp.send(buf).expect("send measures");
+    assert_eq!(recv(), vec!["update_field", "update_field"]);
+    assert!(from_ccp.try_recv().is_err());
+
+    h.kill();
+    drop(to_ccp);
+    h.wait().ok();
+}
+
+fn test_report() -> (super::Report, ::lang::Scope) {
+    let (_, sc) = ::lang::compile(
+        b"
+        (def (Report (volatile acked 0) (minrtt +infinity) (timeout false)))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (:= Report.timeout Flow.was_timeout)
+        )
+        ",
+        &[],
+    )
+    .expect("compile");
+
+    let r = super::Report {
+        program_uid: sc.program_uid,
+        fields: vec![4242, 100, 1],
+    };
+
+    (r, sc)
+}
+
+#[test]
+fn test_report_field_handle() {
+    let (r, sc) = test_report();
+    let minrtt = sc.report_field("Report.minrtt").expect("report field");
+    assert_eq!(r.get(minrtt).unwrap(), 100);
+    assert_eq!(
+        r.get(minrtt).unwrap(),
+        r.get_field("Report.minrtt", &sc).unwrap()
+    );
+    assert!(sc.report_field("Ack.bytes_acked").is_none());
+    assert!(sc.report_field("Report.nonexistent").is_none());
+
+    let stale = super::Report {
+        program_uid: sc.program_uid + 1,
+        fields: vec![4242, 100, 1],
+    };
+    assert!(stale.get(minrtt).is_err());
+}
+
+#[test]
+fn test_report_iter() {
+    let (r, sc) = test_report();
+    let fields: Vec<(&str, u64, bool)> = r.iter(&sc).expect("iter").collect();
+    assert_eq!(
+        fields,
+        vec![
+            ("Report.acked", 4242, true),
+            ("Report.minrtt", 100, false),
+            ("Report.timeout", 1, false),
+        ]
+    );
+
+    let m = r.to_map(&sc).expect("to_map");
+    assert_eq!(m.len(), 3);
+    assert_eq!(m["Report.acked"], 4242);
+    assert_eq!(m["Report.timeout"], 1);
+}
+
+#[test]
+fn test_report_signed_field() {
+    let (_, sc) = ::lang::compile(
+        b"
+        (def (Report (signed grad 0) (prev 0)))
+        (when true
+            (:= Report.grad (- Flow.rtt_sample_us Report.prev))
+            (:= Report.prev Flow.rtt_sample_us)
+        )
+        ",
+        &[],
+    )
+    .expect("compile");
+
+    let r = super::Report {
+        program_uid: sc.program_uid,
+        fields: vec![-250i64 as u64, 1000],
+    };
+
+    assert_eq!(r.get_signed_field("Report.grad", &sc).unwrap(), -250);
+    assert!(r.get_signed_field("Report.prev", &sc).is_err());
+}
+
+struct EventAlg(crossbeam::channel::Sender<(&'static str, u32)>);
+
+struct EventFlow(crossbeam::channel::Sender<(&'static str, u32)>);
+
+impl super::Flow for EventFlow {
+    fn on_report(&mut self, sock_id: u32, _m: super::Report) {
+        self.0.send(("report", sock_id)).expect("event chan send");
+    }
+}
+
+impl<I: ipc::Ipc> super::CongAlg<I> for EventAlg {
+    type Flow = EventFlow;
+
+    fn name() -> &'static str {
+        "event"
+    }
+
+    fn datapath_programs(&self) -> ::fnv::FnvHashMap<&'static str, String> {
+        let mut h = ::fnv::FnvHashMap::default();
+        h.insert(
+            "TestProg",
+            "(def (Report.acked 0)) (when true (:= Report.acked Ack.bytes_acked))".to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, _control: super::Datapath<I>, info: super::DatapathInfo) -> Self::Flow {
+        self.0
+            .send(("create", info.sock_id))
+            .expect("event chan send");
+        EventFlow(self.0.clone())
+    }
+}
+
+#[test]
+fn test_record_replay() {
+    use ipc::record::{Recorder, Replay};
+
+    let timeout = std::time::Duration::from_secs(5);
+    let path = std::env::temp_dir().join(format!("portus-replay-{}.cap", std::process::id()));
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+        })
+        .expect("serialize")
+    };
+    let measure = |sid| {
+        serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 1,
+            num_fields: 1,
+            fields: vec![42],
+        })
+        .expect("serialize")
+    };
+    let msgs = vec![create(1), create(2), measure(2), measure(1), measure(2)];
+
+    // record a run
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded::<Vec<u8>>();
+    let sk = ipc::chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let (event_tx, event_rx) = crossbeam::channel::unbounded();
+    let h = super::spawn(
+        ipc::BackendBuilder {
+            sock: Recorder::new(sk, &path).expect("init recorder"),
+        },
+        super::Config::default(),
+        EventAlg(event_tx),
+    );
+    from_ccp.recv_timeout(timeout).expect("install message");
+    for m in &msgs {
+        to_ccp.send(m.clone()).expect("send to ccp");
+    }
+
+    let recorded: Vec<_> = (0..msgs.len())
+        .map(|_| event_rx.recv_timeout(timeout).expect("recorded event"))
+        .collect();
+    h.kill();
+    h.wait().expect("recording ccp exits cleanly");
+
+    // the replayed run sees the same flows and reports in the same order
+    let replay = Replay::open(&path)
+        .expect("open capture")
+        .speed(f64::INFINITY);
+    std::fs::remove_file(&path).expect("remove capture");
+    let finished = replay.finished();
+    let (event_tx, event_rx) = crossbeam::channel::unbounded();
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: replay },
+        super::Config::default(),
+        EventAlg(event_tx),
+    );
+    let replayed: Vec<_> = (0..msgs.len())
+        .map(|_| event_rx.recv_timeout(timeout).expect("replayed event"))
+        .collect();
+    assert_eq!(replayed, recorded);
+    assert!(finished.load(atomic::Ordering::SeqCst));
+    h.kill();
+    h.wait().expect("replaying ccp exits cleanly");
+}
+
+#[test]
+fn test_tcp_close_ends_run() {
+    use bytes::{ByteOrder, LittleEndian};
+    use std::io::Read;
+
+    let l = std::net::TcpListener::bind("127.0.0.1:0").expect("bind datapath");
+    let sk = ipc::tcp::Socket::<Blocking>::connect(l.local_addr().unwrap()).expect("init ccp");
+    let (mut dp, _) = l.accept().expect("accept ccp");
+    let (event_tx, _event_rx) = crossbeam::channel::unbounded();
+    let h = super::spawn(
+        ipc::BackendBuilder { sock: sk },
+        super::Config::default(),
+        EventAlg(event_tx),
+    );
+
+    // take the program install, then hang up
+    let mut len = [0u8; 4];
+    dp.read_exact(&mut len).expect("read install length");
+    let mut install = vec![0u8; LittleEndian::read_u32(&len) as usize];
+    dp.read_exact(&mut install).expect("read install");
+    drop(dp);
+
+    let (done_tx, done_rx) = crossbeam::channel::unbounded();
+    thread::spawn(move || done_tx.send(h.wait()).expect("send result"));
+    let res = done_rx
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("ccp exits once the datapath hangs up");
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_run_without_poll_fd_sleeps() {
+    use ipc::Nonblocking;
+    use std::sync::atomic::AtomicUsize;
+
+    // a nonblocking socket with no descriptor to wait on
+    struct CountingIpc(ipc::chan::Socket<Nonblocking>, Arc<AtomicUsize>);
+
+    impl ipc::Ipc for CountingIpc {
+        fn name() -> String {
+            String::from("counting")
+        }
+
+        fn send(&self, msg: &[u8]) -> super::Result<()> {
+            self.0.send(msg)
+        }
+
+        fn recv(&self, msg: &mut [u8]) -> super::Result<usize> {
+            self.1.fetch_add(1, atomic::Ordering::SeqCst);
+            self.0.recv(msg)
+        }
+
+        fn close(&mut self) -> super::Result<()> {
+            self.0.close()
+        }
+    }
+
+    let (to_dp, _from_ccp) = crossbeam::channel::unbounded();
+    let (_to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let recvs = Arc::new(AtomicUsize::new(0));
+    let (event_tx, _event_rx) = crossbeam::channel::unbounded();
+    let h = super::spawn(
+        ipc::BackendBuilder {
+            sock: CountingIpc(ipc::chan::Socket::new(to_dp, from_dp), recvs.clone()),
+        },
+        super::Config::default(),
+        EventAlg(event_tx),
+    );
+
+    thread::sleep(std::time::Duration::from_millis(300));
+    h.kill();
+    h.wait().expect("ccp exits cleanly");
+    // reading every millisecond, rather than spinning on recv
+    let n = recvs.load(atomic::Ordering::SeqCst);
+    assert!(n > 0 && n <= 400, "{} reads in 300ms", n);
+}
+
+#[test]
+fn test_runtime_poll() {
+    use ipc::Nonblocking;
+
+    let timeout = std::time::Duration::from_secs(5);
+    let dp = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind datapath");
+    dp.set_read_timeout(Some(timeout)).expect("set timeout");
+    let sk = ipc::udp::Socket::<Nonblocking>::new("127.0.0.1:0", dp.local_addr().unwrap())
+        .expect("init ccp");
+    dp.connect(sk.local_addr().expect("ccp addr"))
+        .expect("connect datapath");
+
+    let (event_tx, event_rx) = crossbeam::channel::unbounded();
+    let mut buf = [0u8; 1024];
+    let mut rt = super::Runtime::new(
+        ipc::BackendBuilder { sock: sk },
+        super::Config::default(),
+        EventAlg(event_tx),
+        &mut buf[..],
+    )
+    .expect("start runtime");
+    let mut install = [0u8; 1024];
+    dp.recv(&mut install).expect("install message");
+
+    // nothing has arrived, so polling returns at once
+    rt.poll().expect("poll");
+    assert!(event_rx.try_recv().is_err());
+
+    let mut dgram = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4242,
+    })
+    .expect("serialize");
+    dgram.extend(
+        serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid: 1,
+            num_fields: 1,
+            fields: vec![42],
+        })
+        .expect("serialize"),
+    );
+    dp.send(&dgram).expect("send to ccp");
+
+    // wait the way an event loop would
+    let mut pfd = ::libc::pollfd {
+        fd: rt.poll_fd().expect("udp sockets have a descriptor"),
+        events: ::libc::POLLIN,
+        revents: 0,
+    };
+    assert_eq!(unsafe { ::libc::poll(&mut pfd, 1, 5000) }, 1);
+    rt.poll().expect("poll");
+    assert_eq!(event_rx.try_recv(), Ok(("create", 1)));
+    assert_eq!(event_rx.try_recv(), Ok(("report", 1)));
+    assert!(event_rx.try_recv().is_err());
+    assert_eq!(rt.num_flow_panics(), 0);
+}
@@ -0,0 +1,185 @@
+//! Checks that sending messages to the datapath does not allocate. This counts allocations with
+//! its own global allocator, so it is a test target of its own rather than part of the library's
+//! tests.
+
+#![feature(test)]
+
+extern crate crossbeam;
+extern crate fnv;
+extern crate portus;
+extern crate test;
+
+use fnv::FnvHashMap as HashMap;
+use portus::ipc::{self, Blocking, Ipc};
+use portus::lang::{Reg, Type};
+use portus::{serialize, CongAlg, Datapath, DatapathInfo, DatapathTrait, Flow, Report};
+use std::cell::Cell;
+use test::Bencher;
+
+// Counts the allocations each thread makes, so tests can check that a path does not allocate.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCS.try_with(|a| a.set(a.get() + 1)).unwrap_or(());
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+// The number of allocations `f` makes on this thread.
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCS.with(|a| a.get());
+    f();
+    ALLOCS.with(|a| a.get()) - before
+}
+
+fn update_field_msg() -> serialize::update_field::Msg {
+    serialize::update_field::Msg {
+        sid: 42,
+        num_fields: 2,
+        fields: vec![
+            (Reg::Implicit(4, Type::Num(None)), 14480),
+            (Reg::Control(0, Type::Num(None)), 1448),
+        ],
+    }
+}
+
+#[test]
+fn serialize_to_buf_no_alloc() {
+    let m = update_field_msg();
+    let mut buf = [0u8; 128];
+    let mut len = 0;
+    assert_eq!(
+        allocations(|| {
+            len = serialize::serialize_to_buf(&m, &mut buf[..]).expect("serialize");
+        }),
+        0
+    );
+    assert_eq!(
+        &buf[..len],
+        &serialize::serialize(&m).expect("serialize")[..]
+    );
+}
+
+struct UpdatingAlg(crossbeam::channel::Sender<(usize, usize)>);
+
+struct UpdatingFlow;
+
+impl Flow for UpdatingFlow {
+    fn on_report(&mut self, _sock_id: u32, _m: Report) {}
+}
+
+impl<I: Ipc> CongAlg<I> for UpdatingAlg {
+    type Flow = UpdatingFlow;
+
+    fn name() -> &'static str {
+        "updating"
+    }
+
+    fn datapath_programs(&self) -> HashMap<&'static str, String> {
+        let mut h = HashMap::default();
+        h.insert(
+            "TestProg",
+            "(def (Report.acked 0) (Control.state 0)) (when true (:= Report.acked Ack.bytes_acked))"
+                .to_owned(),
+        );
+        h
+    }
+
+    // Sends the number of allocations made by updating fields, and by changing the program
+    // besides copying its scope to return, once the datapath handle has sent a message of each
+    // kind.
+    fn new_flow(&self, mut control: Datapath<I>, _info: DatapathInfo) -> UpdatingFlow {
+        let fields = [("Control.state", 1)];
+        let sc = control
+            .set_program("TestProg", Some(&fields[..]))
+            .expect("set program");
+        let update = [("Cwnd", 14480), ("Control.state", 2)];
+        control.update_field(&sc, &update).expect("update fields");
+
+        let update_allocs = allocations(|| {
+            control.update_field(&sc, &update).expect("update fields");
+        });
+        let set_allocs = allocations(|| {
+            control
+                .set_program("TestProg", Some(&fields[..]))
+                .expect("set program");
+        }) - allocations(|| drop(sc.clone()));
+        self.0
+            .send((update_allocs, set_allocs))
+            .expect("send allocations");
+        UpdatingFlow
+    }
+}
+
+#[test]
+fn datapath_sends_without_alloc() {
+    let (to_ccp, from_dp) = crossbeam::channel::unbounded();
+    let (to_dp, from_ccp) = crossbeam::channel::unbounded::<Vec<u8>>();
+    let (allocs_tx, allocs_rx) = crossbeam::channel::unbounded();
+    let h = portus::spawn(
+        ipc::BackendBuilder {
+            sock: ipc::chan::Socket::<Blocking>::new(to_dp, from_dp),
+        },
+        portus::Config {
+            // hold the messages, since the channel allocates to send them
+            send_batch: Some(1500),
+            ..Default::default()
+        },
+        UpdatingAlg(allocs_tx),
+    );
+
+    let timeout = std::time::Duration::from_secs(5);
+    from_ccp.recv_timeout(timeout).expect("install message");
+    to_ccp
+        .send(
+            serialize::serialize(&serialize::create::Msg {
+                sid: 1,
+                init_cwnd: 14480,
+                mss: 1448,
+                src_ip: 0,
+                src_port: 4242,
+                dst_ip: 0,
+                dst_port: 4242,
+            })
+            .expect("serialize"),
+        )
+        .expect("send create");
+
+    assert_eq!(
+        allocs_rx.recv_timeout(timeout).expect("allocations"),
+        (0, 0)
+    );
+    h.kill();
+    h.wait().expect("ccp exits cleanly");
+}
+
+#[bench]
+fn bench_update_field_serialize(b: &mut Bencher) {
+    let m = update_field_msg();
+    b.iter(|| serialize::serialize(&m).expect("serialize"));
+}
+
+#[bench]
+fn bench_update_field_serialize_to_buf(b: &mut Bencher) {
+    let m = update_field_msg();
+    let mut buf = [0u8; 128];
+    let mut allocs = 0;
+    b.iter(|| {
+        allocs += allocations(|| {
+            serialize::serialize_to_buf(&m, &mut buf[..]).expect("serialize");
+        });
+    });
+    assert_eq!(allocs, 0);
+}
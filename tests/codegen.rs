@@ -0,0 +1,97 @@
+//! Compiles the accessors `lang::gen_accessors` generates, and checks the checked-in copy in
+//! `codegen/accessors.rs` is what it generates for `codegen/accessors.ccp` today.
+
+extern crate portus;
+
+use portus::lang::Scope;
+use portus::{DatapathTrait, Report};
+use std::cell::RefCell;
+
+include!("codegen/accessors.rs");
+
+const SRC: &str = include_str!("codegen/accessors.ccp");
+
+#[test]
+fn generated_code_is_current() {
+    let code = portus::lang::gen_accessors("Test", SRC.as_bytes()).expect("generate accessors");
+    assert!(
+        code == include_str!("codegen/accessors.rs"),
+        "tests/codegen/accessors.rs is stale; replace it with:\n{}",
+        code
+    );
+}
+
+// Records the updates it is asked to send.
+#[derive(Default)]
+struct FakeDatapath(RefCell<Vec<(String, u64)>>);
+
+impl DatapathTrait for FakeDatapath {
+    fn get_sock_id(&self) -> u32 {
+        1
+    }
+
+    fn set_program(
+        &mut self,
+        _program_name: &'static str,
+        _fields: Option<&[(&str, u64)]>,
+    ) -> portus::Result<Scope> {
+        unimplemented!()
+    }
+
+    fn update_field(&self, sc: &Scope, update: &[(&str, u64)]) -> portus::Result<()> {
+        for &(var, v) in update {
+            assert!(sc.get(var).is_some(), "{} is not in the scope", var);
+            self.0.borrow_mut().push((var.to_owned(), v));
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn control_sends_typed_updates() {
+    let (_, sc) = portus::lang::compile(SRC.as_bytes(), &[]).expect("compile");
+    let dp = FakeDatapath::default();
+    TestControl::new()
+        .thresh(20)
+        .enabled(false)
+        .offset(-1)
+        .r#type(3)
+        .cwnd(14480)
+        .rate(125_000)
+        .apply(&dp, &sc)
+        .expect("apply");
+
+    let sent = dp.0.into_inner();
+    assert_eq!(
+        sent,
+        vec![
+            ("Control.thresh".to_owned(), 20),
+            ("Control.enabled".to_owned(), 0),
+            ("Control.offset".to_owned(), -1i64 as u64),
+            ("type".to_owned(), 3),
+            ("Cwnd".to_owned(), 14480),
+            ("Rate".to_owned(), 125_000),
+        ]
+    );
+}
+
+#[test]
+fn report_reader_type_checks() {
+    let from_report: fn(&Report, &Scope) -> portus::Result<TestReport> = TestReport::from_report;
+    let _ = from_report;
+}
+
+#[test]
+fn reserved_names_are_rejected() {
+    for name in &["crate", "self", "Self", "super"] {
+        let src = format!(
+            "(def (Report (ok 0)) (Control.{} 0)) (when true (report))",
+            name
+        );
+        match portus::lang::gen_accessors("Test", src.as_bytes()) {
+            Err(e) => assert!(e.0.contains(&format!("Control.{}", name)), "{}", e.0),
+            Ok(code) => panic!("{} does not compile as a setter:\n{}", name, code),
+        }
+    }
+}
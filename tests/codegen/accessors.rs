@@ -0,0 +1,78 @@
+/// Typed view of a `Report` from datapath program `Test`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TestReport {
+    pub acked: u64,
+    pub timeout: bool,
+    pub grad: i64,
+    pub minrtt: u64,
+}
+
+impl TestReport {
+    /// Read every field of the `Report` struct, checking that `r` matches `sc`.
+    pub fn from_report(r: &::portus::Report, sc: &::portus::lang::Scope) -> ::portus::Result<Self> {
+        Ok(TestReport {
+            acked: r.get_field("Report.acked", sc)?,
+            timeout: r.get_field("Report.timeout", sc)? != 0,
+            grad: r.get_signed_field("Report.grad", sc)?,
+            minrtt: r.get_field("Report.minrtt", sc)?,
+        })
+    }
+}
+
+/// Typed updates to the mutable fields of datapath program `Test`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestControl {
+    fields: Vec<(&'static str, u64)>,
+}
+
+impl TestControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `Control.thresh`.
+    pub fn thresh(mut self, v: u64) -> Self {
+        self.fields.push(("Control.thresh", v));
+        self
+    }
+
+    /// Set `Control.enabled`.
+    pub fn enabled(mut self, v: bool) -> Self {
+        self.fields.push(("Control.enabled", u64::from(v)));
+        self
+    }
+
+    /// Set `Control.offset`.
+    pub fn offset(mut self, v: i64) -> Self {
+        self.fields.push(("Control.offset", v as u64));
+        self
+    }
+
+    /// Set `type`.
+    pub fn r#type(mut self, v: u64) -> Self {
+        self.fields.push(("type", v));
+        self
+    }
+
+    /// Set `Cwnd`.
+    pub fn cwnd(mut self, v: u64) -> Self {
+        self.fields.push(("Cwnd", v));
+        self
+    }
+
+    /// Set `Rate`.
+    pub fn rate(mut self, v: u64) -> Self {
+        self.fields.push(("Rate", v));
+        self
+    }
+
+    /// The updates, in the form accepted by `DatapathTrait::update_field`.
+    pub fn fields(&self) -> &[(&'static str, u64)] {
+        &self.fields[..]
+    }
+
+    /// Send the updates to the datapath.
+    pub fn apply<D: ::portus::DatapathTrait>(&self, dp: &D, sc: &::portus::lang::Scope) -> ::portus::Result<()> {
+        dp.update_field(sc, &self.fields[..])
+    }
+}
@@ -95,6 +95,7 @@ fn start_ccp<T: IntegrationTest + 'static + Send>(
         b,
         portus::Config {
             logger: Some(log.clone()),
+            ..Default::default()
         },
         TestBaseConfig(tx, Some(log.clone()), PhantomData::<T>),
     )
@@ -45,7 +45,7 @@ impl IntegrationTest for TestTwoFlows {
     fn install_test<D: DatapathTrait>(&self, dp: &mut D) -> Option<Scope> {
         let sc = dp.set_program("TestTwoFlows", None).ok()?;
         let flow_num = dp.get_sock_id();
-        dp.update_field(&sc, &[("Control.number", flow_num * 10)])
+        dp.update_field(&sc, &[("Control.number", u64::from(flow_num) * 10)])
             .unwrap();
         Some(sc)
     }
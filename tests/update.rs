@@ -48,7 +48,7 @@ impl IntegrationTest for TestUpdateFields {
     fn install_test<D: DatapathTrait>(&self, dp: &mut D) -> Option<Scope> {
         // fold function that only reports when Cwnd is set to 42
         let sc = dp.set_program("TestUpdateFields", None).ok()?;
-        dp.update_field(&sc, &[("Cwnd", 42u32), ("Rate", 10u32)])
+        dp.update_field(&sc, &[("Cwnd", 42u64), ("Rate", 10u64)])
             .unwrap();
         Some(sc)
     }